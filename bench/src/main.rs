@@ -0,0 +1,270 @@
+//! Benchmark harness: runs guest programs for a fixed number of instructions and reports
+//! MIPS, block cache statistics and the host time spent per subsystem.
+//! With `--json`, results are written one JSON object per line, and `--baseline` compares
+//! against such a file from an earlier commit.
+
+mod programs;
+
+use clap::Parser;
+use rvemu_core::emulator::{Emulator, ExitReason, Profile};
+use rvemu_core::insn::FusionKind;
+#[cfg(feature = "jit")]
+use rvemu_core::jit::JitMode;
+use rvemu_core::{syscall, syscall::SyscallHandler, Error, InsnSet, Result};
+use std::{collections::HashMap, fmt::Write as _, path::PathBuf, time::Instant};
+
+use programs::PROGRAMS;
+
+#[derive(Parser, Debug)]
+#[command(name = "rvemu-bench", about = "Measures the speed of the emulator", long_about = None)]
+struct Args {
+    /// Instructions each program runs
+    #[arg(long, default_value = "50000000")]
+    insns: u64,
+    /// Runs per program, the fastest one is reported
+    #[arg(long, default_value = "3")]
+    runs: usize,
+    /// Only runs programs whose name contains this
+    #[arg(long)]
+    filter: Option<String>,
+    /// Directory of ELF programs to run besides the built-in ones
+    #[arg(long, default_value = "testprogs")]
+    testprogs: PathBuf,
+    /// Writes the results to this file, one JSON object per program
+    #[arg(long)]
+    json: Option<PathBuf>,
+    /// Compares MIPS against the JSON results of an earlier run
+    #[arg(long)]
+    baseline: Option<PathBuf>,
+    /// Stored with the JSON results, e.g. the commit measured
+    #[arg(long, default_value = "")]
+    label: String,
+    /// Runs hot blocks as native code, needs the jit feature
+    #[arg(long)]
+    jit: bool,
+}
+
+struct Workload {
+    name: String,
+    elf: Vec<u8>,
+    /// Built-in programs make Linux syscalls, `testprogs` are built against minilib.
+    linux: bool,
+}
+
+#[derive(Debug)]
+struct Report {
+    name: String,
+    exit: String,
+    insns: u64,
+    secs: f64,
+    block_hits: u64,
+    block_misses: u64,
+    block_chained: u64,
+    jit_compiled: u64,
+    /// Instruction pairs executed fused.
+    fused: u64,
+    /// From a separate run, timing every subsystem slows the emulator down.
+    profile: Profile,
+}
+
+impl Report {
+    fn mips(&self) -> f64 {
+        self.insns as f64 / self.secs / 1e6
+    }
+
+    /// Block entries that needed no translation.
+    fn hit_rate(&self) -> f64 {
+        let hits = self.block_hits + self.block_chained;
+        hits as f64 / (hits + self.block_misses).max(1) as f64
+    }
+
+    fn to_json(&self, label: &str) -> String {
+        let mut json = String::new();
+        write!(json, "{{\"label\":\"{}\",\"name\":\"{}\",\"exit\":\"{}\"",
+            escape(label), escape(&self.name), escape(&self.exit)).unwrap();
+        write!(json, ",\"insns\":{},\"secs\":{:.6},\"mips\":{:.3}", self.insns, self.secs, self.mips()).unwrap();
+        write!(json, ",\"block_hits\":{},\"block_misses\":{},\"block_chained\":{},\"hit_rate\":{:.6},\"jit_compiled\":{},\"fused\":{}",
+            self.block_hits, self.block_misses, self.block_chained, self.hit_rate(), self.jit_compiled, self.fused).unwrap();
+        let profile = &self.profile;
+        write!(json, ",\"execute_secs\":{:.6},\"translate_secs\":{:.6},\"syscall_secs\":{:.6},\"schedule_secs\":{:.6}}}",
+            profile.execute.as_secs_f64(), profile.translate.as_secs_f64(),
+            profile.syscall.as_secs_f64(), profile.schedule.as_secs_f64()).unwrap();
+        json
+    }
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+/// Value of 'key' in a line written by `Report::to_json`.
+fn json_field<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let start = line.find(&format!("\"{}\":", key))? + key.len() + 3;
+    let rest = &line[start..];
+    match rest.strip_prefix('"') {
+        Some(rest) => rest.find('"').map(|end| &rest[..end]),
+        None => rest.find([',', '}']).map(|end| &rest[..end]),
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    rvemu_core::log_init(rvemu_core::Level::Error);
+
+    let mut workloads: Vec<Workload> = PROGRAMS.iter()
+        .map(|program| Workload { name: program.name.to_string(), elf: program.elf(), linux: true })
+        .collect();
+    workloads.extend(testprogs(&args.testprogs));
+    if let Some(filter) = &args.filter {
+        workloads.retain(|workload| workload.name.contains(filter.as_str()));
+    }
+
+    let baseline = match &args.baseline {
+        Some(path) => read_baseline(path)?,
+        None => HashMap::new(),
+    };
+
+    println!("{:<16} {:>12} {:>8} {:>9} {:>7} {:>8} {:>9} {:>10} {:>9} {:>9}  exit",
+        "program", "insns", "MIPS", "change", "hit%", "execute", "translate", "syscall", "schedule", "compiled");
+    let mut json = String::new();
+    for workload in &workloads {
+        let report = match bench(&args, workload) {
+            Ok(report) => report,
+            Err(e) => {
+                eprintln!("[rvemu-bench] {}: {}", workload.name, e);
+                continue;
+            },
+        };
+        let change = match baseline.get(&report.name) {
+            Some(&mips) => format!("{:+.1}%", (report.mips() / mips - 1.0) * 100.0),
+            None => "-".to_string(),
+        };
+        let profile = &report.profile;
+        println!("{:<16} {:>12} {:>8.1} {:>9} {:>7.2} {:>7.3}s {:>8.3}s {:>9.3}s {:>8.3}s {:>9}  {}",
+            report.name, report.insns, report.mips(), change, report.hit_rate() * 100.0,
+            profile.execute.as_secs_f64(), profile.translate.as_secs_f64(),
+            profile.syscall.as_secs_f64(), profile.schedule.as_secs_f64(),
+            report.jit_compiled, report.exit);
+        json.push_str(&report.to_json(&args.label));
+        json.push('\n');
+    }
+
+    if let Some(path) = &args.json {
+        std::fs::write(path, json).map_err(|e| Error::IoError(e, path.to_string_lossy().to_string()))?;
+    }
+    Ok(())
+}
+
+/// Every ELF in 'dir', missing or empty files are skipped.
+fn testprogs(dir: &PathBuf) -> Vec<Workload> {
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return vec![];
+    };
+    let mut paths: Vec<PathBuf> = entries.flatten().map(|entry| entry.path()).filter(|path| path.is_file()).collect();
+    paths.sort();
+    paths.into_iter()
+        .filter_map(|path| {
+            let elf = std::fs::read(&path).ok().filter(|elf| elf.starts_with(b"\x7fELF"))?;
+            let name = path.file_name()?.to_string_lossy().to_string();
+            Some(Workload { name, elf, linux: false })
+        })
+        .collect()
+}
+
+fn read_baseline(path: &PathBuf) -> Result<HashMap<String, f64>> {
+    let text = std::fs::read_to_string(path).map_err(|e| Error::IoError(e, path.to_string_lossy().to_string()))?;
+    Ok(text.lines()
+        .filter_map(|line| {
+            let name = json_field(line, "name")?;
+            let mips = json_field(line, "mips")?.parse().ok()?;
+            Some((name.to_string(), mips))
+        })
+        .collect())
+}
+
+fn emulator(args: &Args, workload: &Workload, profile: bool) -> Result<Emulator> {
+    let syscall: Box<dyn SyscallHandler> = match workload.linux {
+        true => Box::new(syscall::Linux::new()),
+        false => Box::new(syscall::Minilib),
+    };
+    let mut builder = Emulator::new()
+        .syscall(syscall)
+        .decoder(InsnSet::I)
+        .decoder(InsnSet::A)
+        .decoder(InsnSet::Zifencei);
+    if profile {
+        builder = builder.profile();
+    }
+    #[cfg(feature = "jit")]
+    if args.jit {
+        builder = builder.jit(JitMode::Native);
+    }
+    #[cfg(not(feature = "jit"))]
+    if args.jit {
+        return Err(Error::Other("rvemu-bench was built without the jit feature".to_string()));
+    }
+    let mut emulator = builder.build()?;
+    emulator.load_elf(&workload.elf)?;
+    Ok(emulator)
+}
+
+/// Runs 'workload' `args.runs` times for `args.insns` instructions, and once more with profiling.
+fn bench(args: &Args, workload: &Workload) -> Result<Report> {
+    let mut best: Option<Report> = None;
+    for _ in 0..args.runs.max(1) {
+        let mut emulator = emulator(args, workload, false)?;
+        let start = Instant::now();
+        let exit = match emulator.run_for(args.insns)? {
+            ExitReason::BudgetExhausted => "budget".to_string(),
+            ExitReason::Exited(code) => format!("exited({})", code),
+            reason => format!("{:?}", reason),
+        };
+        let secs = start.elapsed().as_secs_f64();
+        let harts = emulator.harts();
+        let report = Report {
+            name: workload.name.clone(),
+            exit,
+            insns: emulator.retired(),
+            secs,
+            block_hits: harts.iter().map(|hart| hart.blocks.hits()).sum(),
+            block_misses: harts.iter().map(|hart| hart.blocks.misses()).sum(),
+            block_chained: harts.iter().map(|hart| hart.blocks.chained()).sum(),
+            jit_compiled: harts.iter().map(|hart| hart.blocks.compiled()).sum(),
+            fused: harts.iter().flat_map(|hart| FusionKind::ALL.map(|kind| hart.fused(kind))).sum(),
+            profile: Profile::default(),
+        };
+        if best.as_ref().is_none_or(|best| report.secs < best.secs) {
+            best = Some(report);
+        }
+    }
+    let mut report = best.unwrap();
+
+    let mut emulator = emulator(args, workload, true)?;
+    emulator.run_for(args.insns)?;
+    report.profile = emulator.profile().unwrap_or_default();
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_programs() {
+        rvemu_core::log_init(rvemu_core::Level::Error);
+
+        let args = Args::parse_from(["rvemu-bench", "--insns", "100000", "--runs", "1"]);
+        for program in PROGRAMS {
+            let workload = Workload { name: program.name.to_string(), elf: program.elf(), linux: true };
+            let report = bench(&args, &workload).unwrap();
+            assert_eq!((report.exit.as_str(), report.insns), ("budget", 100000), "{}", program.name);
+            assert!(report.hit_rate() > 0.9, "{}", program.name);
+            assert!(report.profile.execute > report.profile.translate, "{}", program.name);
+
+            let json = report.to_json("HEAD");
+            assert_eq!(json_field(&json, "name"), Some(program.name));
+            assert_eq!(json_field(&json, "insns"), Some("100000"));
+            assert_eq!(json_field(&json, "label"), Some("HEAD"));
+        }
+    }
+}
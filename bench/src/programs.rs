@@ -0,0 +1,147 @@
+//! Guest programs built into the benchmark, and the ELF images they are loaded from.
+//! Every program loops forever, so a run always retires exactly the instructions it is given.
+
+/// Where the code of a program is loaded.
+const TEXT_BASE: u64 = 0x10000;
+/// Where the zeroed data of a program is mapped, if it has any.
+const BSS_BASE: u64 = 0x20000;
+
+const PT_LOAD: u32 = 1;
+const PF_X: u32 = 1;
+const PF_W: u32 = 2;
+const PF_R: u32 = 4;
+const EM_RISCV: u16 = 243;
+
+#[derive(Debug)]
+pub struct Program {
+    pub name: &'static str,
+    code: &'static [u32],
+    /// Bytes of zeroed, writable memory at `BSS_BASE`.
+    bss: usize,
+}
+
+pub const PROGRAMS: &[Program] = &[
+    // Counts the primes below 20000 by trial subtraction, branch heavy.
+    Program { name: "prime", bss: 0, code: &[
+        0x00200413, // 0: li s0, 2
+        0x00000493, // 4: li s1, 0
+        0x00005937, // 8: lui s2, 5
+        0x00200293, // c: li t0, 2
+        0x0282d063, // 10: bge t0, s0, 0x30 <prime>
+        0x00040313, // 14: mv t1, s0
+        0x00534663, // 18: blt t1, t0, 0x24 <rem>
+        0x40530333, // 1c: sub t1, t1, t0
+        0xff9ff06f, // 20: j 0x18 <sub>
+        0x00030863, // 24: beqz t1, 0x34 <composite>
+        0x00128293, // 28: addi t0, t0, 1
+        0xfe5ff06f, // 2c: j 0x10 <try>
+        0x00148493, // 30: addi s1, s1, 1
+        0x00140413, // 34: addi s0, s0, 1
+        0xfd244ae3, // 38: blt s0, s2, 0xc <next>
+        0xfc5ff06f, // 3c: j 0x0 <restart>
+    ]},
+    // Sweeps a 64 KiB array with loads and stores of every width.
+    Program { name: "memory", bss: 0x10000, code: &[
+        0x000202b7, // 0: lui t0, 32
+        0x00000413, // 4: li s0, 0
+        0x7ff47313, // 8: andi t1, s0, 2047
+        0x00531313, // c: slli t1, t1, 5
+        0x00530333, // 10: add t1, t1, t0
+        0x00033383, // 14: ld t2, 0(t1)
+        0x008383b3, // 18: add t2, t2, s0
+        0x00733023, // 1c: sd t2, 0(t1)
+        0x00832e03, // 20: lw t3, 8(t1)
+        0x00732423, // 24: sw t2, 8(t1)
+        0x01034e83, // 28: lbu t4, 16(t1)
+        0x01c308a3, // 2c: sb t3, 17(t1)
+        0x00140413, // 30: addi s0, s0, 1
+        0xfd5ff06f, // 34: j 0x8 <loop>
+    ]},
+    // Computes fib(20) recursively, calls, returns and stack traffic.
+    Program { name: "calls", bss: 0, code: &[
+        0x01400513, // 0: li a0, 20
+        0x008000ef, // 4: jal 0xc <fib>
+        0xff9ff06f, // 8: j 0x0 <_start>
+        0x00200293, // c: li t0, 2
+        0x04554063, // 10: blt a0, t0, 0x50 <done>
+        0xfe010113, // 14: addi sp, sp, -32
+        0x00113c23, // 18: sd ra, 24(sp)
+        0x00813823, // 1c: sd s0, 16(sp)
+        0x00913423, // 20: sd s1, 8(sp)
+        0x00050413, // 24: mv s0, a0
+        0xfff50513, // 28: addi a0, a0, -1
+        0xfe1ff0ef, // 2c: jal 0xc <fib>
+        0x00050493, // 30: mv s1, a0
+        0xffe40513, // 34: addi a0, s0, -2
+        0xfd5ff0ef, // 38: jal 0xc <fib>
+        0x00950533, // 3c: add a0, a0, s1
+        0x01813083, // 40: ld ra, 24(sp)
+        0x01013403, // 44: ld s0, 16(sp)
+        0x00813483, // 48: ld s1, 8(sp)
+        0x02010113, // 4c: addi sp, sp, 32
+        0x00008067, // 50: ret
+    ]},
+    // Address and constant materialisation as compilers emit it, pairs that run fused.
+    Program { name: "idioms", bss: 0, code: &[
+        0x12345537, // 0: lui a0, 0x12345
+        0x67850513, // 4: addi a0, a0, 0x678
+        0x02051593, // 8: slli a1, a0, 32
+        0x0205d593, // c: srli a1, a1, 32
+        0x00000297, // 10: auipc t0, 0
+        0x0002b603, // 14: ld a2, 0(t0)
+        0x000016b7, // 18: lui a3, 1
+        0xffe6869b, // 1c: addiw a3, a3, -2
+        0x00c58733, // 20: add a4, a1, a2
+        0x00d70733, // 24: add a4, a4, a3
+        0x00000317, // 28: auipc t1, 0
+        0xfd830067, // 2c: jr -40(t1)
+    ]},
+    // Calls getpid, the cost of a round trip through the syscall handler.
+    Program { name: "syscall", bss: 0, code: &[
+        0x0ac00893, // 0: li a7, 172
+        0x00000073, // 4: ecall
+        0xffdff06f, // 8: j 0x4 <loop>
+    ]},
+];
+
+impl Program {
+    /// A static ELF with the code at `TEXT_BASE` as entry point, and no section headers.
+    pub fn elf(&self) -> Vec<u8> {
+        let text: Vec<u8> = self.code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut segments = vec![(TEXT_BASE, text.len(), text.len(), PF_R | PF_X)];
+        if self.bss > 0 {
+            segments.push((BSS_BASE, 0, self.bss, PF_R | PF_W));
+        }
+        let text_offset = 0x1000u64;
+
+        let mut elf = vec![];
+        elf.extend(b"\x7fELF");
+        // 64-bit, little endian, version 1, System V
+        elf.extend([2, 1, 1, 0]);
+        elf.extend([0; 8]);
+        elf.extend(2u16.to_le_bytes()); // ET_EXEC
+        elf.extend(EM_RISCV.to_le_bytes());
+        elf.extend(1u32.to_le_bytes());
+        elf.extend(TEXT_BASE.to_le_bytes());
+        elf.extend(64u64.to_le_bytes()); // e_phoff
+        elf.extend(0u64.to_le_bytes()); // e_shoff
+        elf.extend(0u32.to_le_bytes()); // e_flags
+        elf.extend(64u16.to_le_bytes()); // e_ehsize
+        elf.extend(56u16.to_le_bytes()); // e_phentsize
+        elf.extend((segments.len() as u16).to_le_bytes());
+        elf.extend([0; 6]); // no section headers
+        for (vaddr, filesz, memsz, flags) in segments {
+            elf.extend(PT_LOAD.to_le_bytes());
+            elf.extend(flags.to_le_bytes());
+            elf.extend(if filesz > 0 { text_offset } else { 0 }.to_le_bytes());
+            elf.extend(vaddr.to_le_bytes());
+            elf.extend(vaddr.to_le_bytes());
+            elf.extend((filesz as u64).to_le_bytes());
+            elf.extend((memsz as u64).to_le_bytes());
+            elf.extend(0x1000u64.to_le_bytes());
+        }
+        elf.resize(text_offset as usize, 0);
+        elf.extend(text);
+        elf
+    }
+}
@@ -1,8 +1,11 @@
 #![allow(unused)]
 
 use clap::{Args, Parser, Subcommand, ValueEnum};
-use rvemu_core::{elf, emulator::Emulator, syscall, Error, InsnSet, Result};
-use std::{collections::HashSet, hash::Hash, io::Read, path::PathBuf};
+use rvemu_core::{coredump, elf, emulator::{Emulator, ExitReason}, layout::AddressLayout, syscall, syscall::SyscallHandler, Error, InsnSet, Result};
+use std::{collections::HashSet, hash::Hash, io::Read, path::PathBuf, time::{Duration, Instant}};
+
+/// Exit status when the guest runs out of instructions or time, as in timeout(1)
+const EXIT_BUDGET_EXHAUSTED: i32 = 124;
 
 #[derive(Parser, Debug)]
 #[command(name = "rvemu", author = "doruche", version = "0.1.0",
@@ -33,6 +36,48 @@ pub struct RunArgs {
     /// Stack size in kb (default: 8 MiB)
     #[arg(long, default_value = "8192")]
     stack_size: usize, 
+    /// Size in kb the stack may grow to on demand (default: the stack size)
+    #[arg(long)]
+    stack_limit: Option<usize>,
+    /// Stop after this many instructions, summed over all harts
+    #[arg(long)]
+    max_insns: Option<u64>,
+    /// Stop after this many seconds of host time
+    #[arg(long)]
+    timeout: Option<f64>,
+    /// Write every instruction executed to this file
+    #[arg(long)]
+    trace: Option<PathBuf>,
+    /// Write a checkpoint every this many instructions, summed over all harts
+    #[arg(long)]
+    checkpoint_every: Option<u64>,
+    /// File to write checkpoints to (default: the elf path with .ckpt appended)
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Resume from a checkpoint taken of the same program with the same options
+    #[arg(long)]
+    resume: Option<PathBuf>,
+    /// End of the main stack (default: 0x80000000)
+    #[arg(long, value_parser = parse_addr)]
+    stack_top: Option<u64>,
+    /// Address mmap places mappings below (default: 0x4000000000)
+    #[arg(long, value_parser = parse_addr)]
+    mmap_base: Option<u64>,
+    /// Start of the heap (default: right above the program)
+    #[arg(long, value_parser = parse_addr)]
+    heap_start: Option<u64>,
+    /// Where position-independent programs are loaded (default: 0x2aaaaaa000)
+    #[arg(long, value_parser = parse_addr)]
+    pie_base: Option<u64>,
+    /// Randomise the address-space layout with a seed picked at random
+    #[arg(long)]
+    aslr: bool,
+    /// Randomise the address-space layout with this seed, to replay a run
+    #[arg(long)]
+    aslr_seed: Option<u64>,
+    /// Write an ELF core file here when the program crashes
+    #[arg(long)]
+    core: Option<PathBuf>,
     /// Arguments to pass to the program
     args: Option<Vec<String>>,
 }
@@ -48,7 +93,7 @@ pub enum Syscall {
 fn main() -> Result<()> {
     let cli = Cli::parse();
 
-    rvemu_core::log::log_init(rvemu_core::log::Level::Debug);
+    rvemu_core::log_init(rvemu_core::Level::Debug);
 
     match cli.command {
         Commands::Run(args) => cmd_run(args),
@@ -67,7 +112,7 @@ fn cmd_run(args: RunArgs) -> Result<()> {
                     Some(set) => {
                         sets.insert(set);
                     },
-                    None => return Err(Error::Unimplemented),
+                    None => return Err(Error::Other(format!("Unknown ISA: {}", isa))),
                 }
             }
             sets
@@ -78,11 +123,41 @@ fn cmd_run(args: RunArgs) -> Result<()> {
     };
 
     let stack_size = args.stack_size * 1024;
-    let syscall = match args.syscall {
-        Syscall::Glibc => return Err(Error::Unimplemented),
-        Syscall::Newlib => return Err(Error::Unimplemented),
+    let stack_limit = args.stack_limit.map_or(stack_size, |limit| limit * 1024);
+    let syscall: Box<dyn SyscallHandler> = match args.syscall {
+        Syscall::Glibc => Box::new(syscall::Linux::new().program(&path_str)),
+        Syscall::Newlib => Box::new(syscall::Newlib),
         Syscall::Minilib => Box::new(syscall::Minilib),
     };
+    let max_insns = args.max_insns.unwrap_or(u64::MAX);
+    let deadline = match args.timeout {
+        Some(secs) => Some(Instant::now() + Duration::try_from_secs_f64(secs)
+            .map_err(|e| Error::Other(format!("Invalid timeout: {}", e)))?),
+        None => None,
+    };
+    let trace = args.trace;
+    let checkpoint_every = args.checkpoint_every.filter(|&every| every > 0);
+    let checkpoint_path = args.checkpoint.unwrap_or_else(|| {
+        let mut path = path.clone().into_os_string();
+        path.push(".ckpt");
+        path.into()
+    });
+    let resume = args.resume;
+    let core = args.core;
+    let defaults = AddressLayout::default();
+    let mut layout = AddressLayout {
+        stack_top: args.stack_top.unwrap_or(defaults.stack_top),
+        mmap_base: args.mmap_base.unwrap_or(defaults.mmap_base),
+        heap_start: args.heap_start,
+        pie_base: args.pie_base.unwrap_or(defaults.pie_base),
+        aslr_seed: args.aslr_seed,
+    };
+    if args.aslr && layout.aslr_seed.is_none() {
+        let seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |time| time.as_nanos() as u64);
+        println!("[rvemu] address-space layout randomised with --aslr-seed {}", seed);
+        layout = layout.aslr(seed);
+    }
     let args = args.args.unwrap_or_default();
 
     let mut builder = Emulator::new();
@@ -90,26 +165,99 @@ fn cmd_run(args: RunArgs) -> Result<()> {
     for isa in insn_sets {
         builder = builder.decoder(isa);
     }
-    builder = builder.syscall(syscall).stack_size(stack_size);
+    builder = builder.syscall(syscall).stack_size(stack_size).stack_limit(stack_limit).layout(layout);
+    if let Some(path) = &trace {
+        let file = std::fs::File::create(path)
+            .map_err(|e| Error::IoError(e, path.to_string_lossy().to_string()))?;
+        builder = builder.trace_insns(Box::new(std::io::BufWriter::new(file)));
+    }
     let mut emulator = builder.build()?;
     
     let mut file = std::fs::File::open(path)
-        .map_err(|e| Error::IoError(e))?;
+        .map_err(|e| Error::IoError(e, path_str.clone()))?;
 
     let mut elf_data = Vec::new();
     file.read_to_end(&mut elf_data)
-        .map_err(|e| Error::IoError(e))?;
+        .map_err(|e| Error::IoError(e, path_str.clone()))?;
 
     emulator.load_elf(&elf_data)?;
+    if let Some(resume) = &resume {
+        let data = std::fs::read(resume)
+            .map_err(|e| Error::IoError(e, resume.to_string_lossy().to_string()))?;
+        emulator.resume(&data)?;
+        println!("[rvemu] resumed from {} after {} instructions", resume.display(), emulator.retired());
+    }
 
-    match emulator.run() {
-        Ok(exit_code) => {
-            println!("[rvemu] program exited with code {}", exit_code);
+    let res = match checkpoint_every {
+        Some(every) => run_checkpointed(&mut emulator, max_insns, deadline, every, &checkpoint_path),
+        None => emulator.run_bounded(max_insns, deadline),
+    };
+    if let Some(tracer) = emulator.tracer_mut() {
+        tracer.flush()?;
+    }
+    match res {
+        Ok(ExitReason::Exited(code)) => {
+            println!("[rvemu] program exited with code {}", code);
+            Ok(())
+        }
+        Ok(ExitReason::BudgetExhausted) => {
+            eprintln!("[rvemu] program stopped, instruction budget or timeout exhausted");
+            std::process::exit(EXIT_BUDGET_EXHAUSTED);
+        }
+        Ok(reason) => {
+            eprintln!("[rvemu] program stopped: {:?}", reason);
             Ok(())
         }
         Err(e) => {
             eprintln!("[rvemu] program exited with error: {}", e);
+            if let (Some(path), Some(signal)) = (&core, coredump::crash_signal(&e)) {
+                std::fs::write(path, emulator.core_dump(signal))
+                    .map_err(|e| Error::IoError(e, path.to_string_lossy().to_string()))?;
+                eprintln!("[rvemu] core dumped to {}", path.display());
+            }
             Err(e)
         }
     }
-}
\ No newline at end of file
+}
+/// An address in decimal, or in hex with a 0x prefix.
+fn parse_addr(s: &str) -> std::result::Result<u64, String> {
+    let res = match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16),
+        None => s.replace('_', "").parse(),
+    };
+    res.map_err(|e| format!("Invalid address '{}': {}", s, e))
+}
+
+/// Runs like `Emulator::run_bounded`, writing a checkpoint to 'path' every 'every' instructions.
+fn run_checkpointed(
+    emulator: &mut Emulator,
+    max_insns: u64,
+    deadline: Option<Instant>,
+    every: u64,
+    path: &PathBuf,
+) -> Result<ExitReason> {
+    let mut left = max_insns;
+    loop {
+        let chunk = every.min(left);
+        let start = emulator.retired();
+        let reason = emulator.run_bounded(chunk, deadline)?;
+        left -= emulator.retired() - start;
+        // stopped short of the chunk, so the deadline passed
+        if reason != ExitReason::BudgetExhausted || left == 0 || emulator.retired() - start < chunk {
+            return Ok(reason);
+        }
+        write_checkpoint(emulator, path)?;
+    }
+}
+
+/// Replaces the file at 'path' with a new checkpoint, the old one stays whole until it is written.
+fn write_checkpoint(emulator: &Emulator, path: &PathBuf) -> Result<()> {
+    let data = emulator.checkpoint()?;
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    let path_err = |e| Error::IoError(e, path.to_string_lossy().to_string());
+    std::fs::write(&tmp, data).map_err(path_err)?;
+    std::fs::rename(&tmp, path).map_err(path_err)?;
+    println!("[rvemu] checkpoint after {} instructions written to {}", emulator.retired(), path.display());
+    Ok(())
+}
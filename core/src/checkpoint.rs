@@ -0,0 +1,339 @@
+//! On-disk checkpoints of a whole emulator, written by `Emulator::checkpoint` and read back by
+//! `Emulator::resume`. Resuming gives the same results as a run that was never interrupted.
+//!
+//! A checkpoint starts with `CHECKPOINT_MAGIC` and the format version, followed by the
+//! configuration it was taken with, the scheduler, the harts, guest memory and the syscall
+//! handler's state, in that order. Integers are little-endian, byte strings are prefixed with
+//! their length. Guest memory only stores the pages written since they were mapped.
+
+use crate::emulator::Emulator;
+use crate::error::*;
+use crate::hart::HartStatus;
+use crate::pmp::Privilege;
+use crate::state::{BreakCause, State, TrapCsrs};
+use crate::trigger::{CSR_TDATA1, CSR_TDATA2, CSR_TSELECT, TRIGGER_COUNT};
+
+pub const CHECKPOINT_MAGIC: &[u8; 8] = b"RVEMUCKP";
+
+/// Bumped whenever the format changes, older checkpoints are rejected.
+pub const CHECKPOINT_VERSION: u32 = 5;
+
+/// Builds a checkpoint in memory.
+#[derive(Debug, Default)]
+pub struct CheckpointWriter {
+    buf: Vec<u8>,
+}
+
+impl CheckpointWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn bytes(&mut self, bytes: &[u8]) {
+        self.u64(bytes.len() as u64);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.bytes(s.as_bytes());
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reads a checkpoint back, every read fails with `Error::InvalidCheckpoint` past its end.
+#[derive(Debug)]
+pub struct CheckpointReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> CheckpointReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let end = self.pos.checked_add(len).filter(|&end| end <= self.data.len())
+            .ok_or_else(|| Error::InvalidCheckpoint("Truncated".to_string()))?;
+        let bytes = &self.data[self.pos..end];
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.u8()? != 0)
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.u64()?;
+        self.take(len as usize)
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        String::from_utf8(self.bytes()?.to_vec())
+            .map_err(|_| Error::InvalidCheckpoint("String is not UTF-8".to_string()))
+    }
+
+    /// A count of items that follow, each at least 'item_size' bytes long.
+    pub fn count(&mut self, item_size: usize) -> Result<usize> {
+        let count = self.u64()? as usize;
+        if count.saturating_mul(item_size) > self.data.len() - self.pos {
+            return Err(Error::InvalidCheckpoint("Truncated".to_string()));
+        }
+        Ok(count)
+    }
+}
+
+impl Emulator {
+    /// Writes the whole emulator to a checkpoint, see `checkpoint`.
+    pub fn checkpoint(&self) -> Result<Vec<u8>> {
+        let mut out = CheckpointWriter::new();
+        out.buf.extend_from_slice(CHECKPOINT_MAGIC);
+        out.u32(CHECKPOINT_VERSION);
+        out.u64(self.quantum as u64);
+        out.str(&self.isa_names());
+
+        out.u64(self.cur_hart as u64);
+        out.u64(self.slice_left as u64);
+        out.u64(self.clock);
+        out.u64(self.retired);
+        out.u64(self.harts.len() as u64);
+        for hart in &self.harts {
+            save_state(&mut out, &hart.state);
+            save_status(&mut out, hart.status);
+        }
+        self.guest.save_checkpoint(&mut out)?;
+        self.syscall.save_checkpoint(&mut out)?;
+        Ok(out.into_bytes())
+    }
+
+    /// Puts the emulator in the state 'data', written by `checkpoint`, describes.
+    /// The emulator has to be built the same way as the one the checkpoint was taken of.
+    /// After an error it may be half resumed, and is best dropped.
+    pub fn resume(&mut self, data: &[u8]) -> Result<()> {
+        let mut input = CheckpointReader::new(data);
+        if input.take(CHECKPOINT_MAGIC.len())? != CHECKPOINT_MAGIC {
+            return Err(Error::InvalidCheckpoint("Not a checkpoint".to_string()));
+        }
+        let version = input.u32()?;
+        if version != CHECKPOINT_VERSION {
+            return Err(Error::InvalidCheckpoint(format!("Version {} is not supported", version)));
+        }
+        if input.u64()? != self.quantum as u64 || input.str()? != self.isa_names() {
+            return Err(Error::InvalidCheckpoint("Taken with a different configuration".to_string()));
+        }
+
+        let cur_hart = input.u64()? as usize;
+        let slice_left = input.u64()? as usize;
+        let clock = input.u64()?;
+        let retired = input.u64()?;
+        let num_harts = input.count(8)?;
+        if cur_hart >= num_harts {
+            return Err(Error::InvalidCheckpoint(format!("No hart {}", cur_hart)));
+        }
+        let mut harts = Vec::with_capacity(num_harts);
+        for _ in 0..num_harts {
+            harts.push((load_state(&mut input)?, load_status(&mut input)?));
+        }
+        self.guest.load_checkpoint(&mut input)?;
+        self.syscall.load_checkpoint(&mut input)?;
+        if input.pos != data.len() {
+            return Err(Error::InvalidCheckpoint("Trailing data".to_string()));
+        }
+
+        self.harts.truncate(num_harts);
+        for id in self.harts.len()..num_harts {
+            let hart = self.new_hart(id);
+            self.harts.push(hart);
+        }
+        for (hart, (state, status)) in self.harts.iter_mut().zip(harts) {
+            hart.state = state;
+            hart.status = status;
+        }
+        self.cur_hart = cur_hart;
+        self.slice_left = slice_left;
+        self.clock = clock;
+        self.retired = retired;
+        Ok(())
+    }
+
+    /// The enabled instruction sets, in a fixed order.
+    fn isa_names(&self) -> String {
+        let mut names: Vec<String> = self.isa.iter().map(|set| format!("{:?}", set)).collect();
+        names.sort();
+        names.join(",")
+    }
+}
+
+fn save_state(out: &mut CheckpointWriter, state: &State) {
+    out.u64(state.pc);
+    for &reg in &state.x {
+        out.u64(reg);
+    }
+    out.u8(match state.break_on {
+        None => 0,
+        Some(BreakCause::Ecall) => 1,
+        Some(BreakCause::Ebreak) => 2,
+    });
+    out.u64(state.trap.mstatus);
+    out.u64(state.trap.mtvec);
+    out.u64(state.trap.mepc);
+    out.u64(state.trap.mcause);
+    out.u64(state.trap.mtval);
+    out.u64(state.mhartid);
+    out.u8(state.privilege as u8);
+    let mut triggers = state.triggers.clone();
+    let tselect = triggers.read_csr(CSR_TSELECT).unwrap();
+    for idx in 0..TRIGGER_COUNT as u64 {
+        triggers.write_csr(CSR_TSELECT, idx).unwrap();
+        out.u64(triggers.read_csr(CSR_TDATA1).unwrap());
+        out.u64(triggers.read_csr(CSR_TDATA2).unwrap());
+    }
+    out.u64(tselect);
+}
+
+fn load_state(input: &mut CheckpointReader) -> Result<State> {
+    let mut state = State { pc: input.u64()?, ..State::ZERO };
+    for reg in state.x.iter_mut() {
+        *reg = input.u64()?;
+    }
+    state.break_on = match input.u8()? {
+        0 => None,
+        1 => Some(BreakCause::Ecall),
+        2 => Some(BreakCause::Ebreak),
+        tag => return Err(Error::InvalidCheckpoint(format!("Unknown break cause {}", tag))),
+    };
+    state.trap = TrapCsrs {
+        mstatus: input.u64()?,
+        mtvec: input.u64()?,
+        mepc: input.u64()?,
+        mcause: input.u64()?,
+        mtval: input.u64()?,
+    };
+    state.mhartid = input.u64()?;
+    state.privilege = Privilege::from_bits(input.u8()? as u64)
+        .ok_or_else(|| Error::InvalidCheckpoint("Unknown privilege level".to_string()))?;
+    for idx in 0..TRIGGER_COUNT as u64 {
+        state.triggers.write_csr(CSR_TSELECT, idx).unwrap();
+        state.triggers.write_csr(CSR_TDATA1, input.u64()?).unwrap();
+        state.triggers.write_csr(CSR_TDATA2, input.u64()?).unwrap();
+    }
+    state.triggers.write_csr(CSR_TSELECT, input.u64()?).unwrap();
+    Ok(state)
+}
+
+fn save_status(out: &mut CheckpointWriter, status: HartStatus) {
+    match status {
+        HartStatus::Runnable => out.u8(0),
+        HartStatus::Blocked(None) => out.u8(1),
+        HartStatus::Blocked(Some(until)) => {
+            out.u8(2);
+            out.u64(until);
+        },
+        HartStatus::Exited(code) => {
+            out.u8(3);
+            out.u64(code as u64);
+        },
+    }
+}
+
+fn load_status(input: &mut CheckpointReader) -> Result<HartStatus> {
+    Ok(match input.u8()? {
+        0 => HartStatus::Runnable,
+        1 => HartStatus::Blocked(None),
+        2 => HartStatus::Blocked(Some(input.u64()?)),
+        3 => HartStatus::Exited(input.u64()? as i64),
+        tag => return Err(Error::InvalidCheckpoint(format!("Unknown hart status {}", tag))),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::ExitReason;
+    use crate::guest::MemFlags;
+    use crate::insn::InsnSet;
+    use crate::log;
+
+    fn build() -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Linux::new()))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, &[
+            0x00002437, // 0: lui s0, 2
+            0x00043283, // 4: ld t0, 0(s0)
+            0x00128293, // 8: addi t0, t0, 1
+            0x00543023, // c: sd t0, 0(s0)
+            0x00028513, // 10: mv a0, t0
+            0x05d00893, // 14: li a7, 93
+            0x00000073, // 18: ecall
+        ]);
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        emulator.guest.add_segment(0x2000, 0x1000, 0x1000, rw, Some(&5u64.to_le_bytes())).unwrap();
+        emulator.guest.reset_brk();
+        emulator
+    }
+
+    #[test]
+    fn test_checkpoint() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        let brk = emulator.guest.cur_brk();
+        assert_eq!(emulator.guest.brk(brk + 0x2000), brk + 0x2000);
+        emulator.guest.write_u64(brk + 0x1008, 0x1234).unwrap();
+        assert_eq!(emulator.run_for(4).unwrap(), ExitReason::BudgetExhausted);
+        let data = emulator.checkpoint().unwrap();
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+
+        // a fresh emulator carries on where the checkpoint was taken
+        let mut resumed = build();
+        resumed.resume(&data).unwrap();
+        assert_eq!((resumed.retired(), resumed.hart().state.pc), (4, 0x1010));
+        assert_eq!(resumed.guest.read_u64(0x2000).unwrap(), 6);
+        assert_eq!(resumed.guest.cur_brk(), brk + 0x2000);
+        assert_eq!(resumed.guest.read_u64(brk + 0x1008).unwrap(), 0x1234);
+        assert_eq!(resumed.run().unwrap(), ExitReason::Exited(6));
+        assert_eq!(resumed.retired(), emulator.retired());
+        assert_eq!(resumed.hart().state.x, emulator.hart().state.x);
+
+        assert!(matches!(resumed.resume(&data[..data.len() - 1]), Err(Error::InvalidCheckpoint(_))));
+        let mut newer = data.clone();
+        newer[CHECKPOINT_MAGIC.len()] += 1;
+        assert!(matches!(resumed.resume(&newer), Err(Error::InvalidCheckpoint(_))));
+        assert!(matches!(resumed.resume(b"not a checkpoint"), Err(Error::InvalidCheckpoint(_))));
+    }
+}
@@ -1,8 +1,59 @@
 pub const STACK_SIZE: usize = 0x00800000; // 8 MiB
 
+/// End of the stack of hart 0, the stacks of the other harts follow below
+pub const STACK_TOP: u64 = 0x80000000;
+
+/// Unmapped gap below the lowest address a stack may grow to, like Linux's stack_guard_gap
+pub const STACK_GUARD_SIZE: usize = 0x00100000; // 1 MiB
+
+/// Largest heap `GuestMem::brk` grows, reserved as host address space on first use
+pub const HEAP_MAX_SIZE: usize = 0x40000000; // 1 GiB
+
+/// `GuestMem::mmap` places mappings it chooses the address of below this, top-down
+pub const MMAP_TOP: u64 = 0x40_0000_0000; // end of Sv39 user space
+
+/// Lowest address `GuestMem::mmap` chooses, like Linux's vm.mmap_min_addr
+pub const MMAP_MIN: u64 = 0x10000;
+
+/// Where position-independent executables are loaded, two thirds up Sv39 user space like Linux
+pub const PIE_BASE: u64 = 0x2a_aaaa_a000;
+
+/// Pages the stack top moves down by at most with ASLR
+pub const STACK_ASLR_PAGES: u64 = 1 << 16; // 256 MiB
+
+/// Pages the mmap base moves down by at most with ASLR, like Linux's mmap_rnd_bits
+pub const MMAP_ASLR_PAGES: u64 = 1 << 18; // 1 GiB
+
+/// Pages the heap moves up by at most with ASLR
+pub const HEAP_ASLR_PAGES: u64 = 1 << 13; // 32 MiB
+
+/// Pages the PIE base moves up by at most with ASLR
+pub const PIE_ASLR_PAGES: u64 = 1 << 18; // 1 GiB
+
 /// Interval to poll for events in the event loop
 pub const POLL_INTERVAL: usize = 1024; // 1024 instructions
 
+/// Instructions between two checks of the deadline of `Emulator::run_until`
+pub const DEADLINE_POLL_INTERVAL: u64 = 1 << 16;
+
+/// Default scheduling quantum of a hart
+pub const QUANTUM: usize = 64; // 64 instructions
+
+/// Basic blocks a hart caches before the cache is flushed
+pub const BLOCK_CACHE_SIZE: usize = 1 << 12;
+
+/// Longest basic block, in instructions
+pub const BLOCK_MAX_INSNS: usize = 64;
+
+/// Entries of the direct-mapped TLB in front of the guest page table
+pub const TLB_ENTRIES: usize = 64;
+
+/// Entries into a basic block before the JIT compiles it
+pub const JIT_THRESHOLD: u32 = 16;
+
+/// Virtual time that passes per retired instruction
+pub const NS_PER_INSN: u64 = 1; // 1 GHz
+
 /// Default gdb port
 pub const GDB_PORT: u16 = 3777;
 
@@ -0,0 +1,196 @@
+//! ELF core files of a crashed guest, written by `Emulator::core_dump`, so that
+//! `riscv64-gdb prog core` can inspect the crash offline.
+//!
+//! The core has a `PT_NOTE` segment with an `NT_PRSTATUS` and an `NT_FPREGSET` note per live hart,
+//! the crashed one first, followed by every segment of guest memory as a `PT_LOAD`.
+//! There is no F extension, so the floating point registers are all zero.
+
+use crate::elf::*;
+use crate::emulator::Emulator;
+use crate::error::*;
+use crate::guest::PAGE_SIZE;
+use crate::hart::HartStatus;
+use crate::state::State;
+use crate::syscall::linux::PID;
+
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGSEGV: u32 = 11;
+
+/// Size of the riscv64 `struct elf_prstatus`.
+const PRSTATUS_SIZE: usize = 376;
+/// Offset of the general purpose registers, pc first, in `struct elf_prstatus`.
+const PRSTATUS_REGS: usize = 112;
+/// Size of the riscv64 `struct __riscv_d_ext_state`, 32 registers and fcsr.
+const FPREGSET_SIZE: usize = 264;
+
+/// The signal a guest would have been killed with for 'err', None if it is not a crash.
+pub fn crash_signal(err: &Error) -> Option<u32> {
+    match err {
+        Error::MemAccessFault(..) | Error::PmpViolation(..) | Error::StackOverflow(..) => Some(SIGSEGV),
+        Error::UnknownInsn(..) | Error::InsnUnimplemented(..) => Some(SIGILL),
+        Error::TriggerHit(..) => Some(SIGTRAP),
+        _ => None,
+    }
+}
+
+impl Emulator {
+    /// An ELF core file of the guest as it is now, reporting 'signal' for the current hart.
+    pub fn core_dump(&self, signal: u32) -> Vec<u8> {
+        let cur = &self.harts[self.cur_hart];
+        let others = self.harts.iter().filter(|hart| hart.id != cur.id);
+        let mut notes = vec![];
+        for hart in std::iter::once(cur).chain(others) {
+            if matches!(hart.status, HartStatus::Exited(_)) {
+                continue;
+            }
+            let sig = if hart.id == cur.id { signal } else { 0 };
+            push_note(&mut notes, NT_PRSTATUS, &prstatus(&hart.state, sig, PID + hart.id as u64));
+            push_note(&mut notes, NT_FPREGSET, &[0; FPREGSET_SIZE]);
+        }
+        let segments: Vec<_> = self.guest.segment_bytes().collect();
+
+        let ehdr_size = size_of::<ElfHeader>();
+        let phdr_size = size_of::<ProgramHeader>();
+        let phnum = 1 + segments.len();
+        let notes_offset = ehdr_size + phnum * phdr_size;
+        let mut offset = (notes_offset + notes.len()).next_multiple_of(PAGE_SIZE);
+
+        let mut e_ident = [0; EI_NIDENT];
+        e_ident[..4].copy_from_slice(&ELF_MAGIC);
+        e_ident[EI_CLASS] = ELF_CLASS_64;
+        e_ident[EI_DATA] = ELF_DATA_LSB;
+        e_ident[EI_VERSION] = EV_CURRENT;
+        let ehdr = ElfHeader {
+            e_ident,
+            e_type: ET_CORE,
+            e_machine: EM_RISCV,
+            e_version: EV_CURRENT as u32,
+            e_entry: 0,
+            e_phoff: ehdr_size as u64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsize: ehdr_size as u16,
+            e_phentsize: phdr_size as u16,
+            e_phnum: phnum as u16,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        };
+        let mut out = ehdr.to_bytes().to_vec();
+        let note_phdr = ProgramHeader {
+            p_type: PT_NOTE,
+            p_flags: 0,
+            p_offset: notes_offset as u64,
+            p_vaddr: 0,
+            p_paddr: 0,
+            p_filesz: notes.len() as u64,
+            p_memsz: 0,
+            p_align: 4,
+        };
+        out.extend_from_slice(&note_phdr.to_bytes());
+        for &(gaddr, flags, bytes) in &segments {
+            let phdr = ProgramHeader {
+                p_type: PT_LOAD,
+                p_flags: flags.to_p_flags(),
+                p_offset: offset as u64,
+                p_vaddr: gaddr,
+                p_paddr: 0,
+                p_filesz: bytes.len() as u64,
+                p_memsz: bytes.len() as u64,
+                p_align: PAGE_SIZE as u64,
+            };
+            out.extend_from_slice(&phdr.to_bytes());
+            offset += bytes.len();
+        }
+        out.extend_from_slice(&notes);
+        out.resize(out.len().next_multiple_of(PAGE_SIZE), 0);
+        for &(_, _, bytes) in &segments {
+            out.extend_from_slice(bytes);
+        }
+        out
+    }
+}
+
+/// A riscv64 `struct elf_prstatus`, only the signal, the pid and the registers are filled in.
+fn prstatus(state: &State, signal: u32, pid: u64) -> [u8; PRSTATUS_SIZE] {
+    let mut desc = [0; PRSTATUS_SIZE];
+    // pr_info.si_signo, and pr_cursig after si_code and si_errno
+    desc[0..4].copy_from_slice(&signal.to_le_bytes());
+    desc[12..14].copy_from_slice(&(signal as u16).to_le_bytes());
+    desc[32..36].copy_from_slice(&(pid as u32).to_le_bytes());
+    // pc takes the place of x0
+    let regs = std::iter::once(state.pc).chain(state.x[1..].iter().copied());
+    for (idx, reg) in regs.enumerate() {
+        let at = PRSTATUS_REGS + idx * 8;
+        desc[at..at + 8].copy_from_slice(&reg.to_le_bytes());
+    }
+    desc
+}
+
+/// Appends an ELF note owned by "CORE", name and description padded to 4 bytes.
+fn push_note(notes: &mut Vec<u8>, n_type: u32, desc: &[u8]) {
+    const NAME: &[u8] = b"CORE\0";
+    notes.extend_from_slice(&(NAME.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+    notes.extend_from_slice(&n_type.to_le_bytes());
+    notes.extend_from_slice(NAME);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+    notes.extend_from_slice(desc);
+    notes.resize(notes.len().next_multiple_of(4), 0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest::MemFlags;
+    use crate::insn::InsnSet;
+    use crate::log;
+
+    #[test]
+    fn test_core_dump() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Linux::new()))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, &[
+            0x02a00293, // 0: li t0, 42
+            0x00003303, // 4: ld t1, 0(zero)
+        ]);
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        emulator.guest.add_segment(0x4000, 0x1000, 0x1000, rw, Some(&7u64.to_le_bytes())).unwrap();
+        let err = emulator.run().unwrap_err();
+        let signal = crash_signal(&err).unwrap();
+        assert_eq!(signal, SIGSEGV);
+
+        let core = emulator.core_dump(signal);
+        let ehdr = ElfHeader::from_bytes(&core[..size_of::<ElfHeader>()]).unwrap();
+        assert_eq!((ehdr.e_type, ehdr.e_machine), (ET_CORE, EM_RISCV));
+        let phdrs: Vec<_> = (0..ehdr.e_phnum as usize).map(|idx| {
+            let at = ehdr.e_phoff as usize + idx * size_of::<ProgramHeader>();
+            ProgramHeader::from_bytes(&core[at..at + size_of::<ProgramHeader>()]).unwrap()
+        }).collect();
+
+        // one hart, its NT_PRSTATUS then its NT_FPREGSET
+        assert_eq!(phdrs[0].p_type, PT_NOTE);
+        let notes = &core[phdrs[0].p_offset as usize..][..phdrs[0].p_filesz as usize];
+        let word = |at: usize| u32::from_le_bytes(notes[at..at + 4].try_into().unwrap());
+        assert_eq!((word(0), word(4), word(8), &notes[12..17]), (5, PRSTATUS_SIZE as u32, NT_PRSTATUS, &b"CORE\0"[..]));
+        let prstatus = &notes[20..20 + PRSTATUS_SIZE];
+        let reg = |idx: usize| u64::from_le_bytes(prstatus[PRSTATUS_REGS + idx * 8..][..8].try_into().unwrap());
+        assert_eq!(u32::from_le_bytes(prstatus[..4].try_into().unwrap()), SIGSEGV);
+        assert_eq!((reg(0), reg(5)), (0x1004, 42));
+        let fpregset = 20 + PRSTATUS_SIZE;
+        assert_eq!((word(fpregset + 4), word(fpregset + 8)), (FPREGSET_SIZE as u32, NT_FPREGSET));
+        assert_eq!(notes.len(), 2 * 20 + PRSTATUS_SIZE + FPREGSET_SIZE);
+
+        let data = phdrs.iter().find(|phdr| phdr.p_type == PT_LOAD && phdr.p_vaddr == 0x4000).unwrap();
+        assert_eq!((data.p_flags, data.p_filesz), (PF_R | PF_W, 0x1000));
+        assert_eq!(core[data.p_offset as usize..][..8], 7u64.to_le_bytes());
+        let code = phdrs.iter().find(|phdr| phdr.p_type == PT_LOAD && phdr.p_vaddr == 0x1000).unwrap();
+        assert_eq!(core[code.p_offset as usize..][..4], 0x02a00293u32.to_le_bytes());
+    }
+}
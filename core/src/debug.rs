@@ -15,6 +15,7 @@ use gdbstub::target::ext::base::singlethread::SingleThreadResume;
 use gdbstub::target::ext::base::singlethread::SingleThreadSingleStep;
 use gdbstub::target::ext::breakpoints::Breakpoints;
 use gdbstub::target::ext::breakpoints::SwBreakpoint;
+use gdbstub::target::ext::memory_map::MemoryMap;
 use gdbstub::target::Target;
 use gdbstub::target::TargetError;
 use gdbstub::*;
@@ -195,6 +196,28 @@ impl Target for Emulator {
     fn support_breakpoints(&mut self) -> Option<target::ext::breakpoints::BreakpointsOps<'_, Self>> {
         Some(self)
     }
+
+    #[inline(always)]
+    fn support_memory_map(&mut self) -> Option<target::ext::memory_map::MemoryMapOps<'_, Self>> {
+        Some(self)
+    }
+}
+
+/// Every region is RAM to GDB, it only places software breakpoints in writable memory otherwise.
+impl MemoryMap for Emulator {
+    fn memory_map_xml(&self, offset: u64, length: usize, buf: &mut [u8]) -> target::TargetResult<usize, Self> {
+        let mut xml = String::from(r#"<?xml version="1.0"?><!DOCTYPE memory-map PUBLIC "+//IDN gnu.org//DTD GDB Memory Map V1.0//EN" "http://sourceware.org/gdb/gdb-memory-map.dtd"><memory-map>"#);
+        for region in self.guest.regions() {
+            xml.push_str(&format!(r#"<memory type="ram" start="{:#x}" length="{:#x}"/>"#, region.start, region.end - region.start));
+        }
+        xml.push_str("</memory-map>");
+        let Some(rest) = xml.as_bytes().get(offset as usize..) else {
+            return Ok(0);
+        };
+        let len = rest.len().min(length).min(buf.len());
+        buf[..len].copy_from_slice(&rest[..len]);
+        Ok(len)
+    }
 }
 
 impl SingleThreadBase for Emulator {
@@ -202,10 +225,10 @@ impl SingleThreadBase for Emulator {
         &mut self,
         regs: &mut <Self::Arch as arch::Arch>::Registers,
     ) -> target::TargetResult<(), Self> {
-        for (i, &x) in self.hart.state.x.iter().enumerate() {
+        for (i, &x) in self.hart().state.x.iter().enumerate() {
             regs.x[i] = x;
         }
-        regs.pc = self.hart.state.pc;
+        regs.pc = self.hart().state.pc;
         debug!("Read registers: {:?}", regs);
 
         Ok(())
@@ -216,10 +239,10 @@ impl SingleThreadBase for Emulator {
         regs: &<Self::Arch as arch::Arch>::Registers
     ) -> target::TargetResult<(), Self> {
         for (i, &x) in regs.x.iter().enumerate() {
-            self.hart.state.x[i] = x;
+            self.hart_mut().state.x[i] = x;
         }
-        self.hart.state.pc = regs.pc;
-        debug!("Wrote registers: {:?}", self.hart.state);
+        self.hart_mut().state.pc = regs.pc;
+        debug!("Wrote registers: {:?}", self.hart().state);
         Ok(())
     }
 
@@ -230,7 +253,7 @@ impl SingleThreadBase for Emulator {
     ) -> target::TargetResult<usize, Self> {
         for (i, byte) in data.iter_mut().enumerate() {
             debug!("reading");
-            let b = self.guest.read_u8(start_addr + i as <Self::Arch as arch::Arch>::Usize);
+            let b = self.guest.read_u8_raw(start_addr + i as <Self::Arch as arch::Arch>::Usize, MemAccess::Read);
             match b {
                 Ok(val) => *byte = val,
                 Err(e) => if i > 0 {
@@ -252,8 +275,9 @@ impl SingleThreadBase for Emulator {
         start_addr: <Self::Arch as arch::Arch>::Usize,
         data: &[u8],
     ) -> target::TargetResult<(), Self> {
+        // straight to memory, like `read_addrs`, the debugger is not subject to PMP or triggers
         for (i, &byte) in data.iter().enumerate() {
-            self.guest.write_u8(start_addr + i as u64, byte)?;
+            self.guest.write_u8_raw(start_addr + i as u64, byte)?;
         }
 
         debug!("Wrote {} bytes to address 0x{:x}", data.len(), start_addr);
@@ -289,12 +313,12 @@ impl SingleRegisterAccess<()> for Emulator {
     ) -> target::TargetResult<usize, Self> {
         match reg_id {
             gdbstub_arch::riscv::reg::id::RiscvRegId::Gpr(id) =>  {
-                debug!("Reading GPR {}: {}", id, self.hart.state.x[id as usize]);
-                buf.copy_from_slice(&self.hart.state.x[id as usize].to_le_bytes());
+                debug!("Reading GPR {}: {}", id, self.hart().state.x[id as usize]);
+                buf.copy_from_slice(&self.hart().state.x[id as usize].to_le_bytes());
                 Ok(8)
             },
             gdbstub_arch::riscv::reg::id::RiscvRegId::Pc => {
-                buf.copy_from_slice(&self.hart.state.pc.to_le_bytes());
+                buf.copy_from_slice(&self.hart().state.pc.to_le_bytes());
                 Ok(8)
             },
             _ => Err(TargetError::NonFatal),
@@ -310,11 +334,11 @@ impl SingleRegisterAccess<()> for Emulator {
         match reg_id {
             gdbstub_arch::riscv::reg::id::RiscvRegId::Gpr(id) => {
                 let value = u64::from_le_bytes(val.try_into().unwrap());
-                self.hart.state.x[id as usize] = value;
+                self.hart_mut().state.x[id as usize] = value;
                 Ok(())
             },
             gdbstub_arch::riscv::reg::id::RiscvRegId::Pc => {
-                self.hart.state.pc = u64::from_le_bytes(val.try_into().unwrap());
+                self.hart_mut().state.pc = u64::from_le_bytes(val.try_into().unwrap());
                 Ok(())
             },
             _ => Err(TargetError::NonFatal),
@@ -391,32 +415,36 @@ impl BlockingEventLoop for EventLoop {
             <Self::Connection as conn::Connection>::Error,
         >,
     > {
-        let poller = || {
-            conn.peek().map(|b| b.is_some()).unwrap_or(false)
-        };
-
-        let stop_reason = match target.run_debug(poller) {
-            Ok(o) => match o {
-                ExitReason::DoneStep => SingleThreadStopReason::DoneStep,
-                ExitReason::IncomingData => {
-                    let byte = conn
-                        .read()
-                        .map_err(|e| {
-                            debug!("Failed to read byte: {}", e);
-                            run_blocking::WaitForStopReasonError::Connection(e)
-                        })?;
-                    return Ok(Event::IncomingData(byte));
-                },
-                ExitReason::Exited(code) => {
-                    SingleThreadStopReason::Terminated(Signal::SIGSTOP)
+        let stop_reason = loop {
+            let poller = || {
+                conn.peek().map(|b| b.is_some()).unwrap_or(false)
+            };
+
+            match target.run_debug(poller) {
+                Ok(o) => match o {
+                    ExitReason::DoneStep => break SingleThreadStopReason::DoneStep,
+                    ExitReason::IncomingData => {
+                        let byte = conn
+                            .read()
+                            .map_err(|e| {
+                                debug!("Failed to read byte: {}", e);
+                                run_blocking::WaitForStopReasonError::Connection(e)
+                            })?;
+                        return Ok(Event::IncomingData(byte));
+                    },
+                    ExitReason::Exited(code) => {
+                        break SingleThreadStopReason::Terminated(Signal::SIGSTOP)
+                    },
+                    ExitReason::BreakpointHit(addr) => {
+                        break SingleThreadStopReason::SwBreak(())
+                    },
+                    // not a stop the debugger asked for, running again resumes
+                    ExitReason::BudgetExhausted => continue,
                 },
-                ExitReason::BreakpointHit(addr) => {
-                    SingleThreadStopReason::SwBreak(())
+                Err(e) => {
+                    return Err(run_blocking::WaitForStopReasonError::Target(e.into()))
                 },
-            },
-            Err(e) => {
-                return Err(run_blocking::WaitForStopReasonError::Target(e.into()))
-            },
+            }
         };
 
         Ok(Event::TargetStopped(stop_reason))
@@ -432,9 +460,30 @@ impl BlockingEventLoop for EventLoop {
 impl From<Error> for TargetError<Error> {
     fn from(value: Error) -> Self {
         match value {
-            Error::InternalError(_) => Self::Fatal(value),
-            Error::MemAccessFault(_, _) => Self::Errno(EFAULT),
-            _ => unimplemented!(),
+            Error::MemAccessFault(_, _)
+            | Error::PmpViolation(_, _)
+            | Error::StackOverflow(_, _)
+            | Error::Map(_, _) => Self::Errno(EFAULT),
+            // the request failed, the session goes on
+            Error::RepeatedBreakpoint(_)
+            | Error::RepeatedWatchpoint(_)
+            | Error::BreakpointNotFound(_)
+            | Error::WatchpointNotFound(_)
+            | Error::BreakpointHit
+            | Error::TriggerHit(_, _)
+            | Error::Exited(_) => Self::NonFatal,
+            Error::InvalidElf
+            | Error::InvalidCheckpoint(_)
+            | Error::IoError(_, _)
+            | Error::InsnSetUnimplemented(_)
+            | Error::DecoderConflict(_, _, _)
+            | Error::InsnUnimplemented(_)
+            | Error::UnknownInsn(_, _)
+            | Error::SyscallUnimplemented(_, _)
+            | Error::Other(_)
+            | Error::InternalError(_)
+            | Error::Deadlock
+            | Error::JitMismatch(_) => Self::Fatal(value),
         }
     }
 }
@@ -442,6 +491,7 @@ impl From<Error> for TargetError<Error> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::trigger::*;
 
     #[test]
     fn test_debug() {
@@ -458,4 +508,25 @@ mod tests {
             .unwrap();
         emu.debug().unwrap();
     }
+
+    #[test]
+    fn test_write_addrs() {
+        log::log_init(log::Level::Off);
+
+        let mut emu = Emulator::new()
+            .decoder(InsnSet::I)
+            .syscall(Box::new(Minilib))
+            .debug()
+            .build()
+            .unwrap();
+        emu.guest.add_segment(0x2000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+        // a store trigger the guest armed on the range must not stop the debugger
+        let triggers = emu.guest.triggers_mut();
+        triggers.write_csr(CSR_TDATA1, TRIGGER_TYPE_MCONTROL << TRIGGER_TYPE_SHIFT | TDATA1_M | TDATA1_STORE).unwrap();
+        triggers.write_csr(CSR_TDATA2, 0x2004).unwrap();
+
+        assert!(emu.write_addrs(0x2000, &[1, 2, 3, 4, 5, 6, 7, 8]).is_ok());
+        assert_eq!(emu.guest.read_u64(0x2000).unwrap(), 0x0807060504030201);
+        assert!(matches!(emu.write_addrs(0x3000, &[1]), Err(TargetError::Errno(EFAULT))));
+    }
 }
\ No newline at end of file
@@ -10,6 +10,19 @@ pub const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
 pub const EM_RISCV: u16 = 0xf3;
 /// Index of ELF class in e_ident.
 pub const EI_CLASS: usize = 4;
+/// Index of the data encoding in e_ident.
+pub const EI_DATA: usize = 5;
+/// Index of the ELF version in e_ident.
+pub const EI_VERSION: usize = 6;
+/// Little-endian data encoding.
+pub const ELF_DATA_LSB: u8 = 1;
+/// Current ELF version.
+pub const EV_CURRENT: u8 = 1;
+
+/// Position-independent executable or shared object file type.
+pub const ET_DYN: u16 = 3;
+/// Core file type.
+pub const ET_CORE: u16 = 4;
 
 /// Invalid or unknown ELF class.
 pub const ELF_CLASS_NONE: u8 = 0;
@@ -22,11 +35,26 @@ pub const ELF_CLASS_NUM: u8 = 3;
 
 /// Loadable segment type.
 pub const PT_LOAD: u32 = 1;
+/// Note segment type.
+pub const PT_NOTE: u32 = 4;
+
+/// Note with the registers and signal of a thread, see `coredump`.
+pub const NT_PRSTATUS: u32 = 1;
+/// Note with the floating point registers of a thread.
+pub const NT_FPREGSET: u32 = 2;
 
 pub const PF_X: u32 = 0x1;
 pub const PF_W: u32 = 0x2;
 pub const PF_R: u32 = 0x4;
 
+/// Symbol table section type.
+pub const SHT_SYMTAB: u32 = 2;
+
+/// Object symbol type.
+pub const STT_OBJECT: u8 = 1;
+/// Function symbol type.
+pub const STT_FUNC: u8 = 2;
+
 /// PC-relative 32-bit relocation
 pub const R_X86_64_PC32: u32 = 2;
 
@@ -50,6 +78,11 @@ pub struct ElfHeader {
 }
 
 impl ElfHeader {
+    pub fn to_bytes(&self) -> [u8; size_of::<ElfHeader>()] {
+        // SAFETY: repr(C) without padding
+        unsafe { std::mem::transmute_copy(self) }
+    }
+
     pub fn from_bytes(src: &[u8]) -> Result<Self> {
         if src.len() != size_of::<Self>() {
             warn!("ELF header size mismatch: expected {}, got {}", size_of::<Self>(), src.len());
@@ -96,6 +129,11 @@ pub struct ProgramHeader {
 }
 
 impl ProgramHeader {
+    pub fn to_bytes(&self) -> [u8; size_of::<ProgramHeader>()] {
+        // SAFETY: repr(C) without padding
+        unsafe { std::mem::transmute_copy(self) }
+    }
+
     pub fn from_bytes(src: &[u8]) -> Result<Self> {
         if src.len() != size_of::<Self>() {
             warn!("Program header size mismatch: expected {}, got {}", size_of::<Self>(), src.len());
@@ -126,6 +164,22 @@ pub struct SectionHeader {
     pub sh_entsize: u64,
 }
 
+impl SectionHeader {
+    pub fn from_bytes(src: &[u8]) -> Result<Self> {
+        if src.len() != size_of::<Self>() {
+            warn!("Section header size mismatch: expected {}, got {}", size_of::<Self>(), src.len());
+            return Err(Error::InvalidElf);
+        }
+        let res = unsafe {
+            let mut shdr: Self = std::mem::zeroed();
+            let src_ptr = src.as_ptr();
+            std::ptr::copy_nonoverlapping(src_ptr, &mut shdr as *mut Self as *mut u8, size_of::<Self>());
+            shdr
+        };
+        Ok(res)
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Symbol {
@@ -137,6 +191,105 @@ pub struct Symbol {
     pub st_size: u64,
 }
 
+impl Symbol {
+    pub fn from_bytes(src: &[u8]) -> Result<Self> {
+        if src.len() != size_of::<Self>() {
+            warn!("Symbol size mismatch: expected {}, got {}", size_of::<Self>(), src.len());
+            return Err(Error::InvalidElf);
+        }
+        let res = unsafe {
+            let mut sym: Self = std::mem::zeroed();
+            let src_ptr = src.as_ptr();
+            std::ptr::copy_nonoverlapping(src_ptr, &mut sym as *mut Self as *mut u8, size_of::<Self>());
+            sym
+        };
+        Ok(res)
+    }
+
+    pub fn st_type(&self) -> u8 {
+        self.st_info & 0xf
+    }
+}
+
+/// Function and object symbols of an ELF file, used to symbolise guest addresses.
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    /// (start, size, name) sorted by start address
+    symbols: Vec<(u64, u64, String)>,
+}
+
+impl SymbolTable {
+    /// Reads the symbol table of 'elf', which is empty if the file is stripped.
+    pub fn from_elf(elf: &[u8]) -> Result<Self> {
+        let ehdr = ElfHeader::from_bytes(elf.get(..size_of::<ElfHeader>()).ok_or(Error::InvalidElf)?)?;
+        // offsets and sizes come from the file, their sum may overflow
+        let bytes = |offset: u64, size: u64| -> Result<&[u8]> {
+            let end = offset.checked_add(size).ok_or(Error::InvalidElf)?;
+            elf.get(offset as usize..end as usize).ok_or(Error::InvalidElf)
+        };
+        let section = |idx: usize| -> Result<SectionHeader> {
+            let size = size_of::<SectionHeader>() as u64;
+            let offset = ehdr.e_shoff.checked_add(idx as u64 * size).ok_or(Error::InvalidElf)?;
+            SectionHeader::from_bytes(bytes(offset, size)?)
+        };
+
+        let mut symbols = vec![];
+        for i in 0..ehdr.e_shnum as usize {
+            let shdr = section(i)?;
+            if shdr.sh_type != SHT_SYMTAB {
+                continue;
+            }
+            let strtab = section(shdr.sh_link as usize)?;
+            let strings = bytes(strtab.sh_offset, strtab.sh_size)?;
+            let data = bytes(shdr.sh_offset, shdr.sh_size)?;
+            for chunk in data.chunks_exact(size_of::<Symbol>()) {
+                let sym = Symbol::from_bytes(chunk)?;
+                if !matches!(sym.st_type(), STT_FUNC | STT_OBJECT) || sym.st_value == 0 {
+                    continue;
+                }
+                let name = strings.get(sym.st_name as usize..)
+                    .and_then(|s| s.split(|&b| b == 0).next())
+                    .map(|s| String::from_utf8_lossy(s).into_owned())
+                    .unwrap_or_default();
+                symbols.push((sym.st_value, sym.st_size, name));
+            }
+        }
+        symbols.sort();
+        Ok(Self { symbols })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    /// Moves every symbol up by 'bias', for a program loaded above its link address.
+    pub fn rebase(&mut self, bias: u64) {
+        for (start, _, _) in self.symbols.iter_mut() {
+            *start += bias;
+        }
+    }
+
+    /// Symbol containing 'addr' and the offset into it.
+    pub fn lookup(&self, addr: u64) -> Option<(&str, u64)> {
+        let idx = self.symbols.partition_point(|&(start, _, _)| start <= addr).checked_sub(1)?;
+        let (start, size, name) = &self.symbols[idx];
+        // zero-sized symbols, e.g. from assembly, cover everything up to the next one
+        if *size != 0 && addr >= start + size {
+            return None;
+        }
+        Some((name, addr - start))
+    }
+
+    /// Formats 'addr' as 'symbol+offset', or as a bare address.
+    pub fn describe(&self, addr: u64) -> String {
+        match self.lookup(addr) {
+            Some((name, 0)) => format!("{:#x} <{}>", addr, name),
+            Some((name, offset)) => format!("{:#x} <{}+{:#x}>", addr, name, offset),
+            None => format!("{:#x}", addr),
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Debug)]
 pub struct Relocation {
@@ -165,6 +318,63 @@ mod tests {
         debug!("Parsed ELF header: {:?}", elf_header);
     }
 
+    #[test]
+    fn test_symbol_table() {
+        log::log_init(log::Level::Off);
+
+        let strtab = b"\0main\0counter\0";
+        let sym = |name: u32, info: u8, value: u64, size: u64| {
+            let mut bytes = vec![];
+            bytes.extend(name.to_le_bytes());
+            bytes.extend([info, 0]);
+            bytes.extend(1u16.to_le_bytes());
+            bytes.extend(value.to_le_bytes());
+            bytes.extend(size.to_le_bytes());
+            bytes
+        };
+        let symtab = [sym(0, 0, 0, 0), sym(1, STT_FUNC, 0x1000, 0x40), sym(6, STT_OBJECT, 0x2000, 8)].concat();
+        let shdr = |ty: u32, offset: usize, size: usize, link: u32| {
+            let mut bytes = vec![];
+            bytes.extend(0u32.to_le_bytes());
+            bytes.extend(ty.to_le_bytes());
+            bytes.extend([0u8; 16]);
+            bytes.extend((offset as u64).to_le_bytes());
+            bytes.extend((size as u64).to_le_bytes());
+            bytes.extend(link.to_le_bytes());
+            bytes.extend([0u8; 20]);
+            bytes
+        };
+
+        let mut elf = vec![0u8; size_of::<ElfHeader>()];
+        elf[0..4].copy_from_slice(&ELF_MAGIC);
+        elf[EI_CLASS] = ELF_CLASS_64;
+        elf[EI_NIDENT + 2..EI_NIDENT + 4].copy_from_slice(&EM_RISCV.to_le_bytes());
+        let shoff = elf.len() + symtab.len() + strtab.len();
+        elf.extend(&symtab);
+        elf.extend(strtab);
+        elf[0x28..0x30].copy_from_slice(&(shoff as u64).to_le_bytes());
+        elf[0x3c..0x3e].copy_from_slice(&3u16.to_le_bytes());
+        elf.extend(shdr(0, 0, 0, 0));
+        elf.extend(shdr(SHT_SYMTAB, size_of::<ElfHeader>(), symtab.len(), 2));
+        elf.extend(shdr(3, size_of::<ElfHeader>() + symtab.len(), strtab.len(), 0));
+
+        let symbols = SymbolTable::from_elf(&elf).unwrap();
+        assert_eq!(symbols.lookup(0x1010), Some(("main", 0x10)));
+        assert_eq!(symbols.lookup(0x1040), None);
+        assert_eq!(symbols.describe(0x2004), "0x2004 <counter+0x4>");
+        assert_eq!(symbols.describe(0x2000), "0x2000 <counter>");
+        assert_eq!(symbols.describe(0xfff), "0xfff");
+
+        // offsets and sizes that overflow are rejected
+        let mut bad = elf.clone();
+        let size_field = shoff + size_of::<SectionHeader>() + 0x20;
+        bad[size_field..size_field + 8].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(SymbolTable::from_elf(&bad), Err(Error::InvalidElf)));
+        let mut bad = elf.clone();
+        bad[0x28..0x30].copy_from_slice(&u64::MAX.to_le_bytes());
+        assert!(matches!(SymbolTable::from_elf(&bad), Err(Error::InvalidElf)));
+    }
+
     #[test]
     fn test_parse_file() {
         log::log_init(log::Level::Off);
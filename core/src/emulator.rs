@@ -2,21 +2,29 @@
 //! Operations such as loading programs, running them, and accessing the state of the CPU and memory are provided.
 
 use std::collections::HashSet;
+use std::io::Write;
 use std::net::TcpListener;
 use std::net::TcpStream;
+use std::time::{Duration, Instant};
 
 use gdbstub::conn::ConnectionExt;
 use gdbstub::stub::GdbStub;
 
 use crate::debug::WatchMode;
+use crate::elf::{ElfHeader, SymbolTable};
 use crate::guest::*;
 use crate::insn::*;
+use crate::layout::AddressLayout;
 use crate::*;
 use crate::config::*;
 use crate::error::*;
 use crate::hart::*;
+#[cfg(feature = "jit")]
+use crate::jit::JitMode;
+use crate::race::*;
 use crate::state::*;
 use crate::syscall::*;
+use crate::tracer::InsnTracer;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum EmuMode {
@@ -36,38 +44,96 @@ pub enum ExitReason {
     IncomingData,
     Exited(i64),
     BreakpointHit(u64),
+    /// `run_for` or `run_until` ran out of instructions or time. Running again resumes.
+    BudgetExhausted,
+}
+
+/// Host time spent per subsystem, see `EmulatorBuilder::profile`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Profile {
+    /// Running guest instructions, without translating them.
+    pub execute: Duration,
+    /// Decoding basic blocks, and compiling them if the JIT is on.
+    pub translate: Duration,
+    /// Handling syscalls, including the scheduling requests they make.
+    pub syscall: Duration,
+    /// Picking the next hart.
+    pub schedule: Duration,
 }
 
 pub struct Emulator {
-    // harts: Vec<Hart>,
-    pub(crate) hart: Hart,
+    pub(crate) harts: Vec<Hart>,
+    /// Hart currently scheduled, also the one that stopped the emulator when `run` returns.
+    pub(crate) cur_hart: usize,
+    /// Instructions a hart runs before the next one is scheduled.
+    pub(crate) quantum: usize,
+    /// Instructions left in the current hart's quantum.
+    pub(crate) slice_left: usize,
+    /// Virtual time in nanoseconds, advanced by every retired instruction.
+    pub(crate) clock: u64,
+    /// Instructions retired by all harts.
+    pub(crate) retired: u64,
     // guest: Arc<RwLock<GuestMem>>,
     pub(crate) guest: GuestMem,
     pub(crate) syscall: Box<dyn SyscallHandler>,
     pub(crate) stack_size: usize,
+    /// Size each stack may grow to, at least 'stack_size'.
+    pub(crate) stack_limit: usize,
     pub(crate) breakpoints: HashSet<u64>,
     pub(crate) watchpoints: HashSet<u64>,
     pub(crate) mode: EmuMode,
     pub(crate) isa: Vec<InsnSet>,
+    /// Only present if race detection is enabled.
+    pub(crate) race: Option<RaceDetector>,
+    /// Symbols of the loaded ELF, empty if it is stripped.
+    pub(crate) symbols: SymbolTable,
+    /// Only present if profiling is enabled. Translation is timed by the block caches,
+    /// 'execute' still includes it.
+    pub(crate) profile: Option<Profile>,
+    /// Only present if instruction tracing is enabled.
+    pub(crate) tracer: Option<InsnTracer>,
 }
 
 pub struct EmulatorBuilder {
-    hart: Hart,
+    num_harts: usize,
+    quantum: usize,
     syscall: Option<Box<dyn SyscallHandler>>,
     decoders: Vec<InsnSet>,
     /// default stack size in bytes (8 MiB)
     stack_size: usize,
+    /// size stacks grow to on demand, none by default
+    stack_limit: usize,
+    layout: AddressLayout,
     mode: EmuMode,
+    /// Enables machine-level features such as PMP.
+    system: bool,
+    /// Gives every hart a store buffer, see `storebuf`.
+    weak_memory: bool,
+    race_detector: bool,
+    profile: bool,
+    trace: Option<Box<dyn Write + Send>>,
+    #[cfg(feature = "jit")]
+    jit: JitMode,
 }
 
 impl EmulatorBuilder {
     pub fn new() -> Self {
         Self {
-            hart: Hart::new(0),
+            num_harts: 1,
+            quantum: QUANTUM,
             syscall: None,
             decoders: vec![],
             stack_size: STACK_SIZE,
+            stack_limit: 0,
+            layout: AddressLayout::default(),
             mode: EmuMode::Run,
+            system: false,
+            weak_memory: false,
+            race_detector: false,
+            profile: false,
+            trace: None,
+            #[cfg(feature = "jit")]
+            jit: JitMode::Off,
         }
     }
 
@@ -86,29 +152,133 @@ impl EmulatorBuilder {
         self
     }
 
+    /// Lets stacks grow down on demand up to 'size' bytes, like RLIMIT_STACK.
+    /// Accesses below that raise `Error::StackOverflow`.
+    pub fn stack_limit(mut self, size: usize) -> Self {
+        self.stack_limit = size;
+        self
+    }
+
+    /// Where the stacks, the heap, mmaps and position-independent programs go, see `layout`.
+    pub fn layout(mut self, layout: AddressLayout) -> Self {
+        self.layout = layout;
+        self
+    }
+
     pub fn debug(mut self) -> Self {
         self.mode = EmuMode::Debug(ExecMode::Step);
         self
     }
 
+    /// Number of harts, all of which start at the ELF entry with their own stack.
+    pub fn harts(mut self, num: usize) -> Self {
+        self.num_harts = num;
+        self
+    }
+
+    /// Number of instructions each hart runs before the scheduler switches to the next one.
+    pub fn quantum(mut self, insns: usize) -> Self {
+        self.quantum = insns;
+        self
+    }
+
+    /// Runs the guest as bare-metal firmware, with PMP enforced on every access.
+    pub fn system_mode(mut self) -> Self {
+        self.system = true;
+        self
+    }
+
+    /// Buffers stores per hart, so that the weak behaviours RVWMO allows become observable.
+    /// Meant for litmus tests driven by `litmus::Litmus`.
+    pub fn weak_memory(mut self) -> Self {
+        self.weak_memory = true;
+        self
+    }
+
+    /// Checks guest memory accesses for data races between harts, see `race`.
+    pub fn race_detector(mut self) -> Self {
+        self.race_detector = true;
+        self
+    }
+
+    /// Measures the host time spent per subsystem, see `Emulator::profile`.
+    pub fn profile(mut self) -> Self {
+        self.profile = true;
+        self
+    }
+
+    /// Writes every instruction executed to 'out', see `tracer`.
+    pub fn trace_insns(mut self, out: Box<dyn Write + Send>) -> Self {
+        self.trace = Some(out);
+        self
+    }
+
+    /// Compiles hot basic blocks to native code, see `jit`.
+    #[cfg(feature = "jit")]
+    pub fn jit(mut self, mode: JitMode) -> Self {
+        self.jit = mode;
+        self
+    }
+
     pub fn build(mut self) -> Result<Emulator> {
         if self.syscall.is_none() {
             return Err(Error::Other("Syscall handler not set".to_string()));
         }
+        if self.num_harts == 0 || self.quantum == 0 {
+            return Err(Error::Other("At least one hart and a non-zero quantum are required".to_string()));
+        }
+        let mut boot_hart = Hart::new(0);
         let mut isa = vec![];
         for set in self.decoders.iter() {
-            self.hart.add_decoder(*set)?;
+            boot_hart.add_decoder(*set)?;
             isa.push(*set);
         }
+        let mut harts = Vec::with_capacity(self.num_harts);
+        for id in 1..self.num_harts {
+            let mut hart = Hart::new(id);
+            hart.dispatch = boot_hart.dispatch.clone();
+            harts.push(hart);
+        }
+        harts.insert(0, boot_hart);
+        for hart in harts.iter_mut() {
+            #[cfg(feature = "jit")]
+            {
+                hart.jit = self.jit;
+            }
+            if self.profile {
+                hart.blocks.enable_profile();
+            }
+        }
+        let mut guest = GuestMem::new();
+        guest.set_layout(self.layout.place());
+        if self.system {
+            guest.enable_pmp();
+        }
+        if self.weak_memory {
+            guest.enable_store_buffers();
+        }
+        if self.race_detector {
+            guest.enable_access_log();
+        }
         Ok(Emulator {
-            hart: self.hart,
-            guest: GuestMem::new(),
+            harts,
+            cur_hart: 0,
+            quantum: self.quantum,
+            slice_left: self.quantum,
+            clock: 0,
+            retired: 0,
+            guest,
             syscall: self.syscall.unwrap(),
             stack_size: self.stack_size,
+            stack_limit: round_up!(self.stack_limit.max(self.stack_size), PAGE_SIZE),
             breakpoints: HashSet::new(),
             watchpoints: HashSet::new(),
             mode: self.mode,
             isa,
+            race: self.race_detector.then(RaceDetector::new),
+            symbols: SymbolTable::default(),
+            profile: self.profile.then(Profile::default),
+            tracer: self.trace.map(InsnTracer::new),
         })
     }
 }
@@ -120,26 +290,72 @@ impl Emulator {
 
     pub fn load_elf(&mut self, program: &[u8]) -> Result<()> {
         let entry = self.guest.load_elf(program)?;
-        self.hart.state.pc = entry;
-
-        // allocate stack space
-        self.guest.add_segment(
-            0x8000_0000 - self.stack_size as u64,
-            self.stack_size,
-            0x1000,
-            MemFlags::READ|MemFlags::WRITE,
-            None,
-        )?;
-        self.hart.state.x[2] = 0x8000_0000;
+        self.symbols = SymbolTable::from_elf(program).unwrap_or_else(|e| {
+            warn!("Failed to read the symbol table: {}", e);
+            SymbolTable::default()
+        });
+        // load_elf checked the header
+        let ehdr = ElfHeader::from_bytes(&program[..size_of::<ElfHeader>()])?;
+        self.symbols.rebase(self.guest.load_bias(&ehdr));
+
+        // one stack per hart, each with an unmapped guard region below
+        let stack_top = self.guest.layout().stack_top;
+        for hart in self.harts.iter_mut() {
+            let top = stack_top - (hart.id * (self.stack_limit + STACK_GUARD_SIZE)) as u64;
+            self.guest.add_stack(top, self.stack_size, self.stack_limit)?;
+            hart.state.pc = entry;
+            hart.state.x[2] = top;
+        }
         Ok(())
     }
 
+    pub fn hart(&self) -> &Hart {
+        &self.harts[self.cur_hart]
+    }
+
+    pub fn hart_mut(&mut self) -> &mut Hart {
+        &mut self.harts[self.cur_hart]
+    }
+
+    pub fn harts(&self) -> &[Hart] {
+        &self.harts
+    }
+
+    /// Id of the scheduled hart. After `run` returns, this is the hart that stopped the emulator.
+    pub fn current_hart(&self) -> usize {
+        self.cur_hart
+    }
+
     pub fn run(&mut self) -> Result<ExitReason> {
+        self.run_bounded(u64::MAX, None)
+    }
+
+    /// Runs at most 'max_insns' instructions, summed over all harts.
+    pub fn run_for(&mut self, max_insns: u64) -> Result<ExitReason> {
+        self.run_bounded(max_insns, None)
+    }
+
+    /// Runs until the guest exits or the host clock passes 'deadline'.
+    /// The deadline is checked every `DEADLINE_POLL_INTERVAL` instructions.
+    pub fn run_until(&mut self, deadline: Instant) -> Result<ExitReason> {
+        self.run_bounded(u64::MAX, Some(deadline))
+    }
+
+    /// `run_for` and `run_until` at once, whichever runs out first.
+    pub fn run_bounded(&mut self, max_insns: u64, deadline: Option<Instant>) -> Result<ExitReason> {
         match self.mode {
             EmuMode::Run => {
+                let mut left = max_insns;
+                let mut next_poll = DEADLINE_POLL_INTERVAL;
                 loop {
-                    match self.force_step() {
-                        Ok(_) => {},
+                    if left == 0 {
+                        return Ok(ExitReason::BudgetExhausted);
+                    }
+                    match self.run_slice(left.min(usize::MAX as u64) as usize) {
+                        Ok(retired) => {
+                            left -= retired as u64;
+                            next_poll = next_poll.saturating_sub(retired as u64);
+                        },
                         Err(Error::Exited(code)) => {
                             return Ok(ExitReason::Exited(code));
                         }
@@ -147,6 +363,12 @@ impl Emulator {
                             return Err(e);
                         }
                     }
+                    if next_poll == 0 && let Some(deadline) = deadline {
+                        if Instant::now() >= deadline {
+                            return Ok(ExitReason::BudgetExhausted);
+                        }
+                        next_poll = DEADLINE_POLL_INTERVAL;
+                    }
                 }
             },
             _ => unreachable!(),
@@ -154,25 +376,247 @@ impl Emulator {
     }
 
     pub fn step(&mut self) -> Result<ExitReason> {
-        if self.breakpoints.contains(&self.hart.state.pc) {
+        if self.breakpoints.contains(&self.hart().state.pc) {
             return Err(Error::BreakpointHit);
         }
         self.force_step()
     }
 
     pub fn force_step(&mut self) -> Result<ExitReason> {
-        match self.hart.step(&mut self.guest)? {
+        self.step_hart()?;
+        self.schedule(1)?;
+        Ok(ExitReason::DoneStep)
+    }
+
+    /// Runs the current hart until its quantum or 'limit' is used up, or it needs the emulator,
+    /// then schedules. Instruction-exact like `force_step`, but without the per-instruction overhead.
+    /// Returns the number of instructions retired.
+    fn run_slice(&mut self, limit: usize) -> Result<usize> {
+        // the race detector needs the pc of every access
+        let budget = if self.race.is_some() { 1 } else { self.slice_left.min(limit) };
+        let retired = self.run_hart(budget)?;
+        let start = self.profile.is_some().then(Instant::now);
+        self.schedule(retired)?;
+        self.charge(start, |profile| &mut profile.schedule);
+        Ok(retired)
+    }
+
+    /// Runs one instruction on the current hart, without scheduling.
+    pub(crate) fn step_hart(&mut self) -> Result<()> {
+        self.run_hart(1).map(|_| ())
+    }
+
+    /// Runs up to 'budget' instructions on the current hart, without scheduling.
+    /// Stops after an ecall, returns the number of instructions retired.
+    fn run_hart(&mut self, budget: usize) -> Result<usize> {
+        let num_harts = self.harts.len();
+        let hart = &mut self.harts[self.cur_hart];
+        self.guest.set_hart(self.cur_hart);
+        let pc = hart.state.pc;
+        if self.race.is_some() {
+            // drops accesses of instructions that faulted
+            self.guest.take_accesses();
+        }
+        let mut retired = 0;
+        let start = self.profile.is_some().then(Instant::now);
+        // privilege and triggers are per hart, the guest memory checks accesses against the running one's
+        self.guest.set_privilege(hart.state.privilege, hart.state.trap.mstatus);
+        std::mem::swap(&mut hart.state.triggers, self.guest.triggers_mut());
+        let res = match &mut self.tracer {
+            Some(tracer) => hart.run_traced(&mut self.guest, budget, &mut retired, tracer),
+            None => hart.run(&mut self.guest, budget, &mut retired),
+        };
+        std::mem::swap(&mut hart.state.triggers, self.guest.triggers_mut());
+        hart.state.privilege = self.guest.privilege();
+        self.charge(start, |profile| &mut profile.execute);
+        self.clock += NS_PER_INSN * retired as u64;
+        self.retired += retired as u64;
+        let cause = res?;
+        self.record_accesses(pc, false);
+        match cause {
             Some(BreakCause::Ecall) => {
-                self.syscall.handle(&mut self.hart.state, &mut self.guest)?;
+                // syscalls act as a fence, the kernel sees every store of the hart
+                self.guest.fence()?;
+                let start = self.profile.is_some().then(Instant::now);
+                let hart = &mut self.harts[self.cur_hart];
+                let mut sched = Sched::new(self.cur_hart, num_harts, self.clock);
+                let res = self.syscall.handle_sched(&mut hart.state, &mut self.guest, &mut sched);
+                self.record_accesses(pc, true);
+                res?;
+                self.apply(sched)?;
+                self.charge(start, |profile| &mut profile.syscall);
             }
             Some(BreakCause::Ebreak) => {
                 unimplemented!();
             }
             None => {}
         }
-        Ok(ExitReason::DoneStep)
+        Ok(retired)
+    }
+
+    /// Virtual time in nanoseconds.
+    pub fn clock(&self) -> u64 {
+        self.clock
+    }
+
+    /// The instruction tracer, if enabled.
+    pub fn tracer_mut(&mut self) -> Option<&mut InsnTracer> {
+        self.tracer.as_mut()
+    }
+
+    /// Instructions retired by all harts so far.
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    /// Host time spent per subsystem so far, None unless profiling is enabled.
+    pub fn profile(&self) -> Option<Profile> {
+        let mut profile = self.profile?;
+        profile.translate = self.harts.iter().filter_map(|hart| hart.blocks.translate_time()).sum();
+        profile.execute = profile.execute.saturating_sub(profile.translate);
+        Some(profile)
+    }
+
+    /// Adds the time since 'start' to a field of the profile.
+    fn charge(&mut self, start: Option<Instant>, field: impl FnOnce(&mut Profile) -> &mut Duration) {
+        if let (Some(profile), Some(start)) = (&mut self.profile, start) {
+            *field(profile) += start.elapsed();
+        }
+    }
+
+    /// Guest pages mapped and backed by host memory so far.
+    pub fn mem_stats(&self) -> MemStats {
+        self.guest.stats()
+    }
+
+    /// Races found so far, empty unless the race detector is enabled.
+    pub fn races(&self) -> &[Race] {
+        self.race.as_ref().map_or(&[], RaceDetector::races)
+    }
+
+    /// Describes every race found so far, symbolised with the ELF's symbol table.
+    pub fn race_report(&self) -> Vec<String> {
+        let symbols = (!self.symbols.is_empty()).then_some(&self.symbols);
+        self.races().iter().map(|race| race.describe(symbols)).collect()
+    }
+
+    /// Feeds the accesses and fences of the instruction at 'pc' to the race detector.
+    /// Accesses made by the kernel on behalf of a syscall synchronise like atomics.
+    fn record_accesses(&mut self, pc: u64, kernel: bool) {
+        let Some(race) = &mut self.race else {
+            return;
+        };
+        let mut accesses = self.guest.take_accesses();
+        if kernel {
+            accesses.iter_mut().for_each(|access| access.kind = AccessKind::Atomic);
+        }
+        race.record(self.cur_hart, pc, &accesses);
+    }
+
+    /// A hart configured like the boot hart, for harts added after `build`.
+    pub(crate) fn new_hart(&self, id: usize) -> Hart {
+        let mut hart = Hart::new(id);
+        hart.dispatch = self.harts[0].dispatch.clone();
+        #[cfg(feature = "jit")]
+        {
+            hart.jit = self.harts[0].jit;
+        }
+        if self.profile.is_some() {
+            hart.blocks.enable_profile();
+        }
+        hart
+    }
+
+    fn apply(&mut self, sched: Sched) -> Result<()> {
+        for request in sched.into_requests() {
+            match request {
+                SchedRequest::Spawn(state) => {
+                    let id = self.harts.len();
+                    let mut hart = self.new_hart(id);
+                    hart.state = *state;
+                    hart.state.mhartid = id as u64;
+                    debug!("hart {} spawned hart {} at pc@{:#x}", self.cur_hart, id, hart.state.pc);
+                    self.harts.push(hart);
+                    if let Some(race) = &mut self.race {
+                        race.spawn(self.cur_hart, id);
+                    }
+                },
+                SchedRequest::Block(until) => {
+                    self.harts[self.cur_hart].status = HartStatus::Blocked(until);
+                },
+                SchedRequest::Wake(id, ret) => {
+                    let hart = &mut self.harts[id];
+                    if let HartStatus::Blocked(_) = hart.status {
+                        hart.status = HartStatus::Runnable;
+                        hart.state.x[10] = ret;
+                        if let Some(race) = &mut self.race {
+                            race.wake(self.cur_hart, id);
+                        }
+                    }
+                },
+                SchedRequest::Exit(code) => {
+                    self.harts[self.cur_hart].status = HartStatus::Exited(code);
+                    if self.harts.iter().all(|hart| matches!(hart.status, HartStatus::Exited(_))) {
+                        return Err(Error::Exited(code));
+                    }
+                },
+                SchedRequest::Yield => {
+                    self.slice_left = 1;
+                },
+            }
+        }
+        Ok(())
+    }
+
+    /// Round-robin: moves to the next runnable hart once the current one has used up
+    /// its quantum or cannot run anymore. If every hart is blocked, virtual time skips
+    /// ahead to the earliest timeout.
+    fn schedule(&mut self, retired: usize) -> Result<()> {
+        self.slice_left = self.slice_left.saturating_sub(retired);
+        if self.slice_left > 0 && self.harts[self.cur_hart].is_runnable() {
+            return Ok(());
+        }
+        self.slice_left = self.quantum;
+        loop {
+            for hart in self.harts.iter_mut() {
+                if matches!(hart.status, HartStatus::Blocked(Some(until)) if until <= self.clock) {
+                    hart.status = HartStatus::Runnable;
+                }
+            }
+            let num = self.harts.len();
+            let next = (1..=num)
+                .map(|i| (self.cur_hart + i) % num)
+                .find(|&id| self.harts[id].is_runnable());
+            if let Some(id) = next {
+                self.cur_hart = id;
+                return Ok(());
+            }
+            let earliest = self.harts.iter()
+                .filter_map(|hart| match hart.status {
+                    HartStatus::Blocked(until) => until,
+                    _ => None,
+                })
+                .min();
+            match earliest {
+                Some(until) => self.clock = until,
+                None => return Err(Error::Deadlock),
+            }
+        }
     }
+}
 
+#[cfg(test)]
+impl Emulator {
+    /// Maps 'code' at 'base' and points every hart at it.
+    pub(crate) fn load_code(&mut self, base: u64, code: &[u32]) {
+        let bytes: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        self.guest.add_segment(base, bytes.len(), 0x1000, MemFlags::READ | MemFlags::EXECUTE, Some(&bytes))
+            .unwrap();
+        self.guest.reset_brk();
+        for hart in self.harts.iter_mut() {
+            hart.state.pc = base;
+        }
+    }
 }
 
 #[cfg(test)]
@@ -180,6 +624,9 @@ mod tests {
     use std::{fs::File, io::Read};
 
     use super::*;
+    use crate::elf::*;
+    use crate::pmp::Privilege;
+    use crate::trigger::*;
     
     #[test]
     fn test_minimal() {
@@ -202,6 +649,472 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_store_trigger() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::Ziscr)
+            .build()
+            .unwrap();
+        let mut code = vec![
+            0x000012b7, // lui t0, 1
+            0x1002829b, // addiw t0, t0, 256
+            0x30529073, // csrw mtvec, t0
+            0x00002337, // lui t1, 2
+            0x7a231073, // csrw tdata2, t1
+            0x00100393, // li t2, 1
+            0x03d39393, // slli t2, t2, 61
+            0x04238393, // addi t2, t2, 66 (mcontrol, m, store)
+            0x7a139073, // csrw tdata1, t2
+            0x00700e13, // li t3, 7
+            0x01c33023, // sd t3, 0(t1)
+            0x00100513, // li a0, 1
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ];
+        code.resize(0x100 / 4, 0);
+        // trap handler at 0x1100
+        code.extend([
+            0x34202573, // csrr a0, mcause
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+        ]);
+        emulator.load_code(0x1000, &code);
+        emulator.guest.add_segment(0x2000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(CAUSE_BREAKPOINT as i64));
+        assert_eq!(emulator.hart().state.trap.mepc, 0x1028);
+        assert_eq!(emulator.hart().state.trap.mtval, 0x2000);
+        // the store did not happen
+        assert_eq!(emulator.guest.read_u64(0x2000).unwrap(), 0);
+    }
+
+    /// Machine-mode code that lets user mode run [0x1000, 0x2000) and use [0x2000, 0x3000),
+    /// then loads from 0x3000, which only machine mode may access.
+    const PMP_SETUP: &[u32] = &[
+        0x5ff00293, // 0: li t0, 0x5ff
+        0x3b029073, // 4: csrw pmpaddr0, t0 (NAPOT 0x1000, 4 KiB)
+        0x000012b7, // 8: lui t0, 1
+        0x9ff2829b, // c: addiw t0, t0, -0x601
+        0x3b129073, // 10: csrw pmpaddr1, t0 (NAPOT 0x2000, 4 KiB)
+        0x000022b7, // 14: lui t0, 2
+        0xb1d2829b, // 18: addiw t0, t0, -0x4e3
+        0x3a029073, // 1c: csrw pmpcfg0, t0 (entry 0 rx, entry 1 rw)
+        0x00003337, // 20: lui t1, 3
+        0x00033383, // 24: ld t2, 0(t1)
+    ];
+
+    fn system_emulator(code: &[u32]) -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::Ziscr)
+            .system_mode()
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, code);
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        emulator.guest.add_segment(0x2000, 0x2000, 0x1000, flags, None).unwrap();
+        emulator
+    }
+
+    /// Machine-mode code that installs a trap handler at 0x1080, which exits with mcause,
+    /// and then drops to user mode at 0x1100, where 'user' runs.
+    fn user_mode_code(user: &[u32]) -> Vec<u32> {
+        let mut code = PMP_SETUP.to_vec();
+        code.extend([
+            0x000012b7, // 28: lui t0, 1
+            0x0802829b, // 2c: addiw t0, t0, 0x80
+            0x30529073, // 30: csrw mtvec, t0
+            0x0802829b, // 34: addiw t0, t0, 0x80
+            0x34129073, // 38: csrw mepc, t0
+            0x30001073, // 3c: csrw mstatus, zero (MPP = U)
+            0x30200073, // 40: mret
+        ]);
+        code.resize(0x80 / 4, 0);
+        code.extend([
+            0x34202573, // 80: csrr a0, mcause
+            0x05d00893, // 84: li a7, 93
+            0x00000073, // 88: ecall
+        ]);
+        code.resize(0x100 / 4, 0);
+        code.extend(user);
+        code
+    }
+
+    #[test]
+    fn test_user_mode() {
+        log::log_init(log::Level::Off);
+
+        // PMP violations in user mode trap into the handler with the faulting address in mtval
+        let cases: [(&[u32], i64, u64, u64); 3] = [
+            // lui t1, 2; ld t2, 0(t1); lui t1, 3; ld t2, 0(t1)
+            (&[0x00002337, 0x00033383, 0x00003337, 0x00033383], CAUSE_LOAD_ACCESS as i64, 0x3000, 0x110c),
+            // lui t1, 1; sd t2, 0(t1)
+            (&[0x00001337, 0x00733023], CAUSE_STORE_ACCESS as i64, 0x1000, 0x1104),
+            // lui t1, 2; jr t1
+            (&[0x00002337, 0x00030067], CAUSE_FETCH_ACCESS as i64, 0x2000, 0x2000),
+        ];
+        for (user, cause, tval, epc) in cases {
+            let mut emulator = system_emulator(&user_mode_code(user));
+            assert_eq!(emulator.run().unwrap(), ExitReason::Exited(cause));
+            let state = &emulator.hart().state;
+            assert_eq!((state.trap.mtval, state.trap.mepc), (tval, epc));
+            assert_eq!(state.trap.mstatus & MSTATUS_MPP, 0);
+            assert_eq!(state.privilege, Privilege::Machine);
+        }
+
+        // with MPRV set, machine mode loads as MPP says, but still fetches as machine mode
+        let mut code = PMP_SETUP.to_vec();
+        code.extend([
+            0x000012b7, // 28: lui t0, 1
+            0x0802829b, // 2c: addiw t0, t0, 0x80
+            0x30529073, // 30: csrw mtvec, t0
+            0x000202b7, // 34: lui t0, 0x20
+            0x30029073, // 38: csrw mstatus, t0 (MPRV, MPP = U)
+            0x00033383, // 3c: ld t2, 0(t1)
+        ]);
+        code.resize(0x80 / 4, 0);
+        code.extend([
+            0x34202573, // 80: csrr a0, mcause
+            0x05d00893, // 84: li a7, 93
+            0x00000073, // 88: ecall
+        ]);
+        let mut emulator = system_emulator(&code);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(CAUSE_LOAD_ACCESS as i64));
+        let state = &emulator.hart().state;
+        assert_eq!((state.trap.mtval, state.trap.mepc), (0x3000, 0x103c));
+        assert_eq!(state.trap.mstatus, MSTATUS_MPRV | MSTATUS_MPP);
+    }
+
+    #[test]
+    fn test_per_hart_csrs() {
+        log::log_init(log::Level::Off);
+
+        // Hart 1 drops to user mode and sets a flag at 0x2000, hart 0 stays in machine mode,
+        // waits for the flag and then loads from 0x3000, which user mode may not access.
+        let mut code = vec![
+            0xf1402573, // 0: csrr a0, mhartid
+            0x02051263, // 4: bnez a0, 0x28
+            0x00002337, // 8: lui t1, 2
+            0x00033e03, // c: ld t3, 0(t1)
+            0xfe0e0ee3, // 10: beqz t3, -4
+            0x00003337, // 14: lui t1, 3
+            0x00033383, // 18: ld t2, 0(t1)
+            0x00100513, // 1c: li a0, 1
+            0x05d00893, // 20: li a7, 93
+            0x00000073, // 24: ecall
+            0x000012b7, // 28: lui t0, 1
+            0x1002829b, // 2c: addiw t0, t0, 0x100
+            0x34129073, // 30: csrw mepc, t0
+            0x30001073, // 34: csrw mstatus, zero (MPP = U)
+            0x30200073, // 38: mret
+        ];
+        code.resize(0x100 / 4, 0);
+        code.extend([
+            0x00002337, // 100: lui t1, 2
+            0x00100393, // 104: li t2, 1
+            0x00733023, // 108: sd t2, 0(t1)
+            0x00000013, // 10c: nop
+            0xffdff06f, // 110: j -4
+        ]);
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::Ziscr)
+            .system_mode()
+            .harts(2)
+            .quantum(3)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, &code);
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        emulator.guest.add_segment(0x2000, 0x2000, 0x1000, flags, None).unwrap();
+        let pmp = emulator.guest.pmp_mut().unwrap();
+        pmp.write_addr(crate::pmp::CSR_PMPADDR0, 0x5ff).unwrap();
+        pmp.write_addr(crate::pmp::CSR_PMPADDR0 + 1, 0x9ff).unwrap();
+        pmp.write_cfg(crate::pmp::CSR_PMPCFG0, 0x1b1d).unwrap();
+        // a store trigger on the flag that only hart 0 has, hart 1's store must not fire it
+        let triggers = &mut emulator.harts[0].state.triggers;
+        triggers.write_csr(CSR_TDATA1, TRIGGER_TYPE_MCONTROL << TRIGGER_TYPE_SHIFT | TDATA1_M | TDATA1_U | TDATA1_STORE)
+            .unwrap();
+        triggers.write_csr(CSR_TDATA2, 0x2000).unwrap();
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(1));
+        assert_eq!(emulator.harts()[0].state.privilege, Privilege::Machine);
+        assert_eq!(emulator.harts()[1].state.privilege, Privilege::User);
+        assert!(emulator.harts()[0].state.triggers.armed());
+        assert!(!emulator.harts()[1].state.triggers.armed());
+    }
+
+    #[test]
+    fn test_multi_hart() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::Ziscr)
+            .harts(2)
+            .quantum(3)
+            .build()
+            .unwrap();
+        // Every hart stores 'mhartid + 10' to 0x2000 + 8 * mhartid, hart 1 then spins
+        // while hart 0 waits for it and exits with the sum.
+        let code = [
+            0xf1402573, // csrr a0, mhartid
+            0x00351293, // slli t0, a0, 3
+            0x00002337, // lui t1, 2
+            0x00530333, // add t1, t1, t0
+            0x00a50393, // addi t2, a0, 10
+            0x00733023, // sd t2, 0(t1)
+            0x02051263, // bnez a0, 36
+            0x00002337, // lui t1, 2
+            0x00833e03, // ld t3, 8(t1)
+            0xfe0e0ee3, // beqz t3, -4
+            0x00033e83, // ld t4, 0(t1)
+            0x01de0533, // add a0, t3, t4
+            0x05d00893, // li a7, 93
+            0x00000073, // ecall
+            0x00000013, // nop
+            0xffdff06f, // j -4
+        ];
+        emulator.load_code(0x1000, &code);
+        emulator.guest.add_segment(0x2000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(21));
+        assert_eq!(emulator.current_hart(), 0);
+        assert!(matches!(emulator.harts()[1].state.pc, 0x1038 | 0x103c));
+    }
+
+    #[test]
+    fn test_budget() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        // Sums 1..=100, 2 + 3 * 100 + 2 instructions.
+        emulator.load_code(0x1000, &[
+            0x06400293, // 0: li t0, 100
+            0x00000513, // 4: li a0, 0
+            0x00550533, // 8: add a0, a0, t0
+            0xfff28293, // c: addi t0, t0, -1
+            0xfe029ce3, // 10: bnez t0, 0x8
+            0x05d00893, // 14: li a7, 93
+            0x00000073, // 18: ecall
+        ]);
+
+        assert_eq!(emulator.run_for(0).unwrap(), ExitReason::BudgetExhausted);
+        assert_eq!(emulator.hart().state.pc, 0x1000);
+        assert_eq!(emulator.run_for(300).unwrap(), ExitReason::BudgetExhausted);
+        assert_eq!(emulator.hart().state.pc, 0x100c);
+        assert_eq!(emulator.hart().state.x[10], 5050);
+        assert_eq!(emulator.run_for(100).unwrap(), ExitReason::Exited(5050));
+
+        // a deadline in the past stops at the first check, at the end of a slice
+        emulator.load_code(0x3000, &[
+            0x00000013, // 0: nop
+            0xffdff06f, // 4: j 0
+        ]);
+        let clock = emulator.clock;
+        assert_eq!(emulator.run_until(Instant::now()).unwrap(), ExitReason::BudgetExhausted);
+        let retired = (emulator.clock - clock) / NS_PER_INSN;
+        assert!((DEADLINE_POLL_INTERVAL..DEADLINE_POLL_INTERVAL + QUANTUM as u64).contains(&retired));
+    }
+
+    #[test]
+    fn test_profile() {
+        log::log_init(log::Level::Off);
+
+        for profile in [false, true] {
+            let mut builder = Emulator::new()
+                .syscall(Box::new(crate::Minilib))
+                .decoder(InsnSet::I);
+            if profile {
+                builder = builder.profile();
+            }
+            let mut emulator = builder.build().unwrap();
+            emulator.load_code(0x1000, &[
+                0x00a00293, // 0: li t0, 10
+                0xfff28293, // 4: addi t0, t0, -1
+                0xfe029ee3, // 8: bnez t0, 0x4
+                0x05d00893, // c: li a7, 93
+                0x00000073, // 10: ecall
+            ]);
+
+            assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+            assert_eq!(emulator.retired(), 1 + 2 * 10 + 2);
+            match emulator.profile() {
+                Some(times) => assert!(profile && times.execute > Duration::ZERO && times.translate > Duration::ZERO),
+                None => assert!(!profile),
+            }
+        }
+    }
+
+    #[test]
+    fn test_budget_resume() {
+        log::log_init(log::Level::Off);
+
+        // Stopping every few instructions does not change the schedule.
+        let mut clocks = vec![];
+        for chunk in [u64::MAX, 1, 7] {
+            let mut emulator = Emulator::new()
+                .syscall(Box::new(crate::Minilib))
+                .decoder(InsnSet::I)
+                .decoder(InsnSet::Ziscr)
+                .harts(2)
+                .quantum(3)
+                .build()
+                .unwrap();
+            emulator.load_code(0x1000, &[
+                0xf1402573, // csrr a0, mhartid
+                0x00051863, // bnez a0, 16
+                0x00a00513, // li a0, 10
+                0x05d00893, // li a7, 93
+                0x00000073, // ecall
+                0xfff50513, // addi a0, a0, -1
+                0xfe051ee3, // bnez a0, -4
+                0x0000006f, // j 0
+            ]);
+            loop {
+                match emulator.run_for(chunk).unwrap() {
+                    ExitReason::BudgetExhausted => continue,
+                    reason => assert_eq!(reason, ExitReason::Exited(10)),
+                }
+                break;
+            }
+            clocks.push((emulator.clock, emulator.harts()[1].state.pc));
+        }
+        assert!(clocks.iter().all(|&clock| clock == clocks[0]));
+    }
+
+    #[test]
+    fn test_stack_growth() {
+        log::log_init(log::Level::Off);
+
+        for limit in [0x1000, 0x4000] {
+            let mut emulator = Emulator::new()
+                .syscall(Box::new(crate::Minilib))
+                .decoder(InsnSet::I)
+                .stack_size(0x1000)
+                .stack_limit(limit)
+                .build()
+                .unwrap();
+            emulator.load_code(0x1000, &[
+                0xff010113, // 0: addi sp, sp, -16
+                0x00013023, // 4: sd zero, 0(sp)
+                0xff9ff06f, // 8: j 0
+            ]);
+            emulator.guest.add_stack(STACK_TOP, emulator.stack_size, emulator.stack_limit).unwrap();
+            emulator.hart_mut().state.x[2] = STACK_TOP;
+
+            // pushes until the stack runs into its guard region
+            let overflow = STACK_TOP - limit as u64 - 16;
+            assert!(matches!(emulator.run(), Err(Error::StackOverflow(gaddr, 0x1004)) if gaddr == overflow));
+            assert_eq!(emulator.hart().state.x[2], overflow);
+            assert_eq!(emulator.hart().state.pc, 0x1004);
+            // every push but the last retired three instructions, stores that grew the stack once
+            assert_eq!(emulator.retired(), 3 * limit as u64 / 16 + 1);
+            // mmap keeps out of the range the stack may grow into
+            let flags = MemFlags::READ | MemFlags::WRITE;
+            let hint = STACK_TOP - 0x2000;
+            assert_ne!(emulator.guest.mmap(hint, 0x1000, flags, MapAt::Hint, None).unwrap(), hint);
+        }
+    }
+
+    /// A position-independent executable whose code at address 0 is 'code'.
+    fn pie(code: &[u32]) -> Vec<u8> {
+        let code: Vec<u8> = code.iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        let mut e_ident = [0; EI_NIDENT];
+        e_ident[..4].copy_from_slice(&ELF_MAGIC);
+        e_ident[EI_CLASS] = ELF_CLASS_64;
+        let ehdr = ElfHeader {
+            e_ident,
+            e_type: ET_DYN,
+            e_machine: EM_RISCV,
+            e_version: 1,
+            e_entry: 0,
+            e_phoff: size_of::<ElfHeader>() as u64,
+            e_shoff: 0,
+            e_flags: 0,
+            e_ehsize: size_of::<ElfHeader>() as u16,
+            e_phentsize: size_of::<ProgramHeader>() as u16,
+            e_phnum: 1,
+            e_shentsize: 0,
+            e_shnum: 0,
+            e_shstrndx: 0,
+        };
+        let phdr = ProgramHeader {
+            p_type: PT_LOAD,
+            p_flags: PF_R | PF_X,
+            p_offset: 0x1000,
+            p_vaddr: 0,
+            p_paddr: 0,
+            p_filesz: code.len() as u64,
+            p_memsz: code.len() as u64,
+            p_align: 0x1000,
+        };
+        let mut elf = ehdr.to_bytes().to_vec();
+        elf.extend_from_slice(&phdr.to_bytes());
+        elf.resize(0x1000, 0);
+        elf.extend_from_slice(&code);
+        elf
+    }
+
+    #[test]
+    fn test_layout() {
+        log::log_init(log::Level::Off);
+
+        let elf = pie(&[
+            0x00000513, // 0: li a0, 0
+            0x0d600893, // 4: li a7, 214 (brk)
+            0x00000073, // 8: ecall
+            0x05d00893, // c: li a7, 93
+            0x00000073, // 10: ecall
+        ]);
+        let build = |layout: AddressLayout| {
+            let mut emulator = Emulator::new()
+                .syscall(Box::new(crate::Linux::new()))
+                .decoder(InsnSet::I)
+                .harts(2)
+                .layout(layout)
+                .build()
+                .unwrap();
+            emulator.load_elf(&elf).unwrap();
+            emulator
+        };
+
+        // a fixed layout puts everything where it says
+        let layout = AddressLayout {
+            stack_top: 0x7000_0000,
+            mmap_base: 0x30_0000_0000,
+            heap_start: Some(0x1000_0000),
+            pie_base: 0x5555_0000,
+            aslr_seed: None,
+        };
+        let mut emulator = build(layout);
+        assert_eq!(emulator.hart().state.pc, 0x5555_0000);
+        assert_eq!(emulator.hart().state.x[2], 0x7000_0000);
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        assert_eq!(emulator.guest.mmap(0, 0x1000, flags, MapAt::Hint, None).unwrap(), 0x30_0000_0000 - 0x1000);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0x1000_0000));
+
+        // the same seed gives the same addresses
+        let placed = |emulator: &Emulator| (emulator.harts[0].state.pc, emulator.harts[1].state.x[2], emulator.guest.cur_brk());
+        let first = placed(&build(AddressLayout::new().aslr(7)));
+        assert_eq!(placed(&build(AddressLayout::new().aslr(7))), first);
+        assert_ne!(placed(&build(AddressLayout::new().aslr(8))), first);
+        let mut emulator = build(AddressLayout::new().aslr(7));
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(first.2 as i64));
+        assert_eq!(emulator.hart().state.pc & !0xfff, first.0);
+    }
+
     fn test_inner(test_name: &str) {
 
         let mut emulator = Emulator::new()
@@ -224,7 +1137,7 @@ mod tests {
         let res = emulator.run();
         match res {
             Ok(_) => {
-                match emulator.hart.state.x[3] {
+                match emulator.hart().state.x[3] {
                     1 => {
                         debug!("Test {} passed.", test_name);
                     },
@@ -1,16 +1,23 @@
 use std::error;
 
-use crate::{guest::MemAccess, InsnSet};
+use crate::{guest::{MapError, MemAccess}, InsnSet};
 
 pub type Result<T> = std::result::Result<T, Error>;
 
 #[derive(Debug)]
 pub enum Error {
     InvalidElf,
+    /// A checkpoint is damaged or does not fit the emulator.
+    InvalidCheckpoint(String),
     MemAccessFault(MemAccess, u64),
-    StackOverflow,
+    /// The address space could not be changed as asked, (reason, address)
+    Map(MapError, u64),
+    /// A stack ran into its guard region, (address, pc)
+    StackOverflow(u64, u64),
     IoError(std::io::Error, String),
     InsnSetUnimplemented(InsnSet),
+    /// Two enabled extensions claim overlapping encodings, (registered, new, encoding)
+    DecoderConflict(InsnSet, InsnSet, u32),
     /// Used when building a new instruction set
     InsnUnimplemented(u32),
     /// (insn, pc)
@@ -19,6 +26,10 @@ pub enum Error {
     SyscallUnimplemented(u64, u64),
     Other(String),
     InternalError(String),
+    /// Every hart is blocked and none of them will time out.
+    Deadlock,
+    /// The JIT and the interpreter disagree on the block at this pc.
+    JitMismatch(u64),
 
     // Debug
     RepeatedBreakpoint(u64),
@@ -28,6 +39,10 @@ pub enum Error {
 
     // Control flow exceptions
     BreakpointHit,
+    /// A guest-programmed trigger fired, (trigger index, tval)
+    TriggerHit(usize, u64),
+    /// PMP denied an access, raised into the guest as an access fault, (access, address)
+    PmpViolation(MemAccess, u64),
     Exited(i64),
 }
 
@@ -35,10 +50,15 @@ impl std::fmt::Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::InvalidElf => write!(f, "Invalid ELF file"),
+            Error::InvalidCheckpoint(msg) => write!(f, "Invalid checkpoint: {}", msg),
             Error::MemAccessFault(access, gaddr) => write!(f, "Memory access fault: {:?} at {:#x}", access, gaddr),
-            Error::StackOverflow => write!(f, "Stack overflow"),
+            Error::Map(reason, gaddr) => write!(f, "Cannot map {:#x}: {:?}", gaddr, reason),
+            Error::StackOverflow(gaddr, pc) => write!(f, "Stack overflow: {:#x} accessed at {:#x}", gaddr, pc),
             Error::InternalError(msg) => write!(f, "Internal error: {}", msg),
+            Error::Deadlock => write!(f, "Deadlock: all harts are blocked"),
+            Error::JitMismatch(pc) => write!(f, "JIT and interpreter disagree on the block at {:#x}", pc),
             Error::InsnSetUnimplemented(set) => write!(f, "Instruction set unimplemented: {:?}", set),
+            Error::DecoderConflict(old, new, raw) => write!(f, "Instruction sets {:?} and {:?} both claim encoding {:#010x}", old, new, raw),
             Error::InsnUnimplemented(insn) => write!(f, "Instruction unimplemented: {:#x}", insn),
             Error::UnknownInsn(insn, pc) => write!(f, "Unknown instruction: {:#x} at {:#x}", insn, pc),
             Error::SyscallUnimplemented(syscall, pc) => write!(f, "Syscall unimplemented: {} at {:#x}", syscall, pc),
@@ -47,6 +67,8 @@ impl std::fmt::Display for Error {
             Error::BreakpointNotFound(addr) => write!(f, "Breakpoint not found at {:#x}", addr),
             Error::WatchpointNotFound(addr) => write!(f, "Watchpoint not found at {:#x}", addr),
             Error::BreakpointHit => write!(f, "Breakpoint hit"),
+            Error::TriggerHit(idx, tval) => write!(f, "Trigger {} hit at {:#x}", idx, tval),
+            Error::PmpViolation(access, gaddr) => write!(f, "PMP violation: {:?} at {:#x}", access, gaddr),
             Error::Exited(code) => write!(f, "Exit with code {}", code),
             Error::IoError(err, path) => {
                 let msg = err.to_string();
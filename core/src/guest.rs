@@ -1,12 +1,39 @@
 //! Memory management for guest programs.
 
-use std::collections::BTreeMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ptr;
+use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use bitflags::bitflags;
-use memmap2::{MmapMut, MmapOptions};
+use memmap2::{MmapMut, MmapOptions, UncheckedAdvice};
 use crate::*;
+use crate::config::{HEAP_MAX_SIZE, MMAP_MIN, STACK_GUARD_SIZE, TLB_ENTRIES};
 use crate::elf::*;
+use crate::checkpoint::{CheckpointReader, CheckpointWriter};
+use crate::layout::Placement;
+use crate::pmp::{Pmp, Privilege, CSR_PMPADDR0, CSR_PMPCFG0, CSR_PMPCFG2, PMP_ENTRIES};
+use crate::race::{Access, AccessKind};
+use crate::state::{MSTATUS_MPP, MSTATUS_MPP_SHIFT, MSTATUS_MPRV};
+use crate::storebuf::StoreBuffers;
+use crate::trigger::Triggers;
 
-const PAGE_SIZE: usize = 4096;
+pub const PAGE_SIZE: usize = 4096;
+
+fn page_of(gaddr: u64) -> u64 {
+    gaddr & !(PAGE_SIZE as u64 - 1)
+}
+
+/// 'len' rounded up to whole pages, None if that overflows.
+fn page_len(len: usize) -> Option<u64> {
+    len.checked_next_multiple_of(PAGE_SIZE).map(|len| len as u64)
+}
+
+/// Size of a host page, which may be larger than `PAGE_SIZE`.
+fn host_page_size() -> usize {
+    // SAFETY: sysconf has no preconditions
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MemAccess {
@@ -39,9 +66,43 @@ impl MemFlags {
         }
         flags
     }
+
+    pub fn to_p_flags(self) -> u32 {
+        let mut p_flags = 0;
+        if self.contains(MemFlags::READ) {
+            p_flags |= PF_R;
+        }
+        if self.contains(MemFlags::WRITE) {
+            p_flags |= PF_W;
+        }
+        if self.contains(MemFlags::EXECUTE) {
+            p_flags |= PF_X;
+        }
+        p_flags
+    }
+
+    fn of(access: MemAccess) -> Self {
+        match access {
+            MemAccess::Read => MemFlags::READ,
+            MemAccess::Write => MemFlags::WRITE,
+            MemAccess::Execute => MemFlags::EXECUTE,
+        }
+    }
 }
 
-#[derive(Debug)]
+/// Entry of the TLB, for guest page number 'page'.
+#[derive(Debug, Clone, Copy)]
+struct TlbEntry {
+    page: u64,
+    host: *mut u8,
+    /// Accesses the fast path may do, WRITE is left out for code pages.
+    flags: MemFlags,
+}
+
+const TLB_EMPTY: TlbEntry = TlbEntry { page: u64::MAX, host: ptr::null_mut(), flags: MemFlags::NONE };
+
+/// Clones share the host memory of the segment, only snapshots keep them.
+#[derive(Debug, Clone)]
 pub struct MemSegment {
     // [gaddr_start, gaddr_end)
     gaddr_start: u64,
@@ -50,8 +111,12 @@ pub struct MemSegment {
     // for bss/sbss segments,it additionally indicates more memory pages.
     m_gaddr_start: u64,
     m_gaddr_end: u64,
-    host_mmap: MmapMut,
+    /// Host memory of [m_gaddr_start, m_gaddr_end), inside 'mapping'.
+    host: *mut u8,
+    /// Shared by the pieces of a split segment, and unmapped with the last of them.
+    mapping: Rc<MmapMut>,
     flags: MemFlags,
+    origin: MemOrigin,
 }
 
 impl MemSegment {
@@ -60,8 +125,9 @@ impl MemSegment {
         gaddr_end: u64, 
         m_gaddr_start: u64,
         m_gaddr_end: u64,
-        host_mmap: MmapMut, 
-        flags: MemFlags
+        mut host_mmap: MmapMut, 
+        flags: MemFlags,
+        origin: MemOrigin,
     ) -> Self {
         assert!(gaddr_start < gaddr_end, "Invalid memory segment range");
         Self {
@@ -69,8 +135,10 @@ impl MemSegment {
             gaddr_end,
             m_gaddr_start,
             m_gaddr_end,
-            host_mmap,
+            host: host_mmap.as_mut_ptr(),
+            mapping: Rc::new(host_mmap),
             flags,
+            origin,
         }
     }
 
@@ -93,27 +161,454 @@ impl MemSegment {
     pub fn flags(&self) -> MemFlags {
         self.flags
     }
+
+    pub fn origin(&self) -> MemOrigin {
+        self.origin
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: the range lies in 'mapping', and segments never overlap
+        unsafe { std::slice::from_raw_parts(self.host, (self.m_gaddr_end - self.m_gaddr_start) as usize) }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: as in 'bytes', and no other segment sees this range
+        unsafe { std::slice::from_raw_parts_mut(self.host, (self.m_gaddr_end - self.m_gaddr_start) as usize) }
+    }
+
+    /// Bytes of 'mapping' from the start of the segment on, which it may grow into.
+    fn capacity(&self) -> usize {
+        self.mapping.len() - (self.host as usize - self.mapping.as_ptr() as usize)
+    }
+
+    /// Splits off [gaddr, m_gaddr_end), 'gaddr' is a page boundary inside the segment.
+    fn split_off(&mut self, gaddr: u64) -> MemSegment {
+        // SAFETY: 'gaddr' is inside the segment
+        let host = unsafe { self.host.add((gaddr - self.m_gaddr_start) as usize) };
+        let upper = MemSegment {
+            gaddr_start: self.gaddr_start.max(gaddr),
+            gaddr_end: self.gaddr_end.max(gaddr),
+            m_gaddr_start: gaddr,
+            m_gaddr_end: self.m_gaddr_end,
+            host,
+            mapping: self.mapping.clone(),
+            flags: self.flags,
+            origin: self.origin,
+        };
+        self.gaddr_end = self.gaddr_end.min(gaddr);
+        self.m_gaddr_end = gaddr;
+        upper
+    }
+
+    /// Gives the host memory of the segment back, so that it reads as zero when the segment
+    /// is mapped again. Host pages it shares with its neighbours are kept.
+    fn release(&self) {
+        let host_page = host_page_size();
+        let start = round_up!(self.host as usize, host_page);
+        let end = round_down!(self.host as usize + self.bytes().len(), host_page);
+        if start < end {
+            let offset = start - self.mapping.as_ptr() as usize;
+            // only fails for ranges outside the mapping
+            // SAFETY: the segment is unmapped, no references into its host memory are held and
+            // `unmap` drops the TLB entries of its pages before the next access
+            let _ = unsafe { self.mapping.unchecked_advise_range(UncheckedAdvice::DontNeed, offset, end - start) };
+        }
+    }
+
+    /// Pages of the segment the host backs with memory, those touched so far.
+    fn resident_pages(&self) -> usize {
+        let host_page = host_page_size();
+        let start = round_down!(self.host as usize, host_page);
+        let end = self.host as usize + self.bytes().len();
+        let mut resident = vec![0u8; (end - start).div_ceil(host_page)];
+        // SAFETY: [start, end) lies in 'mapping', whose start is a host page boundary,
+        // and 'resident' has a byte per host page of it
+        if unsafe { libc::mincore(start as *mut libc::c_void, end - start, resident.as_mut_ptr()) } != 0 {
+            return 0;
+        }
+        (0..self.num_pages())
+            .filter(|page| resident[(self.host as usize + page * PAGE_SIZE - start) / host_page] & 1 != 0)
+            .count()
+    }
+
+    /// Whether 'next', which starts where this segment ends, can become part of it.
+    fn mergeable(&self, next: &MemSegment) -> bool {
+        self.m_gaddr_end == next.m_gaddr_start
+            && self.flags == next.flags
+            && self.origin == next.origin
+            && Rc::ptr_eq(&self.mapping, &next.mapping)
+            && self.host as usize + self.bytes().len() == next.host as usize
+    }
+}
+
+/// What a segment was mapped for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemOrigin {
+    /// A `PT_LOAD` segment of the program.
+    Elf,
+    Stack,
+    /// Memory `GuestMem::brk` manages.
+    Heap,
+    /// Mapped with `GuestMem::mmap` or `GuestMem::mremap`.
+    Mmap,
+    /// Mapped directly with `GuestMem::add_segment`, e.g. device memory.
+    Device,
+}
+
+impl MemOrigin {
+    const ALL: [MemOrigin; 5] = [MemOrigin::Elf, MemOrigin::Stack, MemOrigin::Heap, MemOrigin::Mmap, MemOrigin::Device];
+}
+
+/// A mapped range of guest memory, see `GuestMem::regions`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemRegion {
+    /// [start, end), both page boundaries.
+    pub start: u64,
+    pub end: u64,
+    pub flags: MemFlags,
+    pub origin: MemOrigin,
+}
+
+/// Where `GuestMem::mmap` puts a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapAt {
+    /// At the address if it is free and not zero, anywhere else otherwise.
+    Hint,
+    /// At the address, replacing what was mapped there.
+    Fixed,
+    /// At the address, failing if anything is mapped there.
+    FixedNoReplace,
 }
 
+/// Why `GuestMem` refused to change the address space.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MapError {
+    /// The address is not page aligned, or the length is zero.
+    Invalid,
+    /// No free range is large enough, or part of the range is not mapped.
+    NoMemory,
+    /// Part of the range is already mapped.
+    Exists,
+}
+
+/// A stack that grows down on demand, see `GuestMem::add_stack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Stack {
+    /// Lowest address mapped so far.
+    bottom: u64,
+    /// Lowest address it may grow to, the guard region lies below.
+    limit: u64,
+}
+
+/// The range an LR of 'hart' reserved, see `GuestMem::reserve`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct LrReservation {
+    hart: usize,
+    addr: u64,
+    len: usize,
+}
+
+/// What a fault means for the stacks, see `GuestMem::grow_stack`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum StackFault {
+    /// The address is not below any stack.
+    NotStack,
+    /// The stack now covers the address, the access can be retried.
+    Grown,
+    /// The address is in the guard region, or the stack cannot grow that far.
+    Overflow,
+}
+
+/// How much host memory the guest uses, see `GuestMem::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemStats {
+    /// Pages the segments cover.
+    pub mapped_pages: usize,
+    /// Mapped pages backed by host memory, those the guest or the loader touched.
+    /// Untouched pages cost nothing, however large the segment.
+    pub resident_pages: usize,
+}
+
+/// Guest memory as `GuestMem::snapshot` saw it.
+#[derive(Debug)]
+pub struct MemSnapshot {
+    id: u64,
+    /// Keep their host mappings alive, so that `restore` can tell if the layout is still the same.
+    segments: BTreeMap<u64, MemSegment>,
+    /// Start of each part of a written page that a segment covers -> its bytes.
+    /// Pages that were never written read as zero.
+    pages: BTreeMap<u64, Box<[u8]>>,
+    written: HashSet<u64>,
+    init_brk_gaddr: u64,
+    cur_brk_gaddr: u64,
+    stacks: Vec<Stack>,
+    pmp: Option<Pmp>,
+    store_buffers: Option<StoreBuffers>,
+    lr_reservations: Vec<LrReservation>,
+}
+
+/// Ids of snapshots, unique in the process so that one is never taken for another.
+static NEXT_SNAPSHOT: AtomicU64 = AtomicU64::new(0);
+
 #[derive(Debug)]
 pub struct GuestMem {
     /// (base address, segment)
     segments: BTreeMap<u64, MemSegment>,
+    /// Where the heap, mmaps and position-independent programs go.
+    layout: Placement,
     init_brk_gaddr: u64,
     cur_brk_gaddr: u64,
-    stk_base_gaddr: u64,
-    stk_size: usize,
+    stacks: Vec<Stack>,
+    /// Only present in system mode.
+    pmp: Option<Pmp>,
+    /// Privilege level of the hart running, and the one its loads and stores are made at instead
+    /// while mstatus.MPRV is set. Set by `Emulator::run_hart` from the hart's `State`.
+    privilege: Privilege,
+    mprv: Option<Privilege>,
+    /// Sdtrig triggers of the hart running, swapped in from its `State` by `Emulator::run_hart`.
+    triggers: Triggers,
+    /// Only present in weak memory mode.
+    store_buffers: Option<StoreBuffers>,
+    /// Accesses since the last `take_accesses`, only recorded for the race detector.
+    access_log: Option<RefCell<Vec<Access>>>,
+    /// Set while an atomic memory operation runs.
+    in_atomic: bool,
+    /// LR reservations, at most one per hart. Any store to a reserved range breaks the
+    /// reservation, whichever hart makes it and whatever value it stores.
+    lr_reservations: Vec<LrReservation>,
+    /// Pages that decoded instructions came from -> generation, bumped by every store to the page.
+    code_pages: HashMap<u64, u64>,
+    /// Bumped whenever a code page changes, so decode caches only revalidate after that.
+    code_epoch: u64,
+    /// Pages stored to since they were mapped, the ones a snapshot has to save.
+    /// The TLB leaves WRITE out for the others, so that the first store to them takes the slow path.
+    written: HashSet<u64>,
+    /// Pages stored to since the snapshot 'dirty_base' was taken or restored,
+    /// the only ones restoring it again has to copy. WRITE is left out for the others.
+    dirty: HashSet<u64>,
+    dirty_base: Option<u64>,
+    /// Direct-mapped cache of the pages segments cover completely, indexed by page number.
+    /// The host pointers stay valid as long as the segment is mapped.
+    tlb: [Cell<TlbEntry>; TLB_ENTRIES],
 }
 
 impl GuestMem {
     pub fn new() -> Self {
         Self {
             segments: BTreeMap::new(),
+            layout: Placement::default(),
             init_brk_gaddr: 0,
             cur_brk_gaddr: 0,
-            stk_base_gaddr: 0,
-            stk_size: 0,
+            stacks: vec![],
+            pmp: None,
+            privilege: Privilege::Machine,
+            mprv: None,
+            triggers: Triggers::new(),
+            store_buffers: None,
+            access_log: None,
+            in_atomic: false,
+            lr_reservations: vec![],
+            code_pages: HashMap::new(),
+            code_epoch: 0,
+            written: HashSet::new(),
+            dirty: HashSet::new(),
+            dirty_base: None,
+            tlb: [const { Cell::new(TLB_EMPTY) }; TLB_ENTRIES],
+        }
+    }
+
+    /// Takes effect for programs loaded and mappings made from now on.
+    pub fn set_layout(&mut self, layout: Placement) {
+        self.layout = layout;
+    }
+
+    pub fn layout(&self) -> Placement {
+        self.layout
+    }
+
+    pub fn enable_pmp(&mut self) {
+        self.pmp = Some(Pmp::new());
+    }
+
+    pub fn pmp(&self) -> Option<&Pmp> {
+        self.pmp.as_ref()
+    }
+
+    pub fn pmp_mut(&mut self) -> Option<&mut Pmp> {
+        self.pmp.as_mut()
+    }
+
+    /// Privilege level of the hart running.
+    pub fn privilege(&self) -> Privilege {
+        self.privilege
+    }
+
+    /// Switches guest accesses to 'privilege', loads and stores to mstatus.MPP while 'mstatus'
+    /// has MPRV set.
+    pub fn set_privilege(&mut self, privilege: Privilege, mstatus: u64) {
+        self.privilege = privilege;
+        let mpp = Privilege::from_bits((mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT);
+        self.mprv = mpp.filter(|_| mstatus & MSTATUS_MPRV != 0);
+    }
+
+    pub fn triggers(&self) -> &Triggers {
+        &self.triggers
+    }
+
+    pub fn triggers_mut(&mut self) -> &mut Triggers {
+        &mut self.triggers
+    }
+
+    /// Buffers stores per hart so that other harts observe them late, see `storebuf`.
+    pub fn enable_store_buffers(&mut self) {
+        self.store_buffers = Some(StoreBuffers::new());
+    }
+
+    pub fn store_buffers(&self) -> Option<&StoreBuffers> {
+        self.store_buffers.as_ref()
+    }
+
+    /// Routes subsequent accesses through the store buffer of 'hart'.
+    pub(crate) fn set_hart(&mut self, hart: usize) {
+        if let Some(buffers) = &mut self.store_buffers {
+            buffers.set_hart(hart);
+        }
+    }
+
+    /// Writes entry 'idx' of the store buffer of 'hart' to memory.
+    pub fn flush_store(&mut self, hart: usize, idx: usize) -> Result<()> {
+        let Some(store) = self.store_buffers.as_mut().and_then(|buffers| buffers.take(hart, idx)) else {
+            return Err(Error::InternalError(format!("No buffered store {} on hart {}", idx, hart)));
+        };
+        self.write_raw(store.addr, &store.data[..store.len])
+    }
+
+    /// Makes all stores of the current hart visible to other harts.
+    pub fn fence(&mut self) -> Result<()> {
+        let Some(buffers) = &self.store_buffers else {
+            return Ok(());
+        };
+        buffers.record_event();
+        let hart = buffers.hart();
+        while self.store_buffers.as_ref().is_some_and(|buffers| buffers.len(hart) > 0) {
+            self.flush_store(hart, 0)?;
+        }
+        Ok(())
+    }
+
+    /// Flushes every store buffer, oldest entries first and harts in order.
+    pub fn drain_store_buffers(&mut self) -> Result<()> {
+        let harts = self.store_buffers.as_ref().map_or(0, |buffers| buffers.harts());
+        for hart in 0..harts {
+            while self.store_buffers.as_ref().is_some_and(|buffers| buffers.len(hart) > 0) {
+                self.flush_store(hart, 0)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Runs an atomic memory operation on [addr, addr + len).
+    /// Buffered stores of the current hart to the same location are flushed first,
+    /// with 'release' all of them are. The operation's own store is not buffered.
+    pub fn atomic<T>(
+        &mut self,
+        addr: u64,
+        len: usize,
+        release: bool,
+        op: impl FnOnce(&mut Self) -> Result<T>,
+    ) -> Result<T> {
+        let Some(buffers) = &self.store_buffers else {
+            self.in_atomic = true;
+            let res = op(self);
+            self.in_atomic = false;
+            return res;
+        };
+        if release {
+            self.fence()?;
+        } else {
+            let hart = buffers.hart();
+            while let Some(idx) = self.store_buffers.as_ref()
+                .and_then(|buffers| buffers.position(hart, addr, len)) {
+                // entries are flushed in order, older overlapping ones come first
+                self.flush_store(hart, idx)?;
+            }
         }
+        self.set_bypass(true);
+        self.in_atomic = true;
+        let res = op(self);
+        self.in_atomic = false;
+        self.set_bypass(false);
+        res
+    }
+
+    /// Reserves [addr, addr + len) for an SC of 'hart', replacing its previous reservation.
+    pub fn reserve(&mut self, hart: usize, addr: u64, len: usize) {
+        self.lr_reservations.retain(|reservation| reservation.hart != hart);
+        self.lr_reservations.push(LrReservation { hart, addr, len });
+    }
+
+    /// Drops the reservation of 'hart', true if it still covered exactly [addr, addr + len).
+    pub fn take_reservation(&mut self, hart: usize, addr: u64, len: usize) -> bool {
+        let Some(idx) = self.lr_reservations.iter().position(|reservation| reservation.hart == hart) else {
+            return false;
+        };
+        let reservation = self.lr_reservations.swap_remove(idx);
+        reservation.addr == addr && reservation.len == len
+    }
+
+    /// Breaks the reservations overlapping [start, end), after a store there.
+    fn break_reservations(&mut self, start: u64, end: u64) {
+        self.lr_reservations.retain(|reservation| {
+            end <= reservation.addr || start >= reservation.addr + reservation.len as u64
+        });
+    }
+
+    fn set_bypass(&mut self, bypass: bool) {
+        if let Some(buffers) = &mut self.store_buffers {
+            buffers.set_bypass(bypass);
+        }
+    }
+
+    pub fn code_epoch(&self) -> u64 {
+        self.code_epoch
+    }
+
+    /// Generation of the code page holding 'gaddr', None if no instruction was decoded from it.
+    pub fn code_page_gen(&self, gaddr: u64) -> Option<u64> {
+        self.code_pages.get(&page_of(gaddr)).copied()
+    }
+
+    /// Notes that an instruction at 'gaddr' was decoded, returns the generation of its page.
+    pub fn mark_code(&mut self, gaddr: u64) -> u64 {
+        let page = page_of(gaddr);
+        if let Some(&generation) = self.code_pages.get(&page) {
+            return generation;
+        }
+        // stores to the page have to take the slow path from now on, it bumps the generation
+        self.flush_tlb_page(page);
+        self.code_pages.insert(page, 0);
+        0
+    }
+
+    /// Drops every decoded instruction, e.g. on fence.i or when mappings change.
+    pub fn invalidate_code(&mut self) {
+        self.code_pages.clear();
+        self.code_epoch += 1;
+    }
+
+    /// Checks an instruction fetch without reading memory, for instructions that are already decoded.
+    pub fn check_fetch(&self, pc: u64) -> Result<()> {
+        self.check_access(pc, 4, MemAccess::Execute)
+    }
+
+    /// Records loads and stores from now on, see `take_accesses`.
+    pub fn enable_access_log(&mut self) {
+        self.access_log = Some(RefCell::new(vec![]));
+    }
+
+    /// Returns the accesses recorded since the last call.
+    pub(crate) fn take_accesses(&mut self) -> Vec<Access> {
+        self.access_log.as_mut().map(|log| std::mem::take(log.get_mut())).unwrap_or_default()
     }
 
     pub fn load_elf(&mut self, elf: &[u8]) -> Result<u64> {
@@ -122,7 +617,8 @@ impl GuestMem {
             return Err(Error::InvalidElf);
         }
         let ehdr = ElfHeader::from_bytes(&elf[..size_of::<ElfHeader>()])?;
-        let entry = ehdr.e_entry;
+        let bias = self.load_bias(&ehdr);
+        let entry = ehdr.e_entry + bias;
 
         // load program segments
         let mut phdr: ProgramHeader;
@@ -135,27 +631,767 @@ impl GuestMem {
             if phdr.p_type == PT_LOAD {
                 let flags = MemFlags::from_p_flags(phdr.p_flags);
                 let init_data = Some(&elf[phdr.p_offset as usize..(phdr.p_offset + phdr.p_filesz) as usize]);
-                self.add_segment(
-                    phdr.p_vaddr,
+                self.map_segment(
+                    phdr.p_vaddr + bias,
                     phdr.p_memsz as usize,
                     phdr.p_align as usize,
                     flags,
-                    init_data
+                    init_data,
+                    MemOrigin::Elf,
                 )?;
             }
         }
 
-        let mut init_brk_gaddr = 0;
-        for (&gaddr_start, segment) in self.segments.iter() {
+        for segment in self.segments.values() {
             trace!("loaded segment {:#x?}", segment);
-            init_brk_gaddr = (init_brk_gaddr).max(segment.m_gaddr_end);
         }
+        self.reset_brk();
+
+        Ok(entry)
+    }
+
+    /// What `load_elf` adds to the addresses of the program 'ehdr' heads,
+    /// position-independent executables go to the PIE base.
+    pub fn load_bias(&self, ehdr: &ElfHeader) -> u64 {
+        match ehdr.e_type {
+            ET_DYN => self.layout.pie_base,
+            _ => 0,
+        }
+    }
+
+    /// Puts the initial break at the heap start, or above every segment mapped so far.
+    /// `brk` refuses to grow the heap into a segment.
+    pub(crate) fn reset_brk(&mut self) {
+        let end = self.segments.values().map(|segment| segment.m_gaddr_end).max().unwrap_or(0);
+        let init_brk_gaddr = match self.layout.heap_start {
+            Some(start) => start,
+            None if end == 0 => 0,
+            None => end + self.layout.heap_gap,
+        };
         self.init_brk_gaddr = init_brk_gaddr;
         self.cur_brk_gaddr = init_brk_gaddr;
+    }
 
-        Ok(entry)
+    /// The current program break.
+    pub fn cur_brk(&self) -> u64 {
+        self.cur_brk_gaddr
+    }
+
+    /// Start of the heap segment, the first page at or above the initial break.
+    fn heap_base(&self) -> u64 {
+        round_up!(self.init_brk_gaddr, PAGE_SIZE) as u64
+    }
+
+    /// Moves the program break to 'new' with the semantics of Linux brk: returns the new break,
+    /// or the current one if 'new' is below the initial break, collides with another segment
+    /// or is more than `HEAP_MAX_SIZE` above it. The heap maps whole pages up to the break,
+    /// memory it gains reads as zero.
+    pub fn brk(&mut self, new: u64) -> u64 {
+        let cur = self.cur_brk_gaddr;
+        let base = self.heap_base();
+        if new < self.init_brk_gaddr || new == cur {
+            return cur;
+        }
+        let old_end = round_up!(cur, PAGE_SIZE) as u64;
+        let Some(new_end) = new.checked_next_multiple_of(PAGE_SIZE as u64) else {
+            return cur;
+        };
+        if new_end - base > HEAP_MAX_SIZE as u64 {
+            return cur;
+        }
+
+        if new_end > old_end {
+            if self.overlaps(old_end, new_end) {
+                return cur;
+            }
+            // the heap grows in place, unless mprotect or munmap split it
+            match self.segments.range_mut(..old_end).next_back() {
+                Some((_, heap)) if heap.m_gaddr_start >= base
+                    && heap.m_gaddr_end == old_end
+                    && heap.capacity() >= (new_end - heap.m_gaddr_start) as usize =>
+                {
+                    heap.gaddr_end = new_end;
+                    heap.m_gaddr_end = new_end;
+                },
+                _ => {
+                    // reserves the largest heap up front, the host only backs the pages that are used
+                    let reserve = if old_end == base { HEAP_MAX_SIZE } else { (new_end - old_end) as usize };
+                    let Ok(mmap) = MmapOptions::new().len(reserve).map_anon() else {
+                        return cur;
+                    };
+                    let flags = MemFlags::READ | MemFlags::WRITE;
+                    let heap = MemSegment::new(old_end, new_end, old_end, new_end, mmap, flags, MemOrigin::Heap);
+                    self.segments.insert(old_end, heap);
+                },
+            }
+            self.update_pages(old_end, new_end);
+            // the rest of the last page may have been used before the break last shrank,
+            // whole pages were released by `unmap`
+            self.zero(cur.max(base), old_end);
+        } else if new_end < old_end {
+            self.unmap(new_end, old_end);
+        }
+        self.cur_brk_gaddr = new;
+        new
     }
 
+    /// Maps a stack of 'size' bytes ending at 'top', which grows down on demand to 'limit' bytes.
+    /// Accesses to the `STACK_GUARD_SIZE` bytes below that are stack overflows.
+    pub fn add_stack(&mut self, top: u64, size: usize, limit: usize) -> Result<()> {
+        let size = round_up!(size, PAGE_SIZE);
+        let limit = round_up!(limit.max(size), PAGE_SIZE);
+        if top < limit as u64 {
+            return Err(Error::Map(MapError::NoMemory, top));
+        }
+        if size == 0 || !top.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::Map(MapError::Invalid, top));
+        }
+        let bottom = top - size as u64;
+        if self.overlaps(bottom, top) {
+            return Err(Error::Map(MapError::Exists, bottom));
+        }
+        // reserves the largest stack up front, the host only backs the pages that are used
+        let mmap = MmapOptions::new().len(limit).map_anon().map_err(|e| {
+            Error::InternalError(format!("Failed to create memory map: {}", e))
+        })?;
+        let flags = MemFlags::READ | MemFlags::WRITE;
+        let mut segment = MemSegment::new(bottom, top, bottom, top, mmap, flags, MemOrigin::Stack);
+        // SAFETY: the mapping is 'limit' bytes long, the segment takes its last 'size'
+        segment.host = unsafe { segment.host.add(limit - size) };
+        self.segments.insert(bottom, segment);
+        self.update_pages(bottom, top);
+        self.stacks.push(Stack { bottom, limit: top - limit as u64 });
+        Ok(())
+    }
+
+    /// Grows the stack that 'gaddr' lies below down to its page, after an access to it faulted.
+    pub(crate) fn grow_stack(&mut self, gaddr: u64) -> StackFault {
+        let Some(idx) = self.stacks.iter().position(|stack| {
+            gaddr < stack.bottom && gaddr >= stack.limit.saturating_sub(STACK_GUARD_SIZE as u64)
+        }) else {
+            return StackFault::NotStack;
+        };
+        let Stack { bottom, limit } = self.stacks[idx];
+        let new_bottom = page_of(gaddr);
+        if gaddr < limit || self.overlaps(new_bottom, bottom) {
+            return StackFault::Overflow;
+        }
+        let grow = (bottom - new_bottom) as usize;
+        match self.segments.get(&bottom) {
+            // in place, into the host memory reserved below the segment
+            Some(segment) if segment.host as usize - segment.mapping.as_ptr() as usize >= grow => {
+                let mut segment = self.segments.remove(&bottom).unwrap();
+                // SAFETY: checked above that the mapping extends 'grow' bytes below the segment
+                segment.host = unsafe { segment.host.sub(grow) };
+                segment.gaddr_start = new_bottom;
+                segment.m_gaddr_start = new_bottom;
+                self.segments.insert(new_bottom, segment);
+                self.update_pages(new_bottom, bottom);
+            },
+            // munmap or mremap took the stack apart
+            _ => {
+                let flags = MemFlags::READ | MemFlags::WRITE;
+                if self.map_segment(new_bottom, grow, PAGE_SIZE, flags, None, MemOrigin::Stack).is_err() {
+                    return StackFault::Overflow;
+                }
+            },
+        }
+        self.stacks[idx].bottom = new_bottom;
+        StackFault::Grown
+    }
+
+    /// Ranges `brk` and the stacks may grow into, which `mmap` leaves free.
+    fn reservations(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        let base = self.heap_base();
+        let heap = (self.init_brk_gaddr != 0).then_some((base, base + HEAP_MAX_SIZE as u64));
+        let stacks = self.stacks.iter()
+            .map(|stack| (stack.limit.saturating_sub(STACK_GUARD_SIZE as u64), stack.bottom));
+        heap.into_iter().chain(stacks)
+    }
+
+    /// Maps 'len' bytes with 'flags' at or near 'addr' as 'at' says, and returns where.
+    /// 'data' fills the start of the mapping, the rest reads as zero.
+    pub fn mmap(&mut self, addr: u64, len: usize, flags: MemFlags, at: MapAt, data: Option<&[u8]>) -> Result<u64> {
+        if len == 0 || (at != MapAt::Hint && !addr.is_multiple_of(PAGE_SIZE as u64)) {
+            return Err(Error::Map(MapError::Invalid, addr));
+        }
+        let Some((len, end)) = page_len(len).and_then(|len| Some((len, addr.checked_add(len)?))) else {
+            return Err(Error::Map(MapError::NoMemory, addr));
+        };
+        let addr = match at {
+            MapAt::Fixed => {
+                self.unmap(addr, end);
+                addr
+            },
+            MapAt::FixedNoReplace if self.overlaps(addr, end) => return Err(Error::Map(MapError::Exists, addr)),
+            MapAt::FixedNoReplace => addr,
+            MapAt::Hint => {
+                let hint = round_down!(addr, PAGE_SIZE) as u64;
+                match hint >= MMAP_MIN && hint.checked_add(len).is_some_and(|end| self.is_free(hint, end)) {
+                    true => hint,
+                    false => self.find_free(len).ok_or(Error::Map(MapError::NoMemory, addr))?,
+                }
+            },
+        };
+        self.map_segment(addr, len as usize, PAGE_SIZE, flags, data, MemOrigin::Mmap)?;
+        Ok(addr)
+    }
+
+    /// Unmaps every page in [addr, addr + len), which need not be mapped.
+    pub fn munmap(&mut self, addr: u64, len: usize) -> Result<()> {
+        let end = self.page_range(addr, len)?;
+        self.unmap(addr, end);
+        Ok(())
+    }
+
+    /// Changes the flags of [addr, addr + len), every page of which has to be mapped.
+    pub fn mprotect(&mut self, addr: u64, len: usize, flags: MemFlags) -> Result<()> {
+        let end = self.page_range(addr, len)?;
+        if !self.is_mapped(addr, end) {
+            return Err(Error::Map(MapError::NoMemory, addr));
+        }
+        self.split_at(addr);
+        self.split_at(end);
+        for segment in self.segments.range_mut(addr..end).map(|(_, segment)| segment) {
+            segment.flags = flags;
+        }
+        let starts: Vec<u64> = self.segments.range(addr..=end).map(|(&start, _)| start).collect();
+        for start in starts {
+            self.merge_at(start);
+        }
+        self.update_pages(addr, end);
+        Ok(())
+    }
+
+    /// Resizes the mapping [addr, addr + old_len) to 'new_len' bytes, in place if the pages
+    /// after it are free, or else somewhere else if 'may_move'. Returns where it is now.
+    /// Pages it gains read as zero and take the flags of its first page.
+    pub fn mremap(&mut self, addr: u64, old_len: usize, new_len: usize, may_move: bool) -> Result<u64> {
+        let old_end = self.page_range(addr, old_len)?;
+        let new_end = self.page_range(addr, new_len)?;
+        if !self.is_mapped(addr, old_end) {
+            return Err(Error::Map(MapError::NoMemory, addr));
+        }
+        if new_end <= old_end {
+            self.unmap(new_end, old_end);
+            return Ok(addr);
+        }
+        let (flags, origin) = self.segments.range(..=addr).next_back()
+            .map(|(_, segment)| (segment.flags, segment.origin)).unwrap();
+        if self.is_free(old_end, new_end) {
+            self.map_segment(old_end, (new_end - old_end) as usize, PAGE_SIZE, flags, None, origin)?;
+            return Ok(addr);
+        }
+        if !may_move {
+            return Err(Error::Map(MapError::NoMemory, addr));
+        }
+        let to = self.find_free(new_end - addr).ok_or(Error::Map(MapError::NoMemory, addr))?;
+        let data = self.copy_out(addr, old_end);
+        self.map_segment(to, (new_end - addr) as usize, PAGE_SIZE, flags, Some(&data), MemOrigin::Mmap)?;
+        self.unmap(addr, old_end);
+        Ok(to)
+    }
+
+    /// End of the pages [addr, addr + len) cover, 'addr' has to be a page boundary.
+    fn page_range(&self, addr: u64, len: usize) -> Result<u64> {
+        if len == 0 || !addr.is_multiple_of(PAGE_SIZE as u64) {
+            return Err(Error::Map(MapError::Invalid, addr));
+        }
+        page_len(len).and_then(|len| addr.checked_add(len)).ok_or(Error::Map(MapError::NoMemory, addr))
+    }
+
+    /// Every mapped range in address order, neighbours with the same flags and origin are joined.
+    pub fn regions(&self) -> impl Iterator<Item = MemRegion> + '_ {
+        let mut segments = self.segments.values().peekable();
+        std::iter::from_fn(move || {
+            let first = segments.next()?;
+            let mut region = MemRegion {
+                start: first.m_gaddr_start,
+                end: first.m_gaddr_end,
+                flags: first.flags,
+                origin: first.origin,
+            };
+            while let Some(next) = segments.next_if(|next| {
+                next.m_gaddr_start == region.end && next.flags == region.flags && next.origin == region.origin
+            }) {
+                region.end = next.m_gaddr_end;
+            }
+            Some(region)
+        })
+    }
+
+    /// The regions in the layout of Linux's /proc/self/maps, 'program' names the ELF segments.
+    /// Every mapping is private and anonymous as far as the guest can tell.
+    pub fn format_maps(&self, program: &str) -> String {
+        let mut maps = String::new();
+        for region in self.regions() {
+            let perm = |flag, c| if region.flags.contains(flag) { c } else { '-' };
+            let line = format!(
+                "{:08x}-{:08x} {}{}{}p 00000000 00:00 0",
+                region.start,
+                region.end,
+                perm(MemFlags::READ, 'r'),
+                perm(MemFlags::WRITE, 'w'),
+                perm(MemFlags::EXECUTE, 'x'),
+            );
+            let name = match region.origin {
+                MemOrigin::Elf => program,
+                MemOrigin::Heap => "[heap]",
+                MemOrigin::Stack => "[stack]",
+                MemOrigin::Mmap | MemOrigin::Device => "",
+            };
+            match name.is_empty() {
+                true => maps.push_str(&line),
+                // the name starts in column 74, as Linux pads it
+                false => maps.push_str(&format!("{:<73}{}", line, name)),
+            }
+            maps.push('\n');
+        }
+        maps
+    }
+
+    /// Pages mapped and pages the host backs with memory so far.
+    pub fn stats(&self) -> MemStats {
+        self.segments.values().fold(MemStats::default(), |stats, segment| MemStats {
+            mapped_pages: stats.mapped_pages + segment.num_pages(),
+            resident_pages: stats.resident_pages + segment.resident_pages(),
+        })
+    }
+
+    /// Saves the address space, the pages written so far, the break and the stacks. From now on
+    /// stores are tracked per page, so that restoring the snapshot only copies the pages stored to
+    /// since, as long as no mapping changed in between.
+    pub fn snapshot(&mut self) -> MemSnapshot {
+        let pages = self.written_pages();
+        let id = NEXT_SNAPSHOT.fetch_add(1, Ordering::Relaxed);
+        self.dirty.clear();
+        self.dirty_base = Some(id);
+        self.flush_tlb();
+        MemSnapshot {
+            id,
+            segments: self.segments.clone(),
+            pages,
+            written: self.written.clone(),
+            init_brk_gaddr: self.init_brk_gaddr,
+            cur_brk_gaddr: self.cur_brk_gaddr,
+            stacks: self.stacks.clone(),
+            pmp: self.pmp.clone(),
+            store_buffers: self.store_buffers.clone(),
+            lr_reservations: self.lr_reservations.clone(),
+        }
+    }
+
+    /// Puts the guest memory back the way it was when 'snapshot' was taken.
+    pub fn restore(&mut self, snapshot: &MemSnapshot) {
+        let same_layout = self.segments.len() == snapshot.segments.len()
+            && self.segments.values().zip(snapshot.segments.values()).all(|(cur, old)| {
+                cur.gaddr_start == old.gaddr_start
+                    && cur.gaddr_end == old.gaddr_end
+                    && cur.m_gaddr_start == old.m_gaddr_start
+                    && cur.m_gaddr_end == old.m_gaddr_end
+                    && cur.host == old.host
+                    && cur.flags == old.flags
+            });
+        if same_layout && self.dirty_base == Some(snapshot.id) {
+            let dirty = std::mem::take(&mut self.dirty);
+            for &page in &dirty {
+                self.put_back(&snapshot.pages, page);
+            }
+            if dirty.iter().any(|page| self.code_pages.contains_key(page)) {
+                self.invalidate_code();
+            }
+        } else {
+            self.segments = snapshot.segments.clone();
+            let mut mappings: Vec<&Rc<MmapMut>> = self.segments.values().map(|segment| &segment.mapping).collect();
+            mappings.dedup_by(|a, b| Rc::ptr_eq(a, b));
+            for mapping in mappings {
+                // the whole mapping reads as zero again, heap and stack reservations included
+                // SAFETY: no references into the mapping are held, the TLB is flushed below before
+                // the next access and the written pages are copied back
+                let _ = unsafe { mapping.unchecked_advise_range(UncheckedAdvice::DontNeed, 0, mapping.len()) };
+            }
+            for &page in &snapshot.written {
+                self.put_back(&snapshot.pages, page);
+            }
+            self.dirty.clear();
+            self.invalidate_code();
+        }
+        self.written = snapshot.written.clone();
+        self.dirty_base = Some(snapshot.id);
+        self.init_brk_gaddr = snapshot.init_brk_gaddr;
+        self.cur_brk_gaddr = snapshot.cur_brk_gaddr;
+        self.stacks = snapshot.stacks.clone();
+        self.pmp = snapshot.pmp.clone();
+        self.store_buffers = snapshot.store_buffers.clone();
+        self.lr_reservations = snapshot.lr_reservations.clone();
+        self.flush_tlb();
+    }
+
+    /// Copies the page at 'page' back from 'saved', which `written_pages` returned for
+    /// segments laid out like the current ones.
+    fn put_back(&mut self, saved: &BTreeMap<u64, Box<[u8]>>, page: u64) {
+        let end = page + PAGE_SIZE as u64;
+        for segment in self.segments.range_mut(..end).rev().map(|(_, segment)| segment) {
+            if segment.m_gaddr_end <= page {
+                break;
+            }
+            let from = page.max(segment.m_gaddr_start);
+            let to = end.min(segment.m_gaddr_end);
+            let offset = (from - segment.m_gaddr_start) as usize;
+            let bytes = &mut segment.bytes_mut()[offset..offset + (to - from) as usize];
+            match saved.get(&from) {
+                Some(saved) => bytes.copy_from_slice(saved),
+                None => bytes.fill(0),
+            }
+        }
+    }
+
+    /// The bytes of every written page, split where segments start or end.
+    /// Keyed by the start of each part.
+    fn written_pages(&self) -> BTreeMap<u64, Box<[u8]>> {
+        let mut pages = BTreeMap::new();
+        for &page in &self.written {
+            let end = page + PAGE_SIZE as u64;
+            for segment in self.segments.range(..end).rev().map(|(_, segment)| segment) {
+                if segment.m_gaddr_end <= page {
+                    break;
+                }
+                let from = page.max(segment.m_gaddr_start);
+                let to = end.min(segment.m_gaddr_end);
+                let offset = (from - segment.m_gaddr_start) as usize;
+                pages.insert(from, segment.bytes()[offset..offset + (to - from) as usize].into());
+            }
+        }
+        pages
+    }
+
+    /// Writes the segments, the written pages, the break, the stacks and the CSR state kept here
+    /// to a checkpoint, see `checkpoint`.
+    pub(crate) fn save_checkpoint(&self, out: &mut CheckpointWriter) -> Result<()> {
+        if self.store_buffers.as_ref().is_some_and(|buffers| (0..buffers.harts()).any(|hart| buffers.len(hart) > 0)) {
+            return Err(Error::Other("Buffered stores cannot be checkpointed".to_string()));
+        }
+        // host mappings shared by several segments stay shared, so that the heap and
+        // the stacks grow the same way after resuming
+        let mut mappings: Vec<&Rc<MmapMut>> = vec![];
+        out.u64(self.segments.len() as u64);
+        for segment in self.segments.values() {
+            // a new mapping is followed by its length
+            match mappings.iter().position(|mapping| Rc::ptr_eq(mapping, &segment.mapping)) {
+                Some(idx) => out.u64(idx as u64),
+                None => {
+                    out.u64(mappings.len() as u64);
+                    out.u64(segment.mapping.len() as u64);
+                    mappings.push(&segment.mapping);
+                },
+            }
+            out.u64((segment.host as usize - segment.mapping.as_ptr() as usize) as u64);
+            out.u64(segment.gaddr_start);
+            out.u64(segment.gaddr_end);
+            out.u64(segment.m_gaddr_start);
+            out.u64(segment.m_gaddr_end);
+            out.u8(segment.flags.bits());
+            out.u8(segment.origin as u8);
+        }
+        let pages = self.written_pages();
+        out.u64(pages.len() as u64);
+        for (&from, bytes) in &pages {
+            out.u64(from);
+            out.bytes(bytes);
+        }
+        out.u64(self.written.len() as u64);
+        for &page in &self.written {
+            out.u64(page);
+        }
+        out.u64(self.init_brk_gaddr);
+        out.u64(self.cur_brk_gaddr);
+        out.u64(self.stacks.len() as u64);
+        for stack in &self.stacks {
+            out.u64(stack.bottom);
+            out.u64(stack.limit);
+        }
+
+        out.bool(self.pmp.is_some());
+        if let Some(pmp) = &self.pmp {
+            for idx in 0..PMP_ENTRIES as u32 {
+                out.u64(pmp.read_addr(CSR_PMPADDR0 + idx).unwrap());
+            }
+            out.u64(pmp.read_cfg(CSR_PMPCFG0).unwrap());
+            out.u64(pmp.read_cfg(CSR_PMPCFG2).unwrap());
+        }
+        out.u64(self.lr_reservations.len() as u64);
+        for reservation in &self.lr_reservations {
+            out.u64(reservation.hart as u64);
+            out.u64(reservation.addr);
+            out.u64(reservation.len as u64);
+        }
+        Ok(())
+    }
+
+    /// Replaces the whole address space with what `save_checkpoint` wrote.
+    pub(crate) fn load_checkpoint(&mut self, input: &mut CheckpointReader) -> Result<()> {
+        let invalid = |msg: &str| Error::InvalidCheckpoint(msg.to_string());
+        let mut mappings: Vec<Rc<MmapMut>> = vec![];
+        let mut segments = BTreeMap::new();
+        for _ in 0..input.count(8 * 6 + 2)? {
+            let mapping = input.u64()? as usize;
+            if mapping == mappings.len() {
+                let len = input.u64()? as usize;
+                let mmap = MmapOptions::new().len(len).map_anon().map_err(|e| {
+                    Error::InternalError(format!("Failed to create memory map: {}", e))
+                })?;
+                mappings.push(Rc::new(mmap));
+            }
+            let Some(mapping) = mappings.get(mapping) else {
+                return Err(invalid("Unknown host mapping"));
+            };
+            let offset = input.u64()? as usize;
+            let (gaddr_start, gaddr_end) = (input.u64()?, input.u64()?);
+            let (m_gaddr_start, m_gaddr_end) = (input.u64()?, input.u64()?);
+            let flags = MemFlags::from_bits(input.u8()?).ok_or_else(|| invalid("Unknown segment flags"))?;
+            let origin = *MemOrigin::ALL.get(input.u8()? as usize).ok_or_else(|| invalid("Unknown segment origin"))?;
+            if m_gaddr_start > gaddr_start || gaddr_start >= gaddr_end || gaddr_end > m_gaddr_end
+                || offset.checked_add((m_gaddr_end - m_gaddr_start) as usize).is_none_or(|end| end > mapping.len())
+                || segments.range(..m_gaddr_end).next_back()
+                    .is_some_and(|(_, segment): (_, &MemSegment)| segment.m_gaddr_end > m_gaddr_start)
+            {
+                return Err(invalid("Segment out of bounds"));
+            }
+            segments.insert(m_gaddr_start, MemSegment {
+                gaddr_start,
+                gaddr_end,
+                m_gaddr_start,
+                m_gaddr_end,
+                // SAFETY: checked above that the segment lies inside the mapping
+                host: unsafe { mapping.as_ptr().add(offset) as *mut u8 },
+                mapping: mapping.clone(),
+                flags,
+                origin,
+            });
+        }
+        let mut pages = BTreeMap::new();
+        for _ in 0..input.count(16)? {
+            let from = input.u64()?;
+            pages.insert(from, Box::from(input.bytes()?));
+        }
+        let mut written = HashSet::new();
+        for _ in 0..input.count(8)? {
+            written.insert(input.u64()?);
+        }
+        let (init_brk_gaddr, cur_brk_gaddr) = (input.u64()?, input.u64()?);
+        let mut stacks = vec![];
+        for _ in 0..input.count(16)? {
+            stacks.push(Stack { bottom: input.u64()?, limit: input.u64()? });
+        }
+
+        let pmp = match input.bool()? {
+            true => {
+                let mut pmp = Pmp::new();
+                // addresses first, a locked entry would keep them from being written
+                for idx in 0..PMP_ENTRIES as u32 {
+                    pmp.write_addr(CSR_PMPADDR0 + idx, input.u64()?).unwrap();
+                }
+                pmp.write_cfg(CSR_PMPCFG0, input.u64()?).unwrap();
+                pmp.write_cfg(CSR_PMPCFG2, input.u64()?).unwrap();
+                Some(pmp)
+            },
+            false => None,
+        };
+        let mut lr_reservations = vec![];
+        for _ in 0..input.count(24)? {
+            let hart = input.u64()? as usize;
+            let addr = input.u64()?;
+            let len = match input.u64()? {
+                len @ (4 | 8) => len as usize,
+                _ => return Err(invalid("Invalid LR reservation")),
+            };
+            lr_reservations.push(LrReservation { hart, addr, len });
+        }
+
+        self.segments = segments;
+        for &page in &written {
+            self.put_back(&pages, page);
+        }
+        self.written = written;
+        self.dirty.clear();
+        self.dirty_base = None;
+        self.init_brk_gaddr = init_brk_gaddr;
+        self.cur_brk_gaddr = cur_brk_gaddr;
+        self.stacks = stacks;
+        if pmp.is_some() {
+            self.pmp = pmp;
+        }
+        self.lr_reservations = lr_reservations;
+        self.invalidate_code();
+        self.flush_tlb();
+        Ok(())
+    }
+
+    /// Every segment's page-aligned start, flags and bytes, in address order.
+    pub(crate) fn segment_bytes(&self) -> impl Iterator<Item = (u64, MemFlags, &[u8])> {
+        self.segments.values().map(|segment| (segment.m_gaddr_start, segment.flags, segment.bytes()))
+    }
+
+    /// Whether any segment overlaps [start, end).
+    fn overlaps(&self, start: u64, end: u64) -> bool {
+        // segments do not overlap, so only the last one starting below 'end' can reach 'start'
+        self.segments.range(..end).next_back().is_some_and(|(_, segment)| segment.m_gaddr_end > start)
+    }
+
+    /// Whether [start, end) is neither mapped nor kept for the heap or a stack.
+    fn is_free(&self, start: u64, end: u64) -> bool {
+        !self.overlaps(start, end)
+            && self.reservations().all(|(kept_start, kept_end)| end <= kept_start || start >= kept_end)
+    }
+
+    /// Whether every page of [start, end) is mapped.
+    fn is_mapped(&self, start: u64, end: u64) -> bool {
+        let mut next = start;
+        for segment in self.segments.range(..end).map(|(_, segment)| segment) {
+            if segment.m_gaddr_end <= next {
+                continue;
+            }
+            if segment.m_gaddr_start > next {
+                return false;
+            }
+            next = segment.m_gaddr_end;
+        }
+        next >= end
+    }
+
+    /// The highest free range of 'len' bytes below the mmap base, the way Linux lays out mmaps.
+    fn find_free(&self, len: u64) -> Option<u64> {
+        let mut used: Vec<(u64, u64)> = self.segments.values()
+            .map(|segment| (segment.m_gaddr_start, segment.m_gaddr_end))
+            .chain(self.reservations())
+            .collect();
+        used.sort_unstable();
+        let mut top = self.layout.mmap_base;
+        for &(start, end) in used.iter().rev() {
+            if end < top && top - end >= len {
+                return Some(top - len);
+            }
+            top = top.min(start);
+        }
+        top.checked_sub(len).filter(|&start| start >= MMAP_MIN)
+    }
+
+    /// Makes a segment start at 'gaddr', a page boundary, by splitting the one containing it.
+    fn split_at(&mut self, gaddr: u64) {
+        let Some((_, segment)) = self.segments.range_mut(..gaddr).next_back() else {
+            return;
+        };
+        if segment.m_gaddr_end > gaddr {
+            let upper = segment.split_off(gaddr);
+            self.segments.insert(gaddr, upper);
+        }
+    }
+
+    /// Joins the segment starting at 'gaddr' to the one before it, if both are pieces of the
+    /// same host mapping with the same flags.
+    fn merge_at(&mut self, gaddr: u64) {
+        let Some((&start, lower)) = self.segments.range(..gaddr).next_back() else {
+            return;
+        };
+        if !self.segments.get(&gaddr).is_some_and(|upper| lower.mergeable(upper)) {
+            return;
+        }
+        let upper = self.segments.remove(&gaddr).unwrap();
+        let lower = self.segments.get_mut(&start).unwrap();
+        lower.gaddr_end = upper.gaddr_end;
+        lower.m_gaddr_end = upper.m_gaddr_end;
+    }
+
+    /// Drops the segments in [start, end), both page boundaries, splitting those that cross them.
+    fn unmap(&mut self, start: u64, end: u64) {
+        self.split_at(start);
+        self.split_at(end);
+        let starts: Vec<u64> = self.segments.range(start..end).map(|(&start, _)| start).collect();
+        for start in starts {
+            let segment = self.segments.remove(&start).unwrap();
+            // the host unmaps the memory with the last piece of the mapping
+            if Rc::strong_count(&segment.mapping) > 1 {
+                segment.release();
+            }
+        }
+        self.forget_written(start, end);
+        self.break_reservations(start, end);
+        self.update_pages(start, end);
+    }
+
+    /// Drops the TLB entries of [start, end), both page boundaries, after the segments there
+    /// changed. Instructions decoded from these pages are dropped.
+    fn update_pages(&mut self, start: u64, end: u64) {
+        if (end - start) / PAGE_SIZE as u64 > TLB_ENTRIES as u64 {
+            self.flush_tlb();
+        } else {
+            for page in (start..end).step_by(PAGE_SIZE) {
+                self.flush_tlb_page(page);
+            }
+        }
+        if self.code_pages.keys().any(|&page| page >= start && page < end) {
+            self.invalidate_code();
+        }
+    }
+
+    /// Notes a store to [gaddr, gaddr + len), see 'written' and 'dirty'.
+    fn mark_written(&mut self, gaddr: u64, len: usize) {
+        let last = page_of(gaddr + len as u64 - 1);
+        for page in (page_of(gaddr)..=last).step_by(PAGE_SIZE) {
+            let first = self.written.insert(page);
+            let dirtied = self.dirty_base.is_some() && self.dirty.insert(page);
+            if first || dirtied {
+                // lets the next store take the fast path
+                self.flush_tlb_page(page);
+            }
+        }
+    }
+
+    /// Forgets the stores to [start, end), both page boundaries, after the range was unmapped.
+    /// Its pages read as zero if they are mapped again, so they count as dirty.
+    fn forget_written(&mut self, start: u64, end: u64) {
+        let pages: Vec<u64> = if (end - start) / PAGE_SIZE as u64 > self.written.len() as u64 {
+            self.written.iter().copied().filter(|&page| page >= start && page < end).collect()
+        } else {
+            (start..end).step_by(PAGE_SIZE).filter(|page| self.written.contains(page)).collect()
+        };
+        for page in pages {
+            self.written.remove(&page);
+            if self.dirty_base.is_some() {
+                self.dirty.insert(page);
+            }
+        }
+    }
+
+    /// Zeroes the mapped bytes of [start, end), whatever their flags.
+    fn zero(&mut self, start: u64, end: u64) {
+        self.break_reservations(start, end);
+        if start < end {
+            self.mark_written(start, (end - start) as usize);
+        }
+        for segment in self.segments.range_mut(..end).map(|(_, segment)| segment) {
+            if segment.m_gaddr_end > start {
+                let from = start.max(segment.m_gaddr_start) - segment.m_gaddr_start;
+                let to = end.min(segment.m_gaddr_end) - segment.m_gaddr_start;
+                segment.bytes_mut()[from as usize..to as usize].fill(0);
+            }
+        }
+    }
+
+    /// The bytes of [start, end), every page of which is mapped, whatever their flags.
+    fn copy_out(&self, start: u64, end: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity((end - start) as usize);
+        for segment in self.segments.range(..end).map(|(_, segment)| segment) {
+            if segment.m_gaddr_end > start {
+                let from = start.max(segment.m_gaddr_start) - segment.m_gaddr_start;
+                let to = end.min(segment.m_gaddr_end) - segment.m_gaddr_start;
+                bytes.extend_from_slice(&segment.bytes()[from as usize..to as usize]);
+            }
+        }
+        bytes
+    }
+
+    /// Maps a segment that counts as `MemOrigin::Device`.
     pub fn add_segment(
         &mut self,
         gaddr_start: u64,
@@ -163,6 +1399,18 @@ impl GuestMem {
         align: usize,
         flags: MemFlags,
         init_data: Option<&[u8]>,
+    ) -> Result<()> {
+        self.map_segment(gaddr_start, len, align, flags, init_data, MemOrigin::Device)
+    }
+
+    fn map_segment(
+        &mut self,
+        gaddr_start: u64,
+        len: usize,
+        align: usize,
+        flags: MemFlags,
+        init_data: Option<&[u8]>,
+        origin: MemOrigin,
     ) -> Result<()> {
         assert!(len != 0);
 
@@ -171,15 +1419,10 @@ impl GuestMem {
         let m_gaddr_start = round_down!(gaddr_start, align) as u64;
 
         let m_gaddr_end = round_up!(gaddr_end, align) as u64;
-        let m_len = m_gaddr_end - m_gaddr_start as u64;
-
-        // Check if the segment overlaps with existing segments
-        // This should be optimized later.
-        for (&seg_base_gaddr, seg) in self.segments.iter() {
-            if m_gaddr_start < seg.m_gaddr_start && m_gaddr_end > seg.m_gaddr_start
-            || m_gaddr_start >= seg.m_gaddr_start && m_gaddr_start < seg.m_gaddr_end {
-                return Err(Error::InternalError("Memory segment overlaps with existing segment".into()))
-            }
+        let m_len = m_gaddr_end - m_gaddr_start;
+
+        if self.overlaps(m_gaddr_start, m_gaddr_end) {
+            return Err(Error::InternalError("Memory segment overlaps with existing segment".into()))
         }
 
         let mmap = MmapOptions::new()
@@ -192,24 +1435,86 @@ impl GuestMem {
         let mut segment = MemSegment::new(
             gaddr_start, gaddr_end,
             m_gaddr_start, m_gaddr_end, 
-            mmap, flags,
+            mmap, flags, origin,
         );
 
         if let Some(data) = init_data {
             assert!(data.len() <= len);
-            // copy init data
-            segment.host_mmap[(gaddr_start - m_gaddr_start) as usize..(gaddr_start - m_gaddr_start) as usize + data.len()]
-                .copy_from_slice(data);
-            // zero out the rest of the segment
-            segment.host_mmap[..(gaddr_start - m_gaddr_start) as usize].fill(0);
-            segment.host_mmap[(gaddr_start - m_gaddr_start) as usize + data.len()..].fill(0);
+            let offset = (gaddr_start - m_gaddr_start) as usize;
+            // the rest of the segment is fresh anonymous memory, which reads as zero
+            // without the host backing it until it is touched
+            segment.bytes_mut()[offset..offset + data.len()].copy_from_slice(data);
         }
 
         self.segments.insert(m_gaddr_start, segment);
+        if let Some(data) = init_data.filter(|data| !data.is_empty()) {
+            self.mark_written(gaddr_start, data.len());
+        }
+        self.update_pages(m_gaddr_start, m_gaddr_end);
+        self.invalidate_code();
 
         Ok(())
     }
 
+    /// Host address of [gaddr, gaddr + len) if the range lies in one page that allows 'access'
+    /// and stores to it need no bookkeeping. None means the caller has to take the slow path,
+    /// which also raises faults.
+    fn translate(&self, gaddr: u64, len: usize, access: MemAccess) -> Option<*mut u8> {
+        let offset = gaddr as usize & (PAGE_SIZE - 1);
+        if offset + len > PAGE_SIZE {
+            return None;
+        }
+        let page = gaddr / PAGE_SIZE as u64;
+        let slot = &self.tlb[page as usize % TLB_ENTRIES];
+        let mut entry = slot.get();
+        if entry.page != page {
+            entry = self.tlb_miss(page)?;
+            slot.set(entry);
+        }
+        if !entry.flags.contains(MemFlags::of(access)) {
+            return None;
+        }
+        // SAFETY: 'offset' is inside the page
+        Some(unsafe { entry.host.add(offset) })
+    }
+
+    /// The TLB entry of guest page number 'page', None if no segment covers the page completely.
+    #[inline(never)]
+    fn tlb_miss(&self, page: u64) -> Option<TlbEntry> {
+        let start = page * PAGE_SIZE as u64;
+        let (_, segment) = self.segments.range(..=start).next_back()?;
+        // pages the segment covers only partly take the slow path
+        if segment.gaddr_start > start || segment.m_gaddr_end.saturating_sub(start) < PAGE_SIZE as u64 {
+            return None;
+        }
+        let mut flags = segment.flags;
+        // an unmapped page stays dirty after it is mapped again, but it is no longer written
+        if self.code_pages.contains_key(&start)
+            || !self.written.contains(&start)
+            || self.dirty_base.is_some() && !self.dirty.contains(&start)
+        {
+            flags.remove(MemFlags::WRITE);
+        }
+        // SAFETY: the page lies inside the segment's mapping, which does not move
+        let host = unsafe { segment.host.add((start - segment.m_gaddr_start) as usize) };
+        Some(TlbEntry { page, host, flags })
+    }
+
+    /// Drops the TLB entry of the page at 'gaddr', after its flags changed.
+    fn flush_tlb_page(&self, gaddr: u64) {
+        let page = gaddr / PAGE_SIZE as u64;
+        let slot = &self.tlb[page as usize % TLB_ENTRIES];
+        if slot.get().page == page {
+            slot.set(TLB_EMPTY);
+        }
+    }
+
+    fn flush_tlb(&self) {
+        for slot in &self.tlb {
+            slot.set(TLB_EMPTY);
+        }
+    }
+
     /// Decomposes a guest address into its segment and checks access permissions.
     fn decompose(&self, gaddr: u64, access: MemAccess) -> Result<(u64, &MemSegment)> {
         for (&base_gaddr, segment) in self.segments.range(..=gaddr).rev() {
@@ -237,75 +1542,207 @@ impl GuestMem {
         Err(Error::MemAccessFault(access, gaddr))
     }
 
+    /// Checks the whole access against PMP, if enabled, and against address triggers.
+    fn check_access(&self, gaddr: u64, len: usize, access: MemAccess) -> Result<()> {
+        match &self.pmp {
+            Some(pmp) if !pmp.check(gaddr, len, access, self.access_privilege(access)) => return Err(Error::PmpViolation(access, gaddr)),
+            _ => {},
+        }
+        self.check_triggers(gaddr, len, access, None)
+    }
+
+    /// Privilege level an access is checked at, mstatus.MPP for loads and stores while MPRV is set.
+    fn access_privilege(&self, access: MemAccess) -> Privilege {
+        match access {
+            MemAccess::Execute => self.privilege,
+            _ => self.mprv.unwrap_or(self.privilege),
+        }
+    }
+
+    fn check_triggers(&self, gaddr: u64, len: usize, access: MemAccess, data: Option<u64>) -> Result<()> {
+        if !self.triggers.armed() {
+            return Ok(());
+        }
+        match self.triggers.check(access, gaddr, len, data, self.privilege()) {
+            Some(idx) => Err(Error::TriggerHit(idx, gaddr)),
+            None => Ok(()),
+        }
+    }
+
     pub fn fetch_insn(&self, pc: u64) -> Result<u32> {
-        let mut res = [0; 4];
-        for i in 0..4u64 {
-            res[i as usize] = self.read_u8_raw(pc + i, MemAccess::Execute)?;
+        self.check_access(pc, 4, MemAccess::Execute)?;
+        self.read_raw(pc, MemAccess::Execute).map(u32::from_le_bytes)
+    }
+
+    /// Reads [gaddr, gaddr + N) straight from memory.
+    fn read_raw<const N: usize>(&self, gaddr: u64, access: MemAccess) -> Result<[u8; N]> {
+        let mut bytes = [0; N];
+        match self.translate(gaddr, N, access) {
+            // SAFETY: the N bytes lie in one page of a mapped segment
+            Some(host) => unsafe { ptr::copy_nonoverlapping(host, bytes.as_mut_ptr(), N) },
+            None => for (i, byte) in bytes.iter_mut().enumerate() {
+                let gaddr = gaddr + i as u64;
+                let (_, segment) = self.decompose(gaddr, access)?;
+                *byte = segment.bytes()[(gaddr - segment.m_gaddr_start) as usize];
+            },
         }
-        Ok(u32::from_le_bytes(res))
+        Ok(bytes)
     }
 
+    /// Reads the bytes of a load, seeing the current hart's buffered stores.
+    fn load<const N: usize>(&self, gaddr: u64) -> Result<[u8; N]> {
+        let Some(buffers) = &self.store_buffers else {
+            return self.read_raw(gaddr, MemAccess::Read);
+        };
+        let mut bytes = [0; N];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            let gaddr = gaddr + i as u64;
+            let value = self.read_u8_raw(gaddr, MemAccess::Read)?;
+            *byte = buffers.forward(gaddr).unwrap_or(value);
+        }
+        Ok(bytes)
+    }
+
+    /// Writes the bytes of a store, or buffers them in weak memory mode.
+    fn store(&mut self, gaddr: u64, bytes: &[u8]) -> Result<()> {
+        match &self.store_buffers {
+            Some(buffers) if !buffers.bypass() => {
+                // faults are raised by the store, not when it is flushed
+                for i in 0..bytes.len() as u64 {
+                    self.decompose(gaddr + i, MemAccess::Write)?;
+                }
+                self.store_buffers.as_mut().unwrap().push(gaddr, bytes);
+            },
+            _ => self.write_raw(gaddr, bytes)?,
+        }
+        Ok(())
+    }
+
+    /// Notes an access for the store buffers and the race detector.
+    fn record(&self, gaddr: u64, len: usize, access: MemAccess) {
+        if let Some(buffers) = &self.store_buffers {
+            buffers.record_event();
+        }
+        if let Some(log) = &self.access_log {
+            let kind = match access {
+                _ if self.in_atomic => AccessKind::Atomic,
+                MemAccess::Write => AccessKind::Write,
+                _ => AccessKind::Read,
+            };
+            log.borrow_mut().push(Access { addr: gaddr, len, kind });
+        }
+    }
+
+    /// Notes a fence for the race detector, 'acquire' if it orders earlier loads, 'release'
+    /// if it orders later stores.
+    pub fn record_fence(&self, acquire: bool, release: bool) {
+        if let Some(log) = &self.access_log {
+            log.borrow_mut().push(Access { addr: 0, len: 0, kind: AccessKind::Fence { acquire, release } });
+        }
+    }
 
     pub fn read_u8_raw(&self, gaddr: u64, access: MemAccess) -> Result<u8> {
-        let (base_gaddr, segment) = self.decompose(gaddr, access)?;
-        let offset = (gaddr - segment.m_gaddr_start) as usize;
-        Ok(segment.host_mmap[offset])       
+        self.read_raw::<1>(gaddr, access).map(|[value]| value)
+    }
+
+    /// Writes straight to memory, past PMP, triggers and the store buffers.
+    pub fn write_u8_raw(&mut self, gaddr: u64, value: u8) -> Result<()> {
+        self.write_raw(gaddr, &[value])
+    }
+
+    /// Writes 'bytes' at 'gaddr', either all of them or, on a fault, none.
+    fn write_raw(&mut self, gaddr: u64, bytes: &[u8]) -> Result<()> {
+        if !self.lr_reservations.is_empty() {
+            self.break_reservations(gaddr, gaddr + bytes.len() as u64);
+        }
+        if let Some(host) = self.translate(gaddr, bytes.len(), MemAccess::Write) {
+            // SAFETY: the bytes lie in one page of a mapped segment
+            unsafe { ptr::copy_nonoverlapping(bytes.as_ptr(), host, bytes.len()) };
+            return Ok(());
+        }
+
+        // the range may span segments, check all of it before changing anything
+        for i in 0..bytes.len() as u64 {
+            self.decompose(gaddr + i, MemAccess::Write)?;
+        }
+        for (i, &byte) in bytes.iter().enumerate() {
+            let gaddr = gaddr + i as u64;
+            let (_, segment) = self.decompose_mut(gaddr, MemAccess::Write)?;
+            let offset = (gaddr - segment.m_gaddr_start) as usize;
+            segment.bytes_mut()[offset] = byte;
+        }
+        self.mark_written(gaddr, bytes.len());
+
+        if !self.code_pages.is_empty() {
+            let last = page_of(gaddr + bytes.len() as u64 - 1);
+            for page in (page_of(gaddr)..=last).step_by(PAGE_SIZE) {
+                if let Some(generation) = self.code_pages.get_mut(&page) {
+                    *generation += 1;
+                    self.code_epoch += 1;
+                }
+            }
+        }
+        Ok(())
     }
 
     pub fn read_u8(&self, gaddr: u64) -> Result<u8> {
-        self.read_u8_raw(gaddr, MemAccess::Read)
+        self.check_access(gaddr, 1, MemAccess::Read)?;
+        self.record(gaddr, 1, MemAccess::Read);
+        let [value] = self.load(gaddr)?;
+        self.check_triggers(gaddr, 1, MemAccess::Read, Some(value as u64))?;
+        Ok(value)
     }
 
     pub fn write_u8(&mut self, gaddr: u64, value: u8) -> Result<()> {
-        let (base_gaddr, segment) = self.decompose_mut(gaddr, MemAccess::Write)?;
-        let offset = (gaddr - segment.m_gaddr_start) as usize;
-        segment.host_mmap[offset] = value;
-        Ok(())
+        self.check_access(gaddr, 1, MemAccess::Write)?;
+        self.check_triggers(gaddr, 1, MemAccess::Write, Some(value as u64))?;
+        self.record(gaddr, 1, MemAccess::Write);
+        self.store(gaddr, &[value])
     }
 
     pub fn read_u16(&self, gaddr: u64) -> Result<u16> {
-        // We can't ensure the address is aligned, so we read byte by byte.
-        let low = self.read_u8(gaddr)?;
-        let high = self.read_u8(gaddr + 1)?;
-        Ok((high as u16) << 8 | (low as u16))
+        self.check_access(gaddr, 2, MemAccess::Read)?;
+        self.record(gaddr, 2, MemAccess::Read);
+        let value = u16::from_le_bytes(self.load(gaddr)?);
+        self.check_triggers(gaddr, 2, MemAccess::Read, Some(value as u64))?;
+        Ok(value)
     }
 
     pub fn write_u16(&mut self, gaddr: u64, value: u16) -> Result<()> {
-        self.write_u8(gaddr, (value & 0xFF) as u8)?;
-        self.write_u8(gaddr + 1, (value >> 8) as u8)?;
-        Ok(())
+        self.check_access(gaddr, 2, MemAccess::Write)?;
+        self.check_triggers(gaddr, 2, MemAccess::Write, Some(value as u64))?;
+        self.record(gaddr, 2, MemAccess::Write);
+        self.store(gaddr, &value.to_le_bytes())
     }
 
     pub fn read_u32(&self, gaddr: u64) -> Result<u32> {
-        let b0 = self.read_u8(gaddr)?;
-        let b1 = self.read_u8(gaddr + 1)?;
-        let b2 = self.read_u8(gaddr + 2)?;
-        let b3 = self.read_u8(gaddr + 3)?;
-        Ok((b3 as u32) << 24 | (b2 as u32) << 16 | (b1 as u32) << 8 | (b0 as u32))
+        self.check_access(gaddr, 4, MemAccess::Read)?;
+        self.record(gaddr, 4, MemAccess::Read);
+        let value = u32::from_le_bytes(self.load(gaddr)?);
+        self.check_triggers(gaddr, 4, MemAccess::Read, Some(value as u64))?;
+        Ok(value)
     }
 
     pub fn write_u32(&mut self, gaddr: u64, value: u32) -> Result<()> {
-        self.write_u8(gaddr, (value & 0xFF) as u8)?;
-        self.write_u8(gaddr + 1, ((value >> 8) & 0xFF) as u8)?;
-        self.write_u8(gaddr + 2, ((value >> 16) & 0xFF) as u8)?;
-        self.write_u8(gaddr + 3, ((value >> 24) & 0xFF) as u8)?;
-        Ok(())
+        self.check_access(gaddr, 4, MemAccess::Write)?;
+        self.check_triggers(gaddr, 4, MemAccess::Write, Some(value as u64))?;
+        self.record(gaddr, 4, MemAccess::Write);
+        self.store(gaddr, &value.to_le_bytes())
     }
 
     pub fn read_u64(&self, gaddr: u64) -> Result<u64> {
-        let mut res = [0u8; 8];
-        for i in 0..8 {
-            res[i] = self.read_u8(gaddr + i as u64)?;
-        }
-        Ok(u64::from_le_bytes(res))
+        self.check_access(gaddr, 8, MemAccess::Read)?;
+        self.record(gaddr, 8, MemAccess::Read);
+        let value = u64::from_le_bytes(self.load(gaddr)?);
+        self.check_triggers(gaddr, 8, MemAccess::Read, Some(value))?;
+        Ok(value)
     }
 
     pub fn write_u64(&mut self, gaddr: u64, value: u64) -> Result<()> {
-        let bytes = value.to_le_bytes();
-        for i in 0..8 {
-            self.write_u8(gaddr + i as u64, bytes[i])?;
-        }
-        Ok(())
+        self.check_access(gaddr, 8, MemAccess::Write)?;
+        self.check_triggers(gaddr, 8, MemAccess::Write, Some(value))?;
+        self.record(gaddr, 8, MemAccess::Write);
+        self.store(gaddr, &value.to_le_bytes())
     }
 
 }
@@ -313,6 +1750,7 @@ impl GuestMem {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::MMAP_TOP;
 
     #[test]
     fn test_parse_elf() {
@@ -343,4 +1781,323 @@ mod tests {
         assert_eq!(value64, 0x9abcdef012345678, "Read value does not match written value");
         debug!("Read u32: {:#x}, Read u64: {:#x}", value, value64);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pmp_access() {
+        log::log_init(log::Level::Off);
+
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x1000, 0x2000, 0x1000, MemFlags::READ | MemFlags::WRITE, None)
+            .expect("Failed to add segment");
+        guest_mem.enable_pmp();
+        let pmp = guest_mem.pmp_mut().unwrap();
+        // entry 0: [0x1000, 0x2000) read-only, NAPOT
+        pmp.write_addr(crate::pmp::CSR_PMPADDR0, (0x1000 >> 2) | 0x1ff).unwrap();
+        pmp.write_cfg(crate::pmp::CSR_PMPCFG0, 0x19).unwrap();
+        guest_mem.set_privilege(Privilege::User, 0);
+
+        assert!(guest_mem.read_u64(0x1000).is_ok());
+        assert!(matches!(guest_mem.write_u32(0x1000, 1), Err(Error::PmpViolation(MemAccess::Write, 0x1000))));
+        // straddles the end of entry 0
+        assert!(guest_mem.read_u32(0x1ffe).is_err());
+        // not covered by any entry
+        assert!(guest_mem.read_u8(0x2000).is_err());
+
+        guest_mem.set_privilege(Privilege::Machine, 0);
+        assert!(guest_mem.write_u32(0x1000, 1).is_ok());
+    }
+
+    #[test]
+    fn test_tlb() {
+        log::log_init(log::Level::Off);
+
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x1000, 0x2000, 0x1000, MemFlags::READ | MemFlags::WRITE, None)
+            .expect("Failed to add segment");
+        // page 0x41 shares its TLB entry with page 0x1
+        let ro_gaddr = 0x1000 + (TLB_ENTRIES * PAGE_SIZE) as u64;
+        guest_mem.add_segment(ro_gaddr, 0x1000, 0x1000, MemFlags::READ, Some(&[0xaa; 8]))
+            .expect("Failed to add segment");
+
+        guest_mem.write_u64(0x1008, 0x1122334455667788).unwrap();
+        assert_eq!(guest_mem.read_u32(0x100c).unwrap(), 0x11223344);
+        // crosses into the next page
+        guest_mem.write_u64(0x1ffc, 0x9abcdef012345678).unwrap();
+        assert_eq!(guest_mem.read_u32(0x2000).unwrap(), 0x9abcdef0);
+        assert_eq!(guest_mem.read_u64(0x1ffc).unwrap(), 0x9abcdef012345678);
+
+        assert_eq!(guest_mem.read_u64(ro_gaddr).unwrap(), 0xaaaaaaaaaaaaaaaa);
+        assert!(matches!(guest_mem.write_u8(ro_gaddr, 1), Err(Error::MemAccessFault(MemAccess::Write, _))));
+        assert_eq!(guest_mem.read_u16(0x100e).unwrap(), 0x1122);
+        assert!(guest_mem.read_u8(0x3000).is_err());
+
+        // stores to code go through the slow path, which bumps the generation
+        guest_mem.mark_code(0x1000);
+        guest_mem.write_u32(0x1010, 0x13).unwrap();
+        assert_ne!(guest_mem.code_page_gen(0x1000), Some(0));
+        assert_eq!(guest_mem.read_u32(0x1010).unwrap(), 0x13);
+    }
+
+    #[test]
+    fn test_store_fault() {
+        log::log_init(log::Level::Off);
+
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x1000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None)
+            .expect("Failed to add segment");
+        guest_mem.add_segment(0x2000, 0x1000, 0x1000, MemFlags::READ, None)
+            .expect("Failed to add segment");
+        guest_mem.add_segment(0x4000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None)
+            .expect("Failed to add segment");
+
+        // into a read-only segment
+        guest_mem.write_u64(0x1ff8, u64::MAX).unwrap();
+        assert!(matches!(guest_mem.write_u64(0x1ffc, 0), Err(Error::MemAccessFault(MemAccess::Write, 0x2000))));
+        assert_eq!(guest_mem.read_u64(0x1ff8).unwrap(), u64::MAX);
+        // off the end of a segment
+        guest_mem.write_u32(0x4ffc, u32::MAX).unwrap();
+        assert!(guest_mem.write_u64(0x4ffc, 0).is_err());
+        assert_eq!(guest_mem.read_u32(0x4ffc).unwrap(), u32::MAX);
+
+        // same through the store buffers, where the fault is raised by the store
+        guest_mem.enable_store_buffers();
+        assert!(guest_mem.write_u16(0x1fff, 0).is_err());
+        guest_mem.drain_store_buffers().unwrap();
+        assert_eq!(guest_mem.read_u8(0x1fff).unwrap(), 0xff);
+    }
+
+    #[test]
+    fn test_brk() {
+        log::log_init(log::Level::Off);
+
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x10000, 0x800, 0x1000, MemFlags::READ | MemFlags::WRITE, None)
+            .expect("Failed to add segment");
+        guest_mem.add_segment(0x20000, 0x1000, 0x1000, MemFlags::READ, None)
+            .expect("Failed to add segment");
+        guest_mem.reset_brk();
+        assert_eq!(guest_mem.brk(0), 0x21000);
+        guest_mem.init_brk_gaddr = 0x10800;
+        guest_mem.cur_brk_gaddr = 0x10800;
+
+        // within the last page of the program, then into a heap page
+        assert_eq!(guest_mem.brk(0x10900), 0x10900);
+        assert_eq!(guest_mem.brk(0x11010), 0x11010);
+        guest_mem.write_u64(0x11ff8, u64::MAX).unwrap();
+        assert!(guest_mem.read_u8(0x12000).is_err());
+
+        // runs into the segment at 0x20000, or below the initial break
+        assert_eq!(guest_mem.brk(0x20010), 0x11010);
+        assert_eq!(guest_mem.brk(0x10000), 0x11010);
+        // the end of the address space, whose page cannot be rounded up to
+        assert_eq!(guest_mem.brk(u64::MAX - 5), 0x11010);
+
+        // freed pages fault, and read as zero once the heap grows again
+        assert_eq!(guest_mem.brk(0x10800), 0x10800);
+        assert!(guest_mem.read_u8(0x11000).is_err());
+        assert_eq!(guest_mem.brk(0x12000), 0x12000);
+        assert_eq!(guest_mem.read_u64(0x11ff8).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_regions() {
+        log::log_init(log::Level::Off);
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let rx = MemFlags::READ | MemFlags::EXECUTE;
+        let mut guest_mem = GuestMem::new();
+        guest_mem.map_segment(0x10000, 0x1800, 0x1000, rx, None, MemOrigin::Elf).unwrap();
+        guest_mem.map_segment(0x12000, 0x1000, 0x1000, rw, None, MemOrigin::Elf).unwrap();
+        guest_mem.reset_brk();
+        guest_mem.brk(0x15000);
+        guest_mem.add_stack(0x80000000, 0x2000, 0x2000).unwrap();
+        let a = guest_mem.mmap(0, 0x3000, rw, MapAt::Hint, None).unwrap();
+        guest_mem.mprotect(a + 0x1000, 0x1000, MemFlags::READ).unwrap();
+        guest_mem.add_segment(0x1000, 0x1000, 0x1000, MemFlags::NONE, None).unwrap();
+
+        let region = |start, end, flags, origin| MemRegion { start, end, flags, origin };
+        assert_eq!(guest_mem.regions().collect::<Vec<_>>(), [
+            region(0x1000, 0x2000, MemFlags::NONE, MemOrigin::Device),
+            region(0x10000, 0x12000, rx, MemOrigin::Elf),
+            region(0x12000, 0x13000, rw, MemOrigin::Elf),
+            region(0x13000, 0x15000, rw, MemOrigin::Heap),
+            region(0x7fffe000, 0x80000000, rw, MemOrigin::Stack),
+            region(a, a + 0x1000, rw, MemOrigin::Mmap),
+            region(a + 0x1000, a + 0x2000, MemFlags::READ, MemOrigin::Mmap),
+            region(a + 0x2000, a + 0x3000, rw, MemOrigin::Mmap),
+        ]);
+
+        let maps = guest_mem.format_maps("/bin/prog");
+        let lines: Vec<&str> = maps.lines().collect();
+        assert_eq!(lines.len(), 8);
+        assert_eq!(lines[0], "00001000-00002000 ---p 00000000 00:00 0");
+        assert_eq!(lines[1], format!("{:<73}/bin/prog", "00010000-00012000 r-xp 00000000 00:00 0"));
+        assert!(lines[3].starts_with("00013000-00015000 rw-p ") && lines[3].ends_with(" [heap]"));
+        assert!(lines[4].starts_with("7fffe000-80000000 rw-p ") && lines[4].ends_with(" [stack]"));
+        assert_eq!(lines[6], format!("{:08x}-{:08x} r--p 00000000 00:00 0", a + 0x1000, a + 0x2000));
+    }
+
+    #[test]
+    fn test_mmap() {
+        log::log_init(log::Level::Off);
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x10000, 0x1000, 0x1000, rw, None).expect("Failed to add segment");
+        guest_mem.reset_brk();
+
+        // top-down below MMAP_TOP, a hint in the heap's range is not taken
+        let a = guest_mem.mmap(0, 0x3000, rw, MapAt::Hint, None).unwrap();
+        assert_eq!(a, MMAP_TOP - 0x3000);
+        let b = guest_mem.mmap(0x20000, 0x10, MemFlags::READ, MapAt::Hint, Some(b"hello")).unwrap();
+        assert_eq!(b, a - 0x1000);
+        assert_eq!(guest_mem.read_u8(b + 1).unwrap(), b'e');
+        assert!(guest_mem.write_u8(b, 0).is_err());
+
+        // unmapping the middle page splits the mapping
+        guest_mem.write_u64(a, 1).unwrap();
+        guest_mem.write_u64(a + 0x2000, 2).unwrap();
+        guest_mem.munmap(a + 0x1000, 0x1000).unwrap();
+        assert!(guest_mem.read_u8(a + 0x1000).is_err());
+        assert_eq!(guest_mem.read_u64(a).unwrap(), 1);
+        assert_eq!(guest_mem.read_u64(a + 0x2000).unwrap(), 2);
+        assert_eq!(guest_mem.segments.len(), 4);
+        assert!(matches!(guest_mem.mprotect(a, 0x3000, MemFlags::READ), Err(Error::Map(MapError::NoMemory, _))));
+
+        // re-protecting a page splits a mapping, and restoring it merges the pieces again
+        let c = guest_mem.mmap(0, 0x3000, rw, MapAt::Hint, None).unwrap();
+        guest_mem.write_u64(c + 0x1000, 3).unwrap();
+        guest_mem.mark_code(c + 0x1000);
+        let epoch = guest_mem.code_epoch();
+        guest_mem.mprotect(c + 0x1000, 0x1000, MemFlags::READ).unwrap();
+        assert_ne!(guest_mem.code_epoch(), epoch);
+        assert_eq!(guest_mem.segments.len(), 7);
+        assert!(guest_mem.write_u8(c + 0x1000, 0).is_err());
+        guest_mem.write_u8(c + 0x2000, 0).unwrap();
+        guest_mem.mprotect(c + 0x1000, 0x1000, rw).unwrap();
+        assert_eq!(guest_mem.segments.len(), 5);
+        assert_eq!(guest_mem.read_u64(c + 0x1000).unwrap(), 3);
+
+        // fixed mappings replace what was there, unless asked not to
+        assert!(matches!(guest_mem.mmap(a, 0x1000, rw, MapAt::FixedNoReplace, None), Err(Error::Map(MapError::Exists, _))));
+        assert_eq!(guest_mem.mmap(a, 0x1000, rw, MapAt::Fixed, None).unwrap(), a);
+        assert_eq!(guest_mem.read_u64(a).unwrap(), 0);
+
+        // grows in place into the hole, then has to move
+        guest_mem.write_u64(a, 4).unwrap();
+        assert_eq!(guest_mem.mremap(a, 0x1000, 0x2000, false).unwrap(), a);
+        guest_mem.write_u64(a + 0x1000, 5).unwrap();
+        assert!(matches!(guest_mem.mremap(a, 0x2000, 0x4000, false), Err(Error::Map(MapError::NoMemory, _))));
+        let d = guest_mem.mremap(a, 0x2000, 0x4000, true).unwrap();
+        assert!(guest_mem.read_u8(a).is_err());
+        assert_eq!(guest_mem.read_u64(d).unwrap(), 4);
+        assert_eq!(guest_mem.read_u64(d + 0x1000).unwrap(), 5);
+        assert_eq!(guest_mem.read_u64(d + 0x3000).unwrap(), 0);
+
+        // lengths that overflow once rounded up to pages are refused
+        for at in [MapAt::Hint, MapAt::Fixed, MapAt::FixedNoReplace] {
+            assert!(matches!(guest_mem.mmap(0x10000000, usize::MAX, rw, at, None), Err(Error::Map(MapError::NoMemory, _))));
+        }
+        let huge = usize::MAX - 2 * PAGE_SIZE;
+        for addr in [0, 0x10000000] {
+            assert!(matches!(guest_mem.mmap(addr, huge, rw, MapAt::Hint, None), Err(Error::Map(MapError::NoMemory, _))));
+        }
+        assert!(matches!(guest_mem.munmap(d, usize::MAX), Err(Error::Map(MapError::NoMemory, _))));
+        assert!(matches!(guest_mem.mprotect(d, usize::MAX, MemFlags::READ), Err(Error::Map(MapError::NoMemory, _))));
+        assert!(matches!(guest_mem.mremap(d, 0x4000, usize::MAX, true), Err(Error::Map(MapError::NoMemory, _))));
+        assert_eq!(guest_mem.read_u64(d).unwrap(), 4);
+
+        // unmapping everything only visits what is mapped
+        guest_mem.munmap(0, MMAP_TOP as usize).unwrap();
+        assert!(guest_mem.segments.is_empty());
+    }
+
+    #[test]
+    fn test_lazy_pages() {
+        log::log_init(log::Level::Off);
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut guest_mem = GuestMem::new();
+        // a page of data followed by a 1 GiB bss, only the data is touched
+        guest_mem.add_segment(0x10000, 0x40001000, 0x1000, rw, Some(&[1; 0x1000]))
+            .expect("Failed to add segment");
+        guest_mem.reset_brk();
+        assert_eq!(guest_mem.stats(), MemStats { mapped_pages: 0x40001, resident_pages: 1 });
+        guest_mem.write_u64(0x20000000, 1).unwrap();
+        assert_eq!(guest_mem.stats().resident_pages, 2);
+
+        // the heap is reserved up front and backed as it is used
+        let base = guest_mem.cur_brk();
+        assert_eq!(guest_mem.brk(base + 0x1000000), base + 0x1000000);
+        guest_mem.write_u64(base + 0x8000, 2).unwrap();
+        assert_eq!(guest_mem.stats(), MemStats { mapped_pages: 0x41001, resident_pages: 3 });
+
+        // shrinking gives the pages back, they read as zero when the heap grows over them again
+        assert_eq!(guest_mem.brk(base + 0x1000), base + 0x1000);
+        assert_eq!(guest_mem.stats(), MemStats { mapped_pages: 0x40002, resident_pages: 2 });
+        assert_eq!(guest_mem.brk(base + 0x10000), base + 0x10000);
+        assert_eq!(guest_mem.read_u64(base + 0x8000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_snapshot() {
+        log::log_init(log::Level::Off);
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut guest_mem = GuestMem::new();
+        guest_mem.add_segment(0x10000, 0x4000, 0x1000, rw, Some(&[1; 0x1800])).expect("Failed to add segment");
+        guest_mem.reset_brk();
+        assert_eq!(guest_mem.brk(0x16000), 0x16000);
+        guest_mem.write_u64(0x15000, 2).unwrap();
+        let snapshot = guest_mem.snapshot();
+        assert_eq!(snapshot.pages.len(), 3);
+
+        // only the pages stored to are copied back
+        guest_mem.write_u64(0x10000, 3).unwrap();
+        guest_mem.write_u64(0x10008, 3).unwrap();
+        guest_mem.write_u64(0x13000, 3).unwrap();
+        assert_eq!(guest_mem.dirty.len(), 2);
+        guest_mem.restore(&snapshot);
+        assert!(guest_mem.dirty.is_empty());
+        assert_eq!(guest_mem.read_u64(0x10000).unwrap(), 0x0101010101010101);
+        assert_eq!(guest_mem.read_u64(0x13000).unwrap(), 0);
+
+        // the heap shrinking and growing back in place reads as a change
+        assert_eq!(guest_mem.brk(0x15000), 0x15000);
+        assert_eq!(guest_mem.brk(0x16000), 0x16000);
+        assert_eq!(guest_mem.read_u64(0x15000).unwrap(), 0);
+        guest_mem.restore(&snapshot);
+        assert_eq!(guest_mem.read_u64(0x15000).unwrap(), 2);
+
+        // the layout changed, so everything is copied back
+        guest_mem.mprotect(0x10000, 0x1000, MemFlags::READ).unwrap();
+        assert_eq!(guest_mem.brk(0x20000), 0x20000);
+        guest_mem.write_u64(0x1f000, 4).unwrap();
+        guest_mem.restore(&snapshot);
+        assert_eq!(guest_mem.segments.len(), snapshot.segments.len());
+        guest_mem.write_u64(0x10000, 5).unwrap();
+        assert!(guest_mem.read_u8(0x16000).is_err());
+        assert_eq!(guest_mem.read_u64(0x15000).unwrap(), 2);
+        assert_eq!(guest_mem.brk(0x20000), 0x20000);
+        assert_eq!(guest_mem.read_u64(0x1f000).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_snapshot_remap() {
+        log::log_init(log::Level::Off);
+
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        let mut guest_mem = GuestMem::new();
+        let addr = guest_mem.mmap(0, PAGE_SIZE, rw, MapAt::Hint, None).unwrap();
+        guest_mem.write_u8(addr, 0x33).unwrap();
+        guest_mem.snapshot();
+        // the page is dirty but no longer written, the store to it has to be tracked again
+        guest_mem.munmap(addr, PAGE_SIZE).unwrap();
+        assert_eq!(guest_mem.mmap(addr, PAGE_SIZE, rw, MapAt::Fixed, None).unwrap(), addr);
+        guest_mem.write_u8(addr, 0x55).unwrap();
+        let snapshot = guest_mem.snapshot();
+        guest_mem.write_u8(addr, 0x77).unwrap();
+        guest_mem.restore(&snapshot);
+        assert_eq!(guest_mem.read_u8(addr).unwrap(), 0x55);
+    }
+}
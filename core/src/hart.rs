@@ -6,76 +6,340 @@ use crate::*;
 use crate::guest::*;
 use crate::state::*;
 use crate::insn::*;
+use crate::pmp::Privilege;
+use crate::trigger::TriggerAction;
+use crate::tracer::InsnTracer;
+#[cfg(feature = "jit")]
+use crate::jit::JitMode;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HartStatus {
+    Runnable,
+    /// Waiting to be woken up, or until the given virtual time.
+    Blocked(Option<u64>),
+    Exited(i64),
+}
 
 /// Virtual Hart representing a RISC-V core.
-/// 'id' can be seen as the tid of the hart, not real hardware id.
+/// 'id' is the index of the hart in the emulator, reported to the guest through mhartid.
 #[derive(Debug)]
 pub struct Hart {
     pub id: usize,
     pub state: State,
-    pub decoders: Vec<Arc<dyn Decoder>>,
+    pub status: HartStatus,
+    /// Shared by all harts of an emulator.
+    pub dispatch: Arc<DispatchTable>,
+    pub blocks: BlockCache,
+    #[cfg(feature = "jit")]
+    pub jit: JitMode,
+    /// Fused pairs executed, indexed by `FusionKind`.
+    fused: [u64; FusionKind::ALL.len()],
 }
 
 impl Hart {
     pub fn new(id: usize) -> Self {
         Self {
             id,
-            state: State::default(),
-            decoders: vec![],
+            state: State {
+                mhartid: id as u64,
+                ..State::default()
+            },
+            status: HartStatus::Runnable,
+            dispatch: Arc::new(DispatchTable::new()),
+            blocks: BlockCache::new(),
+            #[cfg(feature = "jit")]
+            jit: JitMode::Off,
+            fused: [0; FusionKind::ALL.len()],
         }
     }
 
+    /// How many times pairs of 'kind' were executed fused.
+    pub fn fused(&self, kind: FusionKind) -> u64 {
+        self.fused[kind as usize]
+    }
+
+    pub fn is_runnable(&self) -> bool {
+        self.status == HartStatus::Runnable
+    }
+
     pub fn add_decoder(&mut self, set: InsnSet) -> Result<()> {
         let decoder: Arc<dyn Decoder> = match set {
             InsnSet::I => Arc::new(insn::Rv64IDecoder),
+            InsnSet::A => Arc::new(insn::Rv64ADecoder),
             InsnSet::Zifencei => Arc::new(insn::ZifenceiDecoder),
             InsnSet::Ziscr => Arc::new(insn::ZicsrDecoder),
             _ => return Err(Error::InsnSetUnimplemented(set)),
         };
-        self.decoders.push(decoder);
-        
-        Ok(())
+        Arc::make_mut(&mut self.dispatch).register(set, decoder)
     }
 
     pub fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
-        for decoder in &self.decoders {
-            if let Some((insn, executor)) = decoder.decode(raw)? {
-                return Ok(Some((insn, executor)));
+        self.dispatch.decode(raw)
+    }
+
+    /// Runs up to 'budget' instructions, stopping early after one that needs the emulator.
+    /// 'retired' counts the instructions that completed, also when an error is returned.
+    pub fn run(&mut self, guest: &mut GuestMem, budget: usize, retired: &mut usize) -> Result<Option<BreakCause>> {
+        self.run_inner::<false>(guest, budget, retired, None)
+    }
+
+    /// Like `run`, writing every instruction executed to 'tracer'.
+    pub fn run_traced(
+        &mut self,
+        guest: &mut GuestMem,
+        budget: usize,
+        retired: &mut usize,
+        tracer: &mut InsnTracer,
+    ) -> Result<Option<BreakCause>> {
+        self.run_inner::<true>(guest, budget, retired, Some(tracer))
+    }
+
+    /// The interpreter loop, compiled once without the tracer and once with it.
+    fn run_inner<const TRACE: bool>(
+        &mut self,
+        guest: &mut GuestMem,
+        budget: usize,
+        retired: &mut usize,
+        mut tracer: Option<&mut InsnTracer>,
+    ) -> Result<Option<BreakCause>> {
+        while *retired < budget {
+            let pc = self.state.pc;
+            // For compressed instructions, we only consume 16 bits.
+            if pc % 2 != 0 {
+                return Err(Error::InternalError(format!("PC is not aligned: {:#x}", pc)));
+            }
+            // checked before translating, so that an execute trigger on 'pc' fires instead of ending the block
+            let entry = guest.check_fetch(pc).and_then(|_| self.blocks.enter(guest, &self.dispatch, pc));
+            let (block, start) = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    if Self::fault(&mut self.state, guest, pc, e)? {
+                        *retired += 1;
+                    }
+                    continue;
+                },
+            };
+
+            // State the JIT computed for the first 'end' instructions, to compare in lockstep mode.
+            #[cfg(feature = "jit")]
+            let mut expected = None;
+            #[cfg(feature = "jit")]
+            if !TRACE
+                && start == 0
+                && self.jit != JitMode::Off
+                && guest.pmp().is_none()
+                && !guest.triggers().armed()
+                && let Some(native) = self.blocks.native(block)?
+                && native.len() <= budget - *retired
+            {
+                // Compiled instructions neither access memory nor fault, and a control transfer
+                // is always the last instruction of a block, so 'len' is where execution continues.
+                let len = native.len();
+                if self.jit == JitMode::Lockstep {
+                    let mut state = self.state.clone();
+                    state.x[0] = 0;
+                    state.pc = native.call(&mut state.x);
+                    expected = Some((state, len));
+                } else {
+                    self.state.x[0] = 0;
+                    self.state.pc = native.call(&mut self.state.x);
+                    *retired += len;
+                    self.blocks.leave(block, len, self.state.pc);
+                    continue;
+                }
+            }
+
+            // Runs the block until it branches away, or a store changes code.
+            let insns = self.blocks.insns(block);
+            let epoch = guest.code_epoch();
+            let mut idx = start;
+            #[cfg(feature = "jit")]
+            let end = expected.as_ref().map_or(insns.len(), |(_, len)| *len);
+            #[cfg(not(feature = "jit"))]
+            let end = insns.len();
+            let mut cause = None;
+            // a PMP check or trigger on the second instruction of a pair has to see the first retired
+            if !TRACE
+                && !self.blocks.fusions(block).is_empty()
+                && guest.pmp().is_none()
+                && !guest.triggers().armed()
+            {
+                (idx, cause) = self.run_fused(guest, block, start, end, budget, retired)?;
+            } else {
+                while idx < end && *retired < budget {
+                    let (insn, executor) = &insns[idx];
+                    let cur_pc = self.state.pc;
+                    let before = TRACE.then_some(self.state.x);
+                    cause = Self::execute(&mut self.state, guest, cur_pc, insn, *executor, retired)?;
+                    if let (Some(tracer), Some(before)) = (&mut tracer, &before) {
+                        tracer.record(self.id, cur_pc, insn, before, &self.state.x)?;
+                    }
+                    idx += 1;
+                    if self.state.pc != cur_pc + insn.step_size() as u64 {
+                        // branched or trapped, the rest of the block is not next
+                        idx = insns.len();
+                        break;
+                    }
+                    if cause.is_some() || guest.code_epoch() != epoch {
+                        break;
+                    }
+                }
+            }
+            self.blocks.leave(block, idx, self.state.pc);
+            #[cfg(feature = "jit")]
+            if let Some((expected, _)) = expected {
+                let mut state = self.state.clone();
+                state.x[0] = 0;
+                if state != expected {
+                    warn!("JIT mismatch in the block at pc@{:#x}", pc);
+                    warn!("interpreter: {:x?}", state);
+                    warn!("JIT: {:x?}", expected);
+                    return Err(Error::JitMismatch(pc));
+                }
+            }
+            if cause.is_some() {
+                return Ok(cause);
             }
         }
         Ok(None)
     }
 
+    /// The loop of `run_inner` over 'block' from instruction 'idx' up to 'end', which executes
+    /// marked pairs fused. Kept apart so that blocks without pairs do not pay for the check.
+    /// Returns the instruction to continue at, and why the hart stopped, if it did.
+    fn run_fused(
+        &mut self,
+        guest: &mut GuestMem,
+        block: usize,
+        mut idx: usize,
+        end: usize,
+        budget: usize,
+        retired: &mut usize,
+    ) -> Result<(usize, Option<BreakCause>)> {
+        let epoch = guest.code_epoch();
+        let len = self.blocks.insns(block).len();
+        while idx < end && *retired < budget {
+            let (insn, executor) = self.blocks.insns(block)[idx];
+            let cur_pc = self.state.pc;
+            let next_pc = cur_pc + insn.step_size() as u64;
+            match self.blocks.fusions(block)[idx] {
+                Some(fusion) if idx + 1 < end && budget - *retired >= 2 => {
+                    let (second, _) = self.blocks.insns(block)[idx + 1];
+                    self.state.x[0] = 0;
+                    hot_trace!("pc@{:#x}: executing fused {}: {:x?}, {:x?}", cur_pc, fusion.kind.as_str(), insn, second);
+                    let res = (fusion.exec)(&mut self.state, guest, &insn, &second);
+                    *retired += 1;
+                    let second_retired = match res {
+                        Ok(()) => {
+                            self.fused[fusion.kind as usize] += 1;
+                            true
+                        },
+                        Err(e) => Self::fault(&mut self.state, guest, next_pc, e)?,
+                    };
+                    if second_retired {
+                        *retired += 1;
+                    }
+                    idx += 2;
+                    if self.state.pc != next_pc + second.step_size() as u64 {
+                        return Ok((len, None));
+                    }
+                },
+                _ => {
+                    let cause = Self::execute(&mut self.state, guest, cur_pc, &insn, executor, retired)?;
+                    idx += 1;
+                    if self.state.pc != next_pc {
+                        return Ok((len, cause));
+                    }
+                    if cause.is_some() {
+                        return Ok((idx, cause));
+                    }
+                },
+            }
+            if guest.code_epoch() != epoch {
+                break;
+            }
+        }
+        Ok((idx, None))
+    }
+
     pub fn step(&mut self, guest: &mut GuestMem) -> Result<Option<BreakCause>> {
-        self.state.x[0] = 0;
-        self.state.break_on = None;
+        let mut retired = 0;
+        self.run(guest, 1, &mut retired)
+    }
 
-        let cur_pc = self.state.pc;
-        // For compressed instructions, we only consume 16 bits.
-        if cur_pc % 2 != 0 {
-            return Err(Error::InternalError(format!("PC is not aligned: {:#x}", cur_pc)));
-        }
-        
-        let raw = guest.fetch_insn(cur_pc)?;
-        let (insn, executor) = match self.decode(raw)? {
-            Some((insn, executor)) => (insn, executor),
-            None => {
-                return Err(Error::UnknownInsn(raw, cur_pc))
+    /// Handles a trigger hit, a PMP violation or a fault below a stack at 'cur_pc', other errors
+    /// are passed on.
+    /// Returns whether the instruction counts as retired, not if it has to run again.
+    fn fault(state: &mut State, guest: &mut GuestMem, cur_pc: u64, err: Error) -> Result<bool> {
+        match err {
+            Error::TriggerHit(idx, tval) => match guest.triggers_mut().fire(idx) {
+                TriggerAction::Exception => {
+                    debug!("trigger {} hit at {:#x}, pc@{:#x}", idx, tval, cur_pc);
+                    state.pc = cur_pc;
+                    state.take_trap(CAUSE_BREAKPOINT, tval, guest.privilege());
+                    guest.set_privilege(Privilege::Machine, state.trap.mstatus);
+                    Ok(true)
+                },
+                TriggerAction::DebugMode => {
+                    state.pc = cur_pc;
+                    Err(Error::BreakpointHit)
+                },
             },
-        };
+            Error::PmpViolation(access, gaddr) => {
+                debug!("PMP violation: {:?} at {:#x}, pc@{:#x}", access, gaddr, cur_pc);
+                let cause = match access {
+                    MemAccess::Execute => CAUSE_FETCH_ACCESS,
+                    MemAccess::Read => CAUSE_LOAD_ACCESS,
+                    MemAccess::Write => CAUSE_STORE_ACCESS,
+                };
+                state.pc = cur_pc;
+                state.take_trap(cause, gaddr, guest.privilege());
+                guest.set_privilege(Privilege::Machine, state.trap.mstatus);
+                Ok(true)
+            },
+            Error::MemAccessFault(access, gaddr) if access != MemAccess::Execute => match guest.grow_stack(gaddr) {
+                StackFault::Grown => {
+                    // runs the instruction again
+                    state.pc = cur_pc;
+                    Ok(false)
+                },
+                StackFault::Overflow => Err(Error::StackOverflow(gaddr, cur_pc)),
+                StackFault::NotStack => Err(err),
+            },
+            e => Err(e),
+        }
+    }
 
-        trace!("pc@{:#x}: executing instruction: {:x?}", self.state.pc, insn);
-        trace!("state before: {:x?}", self.state);
-        executor(&mut self.state, guest, &insn)?;
+    /// Executes 'insn' at 'cur_pc' and counts it in 'retired', unless a fault makes it run again.
+    #[inline]
+    fn execute(
+        state: &mut State,
+        guest: &mut GuestMem,
+        cur_pc: u64,
+        insn: &Instruction,
+        executor: Executor,
+        retired: &mut usize,
+    ) -> Result<Option<BreakCause>> {
+        state.x[0] = 0;
+        state.break_on = None;
 
-        if cur_pc == self.state.pc {
+        hot_trace!("pc@{:#x}: executing instruction: {:x?}", cur_pc, insn);
+        hot_trace!("state before: {:x?}", state);
+        if let Err(e) = guest.check_fetch(cur_pc).and_then(|_| executor(state, guest, insn)) {
+            if Self::fault(state, guest, cur_pc, e)? {
+                *retired += 1;
+            }
+            return Ok(None);
+        }
+        *retired += 1;
+
+        if cur_pc == state.pc {
             // if pc did not change, it must be a normal instruction, otherwise some branch...
-            self.state.pc = cur_pc + insn.step_size() as u64;
+            state.pc = cur_pc + insn.step_size() as u64;
         }
         
-        Ok(self.state.break_on.take().map(|cause| {
-            trace!("break on: {:?}", cause);
-            cause
+        Ok(state.break_on.take().inspect(|cause| {
+            hot_trace!("break on: {:?}", cause);
         }))
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,356 @@
+//! Per-hart cache of translated basic blocks.
+//! A block is a straight-line run of decoded instructions with their executors, ending at the
+//! first control transfer, at a page boundary, or after `BLOCK_MAX_INSNS` instructions.
+//! Blocks remember the blocks that followed them, so the common path from one block to the
+//! next needs no lookup at all.
+//! `Hart::run` executes the instructions of a block back to back, but still retires them one
+//! at a time, so breakpoints, triggers and exits keep their instruction-exact semantics.
+//! Blocks remember the generation of their code page in `GuestMem`. A store to a code page
+//! bumps its generation, fence.i drops all pages, and the cache flushes itself the next
+//! time it is used.
+//! Translation also marks the instruction pairs that can run fused, see `fusion`.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use crate::config::{BLOCK_CACHE_SIZE, BLOCK_MAX_INSNS};
+use crate::error::*;
+use crate::guest::{GuestMem, PAGE_SIZE};
+use crate::insn::rv64i::{RV64I_OPCODE_BRANCH, RV64I_OPCODE_JAL, RV64I_OPCODE_JALR, RV64I_OPCODE_SYSTEM};
+use crate::insn::fusion::{self, Fusion};
+use crate::insn::{DispatchTable, Executor, Instruction};
+#[cfg(feature = "jit")]
+use crate::config::JIT_THRESHOLD;
+#[cfg(feature = "jit")]
+use crate::jit::{self, JitBlock};
+
+#[derive(Debug)]
+struct Block {
+    start: u64,
+    insns: Vec<(Instruction, Executor)>,
+    /// For every instruction, the fusion with the one after it, if any. Empty if there is none.
+    fusions: Vec<Option<Fusion>>,
+    /// Generation of the code page holding the block.
+    generation: u64,
+    /// Blocks that ran after this one, as (start, index into 'blocks').
+    /// A conditional branch has two successors, other blocks only one.
+    succ: [Option<(u64, usize)>; 2],
+    /// Entries at the start of the block, it is compiled once they reach `JIT_THRESHOLD`.
+    #[cfg(feature = "jit")]
+    heat: u32,
+    #[cfg(feature = "jit")]
+    native: Option<JitBlock>,
+}
+
+#[derive(Debug, Default)]
+pub struct BlockCache {
+    blocks: Vec<Block>,
+    /// start -> index into 'blocks'
+    index: HashMap<u64, usize>,
+    /// (block, instruction, pc) where execution left off.
+    cursor: Option<(usize, usize, u64)>,
+    /// `GuestMem::code_epoch` the blocks were last validated against.
+    epoch: u64,
+    hits: u64,
+    misses: u64,
+    chained: u64,
+    compiled: u64,
+    /// Time spent translating and compiling blocks, only measured if present.
+    translate_time: Option<Duration>,
+}
+
+impl BlockCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the block holding the instruction at 'pc' and the index of that instruction,
+    /// translating a new block if needed.
+    pub fn enter(&mut self, guest: &mut GuestMem, dispatch: &DispatchTable, pc: u64) -> Result<(usize, usize)> {
+        if self.epoch != guest.code_epoch() {
+            if self.blocks.iter().any(|block| guest.code_page_gen(block.start) != Some(block.generation)) {
+                self.clear();
+            }
+            self.epoch = guest.code_epoch();
+        }
+        if self.blocks.len() >= BLOCK_CACHE_SIZE {
+            // before the cursor is used, a link to a block of the flushed cache would dangle
+            self.clear();
+        }
+
+        let prev = match self.cursor {
+            Some((block, idx, next_pc)) if next_pc == pc => {
+                if idx < self.blocks[block].insns.len() {
+                    self.hits += 1;
+                    return Ok((block, idx));
+                }
+                match self.blocks[block].succ.iter().flatten().find(|(start, _)| *start == pc) {
+                    Some(&(_, next)) => {
+                        self.chained += 1;
+                        return Ok((next, 0));
+                    },
+                    None => Some(block),
+                }
+            },
+            _ => None,
+        };
+
+        let next = match self.index.get(&pc) {
+            Some(&next) => {
+                self.hits += 1;
+                next
+            },
+            None => {
+                self.misses += 1;
+                let start = self.translate_time.is_some().then(Instant::now);
+                let next = self.translate(guest, dispatch, pc)?;
+                self.charge(start);
+                next
+            },
+        };
+        if let Some(prev) = prev
+            && let Some(slot) = self.blocks[prev].succ.iter_mut().find(|slot| slot.is_none())
+        {
+            *slot = Some((pc, next));
+        }
+        Ok((next, 0))
+    }
+
+    pub fn insns(&self, block: usize) -> &[(Instruction, Executor)] {
+        &self.blocks[block].insns
+    }
+
+    pub fn fusions(&self, block: usize) -> &[Option<Fusion>] {
+        &self.blocks[block].fusions
+    }
+
+    /// Notes that execution left 'block' before instruction 'idx', with 'pc' to run next.
+    /// 'idx' is the length of the block if it was left by a jump, entering at 'pc' then follows
+    /// or creates a link, otherwise it resumes the block.
+    pub fn leave(&mut self, block: usize, idx: usize, pc: u64) {
+        self.cursor = Some((block, idx, pc));
+    }
+
+    /// Decodes the block starting at 'pc'. Only the first instruction may fail, a later one
+    /// that cannot be fetched or decoded ends the block and faults when it is reached.
+    fn translate(&mut self, guest: &mut GuestMem, dispatch: &DispatchTable, pc: u64) -> Result<usize> {
+        let mut insns = vec![];
+        let mut cur_pc = pc;
+        loop {
+            let decoded = guest.fetch_insn(cur_pc).and_then(|raw| match dispatch.decode(raw)? {
+                Some(decoded) => Ok(decoded),
+                None => Err(Error::UnknownInsn(raw, cur_pc)),
+            });
+            let (insn, executor) = match decoded {
+                Ok(decoded) => decoded,
+                Err(e) if insns.is_empty() => return Err(e),
+                Err(_) => break,
+            };
+            insns.push((insn, executor));
+            cur_pc += insn.step_size() as u64;
+            let ends_block = matches!(
+                insn.opcode(),
+                RV64I_OPCODE_BRANCH | RV64I_OPCODE_JAL | RV64I_OPCODE_JALR | RV64I_OPCODE_SYSTEM
+            );
+            if ends_block || cur_pc.is_multiple_of(PAGE_SIZE as u64) || insns.len() >= BLOCK_MAX_INSNS {
+                break;
+            }
+        }
+
+        let mut fusions: Vec<Option<Fusion>> = insns.windows(2).map(|pair| fusion::fuse(&pair[0].0, &pair[1].0)).collect();
+        fusions.push(None);
+        if fusions.iter().all(Option::is_none) {
+            fusions.clear();
+        }
+
+        let generation = guest.mark_code(pc);
+        let idx = self.blocks.len();
+        self.blocks.push(Block {
+            start: pc,
+            insns,
+            fusions,
+            generation,
+            succ: [None; 2],
+            #[cfg(feature = "jit")]
+            heat: 0,
+            #[cfg(feature = "jit")]
+            native: None,
+        });
+        self.index.insert(pc, idx);
+        Ok(idx)
+    }
+
+    /// Counts an entry at the start of 'block', and returns its native code once it is hot.
+    #[cfg(feature = "jit")]
+    pub fn native(&mut self, block: usize) -> Result<Option<&JitBlock>> {
+        let entry = &mut self.blocks[block];
+        if entry.heat < JIT_THRESHOLD {
+            entry.heat += 1;
+            if entry.heat == JIT_THRESHOLD {
+                let start = self.translate_time.is_some().then(Instant::now);
+                entry.native = jit::compile(entry.start, &entry.insns)?;
+                self.compiled += entry.native.is_some() as u64;
+                self.charge(start);
+            }
+        }
+        Ok(self.blocks[block].native.as_ref())
+    }
+
+    /// Starts measuring the time spent translating, see `translate_time`.
+    pub fn enable_profile(&mut self) {
+        self.translate_time.get_or_insert(Duration::ZERO);
+    }
+
+    fn charge(&mut self, start: Option<Instant>) {
+        if let (Some(time), Some(start)) = (&mut self.translate_time, start) {
+            *time += start.elapsed();
+        }
+    }
+
+    fn clear(&mut self) {
+        self.blocks.clear();
+        self.index.clear();
+        self.cursor = None;
+    }
+
+    /// Number of translated blocks.
+    pub fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    /// Blocks entered without translating them, by resuming one or looking it up.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Blocks translated.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    /// Blocks entered through the link of the previous block.
+    pub fn chained(&self) -> u64 {
+        self.chained
+    }
+
+    /// Blocks compiled by the JIT.
+    pub fn compiled(&self) -> u64 {
+        self.compiled
+    }
+
+    /// Time spent translating and compiling blocks, None unless profiling is enabled.
+    pub fn translate_time(&self) -> Option<Duration> {
+        self.translate_time
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::*;
+    use crate::guest::MemFlags;
+    use crate::insn::Rv64IDecoder;
+    use crate::*;
+
+    #[test]
+    fn test_invalidation() {
+        log::log_init(log::Level::Off);
+
+        let mut guest = GuestMem::new();
+        let code: Vec<u8> = [
+            0x00100513, // li a0, 1
+            0x0000006fu32, // j 0
+        ].iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        guest.add_segment(0x1000, 0x2000, 0x1000, MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE, None)
+            .unwrap();
+        for base in [0x1000, 0x2000] {
+            for (i, byte) in code.iter().enumerate() {
+                guest.write_u8(base + i as u64, *byte).unwrap();
+            }
+        }
+        let mut dispatch = DispatchTable::new();
+        dispatch.register(InsnSet::I, std::sync::Arc::new(Rv64IDecoder)).unwrap();
+
+        let mut cache = BlockCache::new();
+        assert_eq!(cache.enter(&mut guest, &dispatch, 0x1000).unwrap(), (0, 0));
+        cache.leave(0, 1, 0x1004);
+        assert_eq!(cache.enter(&mut guest, &dispatch, 0x1004).unwrap(), (0, 1));
+        assert_eq!(cache.enter(&mut guest, &dispatch, 0x2000).unwrap(), (1, 0));
+        assert_eq!(cache.enter(&mut guest, &dispatch, 0x1000).unwrap(), (0, 0));
+        assert_eq!((cache.len(), cache.misses(), cache.hits()), (2, 2, 2));
+
+        // a store to a code page flushes the cache
+        guest.write_u32(0x1800, 0).unwrap();
+        cache.enter(&mut guest, &dispatch, 0x2000).unwrap();
+        assert_eq!(cache.len(), 1);
+
+        // fence.i evicts everything
+        guest.invalidate_code();
+        cache.enter(&mut guest, &dispatch, 0x1000).unwrap();
+        assert_eq!((cache.len(), cache.misses()), (1, 4));
+    }
+
+    #[test]
+    fn test_chaining() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .quantum(1000)
+            .build()
+            .unwrap();
+        // Sums 1..=100 in a loop of one block that branches back to itself.
+        emulator.load_code(0x1000, &[
+            0x06400293, // 0: li t0, 100
+            0x00000513, // 4: li a0, 0
+            0x00550533, // 8: add a0, a0, t0
+            0xfff28293, // c: addi t0, t0, -1
+            0xfe029ce3, // 10: bnez t0, 0x8
+            0x05d00893, // 14: li a7, 93
+            0x00000073, // 18: ecall
+        ]);
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(5050));
+        let cache = &emulator.hart().blocks;
+        // [0, 0x10], [0x8, 0x10], [0x14, 0x18]
+        assert_eq!(cache.len(), 3);
+        // of the 99 passes through 0x8, the first two translate and look up the loop block,
+        // the others take the link
+        assert_eq!(cache.chained(), 97);
+    }
+
+    #[test]
+    fn test_self_modifying_code() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        // The second pass runs the 'li a0, 2' that the first pass wrote over 'li a0, 1'.
+        let code: Vec<u8> = [
+            0x00000297, // 0: auipc t0, 0
+            0x00100513, // 4: li a0, 1
+            0x00148493, // 8: addi s1, s1, 1
+            0x00200313, // c: li t1, 2
+            0x00648a63, // 10: beq s1, t1, 0x24 <exit>
+            0x002003b7, // 14: lui t2, 512
+            0x5133839b, // 18: addiw t2, t2, 1299 (li a0, 2)
+            0x0072a223, // 1c: sw t2, 4(t0)
+            0xfe5ff06f, // 20: j 0x4 <target>
+            0x05d00893, // 24: li a7, 93
+            0x00000073u32, // 28: ecall
+        ].iter().flat_map(|insn| insn.to_le_bytes()).collect();
+        emulator.guest.add_segment(0x1000, code.len(), 0x1000,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::EXECUTE, Some(&code)).unwrap();
+        emulator.hart_mut().state.pc = 0x1000;
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(2));
+    }
+}
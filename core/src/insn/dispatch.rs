@@ -0,0 +1,143 @@
+//! Dispatch of raw instructions to the decoder of the extension that claims them.
+//! Claims are bucketed by major opcode, so decoding costs the same however many
+//! extensions are enabled.
+
+use std::sync::Arc;
+
+use crate::error::*;
+use crate::insn::{Decoder, Executor, InsnSet, Instruction};
+
+/// Number of major opcodes, bits [6:0].
+const NUM_OPCODES: usize = 128;
+
+#[derive(Debug, Clone, Copy)]
+struct Claim {
+    mask: u32,
+    value: u32,
+    /// Index into 'decoders'.
+    decoder: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct DispatchTable {
+    /// major opcode -> claims that can match it
+    buckets: Vec<Vec<Claim>>,
+    decoders: Vec<(InsnSet, Arc<dyn Decoder>)>,
+}
+
+impl Default for DispatchTable {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DispatchTable {
+    pub fn new() -> Self {
+        Self {
+            buckets: vec![vec![]; NUM_OPCODES],
+            decoders: vec![],
+        }
+    }
+
+    /// Extensions registered so far, in order.
+    pub fn sets(&self) -> impl Iterator<Item = InsnSet> + '_ {
+        self.decoders.iter().map(|(set, _)| *set)
+    }
+
+    /// Adds the decoder of 'set'. Fails if one of its claims overlaps a claim of another
+    /// extension, since one of them would silently shadow the other.
+    pub fn register(&mut self, set: InsnSet, decoder: Arc<dyn Decoder>) -> Result<()> {
+        let claims = decoder.claims();
+        for &(mask, value) in claims {
+            for claim in self.buckets.iter().flatten() {
+                let (other, _) = &self.decoders[claim.decoder];
+                if *other != set && (value ^ claim.value) & mask & claim.mask == 0 {
+                    return Err(Error::DecoderConflict(*other, set, value | claim.value));
+                }
+            }
+        }
+
+        let idx = self.decoders.len();
+        self.decoders.push((set, decoder));
+        for &(mask, value) in claims {
+            for (opcode, bucket) in self.buckets.iter_mut().enumerate() {
+                if (opcode as u32 ^ value) & mask & 0x7f == 0 {
+                    bucket.push(Claim { mask, value, decoder: idx });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
+        for claim in self.buckets[(raw & 0x7f) as usize].iter() {
+            if raw & claim.mask == claim.value {
+                return self.decoders[claim.decoder].1.decode(raw);
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::insn::*;
+
+    #[derive(Debug)]
+    struct MulDecoder;
+
+    impl Decoder for MulDecoder {
+        fn claims(&self) -> &'static [(u32, u32)] {
+            // OP with funct7 = 1, next to RV64I's funct7 = 0/0x20
+            &[(0xfe00007f, 0x02000033)]
+        }
+
+        fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
+            Ok(Some((Instruction::U { imm: 0, rd: 0, opcode: 0, raw }, noop_executor)))
+        }
+    }
+
+    #[derive(Debug)]
+    struct GreedyDecoder;
+
+    impl Decoder for GreedyDecoder {
+        fn claims(&self) -> &'static [(u32, u32)] {
+            // every LOAD
+            &[(0x7f, 0x03)]
+        }
+
+        fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn test_dispatch() {
+        let mut table = DispatchTable::new();
+        table.register(InsnSet::I, Arc::new(Rv64IDecoder)).unwrap();
+        table.register(InsnSet::A, Arc::new(Rv64ADecoder)).unwrap();
+        table.register(InsnSet::Ziscr, Arc::new(ZicsrDecoder)).unwrap();
+        table.register(InsnSet::Zifencei, Arc::new(ZifenceiDecoder)).unwrap();
+        table.register(InsnSet::M, Arc::new(MulDecoder)).unwrap();
+
+        let raw_of = |raw: u32| table.decode(raw).unwrap().map(|(insn, _)| insn.raw());
+        // ecall, csrr a0, mhartid, fence.i, fence, amoadd.d, mul a0, a0, a1
+        for raw in [0x00000073, 0xf1402573, 0x0000100f, 0x0ff0000f, 0x007e302f, 0x02b50533] {
+            assert_eq!(raw_of(raw), Some(raw));
+        }
+        // unclaimed encodings
+        assert_eq!(raw_of(0x00000007), None);
+        assert_eq!(raw_of(0x0000200f), None);
+    }
+
+    #[test]
+    fn test_conflict() {
+        let mut table = DispatchTable::new();
+        table.register(InsnSet::I, Arc::new(Rv64IDecoder)).unwrap();
+        let err = table.register(InsnSet::P, Arc::new(GreedyDecoder)).unwrap_err();
+        assert!(matches!(err, Error::DecoderConflict(InsnSet::I, InsnSet::P, _)));
+        // nothing of the rejected decoder was registered
+        assert_eq!(table.sets().collect::<Vec<_>>(), vec![InsnSet::I]);
+    }
+}
@@ -0,0 +1,224 @@
+//! Superinstructions: pairs of instructions compilers emit together, executed as one.
+//! Pairs are recognised when a block is translated, see `BlockCache`. `Hart::run` only
+//! executes a pair fused when it may retire both instructions at once and no PMP or trigger
+//! has to check the second one, so single-stepping still stops between them.
+//! A fused executor leaves the same state as running the pair one by one. If the second
+//! instruction faults, the first one has completed and pc points at the second.
+
+use crate::error::*;
+use crate::guest::GuestMem;
+use crate::insn::Instruction;
+use crate::insn::rv64i::*;
+use crate::state::State;
+use crate::*;
+
+/// Executes a fused pair, (first, second).
+pub type FusedExecutor = fn(&mut State, &mut GuestMem, &Instruction, &Instruction) -> Result<()>;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FusionKind {
+    /// lui rd, hi; addi(w) rd, rd, lo
+    LuiAddi,
+    /// auipc rd, hi; jalr rd2, lo(rd)
+    AuipcJalr,
+    /// auipc rd, hi; ld rd2, lo(rd)
+    AuipcLd,
+    /// slli rd, rs, n; srli rd, rd, n
+    ZeroExtend,
+}
+
+impl FusionKind {
+    pub const ALL: [FusionKind; 4] = [
+        FusionKind::LuiAddi,
+        FusionKind::AuipcJalr,
+        FusionKind::AuipcLd,
+        FusionKind::ZeroExtend,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FusionKind::LuiAddi => "lui+addi",
+            FusionKind::AuipcJalr => "auipc+jalr",
+            FusionKind::AuipcLd => "auipc+ld",
+            FusionKind::ZeroExtend => "slli+srli",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Fusion {
+    pub kind: FusionKind,
+    pub exec: FusedExecutor,
+}
+
+/// The fusion of 'first' and 'second', if they form one of the supported pairs.
+pub fn fuse(first: &Instruction, second: &Instruction) -> Option<Fusion> {
+    let (kind, exec): (FusionKind, FusedExecutor) = match (*first, *second) {
+        (
+            Instruction::U { opcode: RV64I_OPCODE_LUI, rd, .. },
+            Instruction::I { opcode: RV64I_OPCODE_OP_IMM | RV64I_OPCODE_OP_IMM_W, funct3: 0, rd: rd2, rs1, .. },
+        ) if rd != 0 && rd2 == rd && rs1 == rd => (FusionKind::LuiAddi, lui_addi),
+        (
+            Instruction::U { opcode: RV64I_OPCODE_AUIPC, rd, .. },
+            Instruction::I { opcode: RV64I_OPCODE_JALR, funct3: 0, rs1, .. },
+        ) if rd != 0 && rs1 == rd => (FusionKind::AuipcJalr, auipc_jalr),
+        (
+            Instruction::U { opcode: RV64I_OPCODE_AUIPC, rd, .. },
+            Instruction::I { opcode: RV64I_OPCODE_LOAD, funct3: 3, rs1, .. },
+        ) if rd != 0 && rs1 == rd => (FusionKind::AuipcLd, auipc_ld),
+        (
+            Instruction::I { opcode: RV64I_OPCODE_OP_IMM, funct3: 1, rd, imm, .. },
+            Instruction::I { opcode: RV64I_OPCODE_OP_IMM, funct3: 5, rd: rd2, rs1, imm: imm2, .. },
+        ) if rd != 0 && rd2 == rd && rs1 == rd && imm == imm2 && imm >> 6 == 0 => (FusionKind::ZeroExtend, zero_extend),
+        _ => return None,
+    };
+    Some(Fusion { kind, exec })
+}
+
+/// The fields of a fused pair, which `fuse` already matched.
+macro_rules! pair {
+    ($first:expr, $second:expr, $a:ident { $($f1:tt)* }, $b:ident { $($f2:tt)* }) => {
+        let (&Instruction::$a { $($f1)*, .. }, &Instruction::$b { $($f2)*, .. }) = ($first, $second) else {
+            return Err(Error::InternalError(format!("Malformed fused pair {:x?}, {:x?}", $first, $second)));
+        };
+    };
+}
+
+fn lui_addi(state: &mut State, guest: &mut GuestMem, first: &Instruction, second: &Instruction) -> Result<()> {
+    pair!(first, second, U { rd, imm: hi }, I { opcode, imm: lo });
+    let value = (sign_extend!(hi, 32) as u64).wrapping_add(sign_extend!(lo, 12) as u64);
+    state.x[rd as usize] = match opcode {
+        RV64I_OPCODE_OP_IMM_W => sign_extend!(value, 32) as u64,
+        _ => value,
+    };
+    state.pc += 8;
+    Ok(())
+}
+
+fn auipc_jalr(state: &mut State, guest: &mut GuestMem, first: &Instruction, second: &Instruction) -> Result<()> {
+    pair!(first, second, U { rd, imm: hi }, I { rd: link, imm: lo });
+    let base = state.pc.wrapping_add(sign_extend!(hi, 32) as u64);
+    state.x[rd as usize] = base;
+    state.x[link as usize] = state.pc + 8;
+    state.pc = base.wrapping_add(sign_extend!(lo, 12) as u64) & !1;
+    Ok(())
+}
+
+fn auipc_ld(state: &mut State, guest: &mut GuestMem, first: &Instruction, second: &Instruction) -> Result<()> {
+    pair!(first, second, U { rd, imm: hi }, I { rd: dest, imm: lo });
+    let base = state.pc.wrapping_add(sign_extend!(hi, 32) as u64);
+    state.x[rd as usize] = base;
+    state.pc += 4;
+    state.x[dest as usize] = guest.read_u64(base.wrapping_add(sign_extend!(lo, 12) as u64))?;
+    state.pc += 4;
+    Ok(())
+}
+
+fn zero_extend(state: &mut State, guest: &mut GuestMem, first: &Instruction, second: &Instruction) -> Result<()> {
+    pair!(first, second, I { rd, rs1, imm }, I { opcode });
+    state.x[rd as usize] = state.x[rs1 as usize] & (u64::MAX >> (imm & 0x3f));
+    state.pc += 8;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::*;
+    use crate::guest::MemAccess;
+
+    /// One of every pair, the last block jumps over the word at 0x2c.
+    const PAIRS: &[u32] = &[
+        0x12345437, // 0: lui s0, 0x12345
+        0x67840413, // 4: addi s0, s0, 0x678
+        0x800004b7, // 8: lui s1, 0x80000
+        0xfff4849b, // c: addiw s1, s1, -1
+        0xfff00e13, // 10: li t3, -1
+        0x020e1913, // 14: slli s2, t3, 32
+        0x02095913, // 18: srli s2, s2, 32
+        0x00000297, // 1c: auipc t0, 0
+        0x0242b983, // 20: ld s3, 36(t0)
+        0x00000317, // 24: auipc t1, 0
+        0x00c300e7, // 28: jalr ra, 12(t1)
+        0xc0001073, // 2c: unimp
+        0x00000513, // 30: li a0, 0
+        0x05d00893, // 34: li a7, 93
+        0x00000073, // 38: ecall
+        0x00000013, // 3c: nop
+        0x89abcdef, // 40: .dword 0x0123456789abcdef
+        0x01234567,
+    ];
+
+    fn build(code: &[u32]) -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, code);
+        emulator
+    }
+
+    fn check_pairs(emulator: &Emulator) {
+        let x = &emulator.hart().state.x;
+        assert_eq!(x[8], 0x12345678);
+        assert_eq!(x[9], 0x7fffffff);
+        assert_eq!(x[18], 0xffffffff);
+        assert_eq!(x[5], 0x101c);
+        assert_eq!(x[19], 0x0123456789abcdef);
+        assert_eq!(x[6], 0x1024);
+        assert_eq!(x[1], 0x102c);
+    }
+
+    #[test]
+    fn test_fusion() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build(PAIRS);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+        check_pairs(&emulator);
+        assert_eq!(emulator.retired(), 14);
+        for kind in FusionKind::ALL {
+            let expected = if kind == FusionKind::LuiAddi { 2 } else { 1 };
+            assert_eq!(emulator.hart().fused(kind), expected, "{}", kind.as_str());
+        }
+
+        // single-stepping stops between the instructions of a pair
+        let mut emulator = build(PAIRS);
+        emulator.force_step().unwrap();
+        assert_eq!(emulator.hart().state.pc, 0x1004);
+        assert_eq!(emulator.hart().state.x[8], 0x12345000);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+        check_pairs(&emulator);
+        assert_eq!(emulator.hart().fused(FusionKind::LuiAddi), 1);
+    }
+
+    #[test]
+    fn test_fusion_fault() {
+        log::log_init(log::Level::Off);
+
+        // the load faults after auipc retired
+        let mut emulator = build(&[
+            0x00010297, // 0: auipc t0, 0x10
+            0x0002b303, // 4: ld t1, 0(t0)
+        ]);
+        assert!(matches!(emulator.run(), Err(Error::MemAccessFault(MemAccess::Read, 0x11000))));
+        assert_eq!(emulator.hart().state.pc, 0x1004);
+        assert_eq!(emulator.hart().state.x[5], 0x11000);
+        assert_eq!(emulator.retired(), 1);
+        assert_eq!(emulator.hart().fused(FusionKind::AuipcLd), 0);
+
+        // the load grows the stack and runs again, it only retires then
+        let mut emulator = build(&[
+            0x000fd297, // 0: auipc t0, 0xfd
+            0x0002b303, // 4: ld t1, 0(t0)
+            0x00000513, // 8: li a0, 0
+            0x05d00893, // c: li a7, 93
+            0x00000073, // 10: ecall
+        ]);
+        emulator.guest.add_stack(0x100000, 0x1000, 0x4000).unwrap();
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+        assert_eq!(emulator.hart().state.x[5], 0xfe000);
+        assert_eq!(emulator.retired(), 5);
+    }
+}
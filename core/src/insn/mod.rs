@@ -8,7 +8,7 @@ use crate::*;
 use crate::error::*;
 
 /// The 'imm' field has not been sign-extended yet.
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy)]
 pub enum Instruction {
     R {
         // [31:25] funct7
@@ -135,6 +135,19 @@ impl Instruction {
         }
     }
 
+    pub fn raw(&self) -> u32 {
+        match self {
+            Instruction::R { raw, .. } => *raw,
+            Instruction::I { raw, .. } => *raw,
+            Instruction::S { raw, .. } => *raw,
+            Instruction::B { raw, .. } => *raw,
+            Instruction::U { raw, .. } => *raw,
+            Instruction::J { raw, .. } => *raw,
+            Instruction::R4 { raw, .. } => *raw,
+            Instruction::C { raw, .. } => *raw,
+        }
+    }
+
     pub fn imm(&self) -> Option<u32> {
         use Instruction::*;
         match self {
@@ -227,7 +240,11 @@ impl InsnSet {
     }
 }
 
-pub trait Decoder: Debug {
+pub trait Decoder: Debug + Send + Sync {
+    /// Encodings handled by this decoder as (mask, match) pairs, it is only asked to decode
+    /// 'raw' if 'raw & mask == match' for one of them. Claims of different extensions must not overlap.
+    fn claims(&self) -> &'static [(u32, u32)];
+
     fn decode(&self, insn_raw: u32) -> Result<Option<(Instruction, Executor)>>;
 }
 
@@ -243,11 +260,19 @@ pub fn noop_executor(
 }
 
 
+pub mod cache;
+pub mod dispatch;
+pub mod fusion;
 pub mod rv64i;
+pub mod rv64a;
 pub mod zicsr;
 pub mod zifencei;
 
+pub use cache::BlockCache;
+pub use dispatch::DispatchTable;
+pub use fusion::FusionKind;
 pub use rv64i::Rv64IDecoder;
+pub use rv64a::Rv64ADecoder;
 pub use zicsr::ZicsrDecoder;
 pub use zifencei::ZifenceiDecoder;
 
@@ -0,0 +1,341 @@
+//! RV64A instruction set architecture
+//! LR registers a reservation with `GuestMem`, which any store or AMO to an overlapping range
+//! breaks, from whichever hart, even if it stores the value that was there.
+//! In weak memory mode an AMO or SC stores to memory directly, the rl bit first makes all
+//! earlier stores of the hart visible. Loads are never reordered, so aq needs no work.
+
+use crate::guest::{GuestMem, MemAccess};
+use crate::insn::{Decoder, Executor, Instruction};
+use crate::state::State;
+use crate::*;
+use crate::error::*;
+
+pub const RV64A_OPCODE_AMO: u8 = 0b0101111;
+
+pub const RV64A_FUNCT3_W: u8 = 0b010;
+pub const RV64A_FUNCT3_D: u8 = 0b011;
+
+pub const RV64A_FUNCT5_LR: u8 = 0b00010;
+pub const RV64A_FUNCT5_SC: u8 = 0b00011;
+pub const RV64A_FUNCT5_AMOSWAP: u8 = 0b00001;
+pub const RV64A_FUNCT5_AMOADD: u8 = 0b00000;
+pub const RV64A_FUNCT5_AMOXOR: u8 = 0b00100;
+pub const RV64A_FUNCT5_AMOAND: u8 = 0b01100;
+pub const RV64A_FUNCT5_AMOOR: u8 = 0b01000;
+pub const RV64A_FUNCT5_AMOMIN: u8 = 0b10000;
+pub const RV64A_FUNCT5_AMOMAX: u8 = 0b10100;
+pub const RV64A_FUNCT5_AMOMINU: u8 = 0b11000;
+pub const RV64A_FUNCT5_AMOMAXU: u8 = 0b11100;
+
+/// Acquire and release bits within funct7.
+pub const RV64A_AQ: u8 = 0b10;
+pub const RV64A_RL: u8 = 0b01;
+
+#[derive(Debug)]
+pub struct Rv64ADecoder;
+
+impl Decoder for Rv64ADecoder {
+    fn claims(&self) -> &'static [(u32, u32)] {
+        &[(0x7f, RV64A_OPCODE_AMO as u32)]
+    }
+
+    fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
+        let opcode = (raw & 0x7f) as u8;
+        let rd = ((raw >> 7) & 0x1f) as u8;
+        let funct3 = ((raw >> 12) & 0x07) as u8;
+        let rs1 = ((raw >> 15) & 0x1f) as u8;
+        let rs2 = ((raw >> 20) & 0x1f) as u8;
+        let funct7 = ((raw >> 25) & 0x7f) as u8;
+        let funct5 = funct7 >> 2;
+
+        if opcode != RV64A_OPCODE_AMO {
+            return Ok(None);
+        }
+        let executor = match (funct3, funct5) {
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_LR) if rs2 == 0 => rv64a_lr_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_SC) => rv64a_sc_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOSWAP) => rv64a_amoswap_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOADD) => rv64a_amoadd_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOXOR) => rv64a_amoxor_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOAND) => rv64a_amoand_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOOR) => rv64a_amoor_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOMIN) => rv64a_amomin_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOMAX) => rv64a_amomax_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOMINU) => rv64a_amominu_w as Executor,
+            (RV64A_FUNCT3_W, RV64A_FUNCT5_AMOMAXU) => rv64a_amomaxu_w as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_LR) if rs2 == 0 => rv64a_lr_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_SC) => rv64a_sc_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOSWAP) => rv64a_amoswap_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOADD) => rv64a_amoadd_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOXOR) => rv64a_amoxor_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOAND) => rv64a_amoand_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOOR) => rv64a_amoor_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOMIN) => rv64a_amomin_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOMAX) => rv64a_amomax_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOMINU) => rv64a_amominu_d as Executor,
+            (RV64A_FUNCT3_D, RV64A_FUNCT5_AMOMAXU) => rv64a_amomaxu_d as Executor,
+            _ => return Ok(None),
+        };
+
+        Ok(Some((Instruction::R {
+            funct7,
+            rs2,
+            rs1,
+            funct3,
+            rd,
+            opcode,
+            raw,
+        }, executor)))
+    }
+}
+
+/// AMOs must be naturally aligned, misaligned ones raise an access fault.
+fn check_aligned(addr: u64, size: u64, access: MemAccess) -> Result<()> {
+    if !addr.is_multiple_of(size) {
+        return Err(Error::MemAccessFault(access, addr));
+    }
+    Ok(())
+}
+
+fn release(insn: &Instruction) -> Result<bool> {
+    r!(insn, funct7 => {
+        Ok(funct7 & RV64A_RL != 0)
+    })
+}
+
+fn amo_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction, op: fn(u32, u32) -> u32) -> Result<()> {
+    r!(insn, rd, rs1, rs2 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 4, MemAccess::Write)?;
+        let old = guest.atomic(addr, 4, release(insn)?, |guest| {
+            let old = guest.read_u32(addr)?;
+            guest.write_u32(addr, op(old, state.x[rs2 as usize] as u32))?;
+            Ok(old)
+        })?;
+        state.x[rd as usize] = sign_extend!(old, 32) as u64;
+        Ok(())
+    })
+}
+
+fn amo_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction, op: fn(u64, u64) -> u64) -> Result<()> {
+    r!(insn, rd, rs1, rs2 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 8, MemAccess::Write)?;
+        let old = guest.atomic(addr, 8, release(insn)?, |guest| {
+            let old = guest.read_u64(addr)?;
+            guest.write_u64(addr, op(old, state.x[rs2 as usize]))?;
+            Ok(old)
+        })?;
+        state.x[rd as usize] = old;
+        Ok(())
+    })
+}
+
+pub fn rv64a_lr_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    r!(insn, rd, rs1 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 4, MemAccess::Read)?;
+        let value = guest.atomic(addr, 4, release(insn)?, |guest| guest.read_u32(addr))?;
+        guest.reserve(state.mhartid as usize, addr, 4);
+        state.x[rd as usize] = sign_extend!(value, 32) as u64;
+        Ok(())
+    })
+}
+
+pub fn rv64a_sc_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    r!(insn, rd, rs1, rs2 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 4, MemAccess::Write)?;
+        let hart = state.mhartid as usize;
+        let success = guest.atomic(addr, 4, release(insn)?, |guest| {
+            let success = guest.take_reservation(hart, addr, 4);
+            if success {
+                guest.write_u32(addr, state.x[rs2 as usize] as u32)?;
+            }
+            Ok(success)
+        })?;
+        state.x[rd as usize] = if success { 0 } else { 1 };
+        Ok(())
+    })
+}
+
+pub fn rv64a_lr_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    r!(insn, rd, rs1 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 8, MemAccess::Read)?;
+        let value = guest.atomic(addr, 8, release(insn)?, |guest| guest.read_u64(addr))?;
+        guest.reserve(state.mhartid as usize, addr, 8);
+        state.x[rd as usize] = value;
+        Ok(())
+    })
+}
+
+pub fn rv64a_sc_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    r!(insn, rd, rs1, rs2 => {
+        let addr = state.x[rs1 as usize];
+        check_aligned(addr, 8, MemAccess::Write)?;
+        let hart = state.mhartid as usize;
+        let success = guest.atomic(addr, 8, release(insn)?, |guest| {
+            let success = guest.take_reservation(hart, addr, 8);
+            if success {
+                guest.write_u64(addr, state.x[rs2 as usize])?;
+            }
+            Ok(success)
+        })?;
+        state.x[rd as usize] = if success { 0 } else { 1 };
+        Ok(())
+    })
+}
+
+pub fn rv64a_amoswap_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |_, src| src)
+}
+
+pub fn rv64a_amoadd_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old.wrapping_add(src))
+}
+
+pub fn rv64a_amoxor_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old ^ src)
+}
+
+pub fn rv64a_amoand_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old & src)
+}
+
+pub fn rv64a_amoor_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old | src)
+}
+
+pub fn rv64a_amomin_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| (old as i32).min(src as i32) as u32)
+}
+
+pub fn rv64a_amomax_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| (old as i32).max(src as i32) as u32)
+}
+
+pub fn rv64a_amominu_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old.min(src))
+}
+
+pub fn rv64a_amomaxu_w(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_w(state, guest, insn, |old, src| old.max(src))
+}
+
+pub fn rv64a_amoswap_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |_, src| src)
+}
+
+pub fn rv64a_amoadd_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old.wrapping_add(src))
+}
+
+pub fn rv64a_amoxor_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old ^ src)
+}
+
+pub fn rv64a_amoand_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old & src)
+}
+
+pub fn rv64a_amoor_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old | src)
+}
+
+pub fn rv64a_amomin_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| (old as i64).min(src as i64) as u64)
+}
+
+pub fn rv64a_amomax_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| (old as i64).max(src as i64) as u64)
+}
+
+pub fn rv64a_amominu_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old.min(src))
+}
+
+pub fn rv64a_amomaxu_d(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
+    amo_d(state, guest, insn, |old, src| old.max(src))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::guest::MemFlags;
+
+    fn exec(state: &mut State, guest: &mut GuestMem, raw: u32) {
+        let (insn, executor) = Rv64ADecoder.decode(raw).unwrap().expect("Failed to decode");
+        executor(state, guest, &insn).unwrap();
+    }
+
+    #[test]
+    fn test_amo() {
+        log::log_init(log::Level::Off);
+
+        let mut guest = GuestMem::new();
+        guest.add_segment(0x1000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+        let mut state = State::default();
+        state.x[10] = 0x1000;
+        state.x[11] = 5;
+        guest.write_u64(0x1000, 0xffff_ffff).unwrap();
+
+        // amoadd.w a2, a1, (a0)
+        exec(&mut state, &mut guest, 0x00b5262f);
+        assert_eq!(state.x[12], u64::MAX);
+        assert_eq!(guest.read_u32(0x1000).unwrap(), 4);
+        // amomaxu.d a2, a1, (a0)
+        exec(&mut state, &mut guest, 0xe0b5362f);
+        assert_eq!(state.x[12], 4);
+        assert_eq!(guest.read_u64(0x1000).unwrap(), 5);
+    }
+
+    #[test]
+    fn test_lr_sc() {
+        log::log_init(log::Level::Off);
+
+        let mut guest = GuestMem::new();
+        guest.add_segment(0x1000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+        let mut state = State::default();
+        state.x[10] = 0x1000;
+        state.x[11] = 7;
+
+        // lr.d a2, (a0); sc.d a3, a1, (a0)
+        exec(&mut state, &mut guest, 0x1005362f);
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 0);
+        assert_eq!(guest.read_u64(0x1000).unwrap(), 7);
+
+        // the reservation is consumed by the first sc
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 1);
+
+        // another hart changing the value breaks the reservation
+        exec(&mut state, &mut guest, 0x1005362f);
+        guest.write_u64(0x1000, 8).unwrap();
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 1);
+        assert_eq!(guest.read_u64(0x1000).unwrap(), 8);
+
+        // so does storing the same value, or a store to part of the reserved range
+        exec(&mut state, &mut guest, 0x1005362f);
+        guest.write_u64(0x1000, 8).unwrap();
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 1);
+        exec(&mut state, &mut guest, 0x1005362f);
+        guest.write_u8(0x1007, 0).unwrap();
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 1);
+
+        // a store next to it, or a reservation of another hart, does not
+        let mut other = State { mhartid: 1, ..State::default() };
+        other.x[10] = 0x1008;
+        exec(&mut state, &mut guest, 0x1005362f);
+        exec(&mut other, &mut guest, 0x1005362f);
+        guest.write_u64(0x1008, 1).unwrap();
+        exec(&mut state, &mut guest, 0x18b536af);
+        assert_eq!(state.x[13], 0);
+        exec(&mut other, &mut guest, 0x18b536af);
+        assert_eq!(other.x[13], 1);
+    }
+}
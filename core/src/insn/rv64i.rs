@@ -20,10 +20,39 @@ pub const RV64I_OPCODE_AUIPC: u8 = 0b0010111;
 pub const RV64I_OPCODE_FENCE: u8 = 0b0001111;
 pub const RV64I_OPCODE_SYSTEM: u8 = 0b1110011;
 
+/// Predecessor/successor bits of FENCE.
+pub const RV64I_FENCE_R: u32 = 0b0010;
+pub const RV64I_FENCE_W: u32 = 0b0001;
+
 #[derive(Debug)]
 pub struct Rv64IDecoder;
 
 impl Decoder for Rv64IDecoder {
+    fn claims(&self) -> &'static [(u32, u32)] {
+        const OPCODE: u32 = 0x7f;
+        const FUNCT3: u32 = 0x7 << 12;
+        const FUNCT7: u32 = 0x7f << 25;
+        &[
+            (OPCODE, RV64I_OPCODE_LUI as u32),
+            (OPCODE, RV64I_OPCODE_AUIPC as u32),
+            (OPCODE, RV64I_OPCODE_JAL as u32),
+            (OPCODE, RV64I_OPCODE_JALR as u32),
+            (OPCODE, RV64I_OPCODE_BRANCH as u32),
+            (OPCODE, RV64I_OPCODE_LOAD as u32),
+            (OPCODE, RV64I_OPCODE_STORE as u32),
+            (OPCODE, RV64I_OPCODE_OP_IMM as u32),
+            (OPCODE, RV64I_OPCODE_OP_IMM_W as u32),
+            // funct7 = 0 and 0b0100000, the rest of OP belongs to other extensions
+            (OPCODE | FUNCT7, RV64I_OPCODE_OP as u32),
+            (OPCODE | FUNCT7, 0x20 << 25 | RV64I_OPCODE_OP as u32),
+            (OPCODE | FUNCT7, RV64I_OPCODE_OP_W as u32),
+            (OPCODE | FUNCT7, 0x20 << 25 | RV64I_OPCODE_OP_W as u32),
+            (OPCODE | FUNCT3, RV64I_OPCODE_FENCE as u32),
+            // ecall
+            (u32::MAX, RV64I_OPCODE_SYSTEM as u32),
+        ]
+    }
+
     fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
         let opcode = (raw & 0x7f) as u8;
         let rd = ((raw >> 7) & 0x1f) as u8;
@@ -200,7 +229,8 @@ impl Decoder for Rv64IDecoder {
                     raw,
                     imm: imm_i,
                 }, rv64i_xori as Executor),
-                0b101 => match funct7 {
+                // shift amounts are 6 bits on RV64, which leaves funct6
+                0b101 => match funct7 >> 1 {
                         0 => (Instruction::I {
                             rd,
                             rs1,
@@ -209,7 +239,7 @@ impl Decoder for Rv64IDecoder {
                             raw,
                             imm: imm_i,
                         }, rv64i_srli as Executor),
-                        0b0100000 => (Instruction::I {
+                        0b010000 => (Instruction::I {
                             rd,
                             rs1,
                             funct3,
@@ -977,9 +1007,18 @@ pub fn rv64i_ebreak(state: &mut State, guest: &mut GuestMem, insn: &Instruction)
     Ok(())
 }
 
+/// Loads are performed in order, so only fences ordering earlier stores have work to do:
+/// they flush the store buffer in weak memory mode. The race detector sees every fence.
 pub fn rv64i_fence(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
-    trace!("dummy fence");
-    Ok(())
+    i!(insn, imm => {
+        let pred = (imm >> 4) & 0xf;
+        let succ = imm & 0xf;
+        guest.record_fence(pred & RV64I_FENCE_R != 0, succ & RV64I_FENCE_W != 0);
+        if pred & RV64I_FENCE_W != 0 && succ & (RV64I_FENCE_R | RV64I_FENCE_W) != 0 {
+            guest.fence()?;
+        }
+        Ok(())
+    })
 }
 
 #[cfg(test)]
@@ -1005,4 +1044,17 @@ mod tests {
             }
         }
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_decode_shifts() {
+        let decoder = Rv64IDecoder;
+        // shift amounts of 32 and above set bit 25
+        for (raw, executor) in [
+            (0x02095913, rv64i_srli as Executor), // srli s2, s2, 32
+            (0x43f95913, rv64i_srai as Executor), // srai s2, s2, 63
+        ] {
+            let (_, decoded) = decoder.decode(raw).unwrap().unwrap();
+            assert_eq!(decoded as usize, executor as usize, "{:#x}", raw);
+        }
+        assert!(decoder.decode(0x82095913).unwrap().is_none());
+    }
+}
@@ -3,8 +3,10 @@
 use crate::*;
 use crate::error::*;
 use crate::guest::*;
-use crate::state::State;
+use crate::state::*;
 use crate::insn::*;
+use crate::pmp::*;
+use crate::trigger::*;
 
 pub const ZICSR_OPCODE: u8 = 0b1110011;
 pub const ZICSR_FUNCT3_CSRRW: u8 = 0b001;
@@ -15,14 +17,34 @@ pub const ZICSR_FUNCT3_CSRRSI: u8 = 0b110;
 pub const ZICSR_FUNCT3_CSRRCI: u8 = 0b111;
 
 pub const CSR_MHARTID: u32 = 0xF14;
+pub const CSR_MSTATUS: u32 = 0x300;
+pub const CSR_MTVEC: u32 = 0x305;
 pub const CSR_MEPC: u32 = 0x341;
+pub const CSR_MCAUSE: u32 = 0x342;
+pub const CSR_MTVAL: u32 = 0x343;
 
 #[derive(Debug)]
 pub struct ZicsrDecoder;
 
-pub static mut MEPC: u64 = 0;
-
 impl Decoder for ZicsrDecoder {
+    fn claims(&self) -> &'static [(u32, u32)] {
+        const MASK: u32 = 0x7 << 12 | 0x7f;
+        const fn csr(funct3: u8) -> (u32, u32) {
+            (MASK, (funct3 as u32) << 12 | ZICSR_OPCODE as u32)
+        }
+        const CLAIMS: &[(u32, u32)] = &[
+            csr(ZICSR_FUNCT3_CSRRW),
+            csr(ZICSR_FUNCT3_CSRRS),
+            csr(ZICSR_FUNCT3_CSRRC),
+            csr(ZICSR_FUNCT3_CSRRWI),
+            csr(ZICSR_FUNCT3_CSRRSI),
+            csr(ZICSR_FUNCT3_CSRRCI),
+            // mret
+            (u32::MAX, 0x30200073),
+        ];
+        CLAIMS
+    }
+
     fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
         let imm_i = Instruction::extract_imm(raw, InsnType::I);
         let opcode = (raw & 0x7f) as u8;
@@ -64,139 +86,123 @@ impl Decoder for ZicsrDecoder {
     }
 }
 
+/// Reads a CSR, None if it is not supported.
+fn csr_read(state: &State, guest: &GuestMem, csr: u32) -> Option<u64> {
+    match csr {
+        CSR_MHARTID => Some(state.mhartid),
+        CSR_MSTATUS => Some(state.trap.mstatus),
+        CSR_MTVEC => Some(state.trap.mtvec),
+        CSR_MEPC => Some(state.trap.mepc),
+        CSR_MCAUSE => Some(state.trap.mcause),
+        CSR_MTVAL => Some(state.trap.mtval),
+        CSR_PMPCFG0 | CSR_PMPCFG2 => guest.pmp()?.read_cfg(csr),
+        CSR_PMPADDR0..=CSR_PMPADDR15 => guest.pmp()?.read_addr(csr),
+        CSR_TSELECT..=CSR_TINFO => guest.triggers().read_csr(csr),
+        _ => None,
+    }
+}
+
+/// Writes a CSR, None if it is not supported. Writes to read-only CSRs are ignored.
+fn csr_write(state: &mut State, guest: &mut GuestMem, csr: u32, value: u64) -> Option<()> {
+    match csr {
+        CSR_MHARTID => {},
+        CSR_MSTATUS => {
+            let mut mstatus = value & MSTATUS_MPRV;
+            // MPP keeps its value on a write of the reserved encoding
+            mstatus |= match Privilege::from_bits((value & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT) {
+                Some(_) => value & MSTATUS_MPP,
+                None => state.trap.mstatus & MSTATUS_MPP,
+            };
+            state.trap.mstatus = mstatus;
+            guest.set_privilege(guest.privilege(), mstatus);
+        },
+        CSR_MTVEC => state.trap.mtvec = value,
+        CSR_MEPC => state.trap.mepc = value & !1,
+        CSR_MCAUSE => state.trap.mcause = value,
+        CSR_MTVAL => state.trap.mtval = value,
+        CSR_PMPCFG0 | CSR_PMPCFG2 => guest.pmp_mut()?.write_cfg(csr, value)?,
+        CSR_PMPADDR0..=CSR_PMPADDR15 => guest.pmp_mut()?.write_addr(csr, value)?,
+        CSR_TSELECT..=CSR_TINFO => guest.triggers_mut().write_csr(csr, value)?,
+        _ => return None,
+    }
+    Some(())
+}
+
+/// Common body of all Zicsr instructions.
+/// 'write' is false for CSRRS/CSRRC whose source is x0 or a zero immediate, which must not write.
+fn csr_op(
+    state: &mut State,
+    guest: &mut GuestMem,
+    csr: u32,
+    rd: u8,
+    write: bool,
+    op: impl FnOnce(u64) -> u64,
+) -> Result<()> {
+    let Some(old) = csr_read(state, guest, csr) else {
+        debug!("Unsupported CSR operation: CSR={:#x}, rd={}", csr, rd);
+        return Ok(());
+    };
+    if write && csr_write(state, guest, csr, op(old)).is_none() {
+        debug!("Unsupported CSR write: CSR={:#x}", csr);
+    }
+    state.x[rd as usize] = old;
+    Ok(())
+}
+
 fn zicsr_csrrw(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC = state.x[rs1 as usize];
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        let src = state.x[rs1 as usize];
+        csr_op(state, guest, imm, rd, true, |_| src)
     })
 }
 
 fn zicsr_csrrs(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC |= state.x[rs1 as usize];
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        let src = state.x[rs1 as usize];
+        csr_op(state, guest, imm, rd, rs1 != 0, |old| old | src)
     })
 }
 
 fn zicsr_csrrc(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC &= !state.x[rs1 as usize];
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        let src = state.x[rs1 as usize];
+        csr_op(state, guest, imm, rd, rs1 != 0, |old| old & !src)
     })
 }
 
 fn zicsr_csrrwi(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
         let uimm = zero_extend!(rs1, 5);
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC = uimm;
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        csr_op(state, guest, imm, rd, true, |_| uimm)
     })
 }
 
 fn zicsr_csrrsi(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
         let uimm = zero_extend!(rs1, 5);
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC |= uimm;
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        csr_op(state, guest, imm, rd, uimm != 0, |old| old | uimm)
     })
 }
 
 fn zicsr_csrrci(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
     i!(insn, imm, rs1, rd => {
-        let csr = imm;
         let uimm = zero_extend!(rs1, 5);
-        match csr {
-            CSR_MHARTID => {
-                state.x[rd as usize] = 0;
-            },
-            CSR_MEPC => {
-                unsafe {
-                    state.x[rd as usize] = MEPC;
-                    MEPC &= !uimm;
-                }
-            },
-            _ => {
-                debug!("Unsupported CSR operation: CSR={:#x}, rd={}, rs1={}", csr, rd, rs1);
-            }
-        }
-        Ok(())
+        csr_op(state, guest, imm, rd, uimm != 0, |old| old & !uimm)
     })
 }
 
 // These instructions should not appear here, but we do for convenience.
+/// Returns to the privilege level in mstatus.MPP, which drops to user mode.
+/// Leaving machine mode clears MPRV.
 fn priv_mret(state: &mut State, guest: &mut GuestMem, insn: &Instruction) -> Result<()> {
-    unsafe {
-        state.pc = MEPC;
+    let mstatus = state.trap.mstatus;
+    let privilege = Privilege::from_bits((mstatus & MSTATUS_MPP) >> MSTATUS_MPP_SHIFT).unwrap_or_default();
+    let mut mstatus = mstatus & !MSTATUS_MPP;
+    if privilege != Privilege::Machine {
+        mstatus &= !MSTATUS_MPRV;
     }
+    state.trap.mstatus = mstatus;
+    guest.set_privilege(privilege, mstatus);
+    state.pc = state.trap.mepc;
     Ok(())
 }
\ No newline at end of file
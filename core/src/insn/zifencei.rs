@@ -1,4 +1,4 @@
-//! Currently no-op.
+//! fence.i drops every decoded instruction, so that code written before it is refetched.
 
 use crate::*;
 use crate::error::*;
@@ -12,6 +12,10 @@ pub const ZIFENCEI_INSN: u32 = 0x0000100f;
 pub struct ZifenceiDecoder;
 
 impl Decoder for ZifenceiDecoder {
+    fn claims(&self) -> &'static [(u32, u32)] {
+        &[(u32::MAX, ZIFENCEI_INSN)]
+    }
+
     fn decode(&self, raw: u32) -> Result<Option<(Instruction, Executor)>> {
         if raw == ZIFENCEI_INSN {
             let insn = Instruction::R {
@@ -30,6 +34,7 @@ impl Decoder for ZifenceiDecoder {
     }
 }
 
-fn zifencei(_state: &mut State, _guest: &mut GuestMem, _insn: &Instruction) -> Result<()> {
+fn zifencei(_state: &mut State, guest: &mut GuestMem, _insn: &Instruction) -> Result<()> {
+    guest.invalidate_code();
     Ok(())
 }
\ No newline at end of file
@@ -0,0 +1,160 @@
+//! Minimal x86-64 machine code emitter, only what the translator needs.
+//! Guest registers live in memory, addressed relative to rdi, and are moved through
+//! rax, rcx and rdx.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Reg {
+    Rax = 0,
+    Rcx = 1,
+    Rdx = 2,
+}
+
+/// Two-operand ALU instructions, 'op rax, rcx'.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Alu {
+    Add = 0x01,
+    Or = 0x09,
+    And = 0x21,
+    Sub = 0x29,
+    Xor = 0x31,
+    Cmp = 0x39,
+}
+
+/// Shifts of rax by cl, the value is the /digit of the opcode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shift {
+    Shl = 4,
+    Shr = 5,
+    Sar = 7,
+}
+
+/// Condition codes, as in setcc and cmovcc.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    /// unsigned <
+    B = 0x2,
+    /// unsigned >=
+    Ae = 0x3,
+    E = 0x4,
+    Ne = 0x5,
+    /// signed <
+    L = 0xc,
+    /// signed >=
+    Ge = 0xd,
+}
+
+const REX_W: u8 = 0x48;
+/// ModRM of '[rdi + disp32]' with 'reg' in the reg field.
+const fn modrm_rdi_disp32(reg: Reg) -> u8 {
+    0x80 | (reg as u8) << 3 | 0x7
+}
+
+#[derive(Debug, Default)]
+pub struct Emitter {
+    code: Vec<u8>,
+}
+
+impl Emitter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn code(&self) -> &[u8] {
+        &self.code
+    }
+
+    fn rex(&mut self, wide: bool) {
+        if wide {
+            self.code.push(REX_W);
+        }
+    }
+
+    fn disp(xreg: u8) -> [u8; 4] {
+        (xreg as u32 * 8).to_le_bytes()
+    }
+
+    /// 'mov reg, [rdi + 8 * xreg]', the 32-bit form zero-extends.
+    pub fn load(&mut self, reg: Reg, xreg: u8, wide: bool) {
+        self.rex(wide);
+        self.code.extend([0x8b, modrm_rdi_disp32(reg)]);
+        self.code.extend(Self::disp(xreg));
+    }
+
+    /// 'mov [rdi + 8 * xreg], reg', writes to x0 are dropped.
+    pub fn store(&mut self, xreg: u8, reg: Reg) {
+        if xreg == 0 {
+            return;
+        }
+        self.code.extend([REX_W, 0x89, modrm_rdi_disp32(reg)]);
+        self.code.extend(Self::disp(xreg));
+    }
+
+    pub fn mov_imm(&mut self, reg: Reg, imm: u64) {
+        self.code.extend([REX_W, 0xb8 + reg as u8]);
+        self.code.extend(imm.to_le_bytes());
+    }
+
+    /// 'op rax, rcx', or 'op eax, ecx' if not 'wide'.
+    pub fn alu(&mut self, op: Alu, wide: bool) {
+        self.rex(wide);
+        self.code.extend([op as u8, 0xc8]);
+    }
+
+    /// 'op rax, cl'. x86 masks the count to 6 bits, or 5 bits if not 'wide', like RISC-V does.
+    pub fn shift(&mut self, op: Shift, wide: bool) {
+        self.rex(wide);
+        self.code.extend([0xd3, 0xc0 | (op as u8) << 3]);
+    }
+
+    /// 'movsxd rax, eax'
+    pub fn sext32(&mut self) {
+        self.code.extend([REX_W, 0x63, 0xc0]);
+    }
+
+    /// 'rax = cond ? 1 : 0', after a 'cmp'.
+    pub fn set(&mut self, cond: Cond) {
+        // setcc al; movzx eax, al
+        self.code.extend([0x0f, 0x90 | cond as u8, 0xc0, 0x0f, 0xb6, 0xc0]);
+    }
+
+    /// 'cmovcc rax, rdx'
+    pub fn cmov(&mut self, cond: Cond) {
+        self.code.extend([REX_W, 0x0f, 0x40 | cond as u8, 0xc2]);
+    }
+
+    /// 'and rax, -2'
+    pub fn clear_bit0(&mut self) {
+        self.code.extend([REX_W, 0x83, 0xe0, 0xfe]);
+    }
+
+    pub fn ret(&mut self) {
+        self.code.push(0xc3);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_encoding() {
+        let mut emitter = Emitter::new();
+        emitter.load(Reg::Rcx, 2, true);
+        emitter.load(Reg::Rax, 1, false);
+        emitter.alu(Alu::Sub, true);
+        emitter.shift(Shift::Sar, false);
+        emitter.cmov(Cond::L);
+        emitter.store(0, Reg::Rax);
+        emitter.store(31, Reg::Rdx);
+        emitter.ret();
+        assert_eq!(emitter.code(), [
+            0x48, 0x8b, 0x8f, 0x10, 0x00, 0x00, 0x00, // mov rcx, [rdi + 0x10]
+            0x8b, 0x87, 0x08, 0x00, 0x00, 0x00, // mov eax, [rdi + 0x8]
+            0x48, 0x29, 0xc8, // sub rax, rcx
+            0xd3, 0xf8, // sar eax, cl
+            0x48, 0x0f, 0x4c, 0xc2, // cmovl rax, rdx
+            0x48, 0x89, 0x97, 0xf8, 0x00, 0x00, 0x00, // mov [rdi + 0xf8], rdx
+            0xc3, // ret
+        ]);
+    }
+}
@@ -0,0 +1,334 @@
+//! Template JIT for hot basic blocks, enabled by the 'jit' cargo feature.
+//! Every instruction is translated on its own into a fixed x86-64 sequence that works on
+//! `State::x` in memory. Only instructions that cannot fault are translated: LUI, AUIPC,
+//! register-register and register-immediate arithmetic, and the control transfers that end
+//! a block. A block is compiled up to its first other instruction, the interpreter runs
+//! the rest.
+//! `JitMode::Lockstep` runs the interpreter after every compiled block and compares the
+//! resulting states, to check the translator.
+
+#[cfg(not(target_arch = "x86_64"))]
+compile_error!("the 'jit' feature needs an x86-64 host");
+
+pub mod emit;
+
+use memmap2::{Mmap, MmapMut};
+
+use crate::error::*;
+use crate::insn::rv64i::*;
+use crate::insn::{Executor, InsnType, Instruction};
+use crate::*;
+use emit::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum JitMode {
+    #[default]
+    Off,
+    /// Hot blocks run as native code.
+    Native,
+    /// Hot blocks run as native code and through the interpreter, which have to agree.
+    Lockstep,
+}
+
+/// Native code of the compiled prefix of a block.
+#[derive(Debug)]
+pub struct JitBlock {
+    code: Mmap,
+    /// Instructions covered.
+    len: usize,
+}
+
+impl JitBlock {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Runs the block on the registers 'x', returns the pc of the next instruction.
+    /// x[0] has to be zero.
+    pub fn call(&self, x: &mut [u64; 32]) -> u64 {
+        let entry: extern "sysv64" fn(*mut u64) -> u64 = unsafe { std::mem::transmute(self.code.as_ptr()) };
+        entry(x.as_mut_ptr())
+    }
+}
+
+/// Compiles the longest prefix of the block at 'start' that the JIT supports.
+/// Returns None if there is nothing to compile.
+pub fn compile(start: u64, insns: &[(Instruction, Executor)]) -> Result<Option<JitBlock>> {
+    let mut emitter = Emitter::new();
+    let mut pc = start;
+    let mut len = 0;
+    let mut ended = false;
+    for (insn, _) in insns {
+        match translate(&mut emitter, insn.raw(), pc) {
+            Some(ends_block) => {
+                len += 1;
+                pc += insn.step_size() as u64;
+                if ends_block {
+                    ended = true;
+                    break;
+                }
+            },
+            None => break,
+        }
+    }
+    if len == 0 {
+        return Ok(None);
+    }
+    if !ended {
+        emitter.mov_imm(Reg::Rax, pc);
+        emitter.ret();
+    }
+
+    let code = emitter.code();
+    let map_err = |e: std::io::Error| Error::IoError(e, String::new());
+    let mut mmap = MmapMut::map_anon(code.len()).map_err(map_err)?;
+    mmap.copy_from_slice(code);
+    let code = mmap.make_exec().map_err(map_err)?;
+    Ok(Some(JitBlock { code, len }))
+}
+
+/// Emits the code of the instruction 'raw' at 'pc'. Returns whether it ends the block,
+/// or None if it is not supported.
+fn translate(emitter: &mut Emitter, raw: u32, pc: u64) -> Option<bool> {
+    let opcode = (raw & 0x7f) as u8;
+    let rd = ((raw >> 7) & 0x1f) as u8;
+    let funct3 = (raw >> 12) & 0x7;
+    let rs1 = ((raw >> 15) & 0x1f) as u8;
+    let rs2 = ((raw >> 20) & 0x1f) as u8;
+    let funct7 = raw >> 25;
+    let imm_i = sign_extend!(Instruction::extract_imm(raw, InsnType::I), 12) as u64;
+    let shamt = (raw >> 20) & 0x3f;
+
+    match opcode {
+        RV64I_OPCODE_LUI | RV64I_OPCODE_AUIPC => {
+            let imm = sign_extend!(Instruction::extract_imm(raw, InsnType::U), 32) as u64;
+            let base = if opcode == RV64I_OPCODE_AUIPC { pc } else { 0 };
+            emitter.mov_imm(Reg::Rax, base.wrapping_add(imm));
+            emitter.store(rd, Reg::Rax);
+        },
+        RV64I_OPCODE_OP_IMM | RV64I_OPCODE_OP_IMM_W => {
+            let wide = opcode == RV64I_OPCODE_OP_IMM;
+            emitter.load(Reg::Rax, rs1, wide);
+            match (funct3, wide) {
+                (0b001, _) | (0b101, _) => {
+                    let op = match (funct3, raw >> 30 & 1) {
+                        (0b001, _) => Shift::Shl,
+                        (_, 0) => Shift::Shr,
+                        _ => Shift::Sar,
+                    };
+                    emitter.mov_imm(Reg::Rcx, shamt as u64);
+                    emitter.shift(op, wide);
+                },
+                (0b000, _) => {
+                    emitter.mov_imm(Reg::Rcx, imm_i);
+                    emitter.alu(Alu::Add, wide);
+                },
+                (0b010 | 0b011, true) => {
+                    emitter.mov_imm(Reg::Rcx, imm_i);
+                    emitter.alu(Alu::Cmp, true);
+                    emitter.set(if funct3 == 0b010 { Cond::L } else { Cond::B });
+                },
+                (0b100 | 0b110 | 0b111, true) => {
+                    emitter.mov_imm(Reg::Rcx, imm_i);
+                    emitter.alu(bitwise(funct3), true);
+                },
+                _ => return None,
+            }
+            if !wide {
+                emitter.sext32();
+            }
+            emitter.store(rd, Reg::Rax);
+        },
+        RV64I_OPCODE_OP | RV64I_OPCODE_OP_W => {
+            let wide = opcode == RV64I_OPCODE_OP;
+            if funct7 != 0 && !(funct7 == 0b0100000 && matches!(funct3, 0b000 | 0b101)) {
+                return None;
+            }
+            emitter.load(Reg::Rax, rs1, wide);
+            emitter.load(Reg::Rcx, rs2, wide);
+            match (funct3, wide) {
+                (0b000, _) => emitter.alu(if funct7 == 0 { Alu::Add } else { Alu::Sub }, wide),
+                (0b001, _) => emitter.shift(Shift::Shl, wide),
+                (0b101, _) => emitter.shift(if funct7 == 0 { Shift::Shr } else { Shift::Sar }, wide),
+                (0b010 | 0b011, true) => {
+                    emitter.alu(Alu::Cmp, true);
+                    emitter.set(if funct3 == 0b010 { Cond::L } else { Cond::B });
+                },
+                (0b100 | 0b110 | 0b111, true) => emitter.alu(bitwise(funct3), true),
+                _ => return None,
+            }
+            if !wide {
+                emitter.sext32();
+            }
+            emitter.store(rd, Reg::Rax);
+        },
+        RV64I_OPCODE_BRANCH => {
+            let cond = match funct3 {
+                0b000 => Cond::E,
+                0b001 => Cond::Ne,
+                0b100 => Cond::L,
+                0b101 => Cond::Ge,
+                0b110 => Cond::B,
+                0b111 => Cond::Ae,
+                _ => return None,
+            };
+            let imm = sign_extend!(Instruction::extract_imm(raw, InsnType::B), 13) as u64;
+            emitter.load(Reg::Rax, rs1, true);
+            emitter.load(Reg::Rcx, rs2, true);
+            emitter.alu(Alu::Cmp, true);
+            // mov does not touch the flags
+            emitter.mov_imm(Reg::Rax, pc + 4);
+            emitter.mov_imm(Reg::Rdx, pc.wrapping_add(imm));
+            emitter.cmov(cond);
+            emitter.ret();
+            return Some(true);
+        },
+        RV64I_OPCODE_JAL => {
+            let imm = sign_extend!(Instruction::extract_imm(raw, InsnType::J), 21) as u64;
+            emitter.mov_imm(Reg::Rcx, pc + 4);
+            emitter.store(rd, Reg::Rcx);
+            emitter.mov_imm(Reg::Rax, pc.wrapping_add(imm));
+            emitter.ret();
+            return Some(true);
+        },
+        RV64I_OPCODE_JALR if funct3 == 0 => {
+            // the target is computed first, rd may be rs1
+            emitter.load(Reg::Rax, rs1, true);
+            emitter.mov_imm(Reg::Rcx, imm_i);
+            emitter.alu(Alu::Add, true);
+            emitter.clear_bit0();
+            emitter.mov_imm(Reg::Rcx, pc + 4);
+            emitter.store(rd, Reg::Rcx);
+            emitter.ret();
+            return Some(true);
+        },
+        _ => return None,
+    }
+    Some(false)
+}
+
+fn bitwise(funct3: u32) -> Alu {
+    match funct3 {
+        0b100 => Alu::Xor,
+        0b110 => Alu::Or,
+        _ => Alu::And,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::emulator::*;
+    use crate::insn::InsnSet;
+    use crate::*;
+
+    use super::*;
+
+    fn run(code: &[u32], mode: JitMode) -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .jit(mode)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, code);
+        assert!(matches!(emulator.run().unwrap(), ExitReason::Exited(_)));
+        emulator
+    }
+
+    #[test]
+    fn test_lockstep() {
+        log::log_init(log::Level::Off);
+
+        // Runs every supported operation 50 times on changing operands.
+        let code = [
+            0x03200f93, // 0: li t6, 50
+            0x877652b7, // 4: lui t0, 554853
+            0x3212829b, // 8: addiw t0, t0, 801
+            0xfff00313, // c: li t1, -1
+            0x00000517, // 10: auipc a0, 0
+            0x00628533, // 14: add a0, t0, t1
+            0x406285b3, // 18: sub a1, t0, t1
+            0x00629633, // 1c: sll a2, t0, t1
+            0x0062a6b3, // 20: slt a3, t0, t1
+            0x0062b733, // 24: sltu a4, t0, t1
+            0x0062c7b3, // 28: xor a5, t0, t1
+            0x0062d833, // 2c: srl a6, t0, t1
+            0x4062d8b3, // 30: sra a7, t0, t1
+            0x0062e533, // 34: or a0, t0, t1
+            0x0062f5b3, // 38: and a1, t0, t1
+            0xfff2a613, // 3c: slti a2, t0, -1
+            0x0072b693, // 40: sltiu a3, t0, 7
+            0x8002c713, // 44: xori a4, t0, -2048
+            0x7ff2e793, // 48: ori a5, t0, 2047
+            0x0f02f813, // 4c: andi a6, t0, 240
+            0x02729893, // 50: slli a7, t0, 39
+            0x0012d513, // 54: srli a0, t0, 1
+            0x4032d593, // 58: srai a1, t0, 3
+            0x8002861b, // 5c: addiw a2, t0, -2048
+            0x01f2969b, // 60: slliw a3, t0, 31
+            0x0042d71b, // 64: srliw a4, t0, 4
+            0x4042d79b, // 68: sraiw a5, t0, 4
+            0x0062883b, // 6c: addw a6, t0, t1
+            0x406288bb, // 70: subw a7, t0, t1
+            0x0062953b, // 74: sllw a0, t0, t1
+            0x0062d5bb, // 78: srlw a1, t0, t1
+            0x4062d63b, // 7c: sraw a2, t0, t1
+            0x00b28333, // 80: add t1, t0, a1
+            0x00d292b3, // 84: sll t0, t0, a3
+            0x0102c2b3, // 88: xor t0, t0, a6
+            0x00d80463, // 8c: beq a6, a3, 0x94
+            0x0180006f, // 90: j 0xa8
+            0x00000013, // 94: nop
+            0x00000013, // 98: nop
+            0x00000013, // 9c: nop
+            0x00000013, // a0: nop
+            0x00000013, // a4: nop
+            0x000013b7, // a8: lui t2, 1
+            0x0b838393, // ac: addi t2, t2, 184
+            0x00038e67, // b0: jalr t3, 0(t2)
+            0x00000013, // b4: nop
+            0xffff8f93, // b8: addi t6, t6, -1
+            0xf40f9ae3, // bc: bnez t6, 0x10
+            0x00a5c533, // c0: xor a0, a1, a0
+            0x05d00893, // c4: li a7, 93
+            0x00000073, // c8: ecall
+        ];
+        let native = run(&code, JitMode::Lockstep);
+        assert!(native.hart().blocks.compiled() >= 3);
+        let interpreted = run(&code, JitMode::Off);
+        assert_eq!(interpreted.hart().blocks.compiled(), 0);
+        assert_eq!(native.hart().state.x, interpreted.hart().state.x);
+    }
+
+    #[test]
+    fn test_unsupported_tail() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .jit(JitMode::Native)
+            .build()
+            .unwrap();
+        emulator.guest.add_segment(0x2000, 0x1000, 0x1000, guest::MemFlags::READ | guest::MemFlags::WRITE, None)
+            .unwrap();
+        // The loop body is compiled up to the store, the interpreter runs the rest.
+        emulator.load_code(0x1000, &[
+            0x06400293, // 0: li t0, 100
+            0x00002337, // 4: lui t1, 2
+            0x00550533, // 8: add a0, a0, t0
+            0xfff28293, // c: addi t0, t0, -1
+            0x00a33023, // 10: sd a0, 0(t1)
+            0xfe029ae3, // 14: bnez t0, 0x8
+            0x00033503, // 18: ld a0, 0(t1)
+            0x05d00893, // 1c: li a7, 93
+            0x00000073, // 20: ecall
+        ]);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(5050));
+        assert_eq!(emulator.hart().blocks.compiled(), 1);
+    }
+}
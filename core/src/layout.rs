@@ -0,0 +1,128 @@
+//! Where the stacks, the heap, mmaps and position-independent programs go in the guest address
+//! space, see `EmulatorBuilder::layout`. With ASLR each of them moves by a random number of
+//! pages drawn from a seed, so that a run that fails for one layout can be replayed.
+
+use crate::config::{HEAP_ASLR_PAGES, MMAP_ASLR_PAGES, MMAP_TOP, PIE_ASLR_PAGES, PIE_BASE, STACK_ASLR_PAGES, STACK_TOP};
+use crate::guest::PAGE_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressLayout {
+    /// End of the stack of hart 0, the stacks of the other harts follow below.
+    pub stack_top: u64,
+    /// `GuestMem::mmap` places mappings it chooses the address of below this, top-down.
+    pub mmap_base: u64,
+    /// Lowest address of the heap, None puts it right above the program like Linux.
+    pub heap_start: Option<u64>,
+    /// Where position-independent executables are loaded.
+    pub pie_base: u64,
+    /// Seed of the randomisation, None keeps the addresses above.
+    pub aslr_seed: Option<u64>,
+}
+
+impl Default for AddressLayout {
+    fn default() -> Self {
+        Self {
+            stack_top: STACK_TOP,
+            mmap_base: MMAP_TOP,
+            heap_start: None,
+            pie_base: PIE_BASE,
+            aslr_seed: None,
+        }
+    }
+}
+
+/// The addresses an `AddressLayout` comes to once randomised, see `AddressLayout::place`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Placement {
+    pub stack_top: u64,
+    pub mmap_base: u64,
+    pub heap_start: Option<u64>,
+    /// Unmapped gap between the program and a heap that has no fixed start.
+    pub heap_gap: u64,
+    pub pie_base: u64,
+}
+
+impl Default for Placement {
+    fn default() -> Self {
+        AddressLayout::default().place()
+    }
+}
+
+impl AddressLayout {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Randomises the layout, the same seed gives the same addresses.
+    pub fn aslr(mut self, seed: u64) -> Self {
+        self.aslr_seed = Some(seed);
+        self
+    }
+
+    /// Moves the stack down, the mmap base down, the heap up and the PIE base up, each by
+    /// a random number of pages below a limit like Linux's. Keeps the addresses without a seed.
+    pub fn place(&self) -> Placement {
+        let Some(seed) = self.aslr_seed else {
+            return Placement {
+                stack_top: self.stack_top,
+                mmap_base: self.mmap_base,
+                heap_start: self.heap_start,
+                heap_gap: 0,
+                pie_base: self.pie_base,
+            };
+        };
+        // xorshift64, the state must not be zero
+        let mut rng = (seed ^ 0x9e37_79b9_7f4a_7c15).max(1);
+        let mut pages = |limit: u64| {
+            rng ^= rng << 13;
+            rng ^= rng >> 7;
+            rng ^= rng << 17;
+            (rng % limit) * PAGE_SIZE as u64
+        };
+        // a base never moves by more than half of itself, so it cannot wrap around
+        let down = |base: u64, offset: u64| base - offset.min(base / 2) / PAGE_SIZE as u64 * PAGE_SIZE as u64;
+        let stack_top = down(self.stack_top, pages(STACK_ASLR_PAGES));
+        let mmap_base = down(self.mmap_base, pages(MMAP_ASLR_PAGES));
+        let heap_gap = pages(HEAP_ASLR_PAGES);
+        let pie_base = self.pie_base + pages(PIE_ASLR_PAGES);
+        Placement {
+            stack_top,
+            mmap_base,
+            heap_start: self.heap_start.map(|start| start + heap_gap),
+            heap_gap: if self.heap_start.is_some() { 0 } else { heap_gap },
+            pie_base,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_place() {
+        let layout = AddressLayout::new();
+        let fixed = layout.place();
+        assert_eq!((fixed.stack_top, fixed.mmap_base, fixed.heap_gap, fixed.pie_base), (STACK_TOP, MMAP_TOP, 0, PIE_BASE));
+
+        // the same seed places everything the same way, another one does not
+        let placed = layout.aslr(42).place();
+        assert_eq!(placed, layout.aslr(42).place());
+        assert_ne!(placed, layout.aslr(43).place());
+        assert!(placed.stack_top <= STACK_TOP && placed.stack_top > STACK_TOP - STACK_ASLR_PAGES * PAGE_SIZE as u64);
+        assert!(placed.mmap_base <= MMAP_TOP && placed.mmap_base > MMAP_TOP - MMAP_ASLR_PAGES * PAGE_SIZE as u64);
+        assert!(placed.heap_gap < HEAP_ASLR_PAGES * PAGE_SIZE as u64 && placed.heap_start.is_none());
+        assert!(placed.pie_base >= PIE_BASE && placed.pie_base < PIE_BASE + PIE_ASLR_PAGES * PAGE_SIZE as u64);
+        for addr in [placed.stack_top, placed.mmap_base, placed.heap_gap, placed.pie_base] {
+            assert!(addr.is_multiple_of(PAGE_SIZE as u64));
+        }
+
+        // a fixed heap start moves instead of the gap
+        let heap = AddressLayout { heap_start: Some(0x100000), ..layout }.aslr(42).place();
+        assert_eq!((heap.heap_start, heap.heap_gap), (Some(0x100000 + placed.heap_gap), 0));
+
+        // small bases stay above zero
+        let low = AddressLayout { stack_top: 0x4000, ..layout }.aslr(42).place();
+        assert!(low.stack_top >= 0x2000);
+    }
+}
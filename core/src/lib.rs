@@ -4,7 +4,19 @@
 pub mod hart;
 pub mod state;
 pub mod guest;
+pub mod pmp;
+pub mod trigger;
+pub mod tracer;
+pub mod storebuf;
+pub mod litmus;
+pub mod race;
+pub mod snapshot;
+pub mod checkpoint;
+pub mod coredump;
+pub mod layout;
 pub mod insn;
+#[cfg(feature = "jit")]
+pub mod jit;
 pub mod syscall;
 pub mod elf;
 pub mod emulator;
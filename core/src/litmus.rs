@@ -0,0 +1,360 @@
+//! Litmus test runner for the weak memory mode, in the spirit of herd/litmus.
+//! A test is an emulator built with `EmulatorBuilder::weak_memory`, whose harts run
+//! the threads of the test and end with the Linux `exit` syscall. The runner explores
+//! interleavings of the harts and of store buffer flushes, and collects every distinct
+//! final outcome of the observed registers and memory locations.
+//!
+//! Harts are only switched around memory accesses and fences, instructions that do not
+//! touch memory cannot be observed by other harts and always run back to back.
+//! Loads are performed in program order, so behaviours that need load-load or load-store
+//! reordering are not produced.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use crate::emulator::Emulator;
+use crate::error::*;
+use crate::*;
+
+/// Default number of instructions a single run may retire.
+pub const LITMUS_MAX_STEPS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Observe {
+    /// (hart, register)
+    Reg(usize, usize),
+    /// (address, size in bytes)
+    Mem(u64, usize),
+}
+
+impl fmt::Display for Observe {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Observe::Reg(hart, reg) => write!(f, "{}:x{}", hart, reg),
+            Observe::Mem(addr, _) => write!(f, "[{:#x}]", addr),
+        }
+    }
+}
+
+/// A step the scheduler can take next.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Choice {
+    /// Runs the hart up to and including its next memory access or fence.
+    Step(usize),
+    /// (hart, index) writes a buffered store to memory.
+    Flush(usize, usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct Outcomes {
+    observe: Vec<Observe>,
+    /// Observed values -> number of runs that ended with them.
+    states: BTreeMap<Vec<u64>, usize>,
+    /// Completed runs.
+    pub runs: usize,
+    /// Runs abandoned after the step limit, they are not part of 'states'.
+    pub truncated: usize,
+    /// Set if every interleaving was explored.
+    pub exhaustive: bool,
+}
+
+impl Outcomes {
+    fn new(observe: Vec<Observe>) -> Self {
+        Self {
+            observe,
+            states: BTreeMap::new(),
+            runs: 0,
+            truncated: 0,
+            exhaustive: false,
+        }
+    }
+
+    fn record(&mut self, outcome: Option<Vec<u64>>) {
+        match outcome {
+            Some(values) => {
+                self.runs += 1;
+                *self.states.entry(values).or_default() += 1;
+            },
+            None => self.truncated += 1,
+        }
+    }
+
+    /// Number of distinct outcomes.
+    pub fn len(&self) -> usize {
+        self.states.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.states.is_empty()
+    }
+
+    /// Checks whether some run ended with 'values', given in the order of the observations.
+    pub fn contains(&self, values: &[u64]) -> bool {
+        self.states.contains_key(values)
+    }
+
+    /// Distinct outcomes with the number of runs that produced each.
+    pub fn states(&self) -> impl Iterator<Item = (&[u64], usize)> {
+        self.states.iter().map(|(values, &count)| (values.as_slice(), count))
+    }
+}
+
+impl fmt::Display for Outcomes {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "States {}", self.states.len())?;
+        for (values, count) in self.states.iter() {
+            for (observe, value) in self.observe.iter().zip(values) {
+                write!(f, "{}={}; ", observe, value)?;
+            }
+            writeln!(f, "({})", count)?;
+        }
+        write!(f, "Runs {}", self.runs)?;
+        if self.truncated > 0 {
+            write!(f, ", {} truncated", self.truncated)?;
+        }
+        write!(f, ", {}", if self.exhaustive { "exhaustive" } else { "partial" })
+    }
+}
+
+pub struct Litmus<F: FnMut() -> Result<Emulator>> {
+    /// Builds a fresh emulator with the test loaded, called once per run.
+    build: F,
+    observe: Vec<Observe>,
+    max_steps: usize,
+    max_runs: usize,
+}
+
+impl<F: FnMut() -> Result<Emulator>> Litmus<F> {
+    pub fn new(build: F) -> Self {
+        Self {
+            build,
+            observe: vec![],
+            max_steps: LITMUS_MAX_STEPS,
+            max_runs: usize::MAX,
+        }
+    }
+
+    pub fn observe(mut self, observe: Observe) -> Self {
+        self.observe.push(observe);
+        self
+    }
+
+    /// Instructions a run may retire before it is abandoned, bounds spin loops.
+    pub fn max_steps(mut self, steps: usize) -> Self {
+        self.max_steps = steps;
+        self
+    }
+
+    /// Stops exhaustive exploration after this many runs.
+    pub fn max_runs(mut self, runs: usize) -> Self {
+        self.max_runs = runs;
+        self
+    }
+
+    /// Explores every interleaving, depth first.
+    pub fn exhaustive(&mut self) -> Result<Outcomes> {
+        let mut outcomes = Outcomes::new(self.observe.clone());
+        let mut prefix: Vec<usize> = vec![];
+        loop {
+            // (choice taken, number of choices) at every scheduling point
+            let mut trace: Vec<(usize, usize)> = vec![];
+            let outcome = self.run_once(&mut |num| {
+                let choice = prefix.get(trace.len()).copied().unwrap_or(0);
+                trace.push((choice, num));
+                choice
+            })?;
+            outcomes.record(outcome);
+
+            while let Some((choice, num)) = trace.pop() {
+                if choice + 1 < num {
+                    trace.push((choice + 1, num));
+                    break;
+                }
+            }
+            if trace.is_empty() {
+                outcomes.exhaustive = true;
+                return Ok(outcomes);
+            }
+            if outcomes.runs + outcomes.truncated >= self.max_runs {
+                return Ok(outcomes);
+            }
+            prefix = trace.into_iter().map(|(choice, _)| choice).collect();
+        }
+    }
+
+    /// Runs 'runs' random interleavings, reproducible through 'seed'.
+    pub fn random(&mut self, seed: u64, runs: usize) -> Result<Outcomes> {
+        let mut outcomes = Outcomes::new(self.observe.clone());
+        // xorshift64, the state must not be zero
+        let mut rng = seed | 1;
+        for _ in 0..runs {
+            let outcome = self.run_once(&mut |num| {
+                rng ^= rng << 13;
+                rng ^= rng >> 7;
+                rng ^= rng << 17;
+                (rng % num as u64) as usize
+            })?;
+            outcomes.record(outcome);
+        }
+        Ok(outcomes)
+    }
+
+    /// Runs the test once, 'choose' picks one of the given number of choices.
+    /// Returns None if the run hit the step limit.
+    fn run_once(&mut self, choose: &mut dyn FnMut(usize) -> usize) -> Result<Option<Vec<u64>>> {
+        let mut emulator = (self.build)()?;
+        if emulator.guest.store_buffers().is_none() {
+            return Err(Error::Other("Litmus tests need an emulator in weak memory mode".to_string()));
+        }
+        let mut budget = self.max_steps;
+        loop {
+            let choices = emulator.choices();
+            if choices.is_empty() {
+                return Err(Error::Deadlock);
+            }
+            let choice = match choices.len() {
+                1 => choices[0],
+                num => choices[choose(num)],
+            };
+            match emulator.take(choice, &mut budget) {
+                Ok(true) => {},
+                Ok(false) => return Ok(None),
+                Err(Error::Exited(_)) => break,
+                Err(e) => return Err(e),
+            }
+        }
+        emulator.guest.drain_store_buffers()?;
+
+        let mut values = Vec::with_capacity(self.observe.len());
+        for observe in self.observe.iter() {
+            let value = match *observe {
+                Observe::Reg(hart, reg) => emulator.harts[hart].state.x[reg],
+                Observe::Mem(addr, 1) => emulator.guest.read_u8(addr)? as u64,
+                Observe::Mem(addr, 2) => emulator.guest.read_u16(addr)? as u64,
+                Observe::Mem(addr, 4) => emulator.guest.read_u32(addr)? as u64,
+                Observe::Mem(addr, _) => emulator.guest.read_u64(addr)?,
+            };
+            values.push(value);
+        }
+        Ok(Some(values))
+    }
+}
+
+impl Emulator {
+    /// Steps the scheduler may take next: running a runnable hart or flushing a buffered store.
+    pub fn choices(&self) -> Vec<Choice> {
+        let mut choices: Vec<Choice> = self.harts.iter()
+            .filter(|hart| hart.is_runnable())
+            .map(|hart| Choice::Step(hart.id))
+            .collect();
+        if let Some(buffers) = self.guest.store_buffers() {
+            for hart in 0..self.harts.len() {
+                choices.extend(buffers.flushable(hart).into_iter().map(|idx| Choice::Flush(hart, idx)));
+            }
+        }
+        choices
+    }
+
+    /// Takes 'choice', each retired instruction is charged to 'budget'.
+    /// Returns false once the budget is used up.
+    pub fn take(&mut self, choice: Choice, budget: &mut usize) -> Result<bool> {
+        match choice {
+            Choice::Step(id) => {
+                self.cur_hart = id;
+                let events = self.guest.store_buffers().map_or(0, |buffers| buffers.events());
+                loop {
+                    if *budget == 0 {
+                        return Ok(false);
+                    }
+                    *budget -= 1;
+                    self.step_hart()?;
+                    let now = self.guest.store_buffers().map_or(0, |buffers| buffers.events());
+                    if now != events || !self.harts[id].is_runnable() {
+                        return Ok(true);
+                    }
+                }
+            },
+            Choice::Flush(hart, idx) => {
+                self.guest.flush_store(hart, idx)?;
+                Ok(true)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::*;
+    use crate::guest::MemFlags;
+    use crate::syscall::linux::LinuxSyscallHandler;
+
+    /// SB: every hart stores 1 to its own variable, then loads the other hart's one into a1.
+    fn store_buffering(fence: bool) -> Vec<u32> {
+        vec![
+            0xf1402573, // 0: csrr a0, mhartid
+            0x000022b7, // 4: lui t0, 2
+            0x00351313, // 8: slli t1, a0, 3
+            0x006283b3, // c: add t2, t0, t1
+            0x00154e13, // 10: xori t3, a0, 1
+            0x003e1e13, // 14: slli t3, t3, 3
+            0x01c28e33, // 18: add t3, t0, t3
+            0x00100e93, // 1c: li t4, 1
+            0x01d3b023, // 20: sd t4, 0(t2)
+            if fence { 0x0330000f } else { 0x00000013 }, // 24: fence rw, rw / nop
+            0x000e3583, // 28: ld a1, 0(t3)
+            0x00000513, // 2c: li a0, 0
+            0x05d00893, // 30: li a7, 93
+            0x00000073, // 34: ecall
+        ]
+    }
+
+    fn litmus(code: Vec<u32>) -> Litmus<impl FnMut() -> Result<Emulator>> {
+        Litmus::new(move || {
+            let mut emulator = Emulator::new()
+                .syscall(Box::new(LinuxSyscallHandler::new()))
+                .decoder(InsnSet::I)
+                .decoder(InsnSet::Ziscr)
+                .harts(2)
+                .weak_memory()
+                .build()?;
+            emulator.load_code(0x1000, &code);
+            emulator.guest.add_segment(0x2000, 0x1000, 0x1000, MemFlags::READ | MemFlags::WRITE, None)?;
+            Ok(emulator)
+        })
+        .observe(Observe::Reg(0, 11))
+        .observe(Observe::Reg(1, 11))
+        .observe(Observe::Mem(0x2000, 8))
+    }
+
+    #[test]
+    fn test_store_buffering() {
+        log::log_init(log::Level::Off);
+
+        let outcomes = litmus(store_buffering(false)).exhaustive().unwrap();
+        assert!(outcomes.exhaustive);
+        assert_eq!(outcomes.truncated, 0);
+        assert_eq!(outcomes.len(), 4);
+        // both loads overtake the stores
+        assert!(outcomes.contains(&[0, 0, 1]));
+        assert!(outcomes.to_string().starts_with("States 4\n0:x11=0; 1:x11=0; [0x2000]=1; ("));
+
+        let outcomes = litmus(store_buffering(true)).exhaustive().unwrap();
+        assert_eq!(outcomes.len(), 3);
+        assert!(!outcomes.contains(&[0, 0, 1]));
+        assert!(outcomes.contains(&[1, 1, 1]));
+    }
+
+    #[test]
+    fn test_random_exploration() {
+        log::log_init(log::Level::Off);
+
+        let mut test = litmus(store_buffering(false));
+        let first = test.random(42, 50).unwrap();
+        let second = test.random(42, 50).unwrap();
+        assert!(!first.exhaustive);
+        assert_eq!(first.runs, 50);
+        assert_eq!(first.to_string(), second.to_string());
+        assert!(first.states().all(|(values, _)| values != [1, 1, 0]));
+    }
+}
@@ -94,4 +94,14 @@ gen_loggers!(
     debug, Debug
     warn, Warn
     error, Error
-);
\ No newline at end of file
+);
+
+/// `trace!` for the per-instruction hot path, compiled away unless the `hot-trace` feature
+/// is enabled. The arguments are not evaluated either.
+#[macro_export]
+macro_rules! hot_trace {
+    ($($args:tt)*) => {
+        #[cfg(feature = "hot-trace")]
+        trace!($($args)*);
+    };
+}
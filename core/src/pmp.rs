@@ -0,0 +1,301 @@
+//! Physical Memory Protection (PMP).
+//! Only enforced when the emulator runs in system mode, see `EmulatorBuilder::system_mode`.
+
+use crate::guest::MemAccess;
+
+/// Number of implemented PMP entries.
+pub const PMP_ENTRIES: usize = 16;
+
+pub const CSR_PMPCFG0: u32 = 0x3a0;
+pub const CSR_PMPCFG2: u32 = 0x3a2;
+pub const CSR_PMPADDR0: u32 = 0x3b0;
+pub const CSR_PMPADDR15: u32 = 0x3bf;
+
+pub const PMP_R: u8 = 1 << 0;
+pub const PMP_W: u8 = 1 << 1;
+pub const PMP_X: u8 = 1 << 2;
+pub const PMP_A_SHIFT: u8 = 3;
+pub const PMP_A_MASK: u8 = 0b11 << PMP_A_SHIFT;
+pub const PMP_L: u8 = 1 << 7;
+
+/// pmpaddr holds bits [55:2] of the physical address.
+const PMPADDR_MASK: u64 = (1 << 54) - 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub enum Privilege {
+    User = 0,
+    Supervisor = 1,
+    #[default]
+    Machine = 3,
+}
+
+impl Privilege {
+    /// The level encoded as in mstatus.MPP, None for the reserved encoding.
+    pub fn from_bits(bits: u64) -> Option<Self> {
+        match bits {
+            0 => Some(Privilege::User),
+            1 => Some(Privilege::Supervisor),
+            3 => Some(Privilege::Machine),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddrMatch {
+    Off,
+    /// Top of range, the previous entry holds the bottom.
+    Tor,
+    /// Naturally aligned four-byte region.
+    Na4,
+    /// Naturally aligned power-of-two region, >= 8 bytes.
+    Napot,
+}
+
+impl AddrMatch {
+    pub fn from_cfg(cfg: u8) -> Self {
+        match (cfg & PMP_A_MASK) >> PMP_A_SHIFT {
+            0 => AddrMatch::Off,
+            1 => AddrMatch::Tor,
+            2 => AddrMatch::Na4,
+            _ => AddrMatch::Napot,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pmp {
+    cfg: [u8; PMP_ENTRIES],
+    addr: [u64; PMP_ENTRIES],
+}
+
+impl Pmp {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn cfg(&self, idx: usize) -> u8 {
+        self.cfg[idx]
+    }
+
+    pub fn is_locked(&self, idx: usize) -> bool {
+        self.cfg[idx] & PMP_L != 0
+    }
+
+    /// Reads pmpcfg0 or pmpcfg2, each of which packs eight entries on RV64.
+    pub fn read_cfg(&self, csr: u32) -> Option<u64> {
+        let base = Self::cfg_base(csr)?;
+        let mut bytes = [0u8; 8];
+        bytes.copy_from_slice(&self.cfg[base..base + 8]);
+        Some(u64::from_le_bytes(bytes))
+    }
+
+    pub fn write_cfg(&mut self, csr: u32, value: u64) -> Option<()> {
+        let base = Self::cfg_base(csr)?;
+        for (i, &byte) in value.to_le_bytes().iter().enumerate() {
+            let idx = base + i;
+            if self.is_locked(idx) {
+                continue;
+            }
+            let mut byte = byte & (PMP_R | PMP_W | PMP_X | PMP_A_MASK | PMP_L);
+            // R=0, W=1 is reserved, W is dropped.
+            if byte & PMP_R == 0 {
+                byte &= !PMP_W;
+            }
+            self.cfg[idx] = byte;
+        }
+        Some(())
+    }
+
+    pub fn read_addr(&self, csr: u32) -> Option<u64> {
+        Self::addr_index(csr).map(|idx| self.addr[idx])
+    }
+
+    pub fn write_addr(&mut self, csr: u32, value: u64) -> Option<()> {
+        let idx = Self::addr_index(csr)?;
+        // A locked TOR entry also locks the address register below it.
+        let next_locks = idx + 1 < PMP_ENTRIES
+            && self.is_locked(idx + 1)
+            && AddrMatch::from_cfg(self.cfg[idx + 1]) == AddrMatch::Tor;
+        if !self.is_locked(idx) && !next_locks {
+            self.addr[idx] = value & PMPADDR_MASK;
+        }
+        Some(())
+    }
+
+    /// Byte range [start, end) covered by entry 'idx', None if it is off.
+    pub fn range(&self, idx: usize) -> Option<(u64, u64)> {
+        let addr = self.addr[idx];
+        match AddrMatch::from_cfg(self.cfg[idx]) {
+            AddrMatch::Off => None,
+            AddrMatch::Tor => {
+                let start = if idx == 0 { 0 } else { self.addr[idx - 1] << 2 };
+                let end = addr << 2;
+                if start >= end { None } else { Some((start, end)) }
+            },
+            AddrMatch::Na4 => Some((addr << 2, (addr << 2) + 4)),
+            AddrMatch::Napot => {
+                let ones = addr.trailing_ones() as u64;
+                let size = 1u64 << (ones + 3);
+                let start = (addr & !((1 << ones) - 1)) << 2;
+                Some((start, start.wrapping_add(size)))
+            }
+        }
+    }
+
+    /// Checks an access of 'len' bytes at 'gaddr' made at 'privilege'.
+    /// The lowest-numbered matching entry decides, an access that only partially
+    /// matches that entry fails regardless of its permissions. An empty access touches nothing.
+    pub fn check(&self, gaddr: u64, len: usize, access: MemAccess, privilege: Privilege) -> bool {
+        if len == 0 {
+            return true;
+        }
+        let last = gaddr.wrapping_add(len as u64 - 1);
+        for idx in 0..PMP_ENTRIES {
+            let Some((start, end)) = self.range(idx) else {
+                continue;
+            };
+            let first_in = gaddr >= start && gaddr < end;
+            let last_in = last >= start && last < end;
+            let covers = gaddr < start && last >= end;
+            if !(first_in || last_in || covers) {
+                continue;
+            }
+            if !(first_in && last_in) {
+                return false;
+            }
+            if privilege == Privilege::Machine && !self.is_locked(idx) {
+                return true;
+            }
+            let cfg = self.cfg[idx];
+            return match access {
+                MemAccess::Read => cfg & PMP_R != 0,
+                MemAccess::Write => cfg & PMP_W != 0,
+                MemAccess::Execute => cfg & PMP_X != 0,
+            };
+        }
+        // No match: M-mode succeeds, S/U-mode fails since entries are implemented.
+        privilege == Privilege::Machine
+    }
+
+    fn cfg_base(csr: u32) -> Option<usize> {
+        match csr {
+            CSR_PMPCFG0 => Some(0),
+            CSR_PMPCFG2 => Some(8),
+            _ => None,
+        }
+    }
+
+    fn addr_index(csr: u32) -> Option<usize> {
+        if (CSR_PMPADDR0..=CSR_PMPADDR15).contains(&csr) {
+            Some((csr - CSR_PMPADDR0) as usize)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOR: u8 = 1 << PMP_A_SHIFT;
+    const NA4: u8 = 2 << PMP_A_SHIFT;
+    const NAPOT: u8 = 3 << PMP_A_SHIFT;
+
+    fn napot(base: u64, size: u64) -> u64 {
+        (base >> 2) | ((size >> 3) - 1)
+    }
+
+    fn set_cfg(pmp: &mut Pmp, idx: usize, cfg: u8) {
+        let csr = if idx < 8 { CSR_PMPCFG0 } else { CSR_PMPCFG2 };
+        let shift = (idx % 8) * 8;
+        let old = pmp.read_cfg(csr).unwrap();
+        pmp.write_cfg(csr, (old & !(0xff << shift)) | ((cfg as u64) << shift)).unwrap();
+    }
+
+    #[test]
+    fn test_ranges() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(CSR_PMPADDR0, 0x1000 >> 2).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 1, 0x2000 >> 2).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 2, 0x3000 >> 2).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 3, napot(0x8000, 0x1000)).unwrap();
+        set_cfg(&mut pmp, 0, TOR | PMP_R);
+        set_cfg(&mut pmp, 1, TOR | PMP_R);
+        set_cfg(&mut pmp, 2, NA4 | PMP_R);
+        set_cfg(&mut pmp, 3, NAPOT | PMP_R);
+
+        assert_eq!(pmp.range(0), Some((0, 0x1000)));
+        assert_eq!(pmp.range(1), Some((0x1000, 0x2000)));
+        assert_eq!(pmp.range(2), Some((0x3000, 0x3004)));
+        assert_eq!(pmp.range(3), Some((0x8000, 0x9000)));
+        assert_eq!(pmp.range(4), None);
+    }
+
+    #[test]
+    fn test_region_priority() {
+        let mut pmp = Pmp::new();
+        // entry 0: 0x1000..0x1100 no access, entry 1: 0x0..0x4000 read/write
+        pmp.write_addr(CSR_PMPADDR0, napot(0x1000, 0x100)).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 1, napot(0x0, 0x4000)).unwrap();
+        set_cfg(&mut pmp, 0, NAPOT);
+        set_cfg(&mut pmp, 1, NAPOT | PMP_R | PMP_W);
+
+        assert!(pmp.check(0x0800, 8, MemAccess::Read, Privilege::User));
+        assert!(pmp.check(0x0800, 8, MemAccess::Write, Privilege::User));
+        assert!(!pmp.check(0x0800, 4, MemAccess::Execute, Privilege::User));
+        // the lower-numbered entry wins even though entry 1 also matches
+        assert!(!pmp.check(0x1000, 8, MemAccess::Read, Privilege::User));
+        assert!(!pmp.check(0x10f8, 8, MemAccess::Write, Privilege::User));
+        assert!(pmp.check(0x1100, 8, MemAccess::Read, Privilege::User));
+        // partial match of entry 0 fails
+        assert!(!pmp.check(0x0ffc, 8, MemAccess::Read, Privilege::User));
+        // no match at all fails for U-mode
+        assert!(!pmp.check(0x4000, 4, MemAccess::Read, Privilege::User));
+        assert!(pmp.check(0x1000, 0, MemAccess::Read, Privilege::User));
+    }
+
+    #[test]
+    fn test_machine_mode() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(CSR_PMPADDR0, napot(0x1000, 0x1000)).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 1, napot(0x2000, 0x1000)).unwrap();
+        set_cfg(&mut pmp, 0, NAPOT);
+        set_cfg(&mut pmp, 1, NAPOT | PMP_L | PMP_R);
+
+        // unlocked entries and unmatched addresses do not restrict M-mode
+        assert!(pmp.check(0x1000, 8, MemAccess::Write, Privilege::Machine));
+        assert!(pmp.check(0x8000, 8, MemAccess::Write, Privilege::Machine));
+        // locked entries do
+        assert!(pmp.check(0x2000, 8, MemAccess::Read, Privilege::Machine));
+        assert!(!pmp.check(0x2000, 8, MemAccess::Write, Privilege::Machine));
+    }
+
+    #[test]
+    fn test_lock() {
+        let mut pmp = Pmp::new();
+        pmp.write_addr(CSR_PMPADDR0, 0x1000 >> 2).unwrap();
+        pmp.write_addr(CSR_PMPADDR0 + 1, 0x2000 >> 2).unwrap();
+        set_cfg(&mut pmp, 1, TOR | PMP_L | PMP_R);
+
+        // locked config and address cannot change
+        set_cfg(&mut pmp, 1, NAPOT | PMP_R | PMP_W | PMP_X);
+        assert_eq!(pmp.cfg(1), TOR | PMP_L | PMP_R);
+        pmp.write_addr(CSR_PMPADDR0 + 1, 0x3000 >> 2).unwrap();
+        assert_eq!(pmp.read_addr(CSR_PMPADDR0 + 1), Some(0x2000 >> 2));
+        // the TOR bottom of a locked entry is locked as well
+        pmp.write_addr(CSR_PMPADDR0, 0x1800 >> 2).unwrap();
+        assert_eq!(pmp.read_addr(CSR_PMPADDR0), Some(0x1000 >> 2));
+        // other entries are unaffected
+        set_cfg(&mut pmp, 2, NA4 | PMP_R);
+        assert_eq!(pmp.cfg(2), NA4 | PMP_R);
+    }
+
+    #[test]
+    fn test_reserved_write_only() {
+        let mut pmp = Pmp::new();
+        set_cfg(&mut pmp, 0, NA4 | PMP_W);
+        assert_eq!(pmp.cfg(0), NA4);
+    }
+}
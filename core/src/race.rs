@@ -0,0 +1,505 @@
+//! Happens-before data race detector, in the spirit of ThreadSanitizer.
+//! Every hart carries a vector clock. Synchronisation joins clocks:
+//! - AMOs and LR/SC acquire and release the clock of the location they access,
+//! - fences are events in the hart's clock, following the C11 fence rules: an acquire fence
+//!   ('fence r,...') joins the clocks released to the locations the hart loaded from before it,
+//!   the next store after a release fence ('fence ...,w') releases the hart's clock,
+//! - a futex wake passes the waker's clock to the woken hart,
+//! - a spawned hart starts with its parent's clock.
+//!
+//! Other accesses are tracked per byte in shadow memory, two of them race if they come
+//! from different harts, at least one is a write and neither happens before the other.
+//! Release stores, acquire loads (loads right before an acquire fence, which is why loads are
+//! checked with the hart's next instruction) and loads reading from a release store are tracked
+//! too, they only race with plain accesses.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::elf::SymbolTable;
+use crate::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessKind {
+    Read,
+    Write,
+    /// Part of an AMO or LR/SC.
+    Atomic,
+    /// A FENCE, which accesses nothing, 'addr' and 'len' are zero. 'acquire' if it orders
+    /// earlier loads, 'release' if it orders later stores.
+    Fence { acquire: bool, release: bool },
+}
+
+/// A guest memory access, as recorded by `GuestMem` for the race detector.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Access {
+    pub addr: u64,
+    pub len: usize,
+    pub kind: AccessKind,
+}
+
+/// One side of a race.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RaceAccess {
+    pub hart: usize,
+    pub pc: u64,
+    pub write: bool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Race {
+    pub addr: u64,
+    /// The access that came first.
+    pub first: RaceAccess,
+    pub second: RaceAccess,
+}
+
+impl Race {
+    /// Describes the race, with code addresses symbolised if 'symbols' knows them.
+    pub fn describe(&self, symbols: Option<&SymbolTable>) -> String {
+        let side = |access: &RaceAccess| {
+            let pc = match symbols {
+                Some(symbols) => symbols.describe(access.pc),
+                None => format!("{:#x}", access.pc),
+            };
+            let kind = if access.write { "write" } else { "read" };
+            format!("{} by hart {} at pc {}", kind, access.hart, pc)
+        };
+        let addr = match symbols {
+            Some(symbols) => symbols.describe(self.addr),
+            None => format!("{:#x}", self.addr),
+        };
+        format!("data race on {}: {} conflicts with earlier {}", addr, side(&self.second), side(&self.first))
+    }
+}
+
+impl fmt::Display for Race {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.describe(None))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct VClock(Vec<u64>);
+
+impl VClock {
+    fn get(&self, hart: usize) -> u64 {
+        self.0.get(hart).copied().unwrap_or(0)
+    }
+
+    fn set(&mut self, hart: usize, value: u64) {
+        if self.0.len() <= hart {
+            self.0.resize(hart + 1, 0);
+        }
+        self.0[hart] = value;
+    }
+
+    fn tick(&mut self, hart: usize) {
+        self.set(hart, self.get(hart) + 1);
+    }
+
+    fn join(&mut self, other: &VClock) {
+        if self.0.len() < other.0.len() {
+            self.0.resize(other.0.len(), 0);
+        }
+        for (mine, &theirs) in self.0.iter_mut().zip(other.0.iter()) {
+            *mine = (*mine).max(theirs);
+        }
+    }
+}
+
+/// Access as remembered in shadow memory, 'clock' is the hart's own clock at the time.
+#[derive(Debug, Clone, Copy)]
+struct Epoch {
+    hart: usize,
+    clock: u64,
+    pc: u64,
+    /// A release store or an acquire load, or a load that read from a release store.
+    ordered: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shadow {
+    write: Option<Epoch>,
+    /// Last read of every hart since the last write.
+    reads: Vec<Epoch>,
+}
+
+#[derive(Debug, Default)]
+pub struct RaceDetector {
+    clocks: Vec<VClock>,
+    /// Clocks released to synchronisation locations.
+    sync: HashMap<u64, VClock>,
+    /// Per hart, the clocks released to the locations it loaded from since its last acquire fence.
+    loaded: HashMap<usize, VClock>,
+    /// Harts whose last event was a release fence.
+    releasing: HashSet<usize>,
+    /// Per hart, the pc and the loads of its last instruction, which are checked with the next one.
+    loads: HashMap<usize, (u64, Vec<Access>)>,
+    shadow: HashMap<u64, Shadow>,
+    races: Vec<Race>,
+    /// (first pc, second pc) of reported races, each pair is reported once.
+    reported: HashSet<(u64, u64)>,
+}
+
+impl RaceDetector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn races(&self) -> &[Race] {
+        &self.races
+    }
+
+    fn clock(&mut self, hart: usize) -> &mut VClock {
+        if self.clocks.len() <= hart {
+            self.clocks.resize_with(hart + 1, VClock::default);
+        }
+        let clock = &mut self.clocks[hart];
+        // a hart's own component starts at 1, so that its first accesses are not
+        // considered to happen before everything
+        if clock.get(hart) == 0 {
+            clock.set(hart, 1);
+        }
+        clock
+    }
+
+    /// 'child' was spawned by 'parent' and inherits everything that happened before.
+    pub fn spawn(&mut self, parent: usize, child: usize) {
+        let mut clock = self.clock(parent).clone();
+        self.clock(parent).tick(parent);
+        clock.set(child, 1);
+        *self.clock(child) = clock;
+    }
+
+    /// 'waker' woke up 'wakee' through a futex.
+    pub fn wake(&mut self, waker: usize, wakee: usize) {
+        let clock = self.clock(waker).clone();
+        self.clock(waker).tick(waker);
+        self.clock(wakee).join(&clock);
+    }
+
+    /// Processes the accesses and fences of one instruction of 'hart' at 'pc'. Instructions
+    /// without any count too, they decide how the loads of the instruction before are checked.
+    pub fn record(&mut self, hart: usize, pc: u64, accesses: &[Access]) {
+        let acquire = accesses.iter().any(|access| matches!(access.kind, AccessKind::Fence { acquire: true, .. }));
+        if acquire && let Some(loaded) = self.loaded.remove(&hart) {
+            self.clock(hart).join(&loaded);
+        }
+        // a load right before an acquire fence is an acquire load
+        if let Some((load_pc, loads)) = self.loads.remove(&hart) {
+            for load in &loads {
+                self.check_all(hart, load_pc, load, acquire);
+            }
+        }
+        for access in accesses {
+            if let AccessKind::Fence { release, .. } = access.kind {
+                if release {
+                    self.releasing.insert(hart);
+                }
+                continue;
+            }
+            let release = self.releasing.remove(&hart);
+            match access.kind {
+                AccessKind::Atomic => {
+                    self.acquire(hart, access.addr);
+                    self.release(hart, access.addr);
+                },
+                AccessKind::Read => {
+                    if let Some(released) = self.sync.get(&access.addr) {
+                        self.loaded.entry(hart).or_default().join(released);
+                    }
+                    self.loads.entry(hart).or_insert_with(|| (pc, vec![])).1.push(*access);
+                },
+                AccessKind::Write if release => {
+                    self.check_all(hart, pc, access, true);
+                    self.release(hart, access.addr);
+                },
+                _ => self.check_all(hart, pc, access, false),
+            }
+        }
+    }
+
+    fn acquire(&mut self, hart: usize, addr: u64) {
+        if let Some(released) = self.sync.get(&addr).cloned() {
+            self.clock(hart).join(&released);
+        }
+    }
+
+    fn release(&mut self, hart: usize, addr: u64) {
+        let clock = self.clock(hart).clone();
+        self.sync.entry(addr).or_default().join(&clock);
+        self.clock(hart).tick(hart);
+    }
+
+    fn check_all(&mut self, hart: usize, pc: u64, access: &Access, ordered: bool) {
+        for addr in access.addr..access.addr + access.len as u64 {
+            self.check(hart, pc, addr, access.kind == AccessKind::Write, ordered);
+        }
+    }
+
+    fn check(&mut self, hart: usize, pc: u64, addr: u64, write: bool, ordered: bool) {
+        let clock = self.clock(hart).clone();
+        let shadow = self.shadow.entry(addr).or_default();
+        // a load reading from a release store is an atomic load as far as the store is concerned
+        let ordered = ordered || !write && shadow.write.is_some_and(|prev| prev.ordered);
+        let happens_before = |epoch: &Epoch| {
+            epoch.hart == hart || epoch.clock <= clock.get(epoch.hart) || (ordered && epoch.ordered)
+        };
+
+        let mut conflicts = vec![];
+        if let Some(prev) = shadow.write.filter(|prev| !happens_before(prev)) {
+            conflicts.push(RaceAccess { hart: prev.hart, pc: prev.pc, write: true });
+        }
+        if write {
+            conflicts.extend(shadow.reads.iter()
+                .filter(|prev| !happens_before(prev))
+                .map(|prev| RaceAccess { hart: prev.hart, pc: prev.pc, write: false }));
+        }
+
+        let epoch = Epoch { hart, clock: clock.get(hart), pc, ordered };
+        if write {
+            shadow.write = Some(epoch);
+            shadow.reads.clear();
+        } else {
+            shadow.reads.retain(|prev| prev.hart != hart);
+            shadow.reads.push(epoch);
+        }
+
+        let second = RaceAccess { hart, pc, write };
+        for first in conflicts {
+            if self.reported.insert((first.pc, second.pc)) {
+                let race = Race { addr, first, second };
+                warn!("{}", race);
+                self.races.push(race);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::*;
+    use crate::guest::MemFlags;
+    use crate::syscall::linux::LinuxSyscallHandler;
+
+    fn access(addr: u64, kind: AccessKind) -> [Access; 1] {
+        [Access { addr, len: 4, kind }]
+    }
+
+    fn fence(acquire: bool, release: bool) -> [Access; 1] {
+        [Access { addr: 0, len: 0, kind: AccessKind::Fence { acquire, release } }]
+    }
+
+    #[test]
+    fn test_race_and_sync() {
+        log::log_init(log::Level::Off);
+
+        let mut detector = RaceDetector::new();
+        // unsynchronised write/write
+        detector.record(0, 0x100, &access(0x2000, AccessKind::Write));
+        detector.record(1, 0x200, &access(0x2000, AccessKind::Write));
+        assert_eq!(detector.races().len(), 1);
+        let race = &detector.races()[0];
+        assert_eq!((race.addr, race.first.pc, race.second.pc), (0x2000, 0x100, 0x200));
+        // reported once per pair of pcs
+        detector.record(1, 0x200, &access(0x2000, AccessKind::Write));
+        assert_eq!(detector.races().len(), 1);
+
+        // message passing through a release fence and an acquire load
+        detector.record(0, 0x110, &access(0x3000, AccessKind::Write));
+        detector.record(0, 0x114, &fence(false, true));
+        detector.record(0, 0x118, &access(0x3008, AccessKind::Write));
+        detector.record(1, 0x210, &access(0x3008, AccessKind::Read));
+        detector.record(1, 0x214, &fence(true, false));
+        detector.record(1, 0x218, &access(0x3000, AccessKind::Read));
+        detector.record(1, 0x21c, &[]);
+        assert_eq!(detector.races().len(), 1);
+        // hart 2 did not synchronise
+        detector.record(2, 0x300, &access(0x3000, AccessKind::Write));
+        assert_eq!(detector.races().len(), 3);
+
+        // loads are checked with the next instruction of the hart, without an acquire fence
+        // the flag is still read without a race, the data is not
+        detector.record(0, 0x130, &access(0x5000, AccessKind::Write));
+        detector.record(0, 0x134, &fence(false, true));
+        detector.record(0, 0x138, &access(0x5008, AccessKind::Write));
+        detector.record(1, 0x230, &access(0x5008, AccessKind::Read));
+        detector.record(1, 0x234, &access(0x5000, AccessKind::Read));
+        assert_eq!(detector.races().len(), 3);
+        detector.record(1, 0x238, &[]);
+        assert_eq!(detector.races().len(), 4);
+        assert_eq!((detector.races()[3].first.pc, detector.races()[3].second.pc), (0x130, 0x234));
+
+        // a release store or an acquire load races with plain accesses it is not ordered with,
+        // though not with each other
+        detector.record(0, 0x120, &access(0x4000, AccessKind::Write));
+        detector.record(1, 0x240, &fence(false, true));
+        detector.record(1, 0x244, &access(0x4000, AccessKind::Write));
+        assert_eq!(detector.races().len(), 5);
+        assert_eq!((detector.races()[4].first.pc, detector.races()[4].second.pc), (0x120, 0x244));
+        detector.record(2, 0x320, &access(0x4000, AccessKind::Read));
+        detector.record(2, 0x324, &fence(true, false));
+        detector.record(0, 0x124, &access(0x4000, AccessKind::Read));
+        detector.record(0, 0x128, &[]);
+        detector.record(2, 0x328, &fence(false, true));
+        detector.record(2, 0x32c, &access(0x4000, AccessKind::Write));
+        assert_eq!(detector.races().len(), 5);
+        detector.record(0, 0x12c, &access(0x4000, AccessKind::Write));
+        detector.record(1, 0x248, &access(0x4000, AccessKind::Read));
+        detector.record(1, 0x24c, &fence(true, false));
+        let pcs: Vec<_> = detector.races()[5..].iter().map(|race| (race.first.pc, race.second.pc)).collect();
+        assert_eq!(pcs, [(0x32c, 0x12c), (0x12c, 0x248)]);
+    }
+
+    #[test]
+    fn test_amo_and_wake() {
+        log::log_init(log::Level::Off);
+
+        let mut detector = RaceDetector::new();
+        detector.record(0, 0x100, &access(0x2000, AccessKind::Write));
+        detector.record(0, 0x104, &access(0x2008, AccessKind::Atomic));
+        detector.record(1, 0x200, &access(0x2008, AccessKind::Atomic));
+        detector.record(1, 0x204, &access(0x2000, AccessKind::Write));
+        assert!(detector.races().is_empty());
+
+        detector.spawn(1, 2);
+        detector.record(2, 0x300, &access(0x2000, AccessKind::Read));
+        detector.record(2, 0x304, &access(0x2010, AccessKind::Write));
+        detector.wake(2, 0);
+        detector.record(0, 0x108, &access(0x2010, AccessKind::Read));
+        detector.record(0, 0x10c, &access(0x2000, AccessKind::Write));
+        assert!(detector.races().is_empty());
+    }
+
+    fn build(harts: usize, quantum: usize, code: &[u32]) -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(LinuxSyscallHandler::new()))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::A)
+            .decoder(InsnSet::Ziscr)
+            .harts(harts)
+            .quantum(quantum)
+            .race_detector()
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, code);
+        emulator.guest.add_segment(0x2000, 0x2000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_racy_counter() {
+        log::log_init(log::Level::Off);
+
+        // Both harts increment a plain counter and an atomic one.
+        let mut emulator = build(2, 64, &[
+            0x000022b7, // 0: lui t0, 2
+            0x0002b303, // 4: ld t1, 0(t0)
+            0x00130313, // 8: addi t1, t1, 1
+            0x0062b023, // c: sd t1, 0(t0)
+            0x00828e13, // 10: addi t3, t0, 8
+            0x00100393, // 14: li t2, 1
+            0x007e302f, // 18: amoadd.d zero, t2, (t3)
+            0x00000513, // 1c: li a0, 0
+            0x05d00893, // 20: li a7, 93
+            0x00000073, // 24: ecall
+        ]);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+
+        let races = emulator.races();
+        assert_eq!(races.len(), 2);
+        assert!(races.iter().all(|race| race.addr == 0x2000));
+        assert!(races.iter().all(|race| (race.first.hart, race.second.hart) == (0, 1)));
+        assert_eq!((races[0].first.pc, races[0].second.pc), (0x100c, 0x1004));
+        assert_eq!((races[1].first.pc, races[1].second.pc), (0x100c, 0x100c));
+        assert_eq!(
+            emulator.race_report()[0],
+            "data race on 0x2000: read by hart 1 at pc 0x1004 conflicts with earlier write by hart 0 at pc 0x100c"
+        );
+    }
+
+    #[test]
+    fn test_fences() {
+        log::log_init(log::Level::Off);
+
+        // Hart 0 publishes 42 behind a flag, hart 1 waits for the flag with acquire loads and
+        // exits with the value. The release fence is not next to the store it orders.
+        let mut code = [
+            0xf1402573, // 0: csrr a0, mhartid
+            0x000022b7, // 4: lui t0, 2
+            0x02051263, // 8: bnez a0, 0x2c <reader>
+            0x02a00313, // c: li t1, 42
+            0x0062b423, // 10: sd t1, 8(t0)
+            0x0310000f, // 14: fence rw, w
+            0x00100393, // 18: li t2, 1
+            0x0072b023, // 1c: sd t2, 0(t0)
+            0x00000513, // 20: li a0, 0
+            0x05d00893, // 24: li a7, 93
+            0x00000073, // 28: ecall
+            0x0002be03, // 2c: ld t3, 0(t0)
+            0x0230000f, // 30: fence r, rw
+            0xfe0e0ce3, // 34: beqz t3, 0x2c
+            0x0082b503, // 38: ld a0, 8(t0)
+            0x05e00893, // 3c: li a7, 94
+            0x00000073, // 40: ecall
+        ];
+        for quantum in [1, 5] {
+            let mut emulator = build(2, quantum, &code);
+            assert_eq!(emulator.run().unwrap(), ExitReason::Exited(42));
+            assert!(emulator.races().is_empty(), "{:?}", emulator.race_report());
+        }
+
+        // without the release fence both the flag and the value race
+        code[5] = 0x00000013; // 14: nop
+        let mut emulator = build(2, 5, &code);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(42));
+        let races: Vec<_> = emulator.races().iter().map(|race| (race.addr, race.first.pc, race.second.pc)).collect();
+        assert_eq!(races, [(0x2000, 0x102c, 0x101c), (0x2000, 0x101c, 0x102c), (0x2008, 0x1010, 0x1038)]);
+    }
+
+    #[test]
+    fn test_join_synchronises() {
+        log::log_init(log::Level::Off);
+
+        // The child stores a result that the main thread reads after joining it, either
+        // after a futex wake or after seeing the cleared tid through an acquire load.
+        let code = [
+            0x00002437, // 0: lui s0, 2
+            0x00100293, // 4: li t0, 1
+            0x00542023, // 8: sw t0, 0(s0)
+            0x00290537, // c: lui a0, 656
+            0x1005051b, // 10: addiw a0, a0, 256 (CLONE_VM | CLONE_THREAD | CLONE_SETTLS | CLONE_CHILD_CLEARTID)
+            0x000045b7, // 14: lui a1, 4
+            0x00000613, // 18: li a2, 0
+            0x07700693, // 1c: li a3, 119
+            0x00040713, // 20: mv a4, s0
+            0x0dc00893, // 24: li a7, 220
+            0x00000073, // 28: ecall
+            0x02050a63, // 2c: beqz a0, 0x60 <child>
+            0x00042603, // 30: lw a2, 0(s0)
+            0x0230000f, // 34: fence r, rw
+            0x00060e63, // 38: beqz a2, 0x54 <done>
+            0x00040513, // 3c: mv a0, s0
+            0x00000593, // 40: li a1, 0
+            0x00000693, // 44: li a3, 0
+            0x06200893, // 48: li a7, 98
+            0x00000073, // 4c: ecall
+            0xfe1ff06f, // 50: j 0x30 <wait>
+            0x00843503, // 54: ld a0, 8(s0)
+            0x05e00893, // 58: li a7, 94
+            0x00000073, // 5c: ecall
+            0x02a00313, // 60: li t1, 42
+            0x00643423, // 64: sd t1, 8(s0)
+            0x00000513, // 68: li a0, 0
+            0x05d00893, // 6c: li a7, 93
+            0x00000073, // 70: ecall
+        ];
+        for quantum in [1, 64] {
+            let mut emulator = build(1, quantum, &code);
+            assert_eq!(emulator.run().unwrap(), ExitReason::Exited(42));
+            assert!(emulator.races().is_empty(), "{:?}", emulator.race_report());
+        }
+    }
+}
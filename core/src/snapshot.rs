@@ -0,0 +1,121 @@
+//! Snapshots of a whole emulator, taken with `Emulator::snapshot` and put back with
+//! `Emulator::restore`, e.g. to run a fuzzing input or a test many times from the same state.
+//! Guest memory tracks the pages stored to since the last snapshot or restore, so restoring
+//! the same snapshot again only copies those back, unless mappings changed in between.
+//! Breakpoints, the profile, the tracer and the race detector are not part of a snapshot.
+
+use std::any::Any;
+
+use crate::emulator::Emulator;
+use crate::error::*;
+use crate::guest::MemSnapshot;
+use crate::hart::HartStatus;
+use crate::state::State;
+
+#[derive(Debug)]
+pub struct Snapshot {
+    harts: Vec<(State, HartStatus)>,
+    cur_hart: usize,
+    slice_left: usize,
+    clock: u64,
+    retired: u64,
+    mem: MemSnapshot,
+    /// Whatever the syscall handler saved.
+    syscall: Box<dyn Any>,
+}
+
+impl Emulator {
+    /// Saves the harts, the guest memory, the scheduler and the syscall handler's state.
+    pub fn snapshot(&mut self) -> Result<Snapshot> {
+        Ok(Snapshot {
+            harts: self.harts.iter().map(|hart| (hart.state.clone(), hart.status)).collect(),
+            cur_hart: self.cur_hart,
+            slice_left: self.slice_left,
+            clock: self.clock,
+            retired: self.retired,
+            mem: self.guest.snapshot(),
+            syscall: self.syscall.snapshot()?,
+        })
+    }
+
+    /// Puts the emulator back the way it was when 'snapshot' was taken, harts spawned since are dropped.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.syscall.restore(snapshot.syscall.as_ref())?;
+        self.guest.restore(&snapshot.mem);
+        self.harts.truncate(snapshot.harts.len());
+        for id in self.harts.len()..snapshot.harts.len() {
+            let hart = self.new_hart(id);
+            self.harts.push(hart);
+        }
+        for (hart, (state, status)) in self.harts.iter_mut().zip(&snapshot.harts) {
+            hart.state = state.clone();
+            hart.status = *status;
+        }
+        self.cur_hart = snapshot.cur_hart;
+        self.slice_left = snapshot.slice_left;
+        self.clock = snapshot.clock;
+        self.retired = snapshot.retired;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::ExitReason;
+    use crate::guest::{MapAt, MemFlags};
+    use crate::insn::InsnSet;
+    use crate::log;
+
+    #[test]
+    fn test_snapshot() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(crate::Linux::new()))
+            .decoder(InsnSet::I)
+            .build()
+            .unwrap();
+        emulator.load_code(0x1000, &[
+            0x00002437, // 0: lui s0, 2
+            0x00043283, // 4: ld t0, 0(s0)
+            0x00128293, // 8: addi t0, t0, 1
+            0x00543023, // c: sd t0, 0(s0)
+            0x00028513, // 10: mv a0, t0
+            0x05d00893, // 14: li a7, 93
+            0x00000073, // 18: ecall
+        ]);
+        let rw = MemFlags::READ | MemFlags::WRITE;
+        emulator.guest.add_segment(0x2000, 0x1000, 0x1000, rw, Some(&5u64.to_le_bytes())).unwrap();
+        emulator.guest.reset_brk();
+        let brk = emulator.guest.cur_brk();
+
+        // the counter goes back to 5 every time
+        let snapshot = emulator.snapshot().unwrap();
+        for _ in 0..3 {
+            assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+            assert_eq!(emulator.retired(), 7);
+            emulator.restore(&snapshot).unwrap();
+            assert_eq!((emulator.retired(), emulator.clock(), emulator.hart().state.pc), (0, 0, 0x1000));
+        }
+
+        // mappings made since are dropped
+        assert_eq!(emulator.guest.brk(brk + 0x2000), brk + 0x2000);
+        let mapped = emulator.guest.mmap(0, 0x1000, rw, MapAt::Hint, None).unwrap();
+        emulator.guest.write_u64(0x2000, 41).unwrap();
+        emulator.restore(&snapshot).unwrap();
+        assert_eq!(emulator.guest.cur_brk(), brk);
+        assert!(emulator.guest.read_u8(brk).is_err() && emulator.guest.read_u8(mapped).is_err());
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+
+        // in the middle of the program
+        emulator.restore(&snapshot).unwrap();
+        assert_eq!(emulator.run_for(4).unwrap(), ExitReason::BudgetExhausted);
+        let middle = emulator.snapshot().unwrap();
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+        emulator.restore(&middle).unwrap();
+        assert_eq!(emulator.hart().state.x[5], 6);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+        assert_eq!(emulator.retired(), 7);
+    }
+}
@@ -1,5 +1,19 @@
 //! Current state of the CPU, including registers and flags.
 
+use crate::pmp::Privilege;
+use crate::trigger::Triggers;
+
+// Exception codes, as reported in mcause.
+pub const CAUSE_FETCH_ACCESS: u64 = 1;
+pub const CAUSE_BREAKPOINT: u64 = 3;
+pub const CAUSE_LOAD_ACCESS: u64 = 5;
+pub const CAUSE_STORE_ACCESS: u64 = 7;
+
+pub const MSTATUS_MPP_SHIFT: u64 = 11;
+/// Privilege level the last trap was taken from, which mret returns to.
+pub const MSTATUS_MPP: u64 = 0b11 << MSTATUS_MPP_SHIFT;
+/// Loads and stores are checked at the MPP privilege level while set.
+pub const MSTATUS_MPRV: u64 = 1 << 17;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub enum BreakCause {
@@ -7,11 +21,29 @@ pub enum BreakCause {
     Ebreak
 }
 
-#[derive(Debug, Default)]
+/// Machine-mode trap CSRs.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct TrapCsrs {
+    /// Only MPP and MPRV are implemented.
+    pub mstatus: u64,
+    pub mtvec: u64,
+    pub mepc: u64,
+    pub mcause: u64,
+    pub mtval: u64,
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 pub struct State {
     pub pc: u64,
     pub x: [u64; 32],
     pub break_on: Option<BreakCause>,
+    pub trap: TrapCsrs,
+    pub mhartid: u64,
+    /// Privilege level the hart runs at.
+    pub privilege: Privilege,
+    /// Sdtrig triggers programmed by the hart. While it runs they are swapped into `GuestMem`,
+    /// which matches accesses against them, see `Emulator::run_hart`.
+    pub triggers: Triggers,
 }
 
 impl State {
@@ -19,5 +51,25 @@ impl State {
         pc: 0,
         x: [0; 32],
         break_on: None,
+        trap: TrapCsrs {
+            mstatus: 0,
+            mtvec: 0,
+            mepc: 0,
+            mcause: 0,
+            mtval: 0,
+        },
+        mhartid: 0,
+        privilege: Privilege::Machine,
+        triggers: Triggers::new(),
     };
-}
\ No newline at end of file
+
+    /// Takes a synchronous exception from 'privilege' into the guest's trap handler, which runs
+    /// in machine mode. Only direct mode is supported, vectored mtvec is treated as direct.
+    pub fn take_trap(&mut self, cause: u64, tval: u64, privilege: Privilege) {
+        self.trap.mstatus = (self.trap.mstatus & !MSTATUS_MPP) | (privilege as u64) << MSTATUS_MPP_SHIFT;
+        self.trap.mepc = self.pc;
+        self.trap.mcause = cause;
+        self.trap.mtval = tval;
+        self.pc = self.trap.mtvec & !0b11;
+    }
+}
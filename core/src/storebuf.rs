@@ -0,0 +1,151 @@
+//! Per-hart store buffers used to expose weak (RVWMO) behaviours.
+//! Stores are buffered until they are flushed to memory, either by the scheduler
+//! or by a fence. A hart always sees its own buffered stores.
+//! Entries to different addresses may be flushed in any order, entries that overlap
+//! an older entry of the same hart wait for it, so coherence is preserved.
+
+use std::cell::Cell;
+use std::collections::VecDeque;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferedStore {
+    pub addr: u64,
+    /// Little-endian data, only the first 'len' bytes are valid.
+    pub data: [u8; 8],
+    pub len: usize,
+}
+
+impl BufferedStore {
+    pub fn overlaps(&self, addr: u64, len: usize) -> bool {
+        self.addr < addr + len as u64 && addr < self.addr + self.len as u64
+    }
+
+    fn byte(&self, addr: u64) -> Option<u8> {
+        if addr >= self.addr && addr < self.addr + self.len as u64 {
+            Some(self.data[(addr - self.addr) as usize])
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct StoreBuffers {
+    /// Hart whose accesses go through the buffers.
+    hart: usize,
+    buffers: Vec<VecDeque<BufferedStore>>,
+    /// Set while an atomic memory operation runs, its store goes to memory directly.
+    bypass: bool,
+    /// Number of memory accesses and fences so far, lets the scheduler tell
+    /// purely local instructions apart from ones other harts could observe.
+    events: Cell<u64>,
+}
+
+impl StoreBuffers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    pub fn set_hart(&mut self, hart: usize) {
+        if self.buffers.len() <= hart {
+            self.buffers.resize_with(hart + 1, VecDeque::new);
+        }
+        self.hart = hart;
+    }
+
+    pub fn bypass(&self) -> bool {
+        self.bypass
+    }
+
+    pub fn set_bypass(&mut self, bypass: bool) {
+        self.bypass = bypass;
+    }
+
+    pub fn events(&self) -> u64 {
+        self.events.get()
+    }
+
+    pub fn record_event(&self) {
+        self.events.set(self.events.get() + 1);
+    }
+
+    pub fn push(&mut self, addr: u64, bytes: &[u8]) {
+        let mut data = [0; 8];
+        data[..bytes.len()].copy_from_slice(bytes);
+        let hart = self.hart;
+        self.buffers[hart].push_back(BufferedStore { addr, data, len: bytes.len() });
+    }
+
+    /// Value of the byte at 'addr' as seen by the current hart, if it has a buffered store to it.
+    pub fn forward(&self, addr: u64) -> Option<u8> {
+        self.buffers.get(self.hart)?.iter().rev().find_map(|store| store.byte(addr))
+    }
+
+    pub fn len(&self, hart: usize) -> usize {
+        self.buffers.get(hart).map_or(0, VecDeque::len)
+    }
+
+    /// Number of harts that have a buffer.
+    pub fn harts(&self) -> usize {
+        self.buffers.len()
+    }
+
+    /// Index of the oldest entry of 'hart' overlapping [addr, addr + len).
+    pub fn position(&self, hart: usize, addr: u64, len: usize) -> Option<usize> {
+        self.buffers.get(hart)?.iter().position(|store| store.overlaps(addr, len))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffers.iter().all(VecDeque::is_empty)
+    }
+
+    /// Indices of the entries of 'hart' that may reach memory next.
+    pub fn flushable(&self, hart: usize) -> Vec<usize> {
+        let Some(buffer) = self.buffers.get(hart) else {
+            return vec![];
+        };
+        (0..buffer.len())
+            .filter(|&i| {
+                let store = &buffer[i];
+                !buffer.range(..i).any(|older| older.overlaps(store.addr, store.len))
+            })
+            .collect()
+    }
+
+    /// Removes entry 'idx' of 'hart', the caller writes it to memory.
+    pub fn take(&mut self, hart: usize, idx: usize) -> Option<BufferedStore> {
+        self.buffers.get_mut(hart)?.remove(idx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_and_flushable() {
+        let mut buffers = StoreBuffers::new();
+        buffers.set_hart(1);
+        buffers.push(0x1000, &1u32.to_le_bytes());
+        buffers.push(0x2000, &2u32.to_le_bytes());
+        buffers.push(0x1002, &0xffu8.to_le_bytes());
+
+        assert_eq!(buffers.forward(0x1000), Some(1));
+        assert_eq!(buffers.forward(0x1002), Some(0xff));
+        assert_eq!(buffers.forward(0x1004), None);
+        // the byte store to 0x1002 has to wait for the word store to 0x1000
+        assert_eq!(buffers.flushable(1), vec![0, 1]);
+        assert_eq!(buffers.flushable(0), Vec::<usize>::new());
+
+        assert_eq!(buffers.take(1, 1).unwrap().addr, 0x2000);
+        assert_eq!(buffers.take(1, 0).unwrap().addr, 0x1000);
+        assert_eq!(buffers.flushable(1), vec![0]);
+        // other harts do not see the buffered stores
+        buffers.set_hart(0);
+        assert_eq!(buffers.forward(0x1002), None);
+    }
+}
@@ -0,0 +1,854 @@
+//! Linux syscall ABI for userland programs, including threads.
+//! Every thread runs on its own hart, its tid is derived from the hart id.
+//! Files are opened read-only on the host, for reading and private mappings.
+//! /proc/self/maps is made up from the guest's memory map when it is opened.
+use std::any::Any;
+use std::collections::{HashMap, VecDeque};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::FileExt;
+
+use crate::checkpoint::{CheckpointReader, CheckpointWriter};
+use crate::syscall::*;
+use crate::error::*;
+use crate::guest::{GuestMem, MapAt, MapError, MemFlags, PAGE_SIZE};
+use crate::state::State;
+use crate::*;
+
+pub const SYS_OPENAT: u64 = 56;
+pub const SYS_CLOSE: u64 = 57;
+pub const SYS_READ: u64 = 63;
+pub const SYS_WRITE: u64 = 64;
+pub const SYS_EXIT: u64 = 93;
+pub const SYS_EXIT_GROUP: u64 = 94;
+pub const SYS_SET_TID_ADDRESS: u64 = 96;
+pub const SYS_FUTEX: u64 = 98;
+pub const SYS_SCHED_YIELD: u64 = 124;
+pub const SYS_GETPID: u64 = 172;
+pub const SYS_GETTID: u64 = 178;
+pub const SYS_BRK: u64 = 214;
+pub const SYS_MUNMAP: u64 = 215;
+pub const SYS_MREMAP: u64 = 216;
+pub const SYS_CLONE: u64 = 220;
+pub const SYS_MMAP: u64 = 222;
+pub const SYS_MPROTECT: u64 = 226;
+
+pub const CLONE_VM: u64 = 0x00000100;
+pub const CLONE_THREAD: u64 = 0x00010000;
+pub const CLONE_SETTLS: u64 = 0x00080000;
+pub const CLONE_PARENT_SETTID: u64 = 0x00100000;
+pub const CLONE_CHILD_CLEARTID: u64 = 0x00200000;
+pub const CLONE_CHILD_SETTID: u64 = 0x01000000;
+
+pub const FUTEX_WAIT: u64 = 0;
+pub const FUTEX_WAKE: u64 = 1;
+pub const FUTEX_WAIT_BITSET: u64 = 9;
+pub const FUTEX_WAKE_BITSET: u64 = 10;
+pub const FUTEX_PRIVATE_FLAG: u64 = 128;
+pub const FUTEX_CLOCK_REALTIME: u64 = 256;
+pub const FUTEX_BITSET_MATCH_ANY: u32 = u32::MAX;
+
+pub const AT_FDCWD: i32 = -100;
+pub const O_ACCMODE: u64 = 3;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_SHARED: u64 = 0x01;
+pub const MAP_FIXED: u64 = 0x10;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+pub const MAP_FIXED_NOREPLACE: u64 = 0x100000;
+pub const MREMAP_MAYMOVE: u64 = 1;
+pub const MREMAP_FIXED: u64 = 2;
+
+pub const ENOENT: i64 = 2;
+pub const EIO: i64 = 5;
+pub const EBADF: i64 = 9;
+pub const EAGAIN: i64 = 11;
+pub const ENOMEM: i64 = 12;
+pub const EACCES: i64 = 13;
+pub const EFAULT: i64 = 14;
+pub const EEXIST: i64 = 17;
+pub const ENODEV: i64 = 19;
+pub const EINVAL: i64 = 22;
+pub const ENOSYS: i64 = 38;
+pub const ETIMEDOUT: i64 = 110;
+
+/// Read from the guest's memory map rather than the host.
+pub const PROC_SELF_MAPS: &str = "/proc/self/maps";
+
+/// Process id reported to the guest, which is also the tid of hart 0.
+pub const PID: u64 = 1000;
+
+/// Most bytes read and write copy between the guest and the host at once.
+const IO_CHUNK: usize = 0x10000;
+
+#[derive(Debug, Clone)]
+struct FutexWaiter {
+    hart: usize,
+    bitset: u32,
+    /// Virtual time the wait times out at.
+    deadline: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+pub struct LinuxSyscallHandler {
+    /// Futex word address -> waiters in FIFO order.
+    futexes: HashMap<u64, VecDeque<FutexWaiter>>,
+    /// Hart -> address cleared and woken when its thread exits.
+    clear_child_tid: HashMap<usize, u64>,
+    /// Guest fd -> host file, stdin, stdout and stderr are not in here.
+    files: HashMap<u64, File>,
+    /// Guest fd -> path the file was opened with, to open it again when resuming a checkpoint.
+    paths: HashMap<u64, String>,
+    /// Path of the program, which names its segments in /proc/self/maps.
+    program: String,
+}
+
+/// What `LinuxSyscallHandler::snapshot` saves.
+#[derive(Debug)]
+struct LinuxSnapshot {
+    futexes: HashMap<u64, VecDeque<FutexWaiter>>,
+    clear_child_tid: HashMap<usize, u64>,
+    /// (guest fd, another handle to the host file, its offset then)
+    files: Vec<(u64, File, u64)>,
+    paths: HashMap<u64, String>,
+}
+
+impl SyscallHandler for LinuxSyscallHandler {
+    /// Without the emulator's scheduler there is only one thread,
+    /// so syscalls that block or create threads are unavailable.
+    fn handle(&mut self, state: &mut State, guest: &mut GuestMem) -> Result<()> {
+        match state.x[17] {
+            SYS_EXIT => Err(Error::Exited(state.x[10] as i64)),
+            SYS_CLONE | SYS_FUTEX | SYS_SCHED_YIELD => Err(Error::SyscallUnimplemented(state.x[17], state.pc)),
+            _ => self.handle_sched(state, guest, &mut Sched::new(0, 1, 0)),
+        }
+    }
+
+    fn handle_sched(&mut self, state: &mut State, guest: &mut GuestMem, sched: &mut Sched) -> Result<()> {
+        let args = [state.x[10], state.x[11], state.x[12], state.x[13], state.x[14], state.x[15]];
+        let ret = match state.x[17] {
+            SYS_OPENAT => self.sys_openat(guest, args[0] as i32, args[1], args[2])?,
+            SYS_CLOSE => self.sys_close(args[0]),
+            SYS_READ => self.sys_read(guest, args[0], args[1], args[2])?,
+            SYS_WRITE => self.sys_write(guest, args[0], args[1], args[2])?,
+            SYS_EXIT => self.sys_exit(guest, sched, args[0] as i64)?,
+            SYS_EXIT_GROUP => {
+                debug!("exit_group called with code {}", args[0] as i64);
+                return Err(Error::Exited(args[0] as i64));
+            },
+            SYS_SET_TID_ADDRESS => {
+                self.clear_child_tid.insert(sched.hart(), args[0]);
+                tid(sched.hart())
+            },
+            SYS_FUTEX => self.sys_futex(state, guest, sched, args)?,
+            SYS_SCHED_YIELD => {
+                sched.yield_now();
+                0
+            },
+            SYS_GETPID => PID as i64,
+            SYS_GETTID => tid(sched.hart()),
+            SYS_BRK => guest.brk(args[0]) as i64,
+            SYS_MMAP => self.sys_mmap(guest, args)?,
+            SYS_MUNMAP => map_result(guest.munmap(args[0], args[1] as usize).map(|_| 0))?,
+            SYS_MPROTECT => map_result(guest.mprotect(args[0], args[1] as usize, prot_flags(args[2])).map(|_| 0))?,
+            SYS_MREMAP => self.sys_mremap(guest, args)?,
+            SYS_CLONE => self.sys_clone(state, guest, sched, args)?,
+            _ => return Err(Error::SyscallUnimplemented(state.x[17], state.pc)),
+        };
+        state.x[10] = ret as u64;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Result<Box<dyn Any>> {
+        let mut files = vec![];
+        for (&fd, file) in &self.files {
+            let io_err = |e| Error::IoError(e, format!("fd {}", fd));
+            // shares the offset with the guest's handle
+            let mut file = file.try_clone().map_err(io_err)?;
+            let offset = file.stream_position().map_err(io_err)?;
+            files.push((fd, file, offset));
+        }
+        Ok(Box::new(LinuxSnapshot {
+            futexes: self.futexes.clone(),
+            clear_child_tid: self.clear_child_tid.clone(),
+            files,
+            paths: self.paths.clone(),
+        }))
+    }
+
+    fn restore(&mut self, state: &dyn Any) -> Result<()> {
+        let Some(snapshot) = state.downcast_ref::<LinuxSnapshot>() else {
+            return Err(Error::InternalError("Not a snapshot of the Linux syscall handler".to_string()));
+        };
+        self.files.clear();
+        for (fd, file, offset) in &snapshot.files {
+            let io_err = |e| Error::IoError(e, format!("fd {}", fd));
+            let mut file = file.try_clone().map_err(io_err)?;
+            file.seek(SeekFrom::Start(*offset)).map_err(io_err)?;
+            self.files.insert(*fd, file);
+        }
+        self.futexes = snapshot.futexes.clone();
+        self.clear_child_tid = snapshot.clear_child_tid.clone();
+        self.paths = snapshot.paths.clone();
+        Ok(())
+    }
+
+    /// Open files are saved as their path and offset, and opened again on the host when resuming.
+    fn save_checkpoint(&self, out: &mut CheckpointWriter) -> Result<()> {
+        out.u64(self.futexes.len() as u64);
+        for (&addr, waiters) in &self.futexes {
+            out.u64(addr);
+            out.u64(waiters.len() as u64);
+            for waiter in waiters {
+                out.u64(waiter.hart as u64);
+                out.u32(waiter.bitset);
+                out.bool(waiter.deadline.is_some());
+                out.u64(waiter.deadline.unwrap_or_default());
+            }
+        }
+        out.u64(self.clear_child_tid.len() as u64);
+        for (&hart, &addr) in &self.clear_child_tid {
+            out.u64(hart as u64);
+            out.u64(addr);
+        }
+        out.u64(self.files.len() as u64);
+        for (&fd, file) in &self.files {
+            let io_err = |e| Error::IoError(e, format!("fd {}", fd));
+            let offset = (&*file).stream_position().map_err(io_err)?;
+            out.u64(fd);
+            out.str(&self.paths[&fd]);
+            out.u64(offset);
+            // the memory map may have changed since it was opened
+            if self.paths[&fd] == PROC_SELF_MAPS {
+                let mut contents = vec![];
+                file.try_clone().and_then(|mut file| {
+                    file.rewind()?;
+                    file.read_to_end(&mut contents)?;
+                    file.seek(SeekFrom::Start(offset))
+                }).map_err(io_err)?;
+                out.bytes(&contents);
+            }
+        }
+        Ok(())
+    }
+
+    fn load_checkpoint(&mut self, input: &mut CheckpointReader) -> Result<()> {
+        let mut futexes = HashMap::new();
+        for _ in 0..input.count(16)? {
+            let addr = input.u64()?;
+            let mut waiters = VecDeque::new();
+            for _ in 0..input.count(21)? {
+                let hart = input.u64()? as usize;
+                let bitset = input.u32()?;
+                let timed = input.bool()?;
+                let deadline = input.u64()?;
+                waiters.push_back(FutexWaiter { hart, bitset, deadline: timed.then_some(deadline) });
+            }
+            futexes.insert(addr, waiters);
+        }
+        let mut clear_child_tid = HashMap::new();
+        for _ in 0..input.count(16)? {
+            clear_child_tid.insert(input.u64()? as usize, input.u64()?);
+        }
+        let mut files = HashMap::new();
+        let mut paths = HashMap::new();
+        for _ in 0..input.count(24)? {
+            let fd = input.u64()?;
+            let path = input.str()?;
+            let offset = input.u64()?;
+            let io_err = |e| Error::IoError(e, path.clone());
+            let mut file = match path == PROC_SELF_MAPS {
+                true => memory_file(input.bytes()?).map_err(io_err)?,
+                false => File::open(&path).map_err(io_err)?,
+            };
+            file.seek(SeekFrom::Start(offset)).map_err(io_err)?;
+            files.insert(fd, file);
+            paths.insert(fd, path);
+        }
+        self.futexes = futexes;
+        self.clear_child_tid = clear_child_tid;
+        self.files = files;
+        self.paths = paths;
+        Ok(())
+    }
+}
+
+fn tid(hart: usize) -> i64 {
+    (PID + hart as u64) as i64
+}
+
+fn prot_flags(prot: u64) -> MemFlags {
+    let mut flags = MemFlags::NONE;
+    flags.set(MemFlags::READ, prot & PROT_READ != 0);
+    flags.set(MemFlags::WRITE, prot & PROT_WRITE != 0);
+    flags.set(MemFlags::EXECUTE, prot & PROT_EXEC != 0);
+    flags
+}
+
+/// Turns a refused change of the address space into its errno.
+fn map_result(res: Result<u64>) -> Result<i64> {
+    match res {
+        Ok(addr) => Ok(addr as i64),
+        Err(Error::Map(MapError::Invalid, _)) => Ok(-EINVAL),
+        Err(Error::Map(MapError::NoMemory, _)) => Ok(-ENOMEM),
+        Err(Error::Map(MapError::Exists, _)) => Ok(-EEXIST),
+        Err(e) => Err(e),
+    }
+}
+
+/// A file in host memory holding 'contents', for files the guest sees but the host does not have.
+fn memory_file(contents: &[u8]) -> std::io::Result<File> {
+    // SAFETY: the name is NUL terminated
+    let fd = unsafe { libc::memfd_create(c"rvemu".as_ptr(), libc::MFD_CLOEXEC) };
+    if fd < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    // SAFETY: the fd was just created and nothing else owns it
+    let mut file = unsafe { File::from_raw_fd(fd) };
+    file.write_all(contents)?;
+    file.rewind()?;
+    Ok(file)
+}
+
+/// The errno of a failed host file operation.
+fn host_errno(err: &std::io::Error) -> i64 {
+    -err.raw_os_error().map_or(EIO, |errno| errno as i64)
+}
+
+impl LinuxSyscallHandler {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Names the program's segments in /proc/self/maps after 'path'.
+    pub fn program(mut self, path: &str) -> Self {
+        self.program = path.to_string();
+        self
+    }
+
+    /// Copies the buffer out in chunks of at most `IO_CHUNK` bytes. Like Linux, a fault
+    /// part way returns what was written before it.
+    fn sys_write(&mut self, guest: &GuestMem, fd: u64, buf: u64, count: u64) -> Result<i64> {
+        if fd != 1 && fd != 2 {
+            return Ok(-EBADF);
+        }
+        let mut bytes = Vec::with_capacity(count.min(IO_CHUNK as u64) as usize);
+        let mut written = 0;
+        while written < count {
+            bytes.clear();
+            let mut fault = false;
+            for i in written..count.min(written + IO_CHUNK as u64) {
+                match guest.read_u8(buf.wrapping_add(i)) {
+                    Ok(b) => bytes.push(b),
+                    Err(Error::MemAccessFault(..)) => {
+                        fault = true;
+                        break;
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+            let res = match fd {
+                1 => std::io::stdout().write_all(&bytes),
+                _ => std::io::stderr().write_all(&bytes),
+            };
+            res.map_err(|e| Error::IoError(e, String::new()))?;
+            written += bytes.len() as u64;
+            if fault {
+                return Ok(if written > 0 { written as i64 } else { -EFAULT });
+            }
+        }
+        Ok(written as i64)
+    }
+
+    fn sys_openat(&mut self, guest: &GuestMem, dirfd: i32, path: u64, flags: u64) -> Result<i64> {
+        let mut bytes = vec![];
+        loop {
+            match guest.read_u8(path + bytes.len() as u64) {
+                Ok(0) => break,
+                Ok(b) => bytes.push(b),
+                Err(Error::MemAccessFault(..)) => return Ok(-EFAULT),
+                Err(e) => return Err(e),
+            }
+        }
+        let path = String::from_utf8_lossy(&bytes).to_string();
+        if flags & O_ACCMODE != 0 {
+            warn!("openat: only reading is supported, {} with flags {:#x}", path, flags);
+            return Ok(-EACCES);
+        }
+        if dirfd != AT_FDCWD && !path.starts_with('/') {
+            warn!("openat: paths relative to fd {} are not supported", dirfd);
+            return Ok(-EBADF);
+        }
+        let file = match path == PROC_SELF_MAPS {
+            true => memory_file(guest.format_maps(&self.program).as_bytes()),
+            false => File::open(&path),
+        };
+        let file = match file {
+            Ok(file) => file,
+            Err(e) => return Ok(host_errno(&e)),
+        };
+        let fd = (3..).find(|fd| !self.files.contains_key(fd)).unwrap();
+        debug!("openat: {} is fd {}", path, fd);
+        self.files.insert(fd, file);
+        self.paths.insert(fd, path);
+        Ok(fd as i64)
+    }
+
+    fn sys_close(&mut self, fd: u64) -> i64 {
+        match fd {
+            0..=2 => 0,
+            _ if self.files.remove(&fd).is_some() => {
+                self.paths.remove(&fd);
+                0
+            },
+            _ => -EBADF,
+        }
+    }
+
+    /// Reads in chunks of at most `IO_CHUNK` bytes, until the file has no more. Like Linux,
+    /// a fault part way returns what was copied before it, the file stays positioned there.
+    fn sys_read(&mut self, guest: &mut GuestMem, fd: u64, buf: u64, count: u64) -> Result<i64> {
+        let Some(file) = self.files.get_mut(&fd) else {
+            return Ok(-EBADF);
+        };
+        let mut bytes = vec![0; count.min(IO_CHUNK as u64) as usize];
+        let mut done = 0;
+        while done < count {
+            let want = (count - done).min(IO_CHUNK as u64) as usize;
+            let len = match file.read(&mut bytes[..want]) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(_) if done > 0 => break,
+                Err(e) => return Ok(host_errno(&e)),
+            };
+            for (i, &b) in bytes[..len].iter().enumerate() {
+                match guest.write_u8(buf.wrapping_add(done + i as u64), b) {
+                    Ok(()) => {},
+                    Err(Error::MemAccessFault(..)) => {
+                        let _ = file.seek(SeekFrom::Current(i as i64 - len as i64));
+                        let copied = done + i as u64;
+                        return Ok(if copied > 0 { copied as i64 } else { -EFAULT });
+                    },
+                    Err(e) => return Err(e),
+                }
+            }
+            done += len as u64;
+        }
+        Ok(done as i64)
+    }
+
+    /// Anonymous mappings, and private mappings of files, which are read when mapped.
+    /// Changes to a shared mapping would have to reach the file, so it has to be read-only.
+    fn sys_mmap(&mut self, guest: &mut GuestMem, args: [u64; 6]) -> Result<i64> {
+        let [addr, len, prot, flags, fd, offset] = args;
+        let at = match flags {
+            _ if flags & MAP_FIXED_NOREPLACE != 0 => MapAt::FixedNoReplace,
+            _ if flags & MAP_FIXED != 0 => MapAt::Fixed,
+            _ => MapAt::Hint,
+        };
+        let mut data = None;
+        if flags & MAP_ANONYMOUS == 0 {
+            if flags & MAP_SHARED != 0 && prot & PROT_WRITE != 0 {
+                warn!("mmap: writable shared file mappings are not supported");
+                return Ok(-ENODEV);
+            }
+            if !offset.is_multiple_of(PAGE_SIZE as u64) {
+                return Ok(-EINVAL);
+            }
+            let Some(file) = self.files.get(&fd) else {
+                return Ok(-EBADF);
+            };
+            // pages past the end of the file read as zero
+            let size = match file.metadata() {
+                Ok(metadata) => metadata.len(),
+                Err(e) => return Ok(host_errno(&e)),
+            };
+            let mut bytes = vec![0; len.min(size.saturating_sub(offset)) as usize];
+            let mut read = 0;
+            while read < bytes.len() {
+                match file.read_at(&mut bytes[read..], offset + read as u64) {
+                    Ok(0) => break,
+                    Ok(n) => read += n,
+                    Err(e) => return Ok(host_errno(&e)),
+                }
+            }
+            bytes.truncate(read);
+            data = Some(bytes);
+        }
+        map_result(guest.mmap(addr, len as usize, prot_flags(prot), at, data.as_deref()))
+    }
+
+    fn sys_mremap(&mut self, guest: &mut GuestMem, args: [u64; 6]) -> Result<i64> {
+        let [addr, old_len, new_len, flags, ..] = args;
+        if flags & MREMAP_FIXED != 0 {
+            warn!("mremap: MREMAP_FIXED is not supported");
+            return Ok(-EINVAL);
+        }
+        map_result(guest.mremap(addr, old_len as usize, new_len as usize, flags & MREMAP_MAYMOVE != 0))
+    }
+
+    /// Ends the calling thread. Like Linux, the clear_child_tid word is zeroed and woken.
+    fn sys_exit(&mut self, guest: &mut GuestMem, sched: &mut Sched, code: i64) -> Result<i64> {
+        debug!("thread {} exit called with code {}", tid(sched.hart()), code);
+        if let Some(addr) = self.clear_child_tid.remove(&sched.hart())
+            && addr != 0
+            && guest.write_u32(addr, 0).is_ok()
+        {
+            self.futex_wake(sched, addr, 1, FUTEX_BITSET_MATCH_ANY);
+        }
+        sched.exit(code);
+        Ok(0)
+    }
+
+    fn sys_clone(&mut self, state: &State, guest: &mut GuestMem, sched: &mut Sched, args: [u64; 6]) -> Result<i64> {
+        let [flags, newsp, ptid, tls, ctid, _] = args;
+        if flags & (CLONE_VM | CLONE_THREAD) != (CLONE_VM | CLONE_THREAD) {
+            warn!("clone: only threads sharing memory are supported, flags {:#x}", flags);
+            return Ok(-ENOSYS);
+        }
+
+        let mut child = state.clone();
+        child.x[10] = 0;
+        if newsp != 0 {
+            child.x[2] = newsp;
+        }
+        if flags & CLONE_SETTLS != 0 {
+            child.x[4] = tls;
+        }
+        // the tids are stored before the child is spawned, so that a fault leaves no child behind
+        let hart = sched.next_hart();
+        let child_tid = tid(hart);
+        if flags & CLONE_PARENT_SETTID != 0 && guest.write_u32(ptid, child_tid as u32).is_err() {
+            return Ok(-EFAULT);
+        }
+        if flags & CLONE_CHILD_SETTID != 0 && guest.write_u32(ctid, child_tid as u32).is_err() {
+            return Ok(-EFAULT);
+        }
+        sched.spawn(child);
+        if flags & CLONE_CHILD_CLEARTID != 0 {
+            self.clear_child_tid.insert(hart, ctid);
+        }
+        Ok(child_tid)
+    }
+
+    fn sys_futex(&mut self, state: &State, guest: &GuestMem, sched: &mut Sched, args: [u64; 6]) -> Result<i64> {
+        let [uaddr, op, val, timeout, _uaddr2, val3] = args;
+        match op & !(FUTEX_PRIVATE_FLAG | FUTEX_CLOCK_REALTIME) {
+            cmd @ (FUTEX_WAIT | FUTEX_WAIT_BITSET) => {
+                let bitset = if cmd == FUTEX_WAIT { FUTEX_BITSET_MATCH_ANY } else { val3 as u32 };
+                if bitset == 0 {
+                    return Ok(-EINVAL);
+                }
+                match guest.read_u32(uaddr) {
+                    Ok(cur) if cur != val as u32 => return Ok(-EAGAIN),
+                    Ok(_) => {},
+                    Err(_) => return Ok(-EFAULT),
+                }
+                let deadline = if timeout == 0 {
+                    None
+                } else {
+                    let (Ok(sec), Ok(nsec)) = (guest.read_u64(timeout), guest.read_u64(timeout + 8)) else {
+                        return Ok(-EFAULT);
+                    };
+                    let ns = sec.saturating_mul(1_000_000_000).saturating_add(nsec);
+                    // FUTEX_WAIT takes a relative timeout, FUTEX_WAIT_BITSET an absolute one.
+                    Some(if cmd == FUTEX_WAIT { sched.now().saturating_add(ns) } else { ns })
+                };
+                self.futexes.entry(uaddr).or_default().push_back(FutexWaiter {
+                    hart: sched.hart(),
+                    bitset,
+                    deadline,
+                });
+                sched.block(deadline);
+                // Returned if the wait times out, a wakeup overwrites it with 0.
+                Ok(-ETIMEDOUT)
+            },
+            FUTEX_WAKE => Ok(self.futex_wake(sched, uaddr, val as u32, FUTEX_BITSET_MATCH_ANY)),
+            FUTEX_WAKE_BITSET => {
+                if val3 as u32 == 0 {
+                    return Ok(-EINVAL);
+                }
+                Ok(self.futex_wake(sched, uaddr, val as u32, val3 as u32))
+            },
+            _ => {
+                warn!("futex: unsupported op {:#x} at {:#x}", op, state.pc);
+                Ok(-ENOSYS)
+            }
+        }
+    }
+
+    /// Wakes up to 'count' waiters on 'uaddr', skipping those that already timed out.
+    fn futex_wake(&mut self, sched: &mut Sched, uaddr: u64, count: u32, bitset: u32) -> i64 {
+        let Some(waiters) = self.futexes.get_mut(&uaddr) else {
+            return 0;
+        };
+        let now = sched.now();
+        waiters.retain(|waiter| waiter.deadline.is_none_or(|deadline| deadline > now));
+        let mut woken = 0;
+        waiters.retain(|waiter| {
+            if woken < count && waiter.bitset & bitset != 0 {
+                sched.wake(waiter.hart, 0);
+                woken += 1;
+                false
+            } else {
+                true
+            }
+        });
+        if waiters.is_empty() {
+            self.futexes.remove(&uaddr);
+        }
+        woken as i64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::*;
+    use crate::guest::MemFlags;
+
+    fn build() -> Emulator {
+        let mut emulator = Emulator::new()
+            .syscall(Box::new(LinuxSyscallHandler::new()))
+            .decoder(InsnSet::I)
+            .decoder(InsnSet::A)
+            .quantum(4)
+            .build()
+            .unwrap();
+        emulator.guest.add_segment(0x2000, 0x2000, 0x1000, MemFlags::READ | MemFlags::WRITE, None).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn test_clone_join() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        // The main thread clones a child with CLONE_CHILD_CLEARTID on 0x2000 and
+        // futex-waits on it like pthread_join. The child stores 42 + tp and exits.
+        emulator.load_code(0x1000, &[
+            0x00002437, // 0: lui s0, 2
+            0x00100293, // 4: li t0, 1
+            0x00542023, // 8: sw t0, 0(s0)
+            0x00290537, // c: lui a0, 656
+            0x1005051b, // 10: addiw a0, a0, 256 (CLONE_VM | CLONE_THREAD | CLONE_SETTLS | CLONE_CHILD_CLEARTID)
+            0x000045b7, // 14: lui a1, 4
+            0x00000613, // 18: li a2, 0
+            0x07700693, // 1c: li a3, 119
+            0x00040713, // 20: mv a4, s0
+            0x0dc00893, // 24: li a7, 220
+            0x00000073, // 28: ecall
+            0x02050863, // 2c: beqz a0, 0x5c <child>
+            0x00042603, // 30: lw a2, 0(s0)
+            0x00060e63, // 34: beqz a2, 0x50 <done>
+            0x00040513, // 38: mv a0, s0
+            0x00000593, // 3c: li a1, 0
+            0x00000693, // 40: li a3, 0
+            0x06200893, // 44: li a7, 98
+            0x00000073, // 48: ecall
+            0xfe5ff06f, // 4c: j 0x30 <wait>
+            0x00843503, // 50: ld a0, 8(s0)
+            0x05e00893, // 54: li a7, 94
+            0x00000073, // 58: ecall
+            0x02a00313, // 5c: li t1, 42
+            0x00430333, // 60: add t1, t1, tp
+            0x00643423, // 64: sd t1, 8(s0)
+            0x00000513, // 68: li a0, 0
+            0x05d00893, // 6c: li a7, 93
+            0x00000073, // 70: ecall
+        ]);
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(42 + 0x77));
+        assert_eq!(emulator.harts().len(), 2);
+        assert_eq!(emulator.harts()[1].status, crate::hart::HartStatus::Exited(0));
+        assert_eq!(emulator.harts()[1].state.x[2], 0x4000);
+    }
+
+    #[test]
+    fn test_clone_fault() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        let mut handler = LinuxSyscallHandler::new();
+        let state = emulator.hart().state.clone();
+        let flags = CLONE_VM | CLONE_THREAD | CLONE_PARENT_SETTID;
+        let mut sched = Sched::new(0, 1, 0);
+        let res = handler.sys_clone(&state, &mut emulator.guest, &mut sched, [flags, 0, 0x10000, 0, 0, 0]);
+        assert_eq!(res.unwrap(), -EFAULT);
+        assert!(sched.into_requests().is_empty());
+
+        let mut sched = Sched::new(0, 1, 0);
+        let res = handler.sys_clone(&state, &mut emulator.guest, &mut sched, [flags, 0, 0x2000, 0, 0, 0]);
+        assert_eq!(res.unwrap(), tid(1));
+        assert_eq!(emulator.guest.read_u32(0x2000).unwrap() as i64, tid(1));
+        assert!(matches!(sched.into_requests()[..], [SchedRequest::Spawn(_)]));
+    }
+
+    #[test]
+    fn test_futex_timeout() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        // futex(0x2000, FUTEX_WAIT_PRIVATE, 0, {0s, 500ns}), nobody wakes it up.
+        emulator.load_code(0x1000, &[
+            0x00002437, // 0: lui s0, 2
+            0x1f400293, // 4: li t0, 500
+            0x00543c23, // 8: sd t0, 24(s0)
+            0x00040513, // c: mv a0, s0
+            0x08000593, // 10: li a1, 128
+            0x00000613, // 14: li a2, 0
+            0x01040693, // 18: addi a3, s0, 16
+            0x06200893, // 1c: li a7, 98
+            0x00000073, // 20: ecall
+            0x40a00533, // 24: neg a0, a0
+            0x05e00893, // 28: li a7, 94
+            0x00000073, // 2c: ecall
+        ]);
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(ETIMEDOUT));
+        assert!(emulator.clock() >= 500);
+    }
+
+    #[test]
+    fn test_brk() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        // Grows the heap by 3 pages like sbrk, and uses its last word.
+        emulator.load_code(0x1000, &[
+            0x00000513, // 0: li a0, 0
+            0x0d600893, // 4: li a7, 214
+            0x00000073, // 8: ecall
+            0x00050413, // c: mv s0, a0
+            0x000032b7, // 10: lui t0, 3
+            0x00540533, // 14: add a0, s0, t0
+            0x00000073, // 18: ecall
+            0x00540333, // 1c: add t1, s0, t0
+            0xfe533c23, // 20: sd t0, -8(t1)
+            0xff833383, // 24: ld t2, -8(t1)
+            0x40850533, // 28: sub a0, a0, s0
+            0x00750533, // 2c: add a0, a0, t2
+            0x00c55513, // 30: srli a0, a0, 12
+            0x05d00893, // 34: li a7, 93
+            0x00000073, // 38: ecall
+        ]);
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(6));
+        assert_eq!(emulator.guest.cur_brk(), 0x7000);
+    }
+
+    #[test]
+    fn test_mmap_file() {
+        log::log_init(log::Level::Off);
+
+        let path = std::env::temp_dir().join(format!("rvemu-mmap-{}", std::process::id()));
+        std::fs::write(&path, [7, 42, 9]).unwrap();
+        let mut emulator = build();
+        for (i, b) in path.to_str().unwrap().bytes().chain([0]).enumerate() {
+            emulator.guest.write_u8(0x2000 + i as u64, b).unwrap();
+        }
+        // Opens the file at 0x2000, maps it privately and exits with its second byte.
+        emulator.load_code(0x1000, &[
+            0xf9c00513, // 0: li a0, -100 (AT_FDCWD)
+            0x000025b7, // 4: lui a1, 2
+            0x00000613, // 8: li a2, 0 (O_RDONLY)
+            0x03800893, // c: li a7, 56
+            0x00000073, // 10: ecall
+            0x00050713, // 14: mv a4, a0
+            0x00000513, // 18: li a0, 0
+            0x000015b7, // 1c: lui a1, 1
+            0x00100613, // 20: li a2, 1 (PROT_READ)
+            0x00200693, // 24: li a3, 2 (MAP_PRIVATE)
+            0x00000793, // 28: li a5, 0
+            0x0de00893, // 2c: li a7, 222
+            0x00000073, // 30: ecall
+            0x00154403, // 34: lbu s0, 1(a0)
+            0x00070513, // 38: mv a0, a4
+            0x03900893, // 3c: li a7, 57
+            0x00000073, // 40: ecall
+            0x00040513, // 44: mv a0, s0
+            0x05d00893, // 48: li a7, 93
+            0x00000073, // 4c: ecall
+        ]);
+
+        let exit = emulator.run();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(exit.unwrap(), ExitReason::Exited(42));
+        // the rest of the page reads as zero, and the mapping is read-only
+        let addr = crate::config::MMAP_TOP - PAGE_SIZE as u64;
+        assert_eq!(emulator.guest.read_u64(addr).unwrap(), 0x092a07);
+        assert_eq!(emulator.guest.read_u8(addr + 3).unwrap(), 0);
+        assert!(emulator.guest.write_u8(addr, 0).is_err());
+    }
+
+    #[test]
+    fn test_proc_self_maps() {
+        log::log_init(log::Level::Off);
+
+        let mut emulator = build();
+        for (i, b) in PROC_SELF_MAPS.bytes().chain([0]).enumerate() {
+            emulator.guest.write_u8(0x2000 + i as u64, b).unwrap();
+        }
+        // Reads up to 256 bytes of /proc/self/maps to 0x3000 and exits with their count.
+        emulator.load_code(0x1000, &[
+            0xf9c00513, // 0: li a0, -100 (AT_FDCWD)
+            0x000025b7, // 4: lui a1, 2
+            0x00000613, // 8: li a2, 0 (O_RDONLY)
+            0x03800893, // c: li a7, 56
+            0x00000073, // 10: ecall
+            0x000035b7, // 14: lui a1, 3
+            0x10000613, // 18: li a2, 256
+            0x03f00893, // 1c: li a7, 63
+            0x00000073, // 20: ecall
+            0x05d00893, // 24: li a7, 93
+            0x00000073, // 28: ecall
+        ]);
+
+        let maps = emulator.guest.format_maps("");
+        assert!(maps.len() < 256);
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(maps.len() as i64));
+        for (i, b) in maps.bytes().enumerate() {
+            assert_eq!(emulator.guest.read_u8(0x3000 + i as u64).unwrap(), b);
+        }
+    }
+
+    #[test]
+    fn test_write_fault() {
+        log::log_init(log::Level::Off);
+
+        // the count is not trusted, the buffer faults long before it
+        let emulator = build();
+        let mut handler = LinuxSyscallHandler::new();
+        assert_eq!(handler.sys_write(&emulator.guest, 1, 0x10000, u64::MAX).unwrap(), -EFAULT);
+        assert_eq!(handler.sys_write(&emulator.guest, 1, u64::MAX, 2).unwrap(), -EFAULT);
+        assert_eq!(handler.sys_write(&emulator.guest, 5, 0x2000, u64::MAX).unwrap(), -EBADF);
+    }
+
+    #[test]
+    fn test_read_partial() {
+        log::log_init(log::Level::Off);
+
+        let path = std::env::temp_dir().join(format!("rvemu-read-{}", std::process::id()));
+        std::fs::write(&path, [1, 2, 3]).unwrap();
+        let mut emulator = build();
+        for (i, b) in path.to_str().unwrap().bytes().chain([0]).enumerate() {
+            emulator.guest.write_u8(0x2000 + i as u64, b).unwrap();
+        }
+        let mut handler = LinuxSyscallHandler::new();
+        let mut open = |guest: &GuestMem| handler.sys_openat(guest, AT_FDCWD, 0x2000, 0).unwrap() as u64;
+        let fds = [open(&emulator.guest), open(&emulator.guest), open(&emulator.guest)];
+        std::fs::remove_file(&path).unwrap();
+
+        // the count is not trusted, reading stops at the end of the file
+        assert_eq!(handler.sys_read(&mut emulator.guest, fds[0], 0x3000, u64::MAX).unwrap(), 3);
+        assert_eq!(emulator.guest.read_u32(0x3000).unwrap(), 0x030201);
+        // a fault part way returns what was copied, the rest is read next time
+        assert_eq!(handler.sys_read(&mut emulator.guest, fds[1], 0x3ffe, u64::MAX).unwrap(), 2);
+        assert_eq!(handler.sys_read(&mut emulator.guest, fds[1], 0x3000, 8).unwrap(), 1);
+        assert_eq!(emulator.guest.read_u8(0x3000).unwrap(), 3);
+        assert_eq!(handler.sys_read(&mut emulator.guest, fds[2], 0x10000, 8).unwrap(), -EFAULT);
+    }
+}
@@ -44,7 +44,7 @@ fn sys_puts(state: &mut State, guest: &GuestMem, s: u64) -> Result<()> {
     loop {
         let byte = match guest.read_u8(ptr) {
             Ok(b) => b,
-            Err(Error::MemAccessFault(..)) => {
+            Err(Error::MemAccessFault(..) | Error::PmpViolation(..)) => {
                 warn!("sys_puts: memory access fault at {:#x}", s);
                 state.x[0] = u64::MAX;
                 return Ok(());
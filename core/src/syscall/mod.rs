@@ -1,5 +1,7 @@
+use std::any::Any;
 use std::fmt::Debug;
 
+use crate::checkpoint::{CheckpointReader, CheckpointWriter};
 use crate::guest::GuestMem;
 use crate::state::State;
 use crate::*;
@@ -7,10 +9,117 @@ use crate::error::*;
 
 pub mod minilib;
 pub mod newlib;
+pub mod linux;
 
 pub use newlib::NewlibSyscallHandler as Newlib;
 pub use minilib::MinilibSyscallHandler as Minilib;
+pub use linux::LinuxSyscallHandler as Linux;
 
 pub trait SyscallHandler: Debug {
     fn handle(&mut self, state: &mut State, guest: &mut GuestMem) -> Result<()>;
-}
\ No newline at end of file
+
+    /// Entry point used by the emulator.
+    /// Handlers that manage threads override this to make scheduling requests through 'sched'.
+    fn handle_sched(&mut self, state: &mut State, guest: &mut GuestMem, sched: &mut Sched) -> Result<()> {
+        self.handle(state, guest)
+    }
+
+    /// State the handler keeps across syscalls, saved by `Emulator::snapshot`.
+    /// Stateless handlers keep the default.
+    fn snapshot(&self) -> Result<Box<dyn Any>> {
+        Ok(Box::new(()))
+    }
+
+    /// Puts back the state `snapshot` returned.
+    fn restore(&mut self, state: &dyn Any) -> Result<()> {
+        Ok(())
+    }
+
+    /// Writes the same state as `snapshot` to a checkpoint, see `checkpoint`.
+    fn save_checkpoint(&self, out: &mut CheckpointWriter) -> Result<()> {
+        Ok(())
+    }
+
+    /// Reads back what `save_checkpoint` wrote.
+    fn load_checkpoint(&mut self, input: &mut CheckpointReader) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Scheduling requests a syscall handler makes on behalf of the calling hart.
+/// They are applied by the emulator once the handler returns.
+#[derive(Debug)]
+pub struct Sched {
+    hart: usize,
+    next_hart: usize,
+    now: u64,
+    requests: Vec<SchedRequest>,
+}
+
+#[derive(Debug)]
+pub enum SchedRequest {
+    /// Start a new hart with the given state.
+    Spawn(Box<State>),
+    /// Block the calling hart, until the given virtual time if any.
+    Block(Option<u64>),
+    /// Make a blocked hart runnable again, with a0 set to the value given.
+    Wake(usize, u64),
+    /// Stop the calling hart. The emulator exits once no hart is left.
+    Exit(i64),
+    /// Give up the rest of the calling hart's quantum.
+    Yield,
+}
+
+impl Sched {
+    pub fn new(hart: usize, next_hart: usize, now: u64) -> Self {
+        Self {
+            hart,
+            next_hart,
+            now,
+            requests: vec![],
+        }
+    }
+
+    /// Id of the calling hart.
+    pub fn hart(&self) -> usize {
+        self.hart
+    }
+
+    /// Virtual time in nanoseconds.
+    pub fn now(&self) -> u64 {
+        self.now
+    }
+
+    /// Id the next hart spawned will get.
+    pub fn next_hart(&self) -> usize {
+        self.next_hart
+    }
+
+    /// Returns the id the new hart will get.
+    pub fn spawn(&mut self, state: State) -> usize {
+        let id = self.next_hart;
+        self.next_hart += 1;
+        self.requests.push(SchedRequest::Spawn(Box::new(state)));
+        id
+    }
+
+    pub fn block(&mut self, until: Option<u64>) {
+        self.requests.push(SchedRequest::Block(until));
+    }
+
+    pub fn wake(&mut self, hart: usize, ret: u64) {
+        self.requests.push(SchedRequest::Wake(hart, ret));
+    }
+
+    pub fn exit(&mut self, code: i64) {
+        self.requests.push(SchedRequest::Exit(code));
+    }
+
+    pub fn yield_now(&mut self) {
+        self.requests.push(SchedRequest::Yield);
+    }
+
+    pub(crate) fn into_requests(self) -> Vec<SchedRequest> {
+        self.requests
+    }
+}
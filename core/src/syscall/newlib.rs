@@ -11,6 +11,11 @@ pub struct NewlibSyscallHandler;
 impl SyscallHandler for NewlibSyscallHandler {
     fn handle(&mut self, state: &mut State, guest: &mut GuestMem) -> Result<()> {
         match state.x[17] {
+            // libgloss uses the Linux numbers
+            linux::SYS_BRK => {
+                state.x[10] = guest.brk(state.x[10]);
+                Ok(())
+            },
             _ => {
                 Err(Error::SyscallUnimplemented(state.x[17], state.pc))
             }
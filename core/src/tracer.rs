@@ -0,0 +1,115 @@
+//! Runtime instruction tracer, enabled with `EmulatorBuilder::trace_insns`.
+//! Writes one line per executed instruction, in the style of Spike's commit log:
+//! the hart, the pc, the raw encoding and every integer register the instruction changed.
+//! Harts run a separate copy of the interpreter loop while tracing, so the tracer costs
+//! nothing when it is off. Compiled blocks are not used while tracing.
+//!
+//! ```text
+//! core   0: 0x0000000000001000 (0x06400293) x5  0x0000000000000064
+//! ```
+
+use std::fmt;
+use std::io::Write;
+
+use crate::error::*;
+use crate::insn::Instruction;
+
+pub struct InsnTracer {
+    out: Box<dyn Write + Send>,
+    /// Lines written so far.
+    lines: u64,
+}
+
+impl fmt::Debug for InsnTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InsnTracer").field("lines", &self.lines).finish()
+    }
+}
+
+impl InsnTracer {
+    pub fn new(out: Box<dyn Write + Send>) -> Self {
+        Self { out, lines: 0 }
+    }
+
+    /// Writes the line of 'insn', executed by 'hart' at 'pc', which turned the registers
+    /// 'before' into 'after'.
+    pub fn record(&mut self, hart: usize, pc: u64, insn: &Instruction, before: &[u64; 32], after: &[u64; 32]) -> Result<()> {
+        let res = (|| {
+            match insn.step_size() {
+                2 => write!(self.out, "core {:3}: {:#018x} ({:#06x})", hart, pc, insn.raw() as u16)?,
+                _ => write!(self.out, "core {:3}: {:#018x} ({:#010x})", hart, pc, insn.raw())?,
+            }
+            for (reg, (old, new)) in before.iter().zip(after).enumerate().skip(1) {
+                if old != new {
+                    write!(self.out, " x{:<2} {:#018x}", reg, new)?;
+                }
+            }
+            writeln!(self.out)
+        })();
+        self.lines += 1;
+        res.map_err(|e| Error::IoError(e, "instruction trace".to_string()))
+    }
+
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.out.flush().map_err(|e| Error::IoError(e, "instruction trace".to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::emulator::*;
+    use crate::*;
+
+    /// Collects the trace so the test can read it back.
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_trace() {
+        log::log_init(log::Level::Off);
+
+        let out = Shared::default();
+        let builder = Emulator::new()
+            .syscall(Box::new(crate::Minilib))
+            .decoder(InsnSet::I)
+            .trace_insns(Box::new(out.clone()));
+        // hot enough to be compiled, which tracing has to bypass
+        #[cfg(feature = "jit")]
+        let builder = builder.jit(crate::jit::JitMode::Native);
+        let mut emulator = builder.build().unwrap();
+        emulator.load_code(0x1000, &[
+            0x01400513, // 0: li a0, 20
+            0xfff50513, // 4: addi a0, a0, -1
+            0xfe051ee3, // 8: bnez a0, 0x4
+            0x05d00893, // c: li a7, 93
+            0x00000073, // 10: ecall
+        ]);
+
+        assert_eq!(emulator.run().unwrap(), ExitReason::Exited(0));
+        let trace = String::from_utf8(out.0.lock().unwrap().clone()).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 1 + 2 * 20 + 2);
+        assert_eq!(emulator.tracer_mut().unwrap().lines(), lines.len() as u64);
+        assert_eq!(lines[0], "core   0: 0x0000000000001000 (0x01400513) x10 0x0000000000000014");
+        assert_eq!(lines[1], "core   0: 0x0000000000001004 (0xfff50513) x10 0x0000000000000013");
+        assert_eq!(lines[2], "core   0: 0x0000000000001008 (0xfe051ee3)");
+        assert_eq!(lines[42], "core   0: 0x0000000000001010 (0x00000073)");
+    }
+}
@@ -0,0 +1,281 @@
+//! Debug-spec (Sdtrig) trigger module, as seen by debug agents running inside the guest.
+//! Supports mcontrol (type 2) and mcontrol6 (type 6) address/data match triggers.
+//! Chaining is not supported, the 'chain' bit is ignored.
+
+use crate::guest::MemAccess;
+use crate::pmp::Privilege;
+
+/// Number of implemented triggers.
+pub const TRIGGER_COUNT: usize = 4;
+
+pub const CSR_TSELECT: u32 = 0x7a0;
+pub const CSR_TDATA1: u32 = 0x7a1;
+pub const CSR_TDATA2: u32 = 0x7a2;
+pub const CSR_TDATA3: u32 = 0x7a3;
+pub const CSR_TINFO: u32 = 0x7a4;
+
+pub const TRIGGER_TYPE_SHIFT: u64 = 60;
+pub const TRIGGER_TYPE_MCONTROL: u64 = 2;
+pub const TRIGGER_TYPE_MCONTROL6: u64 = 6;
+pub const TRIGGER_TYPE_DISABLED: u64 = 15;
+
+// Bits shared by mcontrol and mcontrol6.
+pub const TDATA1_LOAD: u64 = 1 << 0;
+pub const TDATA1_STORE: u64 = 1 << 1;
+pub const TDATA1_EXECUTE: u64 = 1 << 2;
+pub const TDATA1_U: u64 = 1 << 3;
+pub const TDATA1_S: u64 = 1 << 4;
+pub const TDATA1_M: u64 = 1 << 6;
+pub const TDATA1_MATCH_SHIFT: u64 = 7;
+pub const TDATA1_ACTION_SHIFT: u64 = 12;
+
+pub const MCONTROL_SELECT: u64 = 1 << 19;
+pub const MCONTROL_HIT: u64 = 1 << 20;
+pub const MCONTROL6_SELECT: u64 = 1 << 21;
+pub const MCONTROL6_HIT0: u64 = 1 << 22;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TriggerAction {
+    /// Raise a breakpoint exception into the guest.
+    Exception,
+    /// Stop and hand control to the host debugger.
+    DebugMode,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Trigger {
+    tdata1: u64,
+    tdata2: u64,
+}
+
+impl Trigger {
+    fn ty(&self) -> u64 {
+        self.tdata1 >> TRIGGER_TYPE_SHIFT
+    }
+
+    fn select(&self) -> bool {
+        match self.ty() {
+            TRIGGER_TYPE_MCONTROL => self.tdata1 & MCONTROL_SELECT != 0,
+            TRIGGER_TYPE_MCONTROL6 => self.tdata1 & MCONTROL6_SELECT != 0,
+            _ => false,
+        }
+    }
+
+    fn set_hit(&mut self) {
+        match self.ty() {
+            TRIGGER_TYPE_MCONTROL => self.tdata1 |= MCONTROL_HIT,
+            TRIGGER_TYPE_MCONTROL6 => self.tdata1 |= MCONTROL6_HIT0,
+            _ => {},
+        }
+    }
+
+    fn action(&self) -> Option<TriggerAction> {
+        match (self.tdata1 >> TDATA1_ACTION_SHIFT) & 0xf {
+            0 => Some(TriggerAction::Exception),
+            1 => Some(TriggerAction::DebugMode),
+            _ => None,
+        }
+    }
+
+    fn enabled_in(&self, privilege: Privilege) -> bool {
+        let bit = match privilege {
+            Privilege::Machine => TDATA1_M,
+            Privilege::Supervisor => TDATA1_S,
+            Privilege::User => TDATA1_U,
+        };
+        self.tdata1 & bit != 0
+    }
+
+    /// Compares 'value' against tdata2, 'len' is the number of bytes accessed when matching addresses.
+    fn matches(&self, value: u64, len: usize) -> bool {
+        let tdata2 = self.tdata2;
+        let last = value.wrapping_add(len.max(1) as u64 - 1);
+        match (self.tdata1 >> TDATA1_MATCH_SHIFT) & 0xf {
+            0 => tdata2 >= value && tdata2 <= last,
+            1 => {
+                let mask = Self::napot_mask(tdata2);
+                (value & !mask) <= (tdata2 & !mask) && (tdata2 & !mask) <= (last & !mask)
+            },
+            2 => value >= tdata2,
+            3 => value < tdata2,
+            8 => !(tdata2 >= value && tdata2 <= last),
+            9 => {
+                let mask = Self::napot_mask(tdata2);
+                (value & !mask) != (tdata2 & !mask)
+            },
+            _ => false,
+        }
+    }
+
+    fn napot_mask(tdata2: u64) -> u64 {
+        let ones = tdata2.trailing_ones();
+        if ones >= 63 { u64::MAX } else { (1u64 << (ones + 1)) - 1 }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Triggers {
+    tselect: usize,
+    triggers: [Trigger; TRIGGER_COUNT],
+    /// Set if any trigger is armed, so accesses can skip matching entirely.
+    armed: bool,
+}
+
+impl Default for Triggers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Triggers {
+    pub const fn new() -> Self {
+        Self {
+            tselect: 0,
+            triggers: [Trigger { tdata1: TRIGGER_TYPE_DISABLED << TRIGGER_TYPE_SHIFT, tdata2: 0 }; TRIGGER_COUNT],
+            armed: false,
+        }
+    }
+
+    pub fn armed(&self) -> bool {
+        self.armed
+    }
+
+    pub fn read_csr(&self, csr: u32) -> Option<u64> {
+        let trigger = &self.triggers[self.tselect];
+        match csr {
+            CSR_TSELECT => Some(self.tselect as u64),
+            CSR_TDATA1 => Some(trigger.tdata1),
+            CSR_TDATA2 => Some(trigger.tdata2),
+            CSR_TDATA3 => Some(0),
+            CSR_TINFO => Some(1 << TRIGGER_TYPE_MCONTROL | 1 << TRIGGER_TYPE_MCONTROL6),
+            _ => None,
+        }
+    }
+
+    pub fn write_csr(&mut self, csr: u32, value: u64) -> Option<()> {
+        match csr {
+            CSR_TSELECT => {
+                // WARL: out-of-range selections are ignored.
+                if (value as usize) < TRIGGER_COUNT {
+                    self.tselect = value as usize;
+                }
+            },
+            CSR_TDATA1 => {
+                let ty = value >> TRIGGER_TYPE_SHIFT;
+                let value = match ty {
+                    TRIGGER_TYPE_MCONTROL | TRIGGER_TYPE_MCONTROL6 => value,
+                    _ => TRIGGER_TYPE_DISABLED << TRIGGER_TYPE_SHIFT,
+                };
+                self.triggers[self.tselect].tdata1 = value;
+            },
+            CSR_TDATA2 => self.triggers[self.tselect].tdata2 = value,
+            CSR_TDATA3 | CSR_TINFO => {},
+            _ => return None,
+        }
+        self.armed = self.triggers.iter().any(|t| {
+            t.tdata1 & (TDATA1_LOAD | TDATA1_STORE | TDATA1_EXECUTE) != 0
+                && matches!(t.ty(), TRIGGER_TYPE_MCONTROL | TRIGGER_TYPE_MCONTROL6)
+        });
+        Some(())
+    }
+
+    /// Matches an access against all triggers, returns the index of the first one that fires.
+    /// Address triggers compare 'addr', data triggers compare 'data' if the access has any.
+    pub fn check(
+        &self,
+        access: MemAccess,
+        addr: u64,
+        len: usize,
+        data: Option<u64>,
+        privilege: Privilege,
+    ) -> Option<usize> {
+        if !self.armed {
+            return None;
+        }
+        let bit = match access {
+            MemAccess::Read => TDATA1_LOAD,
+            MemAccess::Write => TDATA1_STORE,
+            MemAccess::Execute => TDATA1_EXECUTE,
+        };
+        self.triggers.iter().position(|trigger| {
+            if !matches!(trigger.ty(), TRIGGER_TYPE_MCONTROL | TRIGGER_TYPE_MCONTROL6)
+                || trigger.tdata1 & bit == 0
+                || !trigger.enabled_in(privilege)
+                || trigger.action().is_none() {
+                return false;
+            }
+            match (trigger.select(), data) {
+                (false, _) => trigger.matches(addr, len),
+                (true, Some(data)) => trigger.matches(data, 1),
+                (true, None) => false,
+            }
+        })
+    }
+
+    /// Records a hit on trigger 'idx' and returns what it asks for.
+    pub fn fire(&mut self, idx: usize) -> TriggerAction {
+        let trigger = &mut self.triggers[idx];
+        trigger.set_hit();
+        trigger.action().unwrap_or(TriggerAction::Exception)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mcontrol(bits: u64, match_kind: u64) -> u64 {
+        TRIGGER_TYPE_MCONTROL << TRIGGER_TYPE_SHIFT | TDATA1_M | match_kind << TDATA1_MATCH_SHIFT | bits
+    }
+
+    #[test]
+    fn test_select_and_disable() {
+        let mut triggers = Triggers::new();
+        triggers.write_csr(CSR_TSELECT, 2).unwrap();
+        assert_eq!(triggers.read_csr(CSR_TSELECT), Some(2));
+        triggers.write_csr(CSR_TSELECT, TRIGGER_COUNT as u64).unwrap();
+        assert_eq!(triggers.read_csr(CSR_TSELECT), Some(2));
+
+        // unsupported types read back as disabled
+        triggers.write_csr(CSR_TDATA1, 3 << TRIGGER_TYPE_SHIFT).unwrap();
+        assert_eq!(triggers.read_csr(CSR_TDATA1), Some(TRIGGER_TYPE_DISABLED << TRIGGER_TYPE_SHIFT));
+        assert!(!triggers.armed());
+    }
+
+    #[test]
+    fn test_address_match() {
+        let mut triggers = Triggers::new();
+        triggers.write_csr(CSR_TDATA1, mcontrol(TDATA1_STORE, 0)).unwrap();
+        triggers.write_csr(CSR_TDATA2, 0x1004).unwrap();
+        assert!(triggers.armed());
+
+        assert_eq!(triggers.check(MemAccess::Read, 0x1004, 4, None, Privilege::Machine), None);
+        assert_eq!(triggers.check(MemAccess::Write, 0x1000, 4, None, Privilege::Machine), None);
+        assert_eq!(triggers.check(MemAccess::Write, 0x1000, 8, None, Privilege::Machine), Some(0));
+        assert_eq!(triggers.read_csr(CSR_TDATA1).unwrap() & MCONTROL_HIT, 0);
+        assert_eq!(triggers.fire(0), TriggerAction::Exception);
+        assert_ne!(triggers.read_csr(CSR_TDATA1).unwrap() & MCONTROL_HIT, 0);
+        // the trigger is not enabled for U-mode
+        assert_eq!(triggers.check(MemAccess::Write, 0x1004, 4, None, Privilege::User), None);
+    }
+
+    #[test]
+    fn test_napot_and_data_match() {
+        let mut triggers = Triggers::new();
+        // execute anywhere in [0x2000, 0x2010)
+        triggers.write_csr(CSR_TDATA1, mcontrol(TDATA1_EXECUTE, 1)).unwrap();
+        triggers.write_csr(CSR_TDATA2, 0x2000 | 0x7).unwrap();
+        triggers.write_csr(CSR_TSELECT, 1).unwrap();
+        // mcontrol6 load of the value 0x42, entering debug mode
+        let tdata1 = TRIGGER_TYPE_MCONTROL6 << TRIGGER_TYPE_SHIFT | MCONTROL6_SELECT
+            | 1 << TDATA1_ACTION_SHIFT | TDATA1_M | TDATA1_LOAD;
+        triggers.write_csr(CSR_TDATA1, tdata1).unwrap();
+        triggers.write_csr(CSR_TDATA2, 0x42).unwrap();
+
+        assert_eq!(triggers.check(MemAccess::Execute, 0x200c, 4, None, Privilege::Machine), Some(0));
+        assert_eq!(triggers.check(MemAccess::Execute, 0x2010, 4, None, Privilege::Machine), None);
+        assert_eq!(triggers.check(MemAccess::Read, 0x3000, 8, Some(0x41), Privilege::Machine), None);
+        assert_eq!(triggers.check(MemAccess::Read, 0x3000, 8, Some(0x42), Privilege::Machine), Some(1));
+        assert_eq!(triggers.fire(1), TriggerAction::DebugMode);
+    }
+}